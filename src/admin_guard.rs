@@ -0,0 +1,106 @@
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use actix_session::SessionExt;
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    web, Error, HttpMessage, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+use tracing::{info, warn};
+
+use crate::{
+    browser_session,
+    types::{AdminIdentity, AppState},
+};
+
+/// Middleware that only lets administrators who signed in through
+/// `/admin/login` through. Others are sent to sign in, or refused if they
+/// already have and are not an administrator. Handlers can read the
+/// administrator as `web::ReqData<AdminIdentity>`.
+pub struct RequireAdmin;
+
+impl<S, B> Transform<S, ServiceRequest> for RequireAdmin
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireAdminMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireAdminMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequireAdminMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireAdminMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let state = match req.app_data::<web::Data<AppState>>() {
+                Some(s) => s.clone(),
+                None => {
+                    warn!("Admin guard is missing the app state");
+                    let response = HttpResponse::InternalServerError().finish();
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            };
+            if !state.config.admin_panel_enabled {
+                let response = HttpResponse::Forbidden().body("Admin panel is not enabled");
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            let session = req.get_session();
+            // The browser login has to still be valid too, so disabling an
+            // administrator or ending their sessions signs them out here.
+            let user = match browser_session::admin_user_id(&session) {
+                Some(id) => browser_session::current_user(&state, &session)
+                    .await
+                    .filter(|u| u.id == Some(id)),
+                None => None,
+            };
+            let response = match user {
+                Some(u) if u.is_admin => {
+                    req.extensions_mut().insert(AdminIdentity {
+                        id: u.id.unwrap(),
+                        username: u.username,
+                    });
+                    return Ok(service.call(req).await?.map_into_left_body());
+                }
+                Some(u) => {
+                    // Checked on every request so removing the role takes
+                    // effect straight away.
+                    info!("{} is no longer an administrator", u.username);
+                    browser_session::clear_admin_user_id(&session);
+                    HttpResponse::Forbidden().body("This account is not an administrator")
+                }
+                None => HttpResponse::SeeOther()
+                    .insert_header((header::LOCATION, "/admin/login"))
+                    .finish(),
+            };
+            Ok(req.into_response(response).map_into_right_body())
+        })
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    web, Error, HttpMessage, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+use tracing::{info, warn};
+
+use crate::{
+    password,
+    types::{ApiClient, ApiErrorResponse, AppState},
+};
+
+fn unauthorized(message: &str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Bearer error=\"invalid_token\""))
+        .json(ApiErrorResponse {
+            error: "invalid_token".to_owned(),
+            message: message.to_owned(),
+        })
+}
+
+/// Middleware that only lets through requests carrying an access token from
+/// the `client_credentials` grant. Handlers can read the calling
+/// application as `web::ReqData<ApiClient>` and check its scopes.
+pub struct RequireServiceToken;
+
+impl<S, B> Transform<S, ServiceRequest> for RequireServiceToken
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireServiceTokenMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireServiceTokenMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequireServiceTokenMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireServiceTokenMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        Box::pin(async move {
+            let state = match req.app_data::<web::Data<AppState>>() {
+                Some(s) => s.clone(),
+                None => {
+                    warn!("API token check is missing the app state");
+                    let response = HttpResponse::InternalServerError().finish();
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            };
+            let token = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.strip_prefix("Bearer "))
+                .map(str::to_owned);
+            let token = match token {
+                Some(t) => t,
+                None => {
+                    let response = unauthorized("A bearer token is required");
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            };
+            let service_token = match state
+                .database
+                .service_token_by_hash(&password::hash_token(token.trim()))
+                .await
+            {
+                Ok(t) => t,
+                Err(e) => {
+                    warn!("Failed to load service token: {}", e);
+                    let response = HttpResponse::InternalServerError().json(ApiErrorResponse {
+                        error: "server_error".to_owned(),
+                        message: "Failed to check the access token".to_owned(),
+                    });
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            };
+            let app = match service_token {
+                Some(t) => state
+                    .database
+                    .app_by_id(t.client_id)
+                    .await
+                    .map(|app| (app, t.scopes)),
+                None => None,
+            };
+            let (app, mut scopes) = match app {
+                Some(a) => a,
+                None => {
+                    info!("Unknown or expired API token used");
+                    let response = unauthorized("The access token is invalid or expired");
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            };
+            // Scopes taken away from the application since the token was
+            // issued no longer count.
+            scopes.retain(|s| app.allowed_scopes.contains(s));
+            req.extensions_mut().insert(ApiClient {
+                client_id: app.client_id,
+                scopes,
+            });
+            Ok(service.call(req).await?.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,43 @@
+use tracing::{info, warn};
+
+use crate::{db::Database, types::DbAuditEvent};
+
+pub const EVENT_LOGIN_LOCKED: &str = "login_locked";
+pub const EVENT_LOGIN_UNLOCKED: &str = "login_unlocked";
+pub const EVENT_USER_CREATED: &str = "user_created";
+pub const EVENT_USER_UPDATED: &str = "user_updated";
+pub const EVENT_USER_DISABLED: &str = "user_disabled";
+pub const EVENT_USER_ENABLED: &str = "user_enabled";
+pub const EVENT_USER_DELETED: &str = "user_deleted";
+pub const EVENT_USER_PASSWORD_SET: &str = "user_password_set";
+pub const EVENT_USER_LOGGED_OUT: &str = "user_logged_out";
+pub const EVENT_GROUP_CREATED: &str = "group_created";
+pub const EVENT_GROUP_UPDATED: &str = "group_updated";
+pub const EVENT_GROUP_DELETED: &str = "group_deleted";
+pub const EVENT_ROLE_CREATED: &str = "role_created";
+pub const EVENT_ROLE_UPDATED: &str = "role_updated";
+pub const EVENT_ROLE_DELETED: &str = "role_deleted";
+pub const EVENT_POLICY_RULE_CREATED: &str = "policy_rule_created";
+pub const EVENT_POLICY_RULE_UPDATED: &str = "policy_rule_updated";
+pub const EVENT_POLICY_RULE_DELETED: &str = "policy_rule_deleted";
+pub const EVENT_APPLICATION_CREATED: &str = "application_created";
+pub const EVENT_APPLICATION_UPDATED: &str = "application_updated";
+pub const EVENT_APPLICATION_SECRET_ADDED: &str = "application_secret_added";
+pub const EVENT_APPLICATION_SECRET_EXPIRED: &str = "application_secret_expired";
+pub const EVENT_APPLICATION_SECRET_DELETED: &str = "application_secret_deleted";
+pub const EVENT_APPLICATION_DELETED: &str = "application_deleted";
+
+/// Appends an event to the audit log. Failures are logged rather than
+/// returned so auditing never blocks the action being audited.
+pub async fn record(database: &Database, event: &str, subject: &str, detail: String) {
+    info!("Audit: {} {} {}", event, subject, detail);
+    let event = DbAuditEvent {
+        at: bson::DateTime::now(),
+        event: event.to_owned(),
+        subject: subject.to_owned(),
+        detail,
+    };
+    if let Err(e) = database.insert_audit_event(&event).await {
+        warn!("Failed to write audit event: {}", e);
+    }
+}
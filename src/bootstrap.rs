@@ -0,0 +1,51 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{
+    db::Database,
+    password, password_policy,
+    types::{Config, DbUser, UserProfile},
+};
+
+/// `auth-server create-admin <username>`: creates an administrator, or
+/// makes an existing user one, so the admin panel can be signed in to on a
+/// fresh install. The password is read from standard input.
+pub async fn create_admin(
+    config: &Config,
+    database: &Database,
+    username: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(mut user) = database.user_by_username(username).await {
+        user.is_admin = true;
+        database.update_user(&user).await?;
+        println!("{} is now an administrator.", username);
+        return Ok(());
+    }
+    print!("Password for {}: ", username);
+    io::stdout().flush()?;
+    let mut cleartext_password = String::new();
+    io::stdin().lock().read_line(&mut cleartext_password)?;
+    let cleartext_password = cleartext_password.trim_end_matches(['\r', '\n']);
+    if let Err(violations) = password_policy::check(config, username, cleartext_password).await {
+        for violation in violations {
+            eprintln!("{}", violation);
+        }
+        return Err("Password rejected".into());
+    }
+    let password_hash =
+        password::hash_password(cleartext_password).ok_or("Failed to hash password")?;
+    let user = DbUser {
+        id: None,
+        username: username.to_owned(),
+        password_hash,
+        profile: UserProfile::default(),
+        must_verify_email: false,
+        totp: None,
+        is_admin: true,
+        disabled: false,
+        sessions_valid_after: 0,
+        external_id: None,
+    };
+    database.insert_user(&user).await?;
+    println!("Created administrator {}.", username);
+    Ok(())
+}
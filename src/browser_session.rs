@@ -0,0 +1,279 @@
+use actix_session::Session;
+use bson::oid::ObjectId;
+use tracing::warn;
+
+use crate::{
+    routes::auth::{generate_random_code, now},
+    types::{
+        AppState, AuthenticationContext, DbPendingLogin, DbUser, DbWebauthnChallenge,
+        LoginAuthorization,
+    },
+};
+
+const USER_ID_KEY: &str = "user_id";
+const AUTHENTICATION_KEY: &str = "authentication";
+const PENDING_LOGIN_KEY: &str = "pending_login";
+const ADMIN_USER_ID_KEY: &str = "admin_user_id";
+const ADMIN_LOGIN_STATE_KEY: &str = "admin_login_state";
+/// How long a user has to enter their second factor after the password.
+const PENDING_LOGIN_LIFETIME_SECONDS: u64 = 5 * 60;
+pub const MAX_SECOND_FACTOR_ATTEMPTS: u32 = 5;
+/// How long the browser has to answer a WebAuthn challenge.
+const CHALLENGE_LIFETIME_SECONDS: u64 = 5 * 60;
+
+/// WebAuthn ceremonies a challenge can be issued for. Each has its own
+/// slot so starting one does not cancel the other.
+pub enum Ceremony {
+    Registration,
+    Login,
+}
+
+impl Ceremony {
+    fn key(&self) -> &'static str {
+        match self {
+            Ceremony::Registration => "webauthn_registration",
+            Ceremony::Login => "webauthn_login",
+        }
+    }
+}
+
+/// User logged in to this browser, if any.
+pub fn user_id(session: &Session) -> Option<ObjectId> {
+    match session.get::<ObjectId>(USER_ID_KEY) {
+        Ok(id) => id,
+        Err(e) => {
+            warn!("user_id(..) - failed to read session: {}", e);
+            None
+        }
+    }
+}
+
+/// The user logged in to this browser, unless an administrator has since
+/// disabled them or ended their sessions.
+pub async fn current_user(state: &AppState, session: &Session) -> Option<DbUser> {
+    let user = state.database.user_by_id(user_id(session)?).await?;
+    let authentication = authentication(session)?;
+    if user.disabled || authentication.auth_time < user.sessions_valid_after {
+        // Only the login is dropped; the rest of the session, such as the
+        // CSRF token for the form shown next, stays usable.
+        session.remove(USER_ID_KEY);
+        session.remove(AUTHENTICATION_KEY);
+        session.remove(ADMIN_USER_ID_KEY);
+        return None;
+    }
+    Some(user)
+}
+
+pub fn log_in(session: &Session, user_id: ObjectId, authentication: &AuthenticationContext) {
+    // A fresh session ID on login prevents session fixation.
+    session.renew();
+    session.remove(PENDING_LOGIN_KEY);
+    // Admin access belongs to whoever signed in to the panel, so it does
+    // not carry over to a different login.
+    session.remove(ADMIN_USER_ID_KEY);
+    if let Err(e) = session
+        .insert(USER_ID_KEY, user_id)
+        .and_then(|_| session.insert(AUTHENTICATION_KEY, authentication))
+    {
+        warn!("log_in(..) - failed to write session: {}", e);
+    }
+}
+
+/// How the logged in user authenticated to this browser.
+pub fn authentication(session: &Session) -> Option<AuthenticationContext> {
+    match session.get::<AuthenticationContext>(AUTHENTICATION_KEY) {
+        Ok(a) => a,
+        Err(e) => {
+            warn!("authentication(..) - failed to read session: {}", e);
+            None
+        }
+    }
+}
+
+pub fn log_out(session: &Session) {
+    session.purge();
+}
+
+/// User who signed in to the admin panel from this browser, if any.
+pub fn admin_user_id(session: &Session) -> Option<ObjectId> {
+    match session.get::<ObjectId>(ADMIN_USER_ID_KEY) {
+        Ok(id) => id,
+        Err(e) => {
+            warn!("admin_user_id(..) - failed to read session: {}", e);
+            None
+        }
+    }
+}
+
+pub fn set_admin_user_id(session: &Session, user_id: ObjectId) {
+    if let Err(e) = session.insert(ADMIN_USER_ID_KEY, user_id) {
+        warn!("set_admin_user_id(..) - failed to write session: {}", e);
+    }
+}
+
+pub fn clear_admin_user_id(session: &Session) {
+    session.remove(ADMIN_USER_ID_KEY);
+}
+
+/// Starts an admin panel login, returning the `state` its callback has to
+/// bring back.
+pub fn begin_admin_login(session: &Session) -> String {
+    let state = generate_random_code(32);
+    if let Err(e) = session.insert(ADMIN_LOGIN_STATE_KEY, &state) {
+        warn!("begin_admin_login(..) - failed to write session: {}", e);
+    }
+    state
+}
+
+/// Removes and returns the `state` of the admin panel login in progress.
+pub fn take_admin_login_state(session: &Session) -> Option<String> {
+    match session.remove_as::<String>(ADMIN_LOGIN_STATE_KEY)? {
+        Ok(state) => Some(state),
+        Err(e) => {
+            warn!("take_admin_login_state(..) - failed to read session: {}", e);
+            None
+        }
+    }
+}
+
+/// Stores a login waiting for a second factor and points the browser
+/// session at it.
+pub async fn begin_pending_login(
+    state: &AppState,
+    session: &Session,
+    user_id: ObjectId,
+    authorization: Option<LoginAuthorization>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pending = DbPendingLogin {
+        id: generate_random_code(32),
+        user_id,
+        failed_attempts: 0,
+        authorization,
+        expires_at: bson::DateTime::from_millis(
+            ((now() + PENDING_LOGIN_LIFETIME_SECONDS) * 1000) as i64,
+        ),
+    };
+    state.database.insert_pending_login(&pending).await?;
+    session.insert(PENDING_LOGIN_KEY, &pending.id)?;
+    Ok(())
+}
+
+fn pending_login_id(session: &Session) -> Option<String> {
+    match session.get::<String>(PENDING_LOGIN_KEY) {
+        Ok(id) => id,
+        Err(e) => {
+            warn!("pending_login_id(..) - failed to read session: {}", e);
+            None
+        }
+    }
+}
+
+/// The login waiting for a second factor, unless it has expired.
+pub async fn pending_login(state: &AppState, session: &Session) -> Option<DbPendingLogin> {
+    let id = pending_login_id(session)?;
+    match state.database.pending_login(&id).await {
+        Ok(Some(p)) => Some(p),
+        Ok(None) => {
+            session.remove(PENDING_LOGIN_KEY);
+            None
+        }
+        Err(e) => {
+            warn!("pending_login(..) - failed to load pending login: {}", e);
+            None
+        }
+    }
+}
+
+/// Counts a wrong second factor against the pending login. Returns whether
+/// the login may still be tried; after too many failures it is dropped.
+pub async fn record_second_factor_failure(
+    state: &AppState,
+    session: &Session,
+    pending: &DbPendingLogin,
+) -> bool {
+    let failed_attempts = match state
+        .database
+        .record_pending_login_failure(&pending.id)
+        .await
+    {
+        Ok(Some(p)) => p.failed_attempts,
+        Ok(None) => MAX_SECOND_FACTOR_ATTEMPTS,
+        Err(e) => {
+            warn!(
+                "record_second_factor_failure(..) - failed to count attempt: {}",
+                e
+            );
+            MAX_SECOND_FACTOR_ATTEMPTS
+        }
+    };
+    if failed_attempts >= MAX_SECOND_FACTOR_ATTEMPTS {
+        clear_pending_login(state, session).await;
+        return false;
+    }
+    true
+}
+
+pub async fn clear_pending_login(state: &AppState, session: &Session) {
+    if let Some(id) = pending_login_id(session) {
+        if let Err(e) = state.database.delete_pending_login(&id).await {
+            warn!("clear_pending_login(..) - failed to delete: {}", e);
+        }
+    }
+    session.remove(PENDING_LOGIN_KEY);
+}
+
+/// Stores a challenge for `ceremony` and points the browser session at it,
+/// replacing any earlier challenge for the same ceremony.
+pub async fn begin_webauthn_challenge(
+    state: &AppState,
+    session: &Session,
+    ceremony: Ceremony,
+    challenge: String,
+    authorization: Option<LoginAuthorization>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let challenge = DbWebauthnChallenge {
+        id: generate_random_code(32),
+        ceremony: ceremony.key().to_owned(),
+        challenge,
+        expires_at: bson::DateTime::from_millis(
+            ((now() + CHALLENGE_LIFETIME_SECONDS) * 1000) as i64,
+        ),
+        authorization,
+    };
+    state.database.insert_webauthn_challenge(&challenge).await?;
+    session.insert(ceremony.key(), &challenge.id)?;
+    Ok(())
+}
+
+/// Removes and returns the challenge for `ceremony` so it can only be
+/// answered once.
+pub async fn take_webauthn_challenge(
+    state: &AppState,
+    session: &Session,
+    ceremony: Ceremony,
+) -> Option<DbWebauthnChallenge> {
+    let id = match session.remove_as::<String>(ceremony.key())? {
+        Ok(id) => id,
+        Err(e) => {
+            warn!(
+                "take_webauthn_challenge(..) - failed to read session: {}",
+                e
+            );
+            return None;
+        }
+    };
+    match state
+        .database
+        .take_webauthn_challenge(&id, ceremony.key())
+        .await
+    {
+        Ok(challenge) => challenge,
+        Err(e) => {
+            warn!(
+                "take_webauthn_challenge(..) - failed to load challenge: {}",
+                e
+            );
+            None
+        }
+    }
+}
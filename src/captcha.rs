@@ -0,0 +1,28 @@
+use async_trait::async_trait;
+
+/// Hook for challenging sign-ups with a CAPTCHA. Implementations receive the
+/// client IP so they can decide per address whether a challenge is needed,
+/// e.g. only after repeated sign-ups from the same network.
+#[async_trait]
+pub trait Captcha: Send + Sync {
+    /// HTML for the challenge shown on the sign-up form. The widget must
+    /// submit its answer in a `captcha_response` field.
+    fn widget(&self) -> Option<String>;
+
+    /// Checks the answer submitted with a sign-up from `client_ip`.
+    async fn verify(&self, client_ip: &str, response: &str) -> bool;
+}
+
+/// Accepts every sign-up without a challenge.
+pub struct NoCaptcha;
+
+#[async_trait]
+impl Captcha for NoCaptcha {
+    fn widget(&self) -> Option<String> {
+        None
+    }
+
+    async fn verify(&self, _client_ip: &str, _response: &str) -> bool {
+        true
+    }
+}
@@ -0,0 +1,183 @@
+use serde_json::{Map, Value};
+
+use crate::{
+    routes::auth::now,
+    types::{AuthenticationContext, DbApplication, DbUser, UserAuthorization},
+};
+
+pub const SCOPE_OPENID: &str = "openid";
+pub const SCOPE_PROFILE: &str = "profile";
+pub const SCOPE_EMAIL: &str = "email";
+pub const SCOPE_GROUPS: &str = "groups";
+pub const SCOPE_ROLES: &str = "roles";
+pub const SCOPE_PERMISSIONS: &str = "permissions";
+
+/// Authentication method references from RFC 8176.
+pub const AMR_PASSWORD: &str = "pwd";
+pub const AMR_OTP: &str = "otp";
+pub const AMR_HARDWARE_KEY: &str = "hwk";
+pub const AMR_MULTI_FACTOR: &str = "mfa";
+/// RFC 8176 has no value for single-use recovery codes, and they are not a
+/// one-time password from the user's device, so they get their own.
+pub const AMR_RECOVERY_CODE: &str = "recovery_code";
+
+/// Authentication context classes reported in `acr` and accepted in
+/// `acr_values`.
+pub const ACR_SINGLE_FACTOR: &str = "urn:snazzyfellas:acr:sfa";
+pub const ACR_MULTI_FACTOR: &str = "urn:snazzyfellas:acr:mfa";
+/// OpenID PAPE multi-factor policy, accepted as a synonym for
+/// `ACR_MULTI_FACTOR`.
+const ACR_PAPE_MULTI_FACTOR: &str = "http://schemas.openid.net/pape/policies/2007/06/multi-factor";
+
+/// Claim names custom attributes may not use.
+const RESERVED_CLAIMS: [&str; 25] = [
+    "sub",
+    "iss",
+    "aud",
+    "exp",
+    "iat",
+    "nbf",
+    "jti",
+    "nonce",
+    "azp",
+    "auth_time",
+    "acr",
+    "amr",
+    "preferred_username",
+    "name",
+    "given_name",
+    "family_name",
+    "picture",
+    "locale",
+    "zoneinfo",
+    "updated_at",
+    "email",
+    "email_verified",
+    "groups",
+    "roles",
+    "permissions",
+];
+
+/// Splits a space-delimited `scope` parameter. Requests without a scope are
+/// treated as plain OpenID Connect requests.
+pub fn parse_scopes(scope: &str) -> Vec<String> {
+    let mut scopes = Vec::<String>::new();
+    for s in scope.split_whitespace() {
+        if !scopes.iter().any(|existing| existing == s) {
+            scopes.push(s.to_owned());
+        }
+    }
+    if scopes.is_empty() {
+        scopes.push(SCOPE_OPENID.to_owned());
+    }
+    scopes
+}
+
+/// The scopes of a `scope` parameter that `app` may be granted. Apps
+/// without a scope allowlist may be granted any scope.
+pub fn allowed_scopes(app: &DbApplication, scope: &str) -> Vec<String> {
+    let mut scopes = parse_scopes(scope);
+    if !app.allowed_scopes.is_empty() {
+        scopes.retain(|s| app.allowed_scopes.contains(s));
+    }
+    scopes
+}
+
+fn insert_optional(claims: &mut Map<String, Value>, key: &str, value: &Option<String>) {
+    if let Some(v) = value {
+        claims.insert(key.to_owned(), Value::String(v.clone()));
+    }
+}
+
+/// Builds the user claims released for `scopes`, excluding `sub` which
+/// callers always provide themselves.
+pub fn user_claims(user: &DbUser, scopes: &[String]) -> Map<String, Value> {
+    let has_scope = |scope: &str| scopes.iter().any(|s| s == scope);
+    let profile = &user.profile;
+    let mut claims = Map::new();
+    if has_scope(SCOPE_PROFILE) {
+        claims.insert(
+            "preferred_username".to_owned(),
+            Value::String(user.username.clone()),
+        );
+        insert_optional(&mut claims, "name", &profile.name);
+        insert_optional(&mut claims, "given_name", &profile.given_name);
+        insert_optional(&mut claims, "family_name", &profile.family_name);
+        insert_optional(&mut claims, "picture", &profile.picture);
+        insert_optional(&mut claims, "locale", &profile.locale);
+        insert_optional(&mut claims, "zoneinfo", &profile.zoneinfo);
+        if let Some(updated_at) = profile.updated_at {
+            claims.insert("updated_at".to_owned(), Value::from(updated_at));
+        }
+        for (key, value) in &profile.custom_attributes {
+            if !RESERVED_CLAIMS.contains(&key.as_str()) {
+                claims.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    if has_scope(SCOPE_EMAIL) {
+        insert_optional(&mut claims, "email", &profile.email);
+        if profile.email.is_some() {
+            claims.insert(
+                "email_verified".to_owned(),
+                Value::Bool(profile.email_verified),
+            );
+        }
+    }
+    claims
+}
+
+/// Whether `scopes` ask for any of the group, role or permission claims.
+pub fn requests_authorization(scopes: &[String]) -> bool {
+    scopes
+        .iter()
+        .any(|s| s == SCOPE_GROUPS || s == SCOPE_ROLES || s == SCOPE_PERMISSIONS)
+}
+
+/// Builds the group, role and permission claims released for `scopes`.
+/// Roles and permissions are those of the application the token is for.
+pub fn authorization_claims(
+    authorization: &UserAuthorization,
+    scopes: &[String],
+) -> Map<String, Value> {
+    let has_scope = |scope: &str| scopes.iter().any(|s| s == scope);
+    let mut claims = Map::new();
+    if has_scope(SCOPE_GROUPS) {
+        claims.insert("groups".to_owned(), authorization.groups.clone().into());
+    }
+    if has_scope(SCOPE_ROLES) {
+        claims.insert("roles".to_owned(), authorization.roles.clone().into());
+    }
+    if has_scope(SCOPE_PERMISSIONS) {
+        claims.insert(
+            "permissions".to_owned(),
+            authorization.permissions.clone().into(),
+        );
+    }
+    claims
+}
+
+/// Context for a login that just finished using `methods`.
+pub fn authentication_context(methods: &[&str]) -> AuthenticationContext {
+    AuthenticationContext {
+        amr: methods.iter().map(|m| (*m).to_owned()).collect(),
+        auth_time: now(),
+    }
+}
+
+pub fn acr(authentication: &AuthenticationContext) -> &'static str {
+    if authentication.amr.iter().any(|m| m == AMR_MULTI_FACTOR) {
+        ACR_MULTI_FACTOR
+    } else {
+        ACR_SINGLE_FACTOR
+    }
+}
+
+/// Whether `authentication` meets every level requested in `acr_values`.
+/// Unknown values are ignored, as OpenID Connect treats them as voluntary.
+pub fn satisfies_acr_values(authentication: &AuthenticationContext, acr_values: &str) -> bool {
+    let wants_multi_factor = acr_values
+        .split_whitespace()
+        .any(|v| v == ACR_MULTI_FACTOR || v == ACR_PAPE_MULTI_FACTOR);
+    !wants_multi_factor || acr(authentication) == ACR_MULTI_FACTOR
+}
@@ -0,0 +1,451 @@
+use std::str::FromStr;
+
+use actix_web::{http::header, HttpRequest};
+use base64::{self, engine::Engine};
+use jsonwebtoken::{self, Algorithm, DecodingKey, Validation};
+use serde::{de::DeserializeOwned, Deserialize};
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+
+use crate::{
+    db::Database,
+    password,
+    routes::auth::{generate_random_code, now, ISSUER},
+    types::{self, DbApplication, DbClientSecret},
+};
+
+pub const JWT_BEARER_ASSERTION_TYPE: &str =
+    "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+pub const CLIENT_SECRET_BASIC: &str = "client_secret_basic";
+pub const CLIENT_SECRET_POST: &str = "client_secret_post";
+pub const CLIENT_SECRET_JWT: &str = "client_secret_jwt";
+pub const PRIVATE_KEY_JWT: &str = "private_key_jwt";
+pub const SUPPORTED_AUTH_METHODS: [&str; 4] = [
+    CLIENT_SECRET_BASIC,
+    CLIENT_SECRET_POST,
+    CLIENT_SECRET_JWT,
+    PRIVATE_KEY_JWT,
+];
+
+#[derive(Deserialize)]
+struct ClientAssertionClaims {
+    jti: String,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct UnverifiedIssuer {
+    iss: String,
+}
+
+/// Reads `iss` from a JWT without checking its signature. Only used to find
+/// which client's keys the JWT must then be verified with.
+fn unverified_issuer(jwt: &str) -> Option<String> {
+    let payload = jwt.split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()?;
+    let claims = serde_json::from_slice::<UnverifiedIssuer>(&payload).ok()?;
+    Some(claims.iss)
+}
+
+/// Whether `app` keeps its secrets in a form usable as HMAC keys. Only apps
+/// registered for `client_secret_jwt` or for HMAC signed request objects
+/// do; everyone else's are only hashed.
+pub fn keeps_secret_values(app: &DbApplication) -> bool {
+    app.token_endpoint_auth_method.as_deref() == Some(CLIENT_SECRET_JWT)
+        || app
+            .request_object_signing_alg
+            .as_deref()
+            .and_then(|alg| Algorithm::from_str(alg).ok())
+            .is_some_and(is_hmac)
+}
+
+/// Checks a `request_object_signing_alg` a client registers. Algorithms
+/// other than HMAC need the client's JWKS.
+pub fn check_request_object_signing_alg(alg: &str, has_jwks: bool) -> Result<(), String> {
+    match Algorithm::from_str(alg) {
+        Ok(a) if is_hmac(a) || has_jwks => Ok(()),
+        Ok(_) => Err(format!("request_object_signing_alg {} requires jwks", alg)),
+        Err(_) => Err(format!("Unsupported request_object_signing_alg {}", alg)),
+    }
+}
+
+/// Generates a client secret for `app`, returning it along with what is
+/// stored. The secret itself cannot be recovered from the stored form.
+pub fn new_secret(
+    app: &DbApplication,
+    label: &str,
+    expires_at: Option<u64>,
+) -> (String, DbClientSecret) {
+    let secret = generate_random_code(128);
+    let stored = DbClientSecret {
+        id: generate_random_code(16),
+        label: label.to_owned(),
+        hash: password::hash_token(&secret),
+        value: keeps_secret_values(app).then(|| secret.clone()),
+        created_at: now(),
+        expires_at,
+    };
+    (secret, stored)
+}
+
+/// Whether a stored secret is still accepted.
+pub fn secret_active(secret: &DbClientSecret) -> bool {
+    secret.expires_at.is_none_or(|at| at > now())
+}
+
+/// Client secrets `app` currently accepts.
+fn accepted_secrets(app: &DbApplication) -> impl Iterator<Item = &DbClientSecret> {
+    app.secrets.iter().filter(|s| secret_active(s))
+}
+
+/// Selects the keys a JWT signed by `app` may be verified with. HMAC
+/// algorithms use the accepted client secrets, everything else a key from
+/// the application's JWKS.
+pub fn decoding_keys(app: &DbApplication, alg: Algorithm, kid: Option<&str>) -> Vec<DecodingKey> {
+    if is_hmac(alg) {
+        let keys: Vec<DecodingKey> = accepted_secrets(app)
+            .filter_map(|s| s.value.as_deref())
+            .map(|s| DecodingKey::from_secret(s.as_bytes()))
+            .collect();
+        if keys.is_empty() {
+            warn!("decoding_keys(..) - application keeps no secrets usable for HMAC");
+        }
+        return keys;
+    }
+    let jwks = match &app.jwks {
+        Some(j) => j,
+        None => {
+            warn!("decoding_keys(..) - application has no registered JWKS");
+            return vec![];
+        }
+    };
+    let jwk = match kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    };
+    let jwk = match jwk {
+        Some(j) => j,
+        None => {
+            warn!("decoding_keys(..) - no matching key in application JWKS");
+            return vec![];
+        }
+    };
+    match DecodingKey::from_jwk(jwk) {
+        Ok(k) => vec![k],
+        Err(e) => {
+            warn!("decoding_keys(..) - failed to load JWK: {}", e);
+            vec![]
+        }
+    }
+}
+
+/// Decodes a JWT with the first of `keys` that verifies it.
+pub fn decode_with_any<T: DeserializeOwned>(
+    token: &str,
+    keys: &[DecodingKey],
+    validation: &Validation,
+) -> Result<T, String> {
+    let mut last_error = "No key to verify with".to_owned();
+    for key in keys {
+        match jsonwebtoken::decode::<T>(token, key, validation) {
+            Ok(t) => return Ok(t.claims),
+            Err(e) => last_error = e.to_string(),
+        }
+    }
+    Err(last_error)
+}
+
+/// Client ID and secret from an HTTP Basic `Authorization` header.
+pub fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    let auth_header = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = auth_header.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (client_id, client_secret) = decoded.split_once(':')?;
+    Some((
+        urlencoding::decode(client_id).ok()?.into_owned(),
+        urlencoding::decode(client_secret).ok()?.into_owned(),
+    ))
+}
+
+fn method_allowed(app: &DbApplication, method: &str) -> bool {
+    match &app.token_endpoint_auth_method {
+        Some(m) if m != method => {
+            info!("Client {} must authenticate with {}", app.client_id, m);
+            false
+        }
+        _ => true,
+    }
+}
+
+pub fn is_hmac(alg: Algorithm) -> bool {
+    matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+fn secret_matches(app: &DbApplication, secret: &str) -> bool {
+    let hash = password::hash_token(secret);
+    // Check every secret so timing does not show which one matched.
+    accepted_secrets(app).fold(false, |matched, s| {
+        matched | bool::from(s.hash.as_bytes().ct_eq(hash.as_bytes()))
+    })
+}
+
+/// Checks a client assertion's signature and claims, returning the `jti`
+/// record that must then be stored to stop the assertion being replayed.
+fn decode_client_assertion(
+    app: &DbApplication,
+    client_id: &str,
+    assertion: &str,
+) -> Option<types::DbClientAssertionJti> {
+    let header = match jsonwebtoken::decode_header(assertion) {
+        Ok(h) => h,
+        Err(e) => {
+            info!("Failed to decode client assertion header: {}", e);
+            return None;
+        }
+    };
+    let method = if is_hmac(header.alg) {
+        CLIENT_SECRET_JWT
+    } else {
+        PRIVATE_KEY_JWT
+    };
+    if !method_allowed(app, method) {
+        return None;
+    }
+    let keys = decoding_keys(app, header.alg, header.kid.as_deref());
+    let token_endpoint = format!("{}/token", ISSUER);
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[client_id]);
+    validation.set_audience(&[ISSUER, token_endpoint.as_str()]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    validation.sub = Some(client_id.to_owned());
+    let claims = match decode_with_any::<ClientAssertionClaims>(assertion, &keys, &validation) {
+        Ok(c) => c,
+        Err(e) => {
+            info!("Client assertion rejected: {}", e);
+            return None;
+        }
+    };
+    Some(types::DbClientAssertionJti {
+        client_id: client_id.to_owned(),
+        jti: claims.jti,
+        expires_at: bson::DateTime::from_millis(claims.exp.saturating_mul(1000)),
+    })
+}
+
+async fn verify_client_assertion(
+    database: &Database,
+    app: &DbApplication,
+    client_id: &str,
+    assertion: &str,
+) -> bool {
+    let jti = match decode_client_assertion(app, client_id, assertion) {
+        Some(j) => j,
+        None => return false,
+    };
+    match database.record_client_assertion_jti(&jti).await {
+        Ok(true) => true,
+        Ok(false) => {
+            warn!("Client assertion replay detected for {}", client_id);
+            false
+        }
+        Err(e) => {
+            warn!("Failed to record client assertion jti: {}", e);
+            false
+        }
+    }
+}
+
+/// Authenticates the client calling the token or PAR endpoint using
+/// `client_secret_basic`, `client_secret_post`, `client_secret_jwt` or
+/// `private_key_jwt`. Returns the application on success.
+pub async fn authenticate(
+    req: &HttpRequest,
+    request: &types::ClientCredentials,
+    database: &Database,
+) -> Option<DbApplication> {
+    if let Some(assertion) = &request.client_assertion {
+        if request.client_assertion_type.as_deref() != Some(JWT_BEARER_ASSERTION_TYPE) {
+            info!("Unsupported client assertion type");
+            return None;
+        }
+        // The client is identified by the assertion's issuer. Its signature is
+        // checked against that client's keys below.
+        let client_id = match &request.client_id {
+            Some(c) => c.clone(),
+            None => unverified_issuer(assertion)?,
+        };
+        let app = database.app_by_client_id(&client_id).await?;
+        if !verify_client_assertion(database, &app, &client_id, assertion).await {
+            return None;
+        }
+        return Some(app);
+    }
+    let (client_id, client_secret, method) = match basic_credentials(req) {
+        Some((id, secret)) => (id, secret, CLIENT_SECRET_BASIC),
+        None => (
+            request.client_id.clone()?,
+            request.client_secret.clone()?,
+            CLIENT_SECRET_POST,
+        ),
+    };
+    let app = database.app_by_client_id(&client_id).await?;
+    if !method_allowed(&app, method) {
+        return None;
+    }
+    if !secret_matches(&app, &client_secret) {
+        info!("Client {} sent an invalid secret", client_id);
+        return None;
+    }
+    Some(app)
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const CLIENT_ID: &str = "client";
+    const PRIVATE_KEY: &str = include_str!("../tests/fixtures/jwks/client.pem");
+    const JWKS: &str = include_str!("../tests/fixtures/jwks/client.json");
+
+    fn application(method: &str) -> DbApplication {
+        serde_json::from_value(json!({
+            "client_id": CLIENT_ID,
+            "name": "Client",
+            "redirect_uris": ["https://client.example.com/callback"],
+            "token_endpoint_auth_method": method,
+            "jwks": serde_json::from_str::<Value>(JWKS).unwrap(),
+        }))
+        .unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": CLIENT_ID,
+            "sub": CLIENT_ID,
+            "aud": format!("{}/token", ISSUER),
+            "exp": now() + 60,
+            "jti": "assertion-1",
+        })
+    }
+
+    fn sign_rsa(claims: &Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("client-key".to_owned());
+        let key = EncodingKey::from_rsa_pem(PRIVATE_KEY.as_bytes()).unwrap();
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    fn sign_hmac(secret: &[u8], claims: &Value) -> String {
+        let key = EncodingKey::from_secret(secret);
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), claims, &key).unwrap()
+    }
+
+    fn encode_part(value: &Value) -> String {
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(value.to_string())
+    }
+
+    #[test]
+    fn accepts_private_key_jwt_assertions() {
+        let app = application(PRIVATE_KEY_JWT);
+        let jti = decode_client_assertion(&app, CLIENT_ID, &sign_rsa(&claims())).unwrap();
+        assert_eq!(jti.client_id, CLIENT_ID);
+        assert_eq!(jti.jti, "assertion-1");
+    }
+
+    #[test]
+    fn accepts_client_secret_jwt_assertions() {
+        let mut app = application(CLIENT_SECRET_JWT);
+        let (secret, stored) = new_secret(&app, "Test", None);
+        app.secrets.push(stored);
+        let assertion = sign_hmac(secret.as_bytes(), &claims());
+        assert!(decode_client_assertion(&app, CLIENT_ID, &assertion).is_some());
+    }
+
+    #[test]
+    fn rejects_the_wrong_audience() {
+        let app = application(PRIVATE_KEY_JWT);
+        let mut claims = claims();
+        claims["aud"] = json!("https://other.example.com/token");
+        assert!(decode_client_assertion(&app, CLIENT_ID, &sign_rsa(&claims)).is_none());
+    }
+
+    #[test]
+    fn rejects_expired_assertions() {
+        let app = application(PRIVATE_KEY_JWT);
+        let mut claims = claims();
+        claims["exp"] = json!(now() - 3600);
+        assert!(decode_client_assertion(&app, CLIENT_ID, &sign_rsa(&claims)).is_none());
+    }
+
+    #[test]
+    fn rejects_another_clients_assertions() {
+        let app = application(PRIVATE_KEY_JWT);
+        let mut claims = claims();
+        claims["iss"] = json!("other");
+        claims["sub"] = json!("other");
+        assert!(decode_client_assertion(&app, CLIENT_ID, &sign_rsa(&claims)).is_none());
+    }
+
+    #[test]
+    fn rejects_unsigned_assertions() {
+        let app = application(PRIVATE_KEY_JWT);
+        let assertion = format!(
+            "{}.{}.",
+            encode_part(&json!({ "alg": "none", "typ": "JWT" })),
+            encode_part(&claims())
+        );
+        assert!(decode_client_assertion(&app, CLIENT_ID, &assertion).is_none());
+    }
+
+    #[test]
+    fn rejects_hmac_signed_with_the_public_key() {
+        // A client registered for private_key_jwt cannot switch to HS256
+        // and use its public JWKS as the HMAC secret.
+        let app = application(PRIVATE_KEY_JWT);
+        let assertion = sign_hmac(JWKS.as_bytes(), &claims());
+        assert!(decode_client_assertion(&app, CLIENT_ID, &assertion).is_none());
+        let mut app = app;
+        app.token_endpoint_auth_method = None;
+        assert!(decode_client_assertion(&app, CLIENT_ID, &assertion).is_none());
+    }
+
+    #[test]
+    fn rejects_rsa_for_client_secret_jwt_clients() {
+        let app = application(CLIENT_SECRET_JWT);
+        assert!(decode_client_assertion(&app, CLIENT_ID, &sign_rsa(&claims())).is_none());
+    }
+
+    #[test]
+    fn assertions_without_a_jti_cannot_be_tracked() {
+        let app = application(PRIVATE_KEY_JWT);
+        let mut claims = claims();
+        claims.as_object_mut().unwrap().remove("jti");
+        assert!(decode_client_assertion(&app, CLIENT_ID, &sign_rsa(&claims)).is_none());
+    }
+
+    #[test]
+    fn replayed_assertions_record_the_same_jti() {
+        // The unique (client_id, jti) index makes the second insert of the
+        // same record fail, and it is kept until the assertion expires.
+        let app = application(PRIVATE_KEY_JWT);
+        let claims = claims();
+        let assertion = sign_rsa(&claims);
+        let first = decode_client_assertion(&app, CLIENT_ID, &assertion).unwrap();
+        let replay = decode_client_assertion(&app, CLIENT_ID, &assertion).unwrap();
+        assert_eq!((first.client_id, first.jti), (replay.client_id, replay.jti));
+        assert_eq!(
+            first.expires_at.timestamp_millis(),
+            claims["exp"].as_i64().unwrap() * 1000
+        );
+    }
+}
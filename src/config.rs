@@ -1,7 +1,133 @@
-use crate::types;
+use std::net::IpAddr;
+use tracing::warn;
+
+use crate::types::{self, RateLimitKey, RateLimitRule};
 
 fn load_env_config(key: &str) -> String {
-    dotenv::var(key).expect(&format!("Missing {} env var", key))
+    dotenv::var(key).unwrap_or_else(|_| panic!("Missing {} env var", key))
+}
+
+fn load_env_config_or(key: &str, default: &str) -> String {
+    load_optional_env_config(key).unwrap_or_else(|| default.to_owned())
+}
+
+fn load_list_env_config(key: &str) -> Vec<String> {
+    load_optional_env_config(key)
+        .map(|v| {
+            v.split(',')
+                .map(|item| item.trim().to_lowercase())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn load_number_env_config(key: &str, default: usize) -> usize {
+    load_optional_env_config(key)
+        .map(|v| {
+            v.parse()
+                .unwrap_or_else(|_| panic!("{} env var must be a number", key))
+        })
+        .unwrap_or(default)
+}
+
+fn load_ip_list_env_config(key: &str) -> Vec<IpAddr> {
+    load_list_env_config(key)
+        .iter()
+        .map(|ip| {
+            ip.parse()
+                .unwrap_or_else(|_| panic!("{} env var must list IP addresses", key))
+        })
+        .collect()
+}
+
+fn load_optional_env_config(key: &str) -> Option<String> {
+    dotenv::var(key).ok().filter(|v| !v.is_empty())
+}
+
+/// Loads the key TOTP secrets are encrypted with. Falling back to
+/// `JWT_SECRET` keeps existing secrets readable, but means one leaked value
+/// exposes both, so it is reported at startup.
+fn load_mfa_encryption_key() -> String {
+    let jwt_secret = load_env_config("JWT_SECRET");
+    match load_optional_env_config("MFA_ENCRYPTION_KEY") {
+        Some(key) if key != jwt_secret => key,
+        Some(key) => {
+            warn!("MFA_ENCRYPTION_KEY is the same as JWT_SECRET, set a separate key");
+            key
+        }
+        None => {
+            warn!("MFA_ENCRYPTION_KEY is not set, encrypting TOTP secrets with JWT_SECRET");
+            jwt_secret
+        }
+    }
+}
+
+/// Builds a rule whose limit can be overridden with `RATE_LIMIT_<NAME>`
+/// set to `<capacity>/<period seconds>`, e.g. `RATE_LIMIT_LOGIN=10/60`.
+fn rate_limit_rule(
+    name: &str,
+    method: &str,
+    path: &str,
+    (capacity, period_seconds): (u32, u64),
+    keys: &[RateLimitKey],
+) -> RateLimitRule {
+    let key = format!("RATE_LIMIT_{}", name);
+    let (capacity, period_seconds) = match load_optional_env_config(&key) {
+        Some(v) => v
+            .split_once('/')
+            .and_then(|(c, p)| Some((c.trim().parse().ok()?, p.trim().parse().ok()?)))
+            .unwrap_or_else(|| panic!("{} env var must look like 10/60", key)),
+        None => (capacity, period_seconds),
+    };
+    RateLimitRule {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        capacity,
+        period_seconds,
+        keys: keys.to_vec(),
+    }
+}
+
+fn load_rate_limits() -> Vec<RateLimitRule> {
+    use RateLimitKey::{ClientId, Ip, Username};
+    vec![
+        rate_limit_rule("LOGIN", "POST", "/login", (10, 60), &[Ip, Username]),
+        rate_limit_rule(
+            "ACCOUNT_LOGIN",
+            "POST",
+            "/account/login",
+            (10, 60),
+            &[Ip, Username],
+        ),
+        rate_limit_rule("LOGIN_TOTP", "POST", "/login/totp", (10, 60), &[Ip]),
+        rate_limit_rule(
+            "LOGIN_PASSKEY_OPTIONS",
+            "POST",
+            "/login/passkey/options",
+            (20, 60),
+            &[Ip],
+        ),
+        rate_limit_rule("LOGIN_PASSKEY", "POST", "/login/passkey", (10, 60), &[Ip]),
+        rate_limit_rule("AUTHORIZE", "GET", "/auth", (60, 60), &[Ip]),
+        rate_limit_rule("TOKEN", "POST", "/token", (60, 60), &[Ip, ClientId]),
+        rate_limit_rule("USERINFO", "GET", "/userinfo", (120, 60), &[Ip]),
+        rate_limit_rule("PAR", "POST", "/par", (60, 60), &[Ip, ClientId]),
+        rate_limit_rule("REGISTER", "POST", "/register", (10, 60), &[Ip]),
+        rate_limit_rule("REGISTER_CLIENT", "*", "/register/*", (30, 60), &[Ip]),
+        rate_limit_rule("ADMIN_API", "*", "/api/admin/v1/*", (300, 60), &[Ip]),
+        rate_limit_rule("SCIM", "*", "/scim/v2/*", (300, 60), &[Ip]),
+        rate_limit_rule(
+            "PERMISSIONS_CHECK",
+            "POST",
+            "/permissions/check*",
+            (600, 60),
+            &[Ip],
+        ),
+        rate_limit_rule("RESET", "POST", "/reset", (5, 300), &[Ip, Username]),
+        rate_limit_rule("RESET_CONFIRM", "POST", "/reset/confirm", (10, 60), &[Ip]),
+        rate_limit_rule("SIGNUP", "POST", "/signup", (5, 300), &[Ip]),
+    ]
 }
 
 pub fn load_config() -> types::Config {
@@ -11,6 +137,44 @@ pub fn load_config() -> types::Config {
         jwt_secret: load_env_config("JWT_SECRET"),
         listen_address: load_env_config("LISTEN_ADDR"),
         admin_panel_enabled: load_env_config("ADMIN_PANEL") == "1",
+        registration_initial_access_token: load_optional_env_config(
+            "REGISTRATION_INITIAL_ACCESS_TOKEN",
+        ),
+        registration_allowed_scopes: match load_list_env_config("REGISTRATION_ALLOWED_SCOPES") {
+            scopes if scopes.is_empty() => {
+                ["openid", "profile", "email"].map(str::to_owned).to_vec()
+            }
+            scopes => scopes,
+        },
+        smtp_host: load_optional_env_config("SMTP_HOST"),
+        smtp_username: load_optional_env_config("SMTP_USERNAME"),
+        smtp_password: load_optional_env_config("SMTP_PASSWORD"),
+        mail_from: load_env_config_or("MAIL_FROM", "Snazzy Fellas <noreply@snazzyfellas.com>"),
+        mail_file: load_env_config_or("MAIL_FILE", "mail_outbox.log"),
+        signup_enabled: load_optional_env_config("SIGNUP_ENABLED").as_deref() == Some("1"),
+        signup_invite_only: load_optional_env_config("SIGNUP_INVITE_ONLY").as_deref() == Some("1"),
+        signup_allowed_email_domains: load_list_env_config("SIGNUP_ALLOWED_EMAIL_DOMAINS"),
+        signup_username_min_length: load_number_env_config("SIGNUP_USERNAME_MIN_LENGTH", 3),
+        signup_username_max_length: load_number_env_config("SIGNUP_USERNAME_MAX_LENGTH", 32),
+        signup_username_extra_chars: load_env_config_or("SIGNUP_USERNAME_EXTRA_CHARS", "._-"),
+        password_min_length: load_number_env_config("PASSWORD_MIN_LENGTH", 10),
+        password_min_strength: load_number_env_config("PASSWORD_MIN_STRENGTH", 3).min(4) as u8,
+        password_breach_dataset: load_optional_env_config("PASSWORD_BREACH_DATASET"),
+        login_backoff_free_attempts: load_number_env_config("LOGIN_BACKOFF_FREE_ATTEMPTS", 3)
+            as u32,
+        login_lockout_username_threshold: load_number_env_config(
+            "LOGIN_LOCKOUT_USERNAME_THRESHOLD",
+            10,
+        ) as u32,
+        login_lockout_ip_threshold: load_number_env_config("LOGIN_LOCKOUT_IP_THRESHOLD", 100)
+            as u32,
+        login_lockout_seconds: load_number_env_config("LOGIN_LOCKOUT_SECONDS", 15 * 60) as u64,
+        rate_limit_backend: load_env_config_or("RATE_LIMIT_BACKEND", "memory"),
+        rate_limits: load_rate_limits(),
+        mfa_encryption_key: load_mfa_encryption_key(),
+        secure_cookies: load_optional_env_config("SECURE_COOKIES").as_deref() != Some("0"),
+        trusted_proxies: load_ip_list_env_config("TRUSTED_PROXIES"),
+        webauthn_rp_id: load_env_config_or("WEBAUTHN_RP_ID", "auth.snazzyfellas.com"),
+        webauthn_origin: load_env_config_or("WEBAUTHN_ORIGIN", "https://auth.snazzyfellas.com"),
     }
 }
-
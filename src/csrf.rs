@@ -0,0 +1,299 @@
+use std::{
+    future::{ready, Ready},
+    rc::Rc,
+};
+
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, Method},
+    web, Error, HttpResponse,
+};
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+
+use crate::routes::auth::generate_random_code;
+
+const CSRF_TOKEN_KEY: &str = "csrf_token";
+/// Header scripts can send the token in instead of a form field.
+const CSRF_TOKEN_HEADER: &str = "X-CSRF-Token";
+/// POST endpoints called by OAuth clients rather than browsers. They
+/// authenticate with client credentials or bearer tokens, not cookies.
+/// Each covers the path itself and everything below it.
+const EXEMPT_PATH_PREFIXES: &[&str] = &[
+    "/token",
+    "/par",
+    "/register",
+    "/api",
+    "/scim",
+    "/permissions",
+];
+
+/// Whether `path` is one of the exempt paths or below one, matching whole
+/// segments so `/tokens` is not exempt because of `/token`.
+fn is_exempt_path(path: &str) -> bool {
+    EXEMPT_PATH_PREFIXES.iter().any(|prefix| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// This browser session's anti-CSRF token, created on first use. Forms
+/// include it in a hidden `csrf_token` field.
+pub fn token(session: &Session) -> String {
+    match session.get::<String>(CSRF_TOKEN_KEY) {
+        Ok(Some(t)) => return t,
+        Ok(None) => (),
+        Err(e) => warn!("token(..) - failed to read session: {}", e),
+    }
+    let token = generate_random_code(43);
+    if let Err(e) = session.insert(CSRF_TOKEN_KEY, &token) {
+        warn!("token(..) - failed to write session: {}", e);
+    }
+    token
+}
+
+fn is_valid(session: &Session, submitted: Option<&str>) -> bool {
+    let expected = match session.get::<String>(CSRF_TOKEN_KEY) {
+        Ok(Some(t)) => t,
+        Ok(None) => return false,
+        Err(e) => {
+            warn!("is_valid(..) - failed to read session: {}", e);
+            return false;
+        }
+    };
+    submitted.is_some_and(|s| bool::from(s.as_bytes().ct_eq(expected.as_bytes())))
+}
+
+#[derive(Deserialize, Default)]
+struct TokenField {
+    csrf_token: Option<String>,
+}
+
+/// Middleware rejecting browser POSTs that do not carry the session's
+/// anti-CSRF token, either in the form or the `X-CSRF-Token` header.
+pub struct CsrfProtection;
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfProtection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CsrfProtectionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfProtectionMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct CsrfProtectionMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfProtectionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let exempt = req.method() != Method::POST || is_exempt_path(req.path());
+        Box::pin(async move {
+            if exempt {
+                return Ok(service.call(req).await?.map_into_left_body());
+            }
+            let header_token = req
+                .headers()
+                .get(CSRF_TOKEN_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned);
+            let submitted = match header_token {
+                Some(t) => Some(t),
+                None => {
+                    // Read the form for the token, then put it back for the
+                    // handler.
+                    let body = req.extract::<web::Bytes>().await?;
+                    let field: TokenField = serde_urlencoded::from_bytes(&body).unwrap_or_default();
+                    let stream = futures_util::stream::once(async move { Ok(body) });
+                    req.set_payload(Payload::Stream {
+                        payload: Box::pin(stream),
+                    });
+                    field.csrf_token
+                }
+            };
+            if !is_valid(&req.get_session(), submitted.as_deref()) {
+                info!("Rejected {} without a valid CSRF token", req.path());
+                let response = HttpResponse::Forbidden()
+                    .insert_header((header::CACHE_CONTROL, "no-store"))
+                    .body("This form has expired. Go back, reload the page and try again.");
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            Ok(service.call(req).await?.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+    use actix_web::{
+        body::MessageBody,
+        cookie::{Cookie, Key},
+        dev::ServiceFactory,
+        http::StatusCode,
+        test as actix_test, App, Responder,
+    };
+
+    #[derive(Deserialize)]
+    struct Message {
+        message: String,
+    }
+
+    async fn form(session: Session) -> impl Responder {
+        token(&session)
+    }
+
+    async fn submit(form: web::Form<Message>) -> impl Responder {
+        form.into_inner().message
+    }
+
+    fn app() -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = Error,
+            InitError = (),
+        >,
+    > {
+        App::new()
+            .wrap(CsrfProtection)
+            .wrap(
+                SessionMiddleware::builder(CookieSessionStore::default(), Key::generate())
+                    .cookie_secure(false)
+                    .build(),
+            )
+            .route("/form", web::get().to(form))
+            .route("/submit", web::post().to(submit))
+            .route("/token", web::post().to(submit))
+            .route("/tokens", web::post().to(submit))
+            .route("/api/users", web::post().to(submit))
+    }
+
+    fn load_form() -> actix_test::TestRequest {
+        actix_test::TestRequest::get().uri("/form")
+    }
+
+    /// The session cookie and token from loading the form page.
+    async fn session_of(response: ServiceResponse<impl MessageBody>) -> (Cookie<'static>, String) {
+        let cookie = response.response().cookies().next().unwrap().into_owned();
+        let token = String::from_utf8(actix_test::read_body(response).await.to_vec()).unwrap();
+        (cookie, token)
+    }
+
+    fn post(uri: &str, cookie: &Cookie<'static>, body: &str) -> actix_test::TestRequest {
+        actix_test::TestRequest::post()
+            .uri(uri)
+            .cookie(cookie.clone())
+            .insert_header((header::CONTENT_TYPE, "application/x-www-form-urlencoded"))
+            .set_payload(body.to_owned())
+    }
+
+    #[test]
+    fn exempt_paths_match_whole_segments() {
+        assert!(is_exempt_path("/token"));
+        assert!(is_exempt_path("/register/abc"));
+        assert!(is_exempt_path("/api"));
+        assert!(is_exempt_path("/api/users"));
+        assert!(!is_exempt_path("/tokens"));
+        assert!(!is_exempt_path("/registered"));
+        assert!(!is_exempt_path("/apis/users"));
+        assert!(!is_exempt_path("/account/token"));
+    }
+
+    #[actix_web::test]
+    async fn rejects_a_missing_token() {
+        let app = actix_test::init_service(app()).await;
+        let (cookie, _) =
+            session_of(actix_test::call_service(&app, load_form().to_request()).await).await;
+        let response =
+            actix_test::call_service(&app, post("/submit", &cookie, "message=hi").to_request())
+                .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn rejects_a_wrong_token() {
+        let app = actix_test::init_service(app()).await;
+        let (cookie, _) =
+            session_of(actix_test::call_service(&app, load_form().to_request()).await).await;
+        let body = "message=hi&csrf_token=not-the-token";
+        let response =
+            actix_test::call_service(&app, post("/submit", &cookie, body).to_request()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let request = actix_test::TestRequest::post()
+            .uri("/submit")
+            .cookie(cookie)
+            .insert_header((CSRF_TOKEN_HEADER, "not-the-token"))
+            .set_form([("message", "hi")])
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn accepts_a_valid_token_and_keeps_the_form() {
+        let app = actix_test::init_service(app()).await;
+        let (cookie, token) =
+            session_of(actix_test::call_service(&app, load_form().to_request()).await).await;
+        let body = format!("message=hi&csrf_token={}", token);
+        let response =
+            actix_test::call_service(&app, post("/submit", &cookie, &body).to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(actix_test::read_body(response).await, "hi");
+        let request = actix_test::TestRequest::post()
+            .uri("/submit")
+            .cookie(cookie)
+            .insert_header((CSRF_TOKEN_HEADER, token))
+            .set_form([("message", "hello")])
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(actix_test::read_body(response).await, "hello");
+    }
+
+    #[actix_web::test]
+    async fn skips_exempt_paths_only() {
+        let app = actix_test::init_service(app()).await;
+        for uri in ["/token", "/api/users"] {
+            let request = actix_test::TestRequest::post()
+                .uri(uri)
+                .set_form([("message", "hi")])
+                .to_request();
+            let response = actix_test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+        }
+        let request = actix_test::TestRequest::post()
+            .uri("/tokens")
+            .set_form([("message", "hi")])
+            .to_request();
+        let response = actix_test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
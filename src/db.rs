@@ -1,6 +1,19 @@
-use crate::types::{self, DbApplicationGrant};
-use mongodb::{bson::doc, Client};
-use tracing::warn;
+use crate::{
+    client_auth, lockout, password,
+    routes::auth::{generate_random_code, now},
+    types::{self, DbApplicationGrant},
+};
+use mongodb::{
+    bson::doc,
+    error::{ErrorKind, WriteFailure},
+    options::{
+        Collation, CollationStrength, FindOneAndUpdateOptions, FindOneOptions, FindOptions,
+        IndexOptions, ReturnDocument,
+    },
+    Client, IndexModel,
+};
+use std::time::Duration;
+use tracing::{info, warn};
 
 pub struct Database {
     mongo: Client,
@@ -11,12 +24,342 @@ const COLLECTION_NAME_USERS: &str = "users";
 const COLLECTION_NAME_APPS: &str = "apps";
 const COLLECTION_NAME_APP_GRANTS: &str = "app_grants";
 const COLLECTION_NAME_SESSIONS: &str = "sessions";
+const COLLECTION_NAME_AUTHORIZATION_REQUESTS: &str = "authorization_requests";
+const COLLECTION_NAME_PENDING_LOGINS: &str = "pending_logins";
+const COLLECTION_NAME_WEBAUTHN_CHALLENGES: &str = "webauthn_challenges";
+const COLLECTION_NAME_CLIENT_ASSERTION_JTIS: &str = "client_assertion_jtis";
+
+const COLLECTION_NAME_PASSWORD_RESETS: &str = "password_resets";
+
+const COLLECTION_NAME_EMAIL_VERIFICATIONS: &str = "email_verifications";
+
+const COLLECTION_NAME_INVITES: &str = "invites";
+
+const COLLECTION_NAME_WEBAUTHN_CREDENTIALS: &str = "webauthn_credentials";
+
+const COLLECTION_NAME_LOGIN_THROTTLES: &str = "login_throttles";
+
+const COLLECTION_NAME_AUDIT_LOG: &str = "audit_log";
+
+const COLLECTION_NAME_RATE_LIMITS: &str = "rate_limits";
+
+const COLLECTION_NAME_SERVICE_TOKENS: &str = "service_tokens";
+
+const COLLECTION_NAME_GROUPS: &str = "groups";
+
+const COLLECTION_NAME_ROLES: &str = "roles";
+
+const COLLECTION_NAME_POLICY_RULES: &str = "policy_rules";
+
+const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+
+fn unique_index(keys: bson::Document) -> IndexModel {
+    IndexModel::builder()
+        .keys(keys)
+        .options(IndexOptions::builder().unique(true).build())
+        .build()
+}
+
+/// Compares email addresses without regard to case.
+fn email_collation() -> Collation {
+    Collation::builder()
+        .locale("en")
+        .strength(CollationStrength::Secondary)
+        .build()
+}
+
+/// Keeps email addresses unique regardless of case. Users without an email
+/// address are left out.
+fn email_index() -> IndexModel {
+    IndexModel::builder()
+        .keys(doc! { "email": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("email_case_insensitive".to_owned())
+                .unique(true)
+                .collation(email_collation())
+                .partial_filter_expression(doc! { "email": { "$type": "string" } })
+                .build(),
+        )
+        .build()
+}
+
+/// TTL index removing documents once the date in `field` has passed.
+fn expiry_index(field: &str) -> IndexModel {
+    IndexModel::builder()
+        .keys(doc! { field: 1 })
+        .options(
+            IndexOptions::builder()
+                .expire_after(Duration::from_secs(0))
+                .build(),
+        )
+        .build()
+}
+
+/// Escapes `text` so it matches literally inside a regular expression.
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY_ERROR_CODE
+    )
+}
+
+/// A unique user field that saving a user collided with.
+#[derive(Debug, PartialEq)]
+pub enum UserConflict {
+    Username,
+    Email,
+}
+
+/// Whether saving a user failed because another user already has its
+/// username or email address, and which.
+pub fn user_conflict(error: &(dyn std::error::Error + 'static)) -> Option<UserConflict> {
+    let error = error.downcast_ref::<mongodb::error::Error>()?;
+    match error.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY_ERROR_CODE => {
+            // Duplicate key messages name the index that was violated.
+            if e.message.contains("username_1") {
+                Some(UserConflict::Username)
+            } else if e.message.contains("email_1") {
+                Some(UserConflict::Email)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
 
 impl Database {
     pub fn new(client: Client) -> Database {
         Database { mongo: client }
     }
 
+    /// Applications created before `client_id` existed were identified by
+    /// their name, so the name becomes their client ID. Names that are
+    /// shared, or already taken as a client ID, get the application's ID
+    /// appended so client IDs stay unique.
+    pub async fn migrate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let apps = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<bson::Document>(COLLECTION_NAME_APPS);
+        let mut cursor = apps
+            .find(doc! { "client_id": { "$exists": false } }, None)
+            .await?;
+        let mut migrated = 0;
+        while cursor.advance().await? {
+            let app = cursor.deserialize_current()?;
+            let id = app.get_object_id("_id")?;
+            let name = app.get_str("name")?;
+            let shared = apps
+                .count_documents(
+                    doc! {
+                        "_id": { "$ne": id },
+                        "$or": [{ "name": name }, { "client_id": name }],
+                    },
+                    None,
+                )
+                .await?
+                > 0;
+            let client_id = if shared {
+                format!("{}-{}", name, id.to_hex())
+            } else {
+                name.to_owned()
+            };
+            apps.update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "client_id": &client_id } },
+                None,
+            )
+            .await?;
+            if shared {
+                warn!(
+                    "Application {} shares its name with another, migrated it to client ID {}",
+                    name, client_id
+                );
+            }
+            migrated += 1;
+        }
+        if migrated > 0 {
+            info!("Migrated {} applications to explicit client IDs", migrated);
+        }
+        self.migrate_client_secrets().await?;
+        self.migrate_application_grants().await
+    }
+
+    /// Removes grants from before codes expired, which the TTL index would
+    /// never remove and which can no longer be redeemed.
+    async fn migrate_application_grants(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let removed = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<bson::Document>(COLLECTION_NAME_APP_GRANTS)
+            .delete_many(doc! { "expires_at": { "$exists": false } }, None)
+            .await?
+            .deleted_count;
+        if removed > 0 {
+            info!("Removed {} authorization codes without an expiry", removed);
+        }
+        Ok(())
+    }
+
+    /// Replaces the plaintext `secret` of older applications with a hashed
+    /// entry in `secrets`.
+    async fn migrate_client_secrets(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let apps = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<bson::Document>(COLLECTION_NAME_APPS);
+        let mut cursor = apps
+            .find(doc! { "secret": { "$type": "string" } }, None)
+            .await?;
+        let mut migrated = 0;
+        while cursor.advance().await? {
+            let app = cursor.deserialize_current()?;
+            let id = app.get_object_id("_id")?;
+            let keep_value = app.get_str("token_endpoint_auth_method").ok()
+                == Some(client_auth::CLIENT_SECRET_JWT);
+            let secret = app.get_str("secret")?;
+            let stored = types::DbClientSecret {
+                id: generate_random_code(16),
+                label: "Original secret".to_owned(),
+                hash: password::hash_token(secret),
+                value: keep_value.then(|| secret.to_owned()),
+                created_at: now(),
+                expires_at: None,
+            };
+            apps.update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$push": { "secrets": bson::to_bson(&stored)? },
+                    "$unset": { "secret": "" },
+                },
+                None,
+            )
+            .await?;
+            migrated += 1;
+        }
+        if migrated > 0 {
+            info!(
+                "Migrated {} applications to hashed client secrets",
+                migrated
+            );
+        }
+        Ok(())
+    }
+
+    pub async fn ensure_indexes(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let database = self.mongo.database(AUTH_DATABASE_NAME);
+        let indexes = [
+            // Logins match usernames exactly, so case still tells them apart.
+            (COLLECTION_NAME_USERS, unique_index(doc! { "username": 1 })),
+            (COLLECTION_NAME_USERS, email_index()),
+            (COLLECTION_NAME_APPS, unique_index(doc! { "client_id": 1 })),
+            (
+                COLLECTION_NAME_AUTHORIZATION_REQUESTS,
+                expiry_index("expires_at"),
+            ),
+            (COLLECTION_NAME_PENDING_LOGINS, expiry_index("expires_at")),
+            (COLLECTION_NAME_APP_GRANTS, unique_index(doc! { "code": 1 })),
+            (COLLECTION_NAME_APP_GRANTS, expiry_index("expires_at")),
+            (
+                COLLECTION_NAME_WEBAUTHN_CHALLENGES,
+                expiry_index("expires_at"),
+            ),
+            (
+                COLLECTION_NAME_CLIENT_ASSERTION_JTIS,
+                unique_index(doc! { "client_id": 1, "jti": 1 }),
+            ),
+            (
+                COLLECTION_NAME_CLIENT_ASSERTION_JTIS,
+                expiry_index("expires_at"),
+            ),
+            (
+                COLLECTION_NAME_PASSWORD_RESETS,
+                unique_index(doc! { "token_hash": 1 }),
+            ),
+            (COLLECTION_NAME_PASSWORD_RESETS, expiry_index("expires_at")),
+            (
+                COLLECTION_NAME_EMAIL_VERIFICATIONS,
+                unique_index(doc! { "token_hash": 1 }),
+            ),
+            (
+                COLLECTION_NAME_EMAIL_VERIFICATIONS,
+                expiry_index("expires_at"),
+            ),
+            (
+                COLLECTION_NAME_INVITES,
+                unique_index(doc! { "code_hash": 1 }),
+            ),
+            (
+                COLLECTION_NAME_WEBAUTHN_CREDENTIALS,
+                unique_index(doc! { "credential_id": 1 }),
+            ),
+            (
+                COLLECTION_NAME_WEBAUTHN_CREDENTIALS,
+                IndexModel::builder().keys(doc! { "user_id": 1 }).build(),
+            ),
+            (
+                COLLECTION_NAME_LOGIN_THROTTLES,
+                unique_index(doc! { "key": 1 }),
+            ),
+            (COLLECTION_NAME_LOGIN_THROTTLES, expiry_index("expires_at")),
+            (COLLECTION_NAME_RATE_LIMITS, unique_index(doc! { "key": 1 })),
+            (COLLECTION_NAME_RATE_LIMITS, expiry_index("expires_at")),
+            (
+                COLLECTION_NAME_SERVICE_TOKENS,
+                unique_index(doc! { "token_hash": 1 }),
+            ),
+            (COLLECTION_NAME_SERVICE_TOKENS, expiry_index("expires_at")),
+            (COLLECTION_NAME_GROUPS, unique_index(doc! { "name": 1 })),
+            (
+                COLLECTION_NAME_GROUPS,
+                IndexModel::builder().keys(doc! { "members": 1 }).build(),
+            ),
+            (
+                COLLECTION_NAME_ROLES,
+                unique_index(doc! { "application_id": 1, "name": 1 }),
+            ),
+            (
+                COLLECTION_NAME_ROLES,
+                IndexModel::builder().keys(doc! { "users": 1 }).build(),
+            ),
+            (
+                COLLECTION_NAME_ROLES,
+                IndexModel::builder().keys(doc! { "groups": 1 }).build(),
+            ),
+            (
+                COLLECTION_NAME_POLICY_RULES,
+                IndexModel::builder()
+                    .keys(doc! { "application_id": 1 })
+                    .build(),
+            ),
+            (
+                COLLECTION_NAME_AUDIT_LOG,
+                IndexModel::builder().keys(doc! { "at": -1 }).build(),
+            ),
+        ];
+        for (collection, index) in indexes {
+            database
+                .collection::<bson::Document>(collection)
+                .create_index(index, None)
+                .await?;
+        }
+        Ok(())
+    }
+
     pub async fn user_by_username(&self, username: &str) -> Option<types::DbUser> {
         let collection = self
             .mongo
@@ -34,7 +377,21 @@ impl Database {
         };
     }
 
-    pub async fn insert_user(
+    pub async fn user_by_id(&self, id: bson::oid::ObjectId) -> Option<types::DbUser> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbUser>(COLLECTION_NAME_USERS);
+        match collection.find_one(doc! { "_id": id }, None).await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to retrieve user document {}", e);
+                None
+            }
+        }
+    }
+
+    pub async fn update_user(
         &self,
         user: &types::DbUser,
     ) -> Result<(), Box<dyn std::error::Error>> {
@@ -42,99 +399,1363 @@ impl Database {
             .mongo
             .database(AUTH_DATABASE_NAME)
             .collection::<types::DbUser>(COLLECTION_NAME_USERS);
-        collection.insert_one(user, None).await?;
+        let id = user.id.ok_or("User has no ID")?;
+        collection
+            .replace_one(doc! { "_id": id }, user, None)
+            .await?;
         Ok(())
     }
 
-    pub async fn app_by_name(&self, name: &str) -> Option<types::DbApplication> {
+    /// Records a TOTP time step as used, unless it or a later step already
+    /// was. Returns whether this call consumed it.
+    pub async fn use_totp_step(
+        &self,
+        user_id: bson::oid::ObjectId,
+        step: u64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
         let collection = self
             .mongo
             .database(AUTH_DATABASE_NAME)
-            .collection::<types::DbApplication>(COLLECTION_NAME_APPS);
-        return match collection.find_one(doc! { "name": name }, None).await {
+            .collection::<types::DbUser>(COLLECTION_NAME_USERS);
+        let step = step as i64;
+        let result = collection
+            .update_one(
+                doc! { "_id": user_id, "totp.last_used_step": { "$lt": step } },
+                doc! { "$set": { "totp.last_used_step": step } },
+                None,
+            )
+            .await?;
+        Ok(result.matched_count == 1)
+    }
+
+    /// Removes a recovery code hash from a user. Returns whether this call
+    /// removed it, so a code cannot be redeemed by two requests at once.
+    pub async fn use_recovery_code(
+        &self,
+        user_id: bson::oid::ObjectId,
+        hash: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbUser>(COLLECTION_NAME_USERS);
+        let result = collection
+            .update_one(
+                doc! { "_id": user_id, "totp.recovery_code_hashes": hash },
+                doc! { "$pull": { "totp.recovery_code_hashes": hash } },
+                None,
+            )
+            .await?;
+        Ok(result.matched_count == 1)
+    }
+
+    /// Looks a user up by email address, ignoring case.
+    pub async fn user_by_email(&self, email: &str) -> Option<types::DbUser> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbUser>(COLLECTION_NAME_USERS);
+        let options = FindOneOptions::builder()
+            .collation(email_collation())
+            .build();
+        let filter = doc! { "email": { "$eq": email, "$type": "string" } };
+        match collection.find_one(filter, options).await {
             Ok(s) => s,
             Err(e) => {
-                warn!("Failed to retrieve application document {}", e);
+                warn!("Failed to retrieve user document {}", e);
                 None
             }
+        }
+    }
+
+    pub async fn insert_user(
+        &self,
+        user: &types::DbUser,
+    ) -> Result<bson::oid::ObjectId, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbUser>(COLLECTION_NAME_USERS);
+        let result = collection.insert_one(user, None).await?;
+        result
+            .inserted_id
+            .as_object_id()
+            .ok_or("Failed to get inserted user ID".into())
+    }
+
+    /// One page of users, sorted by username, whose username or email
+    /// contains `search`. Also returns how many users match in total.
+    pub async fn list_users(
+        &self,
+        search: &str,
+        skip: u64,
+        limit: i64,
+    ) -> Result<(Vec<types::DbUser>, u64), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbUser>(COLLECTION_NAME_USERS);
+        let filter = if search.is_empty() {
+            doc! {}
+        } else {
+            let pattern = doc! { "$regex": escape_regex(search), "$options": "i" };
+            doc! { "$or": [{ "username": pattern.clone() }, { "email": pattern }] }
         };
+        let total = collection.count_documents(filter.clone(), None).await?;
+        let options = FindOptions::builder()
+            .sort(doc! { "username": 1 })
+            .skip(skip)
+            .limit(limit)
+            .build();
+        let mut cursor = collection.find(filter, options).await?;
+        let mut users = vec![];
+        while cursor.advance().await? {
+            users.push(cursor.deserialize_current()?);
+        }
+        Ok((users, total))
     }
 
-    pub async fn insert_session(
+    /// Users matching a query, sorted by username. Strings in the query are
+    /// compared without regard to case.
+    pub async fn users_matching(
         &self,
-        session: &types::DbSession,
+        query: bson::Document,
+    ) -> Result<Vec<types::DbUser>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbUser>(COLLECTION_NAME_USERS);
+        let options = FindOptions::builder()
+            .sort(doc! { "username": 1 })
+            .collation(email_collation())
+            .build();
+        let mut cursor = collection.find(query, options).await?;
+        let mut users = vec![];
+        while cursor.advance().await? {
+            users.push(cursor.deserialize_current()?);
+        }
+        Ok(users)
+    }
+
+    pub async fn users_by_ids(
+        &self,
+        ids: &[bson::oid::ObjectId],
+    ) -> Result<Vec<types::DbUser>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbUser>(COLLECTION_NAME_USERS);
+        let mut cursor = collection
+            .find(doc! { "_id": { "$in": ids } }, None)
+            .await?;
+        let mut users = vec![];
+        while cursor.advance().await? {
+            users.push(cursor.deserialize_current()?);
+        }
+        Ok(users)
+    }
+
+    /// Deletes a user along with their sessions, grants, passkeys and any
+    /// outstanding password resets or email verifications. They are also
+    /// removed from their groups, roles and application allowlists.
+    pub async fn delete_user(
+        &self,
+        user_id: bson::oid::ObjectId,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let database = self.mongo.database(AUTH_DATABASE_NAME);
+        // Login throttles are keyed by username rather than ID, so a new
+        // user taking the name does not inherit the old one's failures.
+        if let Some(user) = self.user_by_id(user_id).await {
+            database
+                .collection::<types::DbLoginThrottle>(COLLECTION_NAME_LOGIN_THROTTLES)
+                .delete_one(doc! { "key": lockout::username_key(&user.username) }, None)
+                .await?;
+        }
+        for collection in [
+            COLLECTION_NAME_SESSIONS,
+            COLLECTION_NAME_PENDING_LOGINS,
+            COLLECTION_NAME_APP_GRANTS,
+            COLLECTION_NAME_WEBAUTHN_CREDENTIALS,
+            COLLECTION_NAME_PASSWORD_RESETS,
+            COLLECTION_NAME_EMAIL_VERIFICATIONS,
+        ] {
+            database
+                .collection::<bson::Document>(collection)
+                .delete_many(doc! { "user_id": user_id }, None)
+                .await?;
+        }
+        database
+            .collection::<types::DbGroup>(COLLECTION_NAME_GROUPS)
+            .update_many(
+                doc! { "members": user_id },
+                doc! { "$pull": { "members": user_id } },
+                None,
+            )
+            .await?;
+        database
+            .collection::<types::DbRole>(COLLECTION_NAME_ROLES)
+            .update_many(
+                doc! { "users": user_id },
+                doc! { "$pull": { "users": user_id } },
+                None,
+            )
+            .await?;
+        database
+            .collection::<types::DbApplication>(COLLECTION_NAME_APPS)
+            .update_many(
+                doc! { "allowed_users": user_id },
+                doc! { "$pull": { "allowed_users": user_id } },
+                None,
+            )
+            .await?;
+        database
+            .collection::<types::DbUser>(COLLECTION_NAME_USERS)
+            .delete_one(doc! { "_id": user_id }, None)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_groups(&self) -> Result<Vec<types::DbGroup>, Box<dyn std::error::Error>> {
         let collection = self
             .mongo
             .database(AUTH_DATABASE_NAME)
-            .collection::<types::DbSession>(COLLECTION_NAME_SESSIONS);
-        collection.insert_one(session, None).await?;
+            .collection::<types::DbGroup>(COLLECTION_NAME_GROUPS);
+        let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+        let mut cursor = collection.find(doc! {}, options).await?;
+        let mut groups = vec![];
+        while cursor.advance().await? {
+            groups.push(cursor.deserialize_current()?);
+        }
+        Ok(groups)
+    }
+
+    /// Groups matching a query, sorted by name. Strings in the query are
+    /// compared without regard to case.
+    pub async fn groups_matching(
+        &self,
+        query: bson::Document,
+    ) -> Result<Vec<types::DbGroup>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbGroup>(COLLECTION_NAME_GROUPS);
+        let options = FindOptions::builder()
+            .sort(doc! { "name": 1 })
+            .collation(email_collation())
+            .build();
+        let mut cursor = collection.find(query, options).await?;
+        let mut groups = vec![];
+        while cursor.advance().await? {
+            groups.push(cursor.deserialize_current()?);
+        }
+        Ok(groups)
+    }
+
+    /// Groups with any of the given users as members.
+    pub async fn groups_with_members(
+        &self,
+        user_ids: &[bson::oid::ObjectId],
+    ) -> Result<Vec<types::DbGroup>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbGroup>(COLLECTION_NAME_GROUPS);
+        let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+        let mut cursor = collection
+            .find(doc! { "members": { "$in": user_ids } }, options)
+            .await?;
+        let mut groups = vec![];
+        while cursor.advance().await? {
+            groups.push(cursor.deserialize_current()?);
+        }
+        Ok(groups)
+    }
+
+    pub async fn groups_for_user(
+        &self,
+        user_id: bson::oid::ObjectId,
+    ) -> Result<Vec<types::DbGroup>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbGroup>(COLLECTION_NAME_GROUPS);
+        let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+        let mut cursor = collection
+            .find(doc! { "members": user_id }, options)
+            .await?;
+        let mut groups = vec![];
+        while cursor.advance().await? {
+            groups.push(cursor.deserialize_current()?);
+        }
+        Ok(groups)
+    }
+
+    pub async fn group_by_id(
+        &self,
+        id: bson::oid::ObjectId,
+    ) -> Result<Option<types::DbGroup>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbGroup>(COLLECTION_NAME_GROUPS);
+        Ok(collection.find_one(doc! { "_id": id }, None).await?)
+    }
+
+    pub async fn group_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Option<types::DbGroup>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbGroup>(COLLECTION_NAME_GROUPS);
+        Ok(collection.find_one(doc! { "name": name }, None).await?)
+    }
+
+    pub async fn insert_group(
+        &self,
+        group: &types::DbGroup,
+    ) -> Result<bson::oid::ObjectId, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbGroup>(COLLECTION_NAME_GROUPS);
+        let result = collection.insert_one(group, None).await?;
+        result
+            .inserted_id
+            .as_object_id()
+            .ok_or("Failed to get inserted group ID".into())
+    }
+
+    pub async fn update_group(
+        &self,
+        group: &types::DbGroup,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbGroup>(COLLECTION_NAME_GROUPS);
+        let id = group.id.ok_or("Group has no ID")?;
+        collection
+            .replace_one(doc! { "_id": id }, group, None)
+            .await?;
         Ok(())
     }
 
-    pub async fn session_from_key(&self, key: &str) -> Option<types::DbSession> {
+    /// Deletes a group and takes away the roles and application access it
+    /// gave.
+    pub async fn delete_group(
+        &self,
+        id: bson::oid::ObjectId,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let database = self.mongo.database(AUTH_DATABASE_NAME);
+        database
+            .collection::<types::DbRole>(COLLECTION_NAME_ROLES)
+            .update_many(
+                doc! { "groups": id },
+                doc! { "$pull": { "groups": id } },
+                None,
+            )
+            .await?;
+        database
+            .collection::<types::DbApplication>(COLLECTION_NAME_APPS)
+            .update_many(
+                doc! { "allowed_groups": id },
+                doc! { "$pull": { "allowed_groups": id } },
+                None,
+            )
+            .await?;
+        database
+            .collection::<types::DbGroup>(COLLECTION_NAME_GROUPS)
+            .delete_one(doc! { "_id": id }, None)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn roles_for_application(
+        &self,
+        application_id: bson::oid::ObjectId,
+    ) -> Result<Vec<types::DbRole>, Box<dyn std::error::Error>> {
         let collection = self
             .mongo
             .database(AUTH_DATABASE_NAME)
-            .collection::<types::DbSession>(COLLECTION_NAME_SESSIONS);
-        let session = match collection.find_one(doc! { "session_key": key }, None).await {
-            Ok(s) => s,
-            Err(e) => {
-                warn!("Failed to load session: {}", e);
-                return None;
-            }
+            .collection::<types::DbRole>(COLLECTION_NAME_ROLES);
+        let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+        let mut cursor = collection
+            .find(doc! { "application_id": application_id }, options)
+            .await?;
+        let mut roles = vec![];
+        while cursor.advance().await? {
+            roles.push(cursor.deserialize_current()?);
+        }
+        Ok(roles)
+    }
+
+    /// Roles in an application held by the user, directly or through one of
+    /// `group_ids`.
+    pub async fn roles_for_user(
+        &self,
+        application_id: bson::oid::ObjectId,
+        user_id: bson::oid::ObjectId,
+        group_ids: &[bson::oid::ObjectId],
+    ) -> Result<Vec<types::DbRole>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbRole>(COLLECTION_NAME_ROLES);
+        let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+        let filter = doc! {
+            "application_id": application_id,
+            "$or": [
+                { "users": user_id },
+                { "groups": { "$in": group_ids } },
+            ],
         };
-        return session;
+        let mut cursor = collection.find(filter, options).await?;
+        let mut roles = vec![];
+        while cursor.advance().await? {
+            roles.push(cursor.deserialize_current()?);
+        }
+        Ok(roles)
     }
 
-    pub async fn insert_application(
+    pub async fn role_by_id(
         &self,
-        application: &types::DbApplication,
+        id: bson::oid::ObjectId,
+    ) -> Result<Option<types::DbRole>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbRole>(COLLECTION_NAME_ROLES);
+        Ok(collection.find_one(doc! { "_id": id }, None).await?)
+    }
+
+    pub async fn insert_role(
+        &self,
+        role: &types::DbRole,
     ) -> Result<bson::oid::ObjectId, Box<dyn std::error::Error>> {
         let collection = self
             .mongo
             .database(AUTH_DATABASE_NAME)
-            .collection::<types::DbApplication>(COLLECTION_NAME_APPS);
-        let result = collection.insert_one(application, None).await?;
+            .collection::<types::DbRole>(COLLECTION_NAME_ROLES);
+        let result = collection.insert_one(role, None).await?;
         result
             .inserted_id
             .as_object_id()
-            .ok_or(format!("Failed to get inserted application ID").into())
+            .ok_or("Failed to get inserted role ID".into())
     }
 
-    pub async fn insert_application_grant(
+    pub async fn update_role(
         &self,
-        grant: &types::DbApplicationGrant,
+        role: &types::DbRole,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let collection = self
             .mongo
             .database(AUTH_DATABASE_NAME)
-            .collection::<types::DbApplicationGrant>(COLLECTION_NAME_APP_GRANTS);
-        collection.insert_one(grant, None).await?;
+            .collection::<types::DbRole>(COLLECTION_NAME_ROLES);
+        let id = role.id.ok_or("Role has no ID")?;
+        collection
+            .replace_one(doc! { "_id": id }, role, None)
+            .await?;
         Ok(())
     }
 
-    pub async fn remove_application_grant(
+    pub async fn delete_role(
         &self,
-        code: &str,
+        id: bson::oid::ObjectId,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let collection = self
             .mongo
             .database(AUTH_DATABASE_NAME)
-            .collection::<types::DbApplicationGrant>(COLLECTION_NAME_APP_GRANTS);
-        collection.delete_many(doc! { "code": code }, None).await?;
+            .collection::<types::DbRole>(COLLECTION_NAME_ROLES);
+        collection.delete_one(doc! { "_id": id }, None).await?;
         Ok(())
     }
 
-    pub async fn get_application_grant(
+    /// Takes a permission the application no longer defines away from its
+    /// roles.
+    pub async fn remove_permission_from_roles(
         &self,
-        code: &str,
-    ) -> Result<Option<DbApplicationGrant>, Box<dyn std::error::Error>> {
+        application_id: bson::oid::ObjectId,
+        permission: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         let collection = self
             .mongo
             .database(AUTH_DATABASE_NAME)
-            .collection::<types::DbApplicationGrant>(COLLECTION_NAME_APP_GRANTS);
-        Ok(collection.find_one(doc! { "code": &code}, None).await?)
+            .collection::<types::DbRole>(COLLECTION_NAME_ROLES);
+        collection
+            .update_many(
+                doc! { "application_id": application_id },
+                doc! { "$pull": { "permissions": permission } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// The application's policy rules, in the order they were created.
+    pub async fn policy_rules_for_application(
+        &self,
+        application_id: bson::oid::ObjectId,
+    ) -> Result<Vec<types::DbPolicyRule>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPolicyRule>(COLLECTION_NAME_POLICY_RULES);
+        let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let mut cursor = collection
+            .find(doc! { "application_id": application_id }, options)
+            .await?;
+        let mut rules = vec![];
+        while cursor.advance().await? {
+            rules.push(cursor.deserialize_current()?);
+        }
+        Ok(rules)
+    }
+
+    pub async fn policy_rule_by_id(
+        &self,
+        id: bson::oid::ObjectId,
+    ) -> Result<Option<types::DbPolicyRule>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPolicyRule>(COLLECTION_NAME_POLICY_RULES);
+        Ok(collection.find_one(doc! { "_id": id }, None).await?)
     }
-}
 
+    pub async fn insert_policy_rule(
+        &self,
+        rule: &types::DbPolicyRule,
+    ) -> Result<bson::oid::ObjectId, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPolicyRule>(COLLECTION_NAME_POLICY_RULES);
+        let result = collection.insert_one(rule, None).await?;
+        result
+            .inserted_id
+            .as_object_id()
+            .ok_or("Failed to get inserted policy rule ID".into())
+    }
+
+    pub async fn update_policy_rule(
+        &self,
+        rule: &types::DbPolicyRule,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPolicyRule>(COLLECTION_NAME_POLICY_RULES);
+        let id = rule.id.ok_or("Policy rule has no ID")?;
+        collection
+            .replace_one(doc! { "_id": id }, rule, None)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_policy_rule(
+        &self,
+        id: bson::oid::ObjectId,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPolicyRule>(COLLECTION_NAME_POLICY_RULES);
+        collection.delete_one(doc! { "_id": id }, None).await?;
+        Ok(())
+    }
+
+    pub async fn app_by_id(&self, id: bson::oid::ObjectId) -> Option<types::DbApplication> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbApplication>(COLLECTION_NAME_APPS);
+        match collection.find_one(doc! { "_id": id }, None).await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to retrieve application document {}", e);
+                None
+            }
+        }
+    }
+
+    pub async fn app_by_client_id(&self, client_id: &str) -> Option<types::DbApplication> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbApplication>(COLLECTION_NAME_APPS);
+        return match collection
+            .find_one(doc! { "client_id": client_id }, None)
+            .await
+        {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to retrieve application document {}", e);
+                None
+            }
+        };
+    }
+
+    pub async fn insert_session(
+        &self,
+        session: &types::DbSession,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbSession>(COLLECTION_NAME_SESSIONS);
+        collection.insert_one(session, None).await?;
+        Ok(())
+    }
+
+    pub async fn session_from_key(&self, key: &str) -> Option<types::DbSession> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbSession>(COLLECTION_NAME_SESSIONS);
+        let session = match collection.find_one(doc! { "session_key": key }, None).await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to load session: {}", e);
+                return None;
+            }
+        };
+        session
+    }
+
+    pub async fn insert_application(
+        &self,
+        application: &types::DbApplication,
+    ) -> Result<bson::oid::ObjectId, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbApplication>(COLLECTION_NAME_APPS);
+        let result = collection.insert_one(application, None).await?;
+        result
+            .inserted_id
+            .as_object_id()
+            .ok_or("Failed to get inserted application ID".into())
+    }
+
+    pub async fn list_applications(
+        &self,
+    ) -> Result<Vec<types::DbApplication>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbApplication>(COLLECTION_NAME_APPS);
+        let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+        let mut cursor = collection.find(doc! {}, options).await?;
+        let mut applications = vec![];
+        while cursor.advance().await? {
+            applications.push(cursor.deserialize_current()?);
+        }
+        Ok(applications)
+    }
+
+    pub async fn count_sessions_for_application(
+        &self,
+        id: bson::oid::ObjectId,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbSession>(COLLECTION_NAME_SESSIONS);
+        Ok(collection
+            .count_documents(doc! { "client_id": id }, None)
+            .await?)
+    }
+
+    pub async fn update_application(
+        &self,
+        application: &types::DbApplication,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbApplication>(COLLECTION_NAME_APPS);
+        let id = application.id.ok_or("Application has no ID")?;
+        collection
+            .replace_one(doc! { "_id": id }, application, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes an application together with its outstanding grants,
+    /// sessions, roles and policy rules.
+    pub async fn delete_application(
+        &self,
+        id: bson::oid::ObjectId,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let database = self.mongo.database(AUTH_DATABASE_NAME);
+        database
+            .collection::<types::DbApplicationGrant>(COLLECTION_NAME_APP_GRANTS)
+            .delete_many(doc! { "client_id": id }, None)
+            .await?;
+        database
+            .collection::<types::DbSession>(COLLECTION_NAME_SESSIONS)
+            .delete_many(doc! { "client_id": id }, None)
+            .await?;
+        database
+            .collection::<types::DbServiceToken>(COLLECTION_NAME_SERVICE_TOKENS)
+            .delete_many(doc! { "client_id": id }, None)
+            .await?;
+        database
+            .collection::<types::DbRole>(COLLECTION_NAME_ROLES)
+            .delete_many(doc! { "application_id": id }, None)
+            .await?;
+        database
+            .collection::<types::DbPolicyRule>(COLLECTION_NAME_POLICY_RULES)
+            .delete_many(doc! { "application_id": id }, None)
+            .await?;
+        database
+            .collection::<types::DbApplication>(COLLECTION_NAME_APPS)
+            .delete_one(doc! { "_id": id }, None)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn insert_authorization_request(
+        &self,
+        request: &types::DbAuthorizationRequest,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbAuthorizationRequest>(COLLECTION_NAME_AUTHORIZATION_REQUESTS);
+        collection.insert_one(request, None).await?;
+        Ok(())
+    }
+
+    /// An unexpired authorization request the login page was shown for.
+    pub async fn authorization_request(
+        &self,
+        id: &str,
+    ) -> Result<Option<types::DbAuthorizationRequest>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbAuthorizationRequest>(COLLECTION_NAME_AUTHORIZATION_REQUESTS);
+        Ok(collection
+            .find_one(
+                doc! {
+                    "_id": id,
+                    "pushed": false,
+                    "expires_at": { "$gt": bson::DateTime::now() },
+                },
+                None,
+            )
+            .await?)
+    }
+
+    /// Removes and returns an unexpired pushed authorization request, so
+    /// its `request_uri` can only be used once.
+    pub async fn take_pushed_authorization_request(
+        &self,
+        id: &str,
+        client_id: &str,
+    ) -> Result<Option<types::DbAuthorizationRequest>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbAuthorizationRequest>(COLLECTION_NAME_AUTHORIZATION_REQUESTS);
+        Ok(collection
+            .find_one_and_delete(
+                doc! {
+                    "_id": id,
+                    "pushed": true,
+                    "authorization.client_id": client_id,
+                    "expires_at": { "$gt": bson::DateTime::now() },
+                },
+                None,
+            )
+            .await?)
+    }
+
+    pub async fn insert_pending_login(
+        &self,
+        pending: &types::DbPendingLogin,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPendingLogin>(COLLECTION_NAME_PENDING_LOGINS);
+        collection.insert_one(pending, None).await?;
+        Ok(())
+    }
+
+    pub async fn pending_login(
+        &self,
+        id: &str,
+    ) -> Result<Option<types::DbPendingLogin>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPendingLogin>(COLLECTION_NAME_PENDING_LOGINS);
+        Ok(collection
+            .find_one(
+                doc! { "_id": id, "expires_at": { "$gt": bson::DateTime::now() } },
+                None,
+            )
+            .await?)
+    }
+
+    /// Atomically counts a failed second factor and returns the updated
+    /// pending login, if it still exists.
+    pub async fn record_pending_login_failure(
+        &self,
+        id: &str,
+    ) -> Result<Option<types::DbPendingLogin>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPendingLogin>(COLLECTION_NAME_PENDING_LOGINS);
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        Ok(collection
+            .find_one_and_update(
+                doc! { "_id": id, "expires_at": { "$gt": bson::DateTime::now() } },
+                doc! { "$inc": { "failed_attempts": 1 } },
+                options,
+            )
+            .await?)
+    }
+
+    pub async fn delete_pending_login(&self, id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPendingLogin>(COLLECTION_NAME_PENDING_LOGINS);
+        collection.delete_one(doc! { "_id": id }, None).await?;
+        Ok(())
+    }
+
+    pub async fn insert_webauthn_challenge(
+        &self,
+        challenge: &types::DbWebauthnChallenge,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbWebauthnChallenge>(COLLECTION_NAME_WEBAUTHN_CHALLENGES);
+        collection.insert_one(challenge, None).await?;
+        Ok(())
+    }
+
+    /// Removes and returns an unexpired challenge in one step, so two
+    /// responses to it cannot both be accepted.
+    pub async fn take_webauthn_challenge(
+        &self,
+        id: &str,
+        ceremony: &str,
+    ) -> Result<Option<types::DbWebauthnChallenge>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbWebauthnChallenge>(COLLECTION_NAME_WEBAUTHN_CHALLENGES);
+        Ok(collection
+            .find_one_and_delete(
+                doc! {
+                    "_id": id,
+                    "ceremony": ceremony,
+                    "expires_at": { "$gt": bson::DateTime::now() },
+                },
+                None,
+            )
+            .await?)
+    }
+
+    pub async fn insert_application_grant(
+        &self,
+        grant: &types::DbApplicationGrant,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbApplicationGrant>(COLLECTION_NAME_APP_GRANTS);
+        collection.insert_one(grant, None).await?;
+        Ok(())
+    }
+
+    pub async fn remove_application_grant(
+        &self,
+        code: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbApplicationGrant>(COLLECTION_NAME_APP_GRANTS);
+        collection.delete_many(doc! { "code": code }, None).await?;
+        Ok(())
+    }
+
+    /// Removes and returns the grant for `code` if it was issued to the
+    /// application `client_id` and has not expired.
+    pub async fn take_application_grant(
+        &self,
+        code: &str,
+        client_id: bson::oid::ObjectId,
+    ) -> Result<Option<DbApplicationGrant>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbApplicationGrant>(COLLECTION_NAME_APP_GRANTS);
+        Ok(collection
+            .find_one_and_delete(
+                doc! {
+                    "code": code,
+                    "client_id": client_id,
+                    "expires_at": { "$gt": bson::DateTime::now() },
+                },
+                None,
+            )
+            .await?)
+    }
+
+    /// Records a client assertion `jti`. Returns `false` if it was already
+    /// used by the same client, i.e. the assertion is being replayed.
+    pub async fn record_client_assertion_jti(
+        &self,
+        jti: &types::DbClientAssertionJti,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbClientAssertionJti>(COLLECTION_NAME_CLIENT_ASSERTION_JTIS);
+        match collection.insert_one(jti, None).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key_error(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn insert_password_reset(
+        &self,
+        reset: &types::DbPasswordReset,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPasswordReset>(COLLECTION_NAME_PASSWORD_RESETS);
+        collection.insert_one(reset, None).await?;
+        Ok(())
+    }
+
+    /// Looks up an unexpired password reset without consuming it.
+    pub async fn insert_service_token(
+        &self,
+        token: &types::DbServiceToken,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbServiceToken>(COLLECTION_NAME_SERVICE_TOKENS);
+        collection.insert_one(token, None).await?;
+        Ok(())
+    }
+
+    /// Revokes every access token issued to the application through the
+    /// `client_credentials` grant.
+    pub async fn remove_service_tokens(
+        &self,
+        client_id: bson::oid::ObjectId,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbServiceToken>(COLLECTION_NAME_SERVICE_TOKENS);
+        collection
+            .delete_many(doc! { "client_id": client_id }, None)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn service_token_by_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<types::DbServiceToken>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbServiceToken>(COLLECTION_NAME_SERVICE_TOKENS);
+        Ok(collection
+            .find_one(
+                doc! { "token_hash": token_hash, "expires_at": { "$gt": bson::DateTime::now() } },
+                None,
+            )
+            .await?)
+    }
+
+    pub async fn password_reset_by_token_hash(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<types::DbPasswordReset>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPasswordReset>(COLLECTION_NAME_PASSWORD_RESETS);
+        Ok(collection
+            .find_one(
+                doc! { "token_hash": token_hash, "expires_at": { "$gt": bson::DateTime::now() } },
+                None,
+            )
+            .await?)
+    }
+
+    /// Atomically consumes an unexpired password reset so it can only be
+    /// used once.
+    pub async fn take_password_reset(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<types::DbPasswordReset>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPasswordReset>(COLLECTION_NAME_PASSWORD_RESETS);
+        Ok(collection
+            .find_one_and_delete(
+                doc! { "token_hash": token_hash, "expires_at": { "$gt": bson::DateTime::now() } },
+                None,
+            )
+            .await?)
+    }
+
+    pub async fn remove_password_resets_for_user(
+        &self,
+        user_id: bson::oid::ObjectId,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbPasswordReset>(COLLECTION_NAME_PASSWORD_RESETS);
+        collection
+            .delete_many(doc! { "user_id": user_id }, None)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_sessions_for_user(
+        &self,
+        user_id: bson::oid::ObjectId,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbSession>(COLLECTION_NAME_SESSIONS);
+        collection
+            .delete_many(doc! { "user_id": user_id }, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Removes one session. Returns whether it existed.
+    pub async fn remove_session(
+        &self,
+        id: bson::oid::ObjectId,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbSession>(COLLECTION_NAME_SESSIONS);
+        let result = collection.delete_one(doc! { "_id": id }, None).await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    pub async fn sessions_for_user(
+        &self,
+        user_id: bson::oid::ObjectId,
+    ) -> Result<Vec<types::DbSession>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbSession>(COLLECTION_NAME_SESSIONS);
+        let mut cursor = collection.find(doc! { "user_id": user_id }, None).await?;
+        let mut sessions = vec![];
+        while cursor.advance().await? {
+            sessions.push(cursor.deserialize_current()?);
+        }
+        Ok(sessions)
+    }
+
+    pub async fn remove_grants_for_user(
+        &self,
+        user_id: bson::oid::ObjectId,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbApplicationGrant>(COLLECTION_NAME_APP_GRANTS);
+        collection
+            .delete_many(doc! { "user_id": user_id }, None)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn insert_email_verification(
+        &self,
+        verification: &types::DbEmailVerification,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbEmailVerification>(COLLECTION_NAME_EMAIL_VERIFICATIONS);
+        collection.insert_one(verification, None).await?;
+        Ok(())
+    }
+
+    /// Atomically consumes an unexpired email verification.
+    pub async fn take_email_verification(
+        &self,
+        token_hash: &str,
+    ) -> Result<Option<types::DbEmailVerification>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbEmailVerification>(COLLECTION_NAME_EMAIL_VERIFICATIONS);
+        Ok(collection
+            .find_one_and_delete(
+                doc! { "token_hash": token_hash, "expires_at": { "$gt": bson::DateTime::now() } },
+                None,
+            )
+            .await?)
+    }
+
+    pub async fn insert_invite(
+        &self,
+        invite: &types::DbInvite,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbInvite>(COLLECTION_NAME_INVITES);
+        collection.insert_one(invite, None).await?;
+        Ok(())
+    }
+
+    /// Atomically consumes an invite so it can only be used once.
+    pub async fn take_invite(
+        &self,
+        code_hash: &str,
+    ) -> Result<Option<types::DbInvite>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbInvite>(COLLECTION_NAME_INVITES);
+        Ok(collection
+            .find_one_and_delete(doc! { "code_hash": code_hash }, None)
+            .await?)
+    }
+
+    pub async fn insert_webauthn_credential(
+        &self,
+        credential: &types::DbWebauthnCredential,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbWebauthnCredential>(COLLECTION_NAME_WEBAUTHN_CREDENTIALS);
+        collection.insert_one(credential, None).await?;
+        Ok(())
+    }
+
+    pub async fn webauthn_credentials_for_user(
+        &self,
+        user_id: bson::oid::ObjectId,
+    ) -> Result<Vec<types::DbWebauthnCredential>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbWebauthnCredential>(COLLECTION_NAME_WEBAUTHN_CREDENTIALS);
+        let mut cursor = collection.find(doc! { "user_id": user_id }, None).await?;
+        let mut credentials = vec![];
+        while cursor.advance().await? {
+            credentials.push(cursor.deserialize_current()?);
+        }
+        Ok(credentials)
+    }
+
+    pub async fn webauthn_credential_by_credential_id(
+        &self,
+        credential_id: &str,
+    ) -> Option<types::DbWebauthnCredential> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbWebauthnCredential>(COLLECTION_NAME_WEBAUTHN_CREDENTIALS);
+        match collection
+            .find_one(doc! { "credential_id": credential_id }, None)
+            .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("Failed to retrieve webauthn credential {}", e);
+                None
+            }
+        }
+    }
+
+    pub async fn update_webauthn_sign_count(
+        &self,
+        credential_id: &str,
+        sign_count: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbWebauthnCredential>(COLLECTION_NAME_WEBAUTHN_CREDENTIALS);
+        collection
+            .update_one(
+                doc! { "credential_id": credential_id },
+                doc! { "$set": { "sign_count": sign_count } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn login_throttle(&self, key: &str) -> Option<types::DbLoginThrottle> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbLoginThrottle>(COLLECTION_NAME_LOGIN_THROTTLES);
+        match collection.find_one(doc! { "key": key }, None).await {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("Failed to retrieve login throttle {}", e);
+                None
+            }
+        }
+    }
+
+    /// Atomically counts a failed login for `key` and returns the new state.
+    pub async fn record_login_failure(
+        &self,
+        key: &str,
+        now: u64,
+        expires_at: bson::DateTime,
+    ) -> Result<types::DbLoginThrottle, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbLoginThrottle>(COLLECTION_NAME_LOGIN_THROTTLES);
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let throttle = collection
+            .find_one_and_update(
+                doc! { "key": key },
+                doc! {
+                    "$inc": { "failures": 1 },
+                    "$set": { "last_failure_at": now as i64, "expires_at": expires_at },
+                },
+                options,
+            )
+            .await?;
+        Ok(throttle.ok_or("Upserted login throttle is missing")?)
+    }
+
+    pub async fn lock_login_throttle(
+        &self,
+        key: &str,
+        locked_until: u64,
+        expires_at: bson::DateTime,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbLoginThrottle>(COLLECTION_NAME_LOGIN_THROTTLES);
+        collection
+            .update_one(
+                doc! { "key": key },
+                doc! { "$set": { "locked_until": locked_until as i64, "expires_at": expires_at } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove_login_throttle(&self, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbLoginThrottle>(COLLECTION_NAME_LOGIN_THROTTLES);
+        collection.delete_one(doc! { "key": key }, None).await?;
+        Ok(())
+    }
+
+    pub async fn locked_login_throttles(
+        &self,
+        now: u64,
+    ) -> Result<Vec<types::DbLoginThrottle>, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbLoginThrottle>(COLLECTION_NAME_LOGIN_THROTTLES);
+        let mut cursor = collection
+            .find(doc! { "locked_until": { "$gt": now as i64 } }, None)
+            .await?;
+        let mut throttles = vec![];
+        while cursor.advance().await? {
+            throttles.push(cursor.deserialize_current()?);
+        }
+        Ok(throttles)
+    }
+
+    pub async fn insert_audit_event(
+        &self,
+        event: &types::DbAuditEvent,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbAuditEvent>(COLLECTION_NAME_AUDIT_LOG);
+        collection.insert_one(event, None).await?;
+        Ok(())
+    }
+
+    /// Refills a token bucket for the time since it was last used and takes
+    /// one token if available, in a single atomic update.
+    pub async fn take_rate_limit_token(
+        &self,
+        key: &str,
+        capacity: f64,
+        tokens_per_millisecond: f64,
+        now_ms: i64,
+        expires_at: bson::DateTime,
+    ) -> Result<types::DbRateLimitBucket, Box<dyn std::error::Error>> {
+        let collection = self
+            .mongo
+            .database(AUTH_DATABASE_NAME)
+            .collection::<types::DbRateLimitBucket>(COLLECTION_NAME_RATE_LIMITS);
+        let elapsed_ms = doc! { "$subtract": [now_ms, { "$ifNull": ["$updated_at", now_ms] }] };
+        let refilled = doc! {
+            "$add": [
+                { "$ifNull": ["$tokens", capacity] },
+                { "$multiply": [elapsed_ms, tokens_per_millisecond] },
+            ]
+        };
+        let update = vec![
+            doc! { "$set": {
+                "tokens": { "$min": [capacity, refilled] },
+                "updated_at": now_ms,
+                "expires_at": expires_at,
+            } },
+            doc! { "$set": { "allowed": { "$gte": ["$tokens", 1.0] } } },
+            doc! { "$set": {
+                "tokens": { "$cond": ["$allowed", { "$subtract": ["$tokens", 1.0] }, "$tokens"] },
+            } },
+        ];
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let bucket = collection
+            .find_one_and_update(doc! { "key": key }, update, options)
+            .await?;
+        Ok(bucket.ok_or("Upserted rate limit bucket is missing")?)
+    }
+}
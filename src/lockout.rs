@@ -0,0 +1,219 @@
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+
+use crate::{
+    audit,
+    routes::auth::now,
+    types::{AppState, Config, DbLoginThrottle},
+};
+
+/// Longest wait the exponential back-off asks for, in seconds. Past this
+/// the lockout takes over.
+const MAX_BACKOFF_SECONDS: u64 = 5 * 60;
+
+/// Throttle key counting failed logins for `username`, in any case.
+pub fn username_key(username: &str) -> String {
+    format!("username:{}", username.trim().to_lowercase())
+}
+
+fn ip_key(client_ip: &str) -> String {
+    format!("ip:{}", client_ip)
+}
+
+/// Seconds until `throttle` allows another attempt, if it is blocking.
+fn wait_seconds(config: &Config, throttle: &DbLoginThrottle, now: u64) -> Option<u64> {
+    if let Some(locked_until) = throttle.locked_until {
+        if locked_until > now {
+            return Some(locked_until - now);
+        }
+    }
+    let extra_failures = throttle
+        .failures
+        .checked_sub(config.login_backoff_free_attempts)?;
+    let backoff = 2u64
+        .checked_pow(extra_failures)
+        .unwrap_or(u64::MAX)
+        .min(MAX_BACKOFF_SECONDS);
+    let next_attempt_at = throttle.last_failure_at.saturating_add(backoff);
+    (next_attempt_at > now).then(|| next_attempt_at - now)
+}
+
+fn expiry(seconds_from_now: u64) -> bson::DateTime {
+    bson::DateTime::from_system_time(SystemTime::now() + Duration::from_secs(seconds_from_now))
+}
+
+/// Seconds the client has to wait before trying to log in as `username`
+/// again, or `None` if it may try now.
+pub async fn retry_after(state: &AppState, username: &str, client_ip: &str) -> Option<u64> {
+    let now = now();
+    let mut longest = None;
+    for key in [username_key(username), ip_key(client_ip)] {
+        if let Some(throttle) = state.database.login_throttle(&key).await {
+            longest = longest.max(wait_seconds(&state.config, &throttle, now));
+        }
+    }
+    longest
+}
+
+async fn count_failure(state: &AppState, key: &str, threshold: u32) {
+    let config = &state.config;
+    let now = now();
+    let throttle = match state
+        .database
+        .record_login_failure(key, now, expiry(config.login_lockout_seconds))
+        .await
+    {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("Failed to record login failure: {}", e);
+            return;
+        }
+    };
+    let already_locked = throttle.locked_until.is_some_and(|until| until > now);
+    if throttle.failures < threshold || already_locked {
+        return;
+    }
+    let locked_until = now + config.login_lockout_seconds;
+    if let Err(e) = state
+        .database
+        .lock_login_throttle(key, locked_until, expiry(config.login_lockout_seconds))
+        .await
+    {
+        warn!("Failed to lock login: {}", e);
+        return;
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_LOGIN_LOCKED,
+        key,
+        format!(
+            "{} failed logins, locked for {} seconds",
+            throttle.failures, config.login_lockout_seconds
+        ),
+    )
+    .await;
+}
+
+pub async fn record_failure(state: &AppState, username: &str, client_ip: &str) {
+    count_failure(
+        state,
+        &username_key(username),
+        state.config.login_lockout_username_threshold,
+    )
+    .await;
+    count_failure(
+        state,
+        &ip_key(client_ip),
+        state.config.login_lockout_ip_threshold,
+    )
+    .await;
+}
+
+/// Forgets failures for `username` after a correct password. Failures from
+/// the IP are kept, so one good account cannot reset a spraying attack.
+pub async fn record_success(state: &AppState, username: &str) {
+    if let Err(e) = state
+        .database
+        .remove_login_throttle(&username_key(username))
+        .await
+    {
+        warn!("Failed to clear login failures: {}", e);
+    }
+}
+
+/// Lifts a lockout from the admin panel.
+pub async fn unlock(state: &AppState, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+    state.database.remove_login_throttle(key).await?;
+    info!("Login throttle {} cleared by an administrator", key);
+    audit::record(
+        &state.database,
+        audit::EVENT_LOGIN_UNLOCKED,
+        key,
+        "Unlocked from the admin panel".to_owned(),
+    )
+    .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn config() -> Config {
+        Config {
+            login_backoff_free_attempts: 3,
+            ..Default::default()
+        }
+    }
+
+    fn throttle(failures: u32, last_failure_at: u64, locked_until: Option<u64>) -> DbLoginThrottle {
+        DbLoginThrottle {
+            key: username_key("Fella"),
+            failures,
+            last_failure_at,
+            locked_until,
+            expires_at: bson::DateTime::now(),
+        }
+    }
+
+    #[test]
+    fn free_attempts_do_not_wait() {
+        for failures in 0..=2 {
+            assert_eq!(
+                wait_seconds(&config(), &throttle(failures, NOW, None), NOW),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn wait_doubles_past_the_free_attempts() {
+        let config = config();
+        assert_eq!(wait_seconds(&config, &throttle(3, NOW, None), NOW), Some(1));
+        assert_eq!(wait_seconds(&config, &throttle(4, NOW, None), NOW), Some(2));
+        assert_eq!(wait_seconds(&config, &throttle(5, NOW, None), NOW), Some(4));
+        assert_eq!(
+            wait_seconds(&config, &throttle(10, NOW, None), NOW),
+            Some(128)
+        );
+    }
+
+    #[test]
+    fn wait_is_capped() {
+        let config = config();
+        assert_eq!(
+            wait_seconds(&config, &throttle(12, NOW, None), NOW),
+            Some(MAX_BACKOFF_SECONDS)
+        );
+        assert_eq!(
+            wait_seconds(&config, &throttle(200, NOW, None), NOW),
+            Some(MAX_BACKOFF_SECONDS)
+        );
+    }
+
+    #[test]
+    fn wait_counts_from_the_last_failure() {
+        let config = config();
+        let throttle = throttle(5, NOW, None);
+        assert_eq!(wait_seconds(&config, &throttle, NOW + 3), Some(1));
+        assert_eq!(wait_seconds(&config, &throttle, NOW + 4), None);
+    }
+
+    #[test]
+    fn lockout_blocks_until_it_expires() {
+        let config = config();
+        let throttle = throttle(10, NOW - 1_000, Some(NOW + 60));
+        assert_eq!(wait_seconds(&config, &throttle, NOW), Some(60));
+        assert_eq!(wait_seconds(&config, &throttle, NOW + 59), Some(1));
+        assert_eq!(wait_seconds(&config, &throttle, NOW + 60), None);
+    }
+
+    #[test]
+    fn username_keys_ignore_case_and_whitespace() {
+        assert_eq!(username_key(" Fella "), "username:fella");
+        assert_eq!(ip_key("192.0.2.1"), "ip:192.0.2.1");
+    }
+}
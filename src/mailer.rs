@@ -0,0 +1,101 @@
+use async_trait::async_trait;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use std::{error::Error, path::PathBuf};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use tracing::info;
+
+use crate::types::Config;
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers outgoing email such as password reset links.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        username: Option<String>,
+        password: Option<String>,
+        from: &str,
+    ) -> Result<SmtpMailer, Box<dyn Error>> {
+        let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?;
+        if let (Some(username), Some(password)) = (username, password) {
+            transport = transport.credentials(Credentials::new(username, password));
+        }
+        Ok(SmtpMailer {
+            from: from.parse()?,
+            transport: transport.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse()?)
+            .subject(email.subject.clone())
+            .body(email.body.clone())?;
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Appends messages to a local file instead of sending them. Meant for local
+/// development and testing.
+pub struct FileMailer {
+    path: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(path: impl Into<PathBuf>) -> FileMailer {
+        FileMailer { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        let entry = format!(
+            "To: {}\nSubject: {}\n\n{}\n\n---\n",
+            email.to, email.subject, email.body
+        );
+        file.write_all(entry.as_bytes()).await?;
+        info!("Wrote email to {} into {}", email.to, self.path.display());
+        Ok(())
+    }
+}
+
+/// Uses SMTP when `SMTP_HOST` is configured and the file sink otherwise.
+pub fn from_config(config: &Config) -> Result<Box<dyn Mailer>, Box<dyn Error>> {
+    match &config.smtp_host {
+        Some(host) => Ok(Box::new(SmtpMailer::new(
+            host,
+            config.smtp_username.clone(),
+            config.smtp_password.clone(),
+            &config.mail_from,
+        )?)),
+        None => Ok(Box::new(FileMailer::new(&config.mail_file))),
+    }
+}
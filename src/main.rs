@@ -1,14 +1,38 @@
-use actix_web::{http::header::ContentType, web, App, HttpResponse, HttpServer, Responder};
+use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+use actix_web::{
+    cookie::{Key, SameSite},
+    http::header::ContentType,
+    web, App, HttpResponse, HttpServer, Responder,
+};
 use db::Database;
 use mongodb::{options::ClientOptions, Client};
-use tracing_subscriber;
+use sha2::{Digest, Sha512};
+use std::sync::Arc;
 use types::AppState;
 
+pub mod admin_guard;
+pub mod api_auth;
+pub mod audit;
+pub mod bootstrap;
+pub mod browser_session;
+pub mod captcha;
+pub mod claims;
+pub mod client_auth;
 pub mod config;
+pub mod csrf;
 pub mod db;
+pub mod lockout;
+pub mod mailer;
 pub mod password;
+pub mod password_policy;
+pub mod permissions;
+pub mod rate_limit;
+pub mod request_object;
 pub mod routes;
+pub mod scim;
+pub mod totp;
 pub mod types;
+pub mod webauthn;
 
 async fn home_status() -> impl Responder {
     HttpResponse::Ok()
@@ -21,22 +45,402 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
     let config = config::load_config();
     let mongo = Client::with_options(ClientOptions::parse(config.mongodb_uri.clone()).await?)?;
+    let database = Database::new(mongo.clone());
+    database.migrate().await?;
+    database.ensure_indexes().await?;
+    password_policy::check_dataset(&config)?;
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("create-admin") => {
+            let username = args
+                .get(2)
+                .ok_or("Usage: auth-server create-admin <username>")?;
+            return bootstrap::create_admin(&config, &database, username).await;
+        }
+        Some(other) => return Err(format!("Unknown command {}", other).into()),
+        None => (),
+    }
+    if config.admin_panel_enabled {
+        routes::admin::ensure_admin_application(&database).await?;
+    }
+    let mailer: Arc<dyn mailer::Mailer> = Arc::from(mailer::from_config(&config)?);
+    let captcha: Arc<dyn captcha::Captcha> = Arc::new(captcha::NoCaptcha);
+    let rate_limit_store = rate_limit::store_from_config(&config, Database::new(mongo.clone()))?;
+    // Browser sessions are signed and encrypted cookies keyed off the JWT secret.
+    let session_key = Key::from(&Sha512::digest(config.jwt_secret.as_bytes()));
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(AppState {
                 database: Database::new(mongo.clone()),
                 config: config.clone(),
+                mailer: mailer.clone(),
+                captcha: captcha.clone(),
             }))
+            .wrap(csrf::CsrfProtection)
+            .wrap(rate_limit::RateLimiter::new(
+                config.clone(),
+                rate_limit_store.clone(),
+            ))
+            .wrap(
+                SessionMiddleware::builder(CookieSessionStore::default(), session_key.clone())
+                    .cookie_secure(config.secure_cookies)
+                    // Lax keeps the cookie on top-level navigations from
+                    // client apps to /auth, but not on cross-site POSTs.
+                    .cookie_same_site(SameSite::Lax)
+                    .cookie_http_only(true)
+                    .build(),
+            )
             .route("/", web::get().to(home_status))
             .route("/auth", web::get().to(routes::auth::auth))
             .route("/login", web::post().to(routes::auth::login))
+            .route(
+                "/login/second-factor",
+                web::get().to(routes::totp::second_factor_page),
+            )
+            .route("/login/totp", web::post().to(routes::totp::login))
+            .route(
+                "/login/passkey/options",
+                web::post().to(routes::passkey::login_options),
+            )
+            .route("/login/passkey", web::post().to(routes::passkey::login))
             .route("/token", web::post().to(routes::auth::token))
+            .route("/par", web::post().to(routes::auth::pushed_authorization))
+            .service(
+                web::scope(routes::admin_api::API_PATH)
+                    .wrap(api_auth::RequireServiceToken)
+                    .app_data(
+                        web::JsonConfig::default().error_handler(routes::admin_api::json_error),
+                    )
+                    .app_data(
+                        web::QueryConfig::default().error_handler(routes::admin_api::query_error),
+                    )
+                    .route("/users", web::get().to(routes::admin_api::list_users))
+                    .route("/users", web::post().to(routes::admin_api::create_user))
+                    .route("/users/{id}", web::get().to(routes::admin_api::get_user))
+                    .route(
+                        "/users/{id}",
+                        web::patch().to(routes::admin_api::update_user),
+                    )
+                    .route(
+                        "/users/{id}",
+                        web::delete().to(routes::admin_api::delete_user),
+                    )
+                    .route(
+                        "/users/{id}/sessions",
+                        web::get().to(routes::admin_api::user_sessions),
+                    )
+                    .route(
+                        "/users/{id}/sessions",
+                        web::delete().to(routes::admin_api::end_user_sessions),
+                    )
+                    .route(
+                        "/sessions/{id}",
+                        web::delete().to(routes::admin_api::delete_session),
+                    )
+                    .route(
+                        "/applications",
+                        web::get().to(routes::admin_api::list_applications),
+                    )
+                    .route(
+                        "/applications",
+                        web::post().to(routes::admin_api::create_application),
+                    )
+                    .route(
+                        "/applications/{id}",
+                        web::get().to(routes::admin_api::get_application),
+                    )
+                    .route(
+                        "/applications/{id}",
+                        web::patch().to(routes::admin_api::update_application),
+                    )
+                    .route(
+                        "/applications/{id}",
+                        web::delete().to(routes::admin_api::delete_application),
+                    )
+                    .route(
+                        "/applications/{id}/secrets",
+                        web::post().to(routes::admin_api::create_secret),
+                    )
+                    .route(
+                        "/applications/{id}/secrets/{secret_id}",
+                        web::delete().to(routes::admin_api::delete_secret),
+                    )
+                    .route("/groups", web::get().to(routes::admin_api::list_groups))
+                    .route("/groups", web::post().to(routes::admin_api::create_group))
+                    .route("/groups/{id}", web::get().to(routes::admin_api::get_group))
+                    .route(
+                        "/groups/{id}",
+                        web::patch().to(routes::admin_api::update_group),
+                    )
+                    .route(
+                        "/groups/{id}",
+                        web::delete().to(routes::admin_api::delete_group),
+                    )
+                    .route(
+                        "/groups/{id}/members/{user_id}",
+                        web::put().to(routes::admin_api::add_group_member),
+                    )
+                    .route(
+                        "/groups/{id}/members/{user_id}",
+                        web::delete().to(routes::admin_api::remove_group_member),
+                    )
+                    .route(
+                        "/applications/{id}/policies",
+                        web::get().to(routes::admin_api::list_policy_rules),
+                    )
+                    .route(
+                        "/applications/{id}/policies",
+                        web::post().to(routes::admin_api::create_policy_rule),
+                    )
+                    .route(
+                        "/applications/{id}/policies/{rule_id}",
+                        web::put().to(routes::admin_api::update_policy_rule),
+                    )
+                    .route(
+                        "/applications/{id}/policies/{rule_id}",
+                        web::delete().to(routes::admin_api::delete_policy_rule),
+                    ),
+            )
+            .service(
+                web::scope(routes::permissions::CHECK_PATH)
+                    .wrap(api_auth::RequireServiceToken)
+                    .app_data(
+                        web::JsonConfig::default().error_handler(routes::admin_api::json_error),
+                    )
+                    .route("/check", web::post().to(routes::permissions::check))
+                    .route(
+                        "/check/batch",
+                        web::post().to(routes::permissions::check_batch),
+                    ),
+            )
             .route("/userinfo", web::get().to(routes::auth::user_info))
-            .route("/admin", web::get().to(routes::admin::panel))
-            .route("/admin/user", web::post().to(routes::admin::create_user))
+            .service(
+                web::scope(routes::scim::SCIM_PATH)
+                    .wrap(api_auth::RequireServiceToken)
+                    .app_data(web::JsonConfig::default().error_handler(routes::scim::json_error))
+                    .app_data(web::QueryConfig::default().error_handler(routes::scim::query_error))
+                    .route(
+                        "/ServiceProviderConfig",
+                        web::get().to(routes::scim::service_provider_config),
+                    )
+                    .route(
+                        "/ResourceTypes",
+                        web::get().to(routes::scim::list_resource_types),
+                    )
+                    .route(
+                        "/ResourceTypes/{id}",
+                        web::get().to(routes::scim::get_resource_type),
+                    )
+                    .route("/Schemas", web::get().to(routes::scim::list_schemas))
+                    .route("/Schemas/{id}", web::get().to(routes::scim::get_schema))
+                    .route("/Users", web::get().to(routes::scim::list_users))
+                    .route("/Users", web::post().to(routes::scim::create_user))
+                    .route("/Users/{id}", web::get().to(routes::scim::get_user))
+                    .route("/Users/{id}", web::put().to(routes::scim::replace_user))
+                    .route("/Users/{id}", web::patch().to(routes::scim::patch_user))
+                    .route("/Users/{id}", web::delete().to(routes::scim::delete_user))
+                    .route("/Groups", web::get().to(routes::scim::list_groups))
+                    .route("/Groups", web::post().to(routes::scim::create_group))
+                    .route("/Groups/{id}", web::get().to(routes::scim::get_group))
+                    .route("/Groups/{id}", web::put().to(routes::scim::replace_group))
+                    .route("/Groups/{id}", web::patch().to(routes::scim::patch_group))
+                    .route("/Groups/{id}", web::delete().to(routes::scim::delete_group)),
+            )
+            .route("/register", web::post().to(routes::register::register))
+            .route(
+                "/register/{client_id}",
+                web::get().to(routes::register::read_client),
+            )
+            .route(
+                "/register/{client_id}",
+                web::put().to(routes::register::update_client),
+            )
+            .route(
+                "/register/{client_id}",
+                web::delete().to(routes::register::delete_client),
+            )
+            .route("/signup", web::get().to(routes::signup::signup_page))
+            .route("/signup", web::post().to(routes::signup::signup))
+            .route("/reset", web::get().to(routes::reset::request_page))
+            .route("/reset", web::post().to(routes::reset::request_reset))
+            .route("/reset/confirm", web::get().to(routes::reset::confirm_page))
+            .route(
+                "/reset/confirm",
+                web::post().to(routes::reset::confirm_reset),
+            )
+            .route(
+                "/verify-email",
+                web::get().to(routes::verify_email::verify_email),
+            )
+            .route("/account", web::get().to(routes::account::account))
+            .route("/account/login", web::post().to(routes::account::login))
+            .route("/account/logout", web::post().to(routes::account::logout))
+            .route(
+                "/account/passkey/options",
+                web::post().to(routes::passkey::registration_options),
+            )
             .route(
-                "/admin/application",
-                web::post().to(routes::admin::create_application),
+                "/account/passkey",
+                web::post().to(routes::passkey::register),
+            )
+            .route("/account/totp", web::get().to(routes::totp::enroll_page))
+            .route(
+                "/account/totp/enroll",
+                web::post().to(routes::totp::start_enrollment),
+            )
+            .route(
+                "/account/totp",
+                web::post().to(routes::totp::confirm_enrollment),
+            )
+            // Registered before the /admin scope so they are reachable
+            // without being signed in to it.
+            .route("/admin/login", web::get().to(routes::admin::login))
+            .route("/admin/callback", web::get().to(routes::admin::callback))
+            .route("/admin/logout", web::post().to(routes::admin::logout))
+            .service(
+                web::scope("/admin")
+                    .wrap(admin_guard::RequireAdmin)
+                    .route("", web::get().to(routes::admin::panel))
+                    .route("/user", web::post().to(routes::admin::create_user))
+                    .route(
+                        "/user/profile",
+                        web::post().to(routes::admin::update_user_profile),
+                    )
+                    .route("/users", web::get().to(routes::admin_users::list))
+                    .route("/users/{id}", web::get().to(routes::admin_users::detail))
+                    .route(
+                        "/users/{id}/password",
+                        web::post().to(routes::admin_users::set_password),
+                    )
+                    .route(
+                        "/users/{id}/disable",
+                        web::post().to(routes::admin_users::disable),
+                    )
+                    .route(
+                        "/users/{id}/enable",
+                        web::post().to(routes::admin_users::enable),
+                    )
+                    .route(
+                        "/users/{id}/logout",
+                        web::post().to(routes::admin_users::logout),
+                    )
+                    .route(
+                        "/users/{id}/delete",
+                        web::post().to(routes::admin_users::delete),
+                    )
+                    .route("/applications", web::get().to(routes::admin_apps::list))
+                    .route(
+                        "/applications/{id}",
+                        web::get().to(routes::admin_apps::detail),
+                    )
+                    .route(
+                        "/applications/{id}",
+                        web::post().to(routes::admin_apps::update),
+                    )
+                    .route(
+                        "/applications/{id}/secrets",
+                        web::post().to(routes::admin_apps::add_secret),
+                    )
+                    .route(
+                        "/applications/{id}/secrets/{secret_id}/expire",
+                        web::post().to(routes::admin_apps::expire_secret),
+                    )
+                    .route(
+                        "/applications/{id}/secrets/{secret_id}/delete",
+                        web::post().to(routes::admin_apps::delete_secret),
+                    )
+                    .route(
+                        "/applications/{id}/delete",
+                        web::post().to(routes::admin_apps::delete),
+                    )
+                    .route(
+                        "/applications/{id}/access",
+                        web::post().to(routes::permissions::set_access),
+                    )
+                    .route(
+                        "/applications/{id}/access/users",
+                        web::post().to(routes::permissions::add_allowed_user),
+                    )
+                    .route(
+                        "/applications/{id}/access/users/{user_id}/remove",
+                        web::post().to(routes::permissions::remove_allowed_user),
+                    )
+                    .route(
+                        "/applications/{id}/access/groups",
+                        web::post().to(routes::permissions::add_allowed_group),
+                    )
+                    .route(
+                        "/applications/{id}/access/groups/{group_id}/remove",
+                        web::post().to(routes::permissions::remove_allowed_group),
+                    )
+                    .route(
+                        "/applications/{id}/permissions",
+                        web::post().to(routes::permissions::define_permission),
+                    )
+                    .route(
+                        "/applications/{id}/permissions/delete",
+                        web::post().to(routes::permissions::delete_permission),
+                    )
+                    .route(
+                        "/applications/{id}/roles",
+                        web::post().to(routes::permissions::create_role),
+                    )
+                    .route(
+                        "/applications/{id}/roles/{role_id}",
+                        web::get().to(routes::permissions::role_detail),
+                    )
+                    .route(
+                        "/applications/{id}/roles/{role_id}",
+                        web::post().to(routes::permissions::update_role),
+                    )
+                    .route(
+                        "/applications/{id}/roles/{role_id}/users",
+                        web::post().to(routes::permissions::add_role_user),
+                    )
+                    .route(
+                        "/applications/{id}/roles/{role_id}/users/{user_id}/remove",
+                        web::post().to(routes::permissions::remove_role_user),
+                    )
+                    .route(
+                        "/applications/{id}/roles/{role_id}/groups",
+                        web::post().to(routes::permissions::add_role_group),
+                    )
+                    .route(
+                        "/applications/{id}/roles/{role_id}/groups/{group_id}/remove",
+                        web::post().to(routes::permissions::remove_role_group),
+                    )
+                    .route(
+                        "/applications/{id}/roles/{role_id}/delete",
+                        web::post().to(routes::permissions::delete_role),
+                    )
+                    .route("/groups", web::get().to(routes::permissions::list_groups))
+                    .route("/groups", web::post().to(routes::permissions::create_group))
+                    .route(
+                        "/groups/{id}",
+                        web::get().to(routes::permissions::group_detail),
+                    )
+                    .route(
+                        "/groups/{id}",
+                        web::post().to(routes::permissions::rename_group),
+                    )
+                    .route(
+                        "/groups/{id}/members",
+                        web::post().to(routes::permissions::add_member),
+                    )
+                    .route(
+                        "/groups/{id}/members/{user_id}/remove",
+                        web::post().to(routes::permissions::remove_member),
+                    )
+                    .route(
+                        "/groups/{id}/delete",
+                        web::post().to(routes::permissions::delete_group),
+                    )
+                    .route("/invite", web::post().to(routes::admin::create_invite))
+                    .route("/unlock", web::post().to(routes::admin::unlock_login))
+                    .route(
+                        "/application",
+                        web::post().to(routes::admin::create_application),
+                    ),
             )
             .service(actix_files::Files::new("/static", "static").show_files_listing())
     })
@@ -45,4 +449,3 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     .await?;
     Ok(())
 }
-
@@ -4,9 +4,19 @@ use argon2::{
     PasswordVerifier,
 };
 use base64::{self, engine::Engine};
+use sha2::{Digest, Sha256};
 use tracing::warn;
 
-static SALT: &'static str = "GQ7u^e2&fmpWcpe62iTqaCmKkLU&3^";
+static SALT: &str = "GQ7u^e2&fmpWcpe62iTqaCmKkLU&3^";
+/// Checked in place of a real hash when the username does not exist, so a
+/// failed login costs the same either way. Must use the same Argon2
+/// parameters as `hash_password`.
+static UNKNOWN_USER_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$R1E3dV5lMiZmbXBXY3BlNjJpVHFhQ21La0xVJjNe$KGDFCQqHyI8wZk+pVSfeTX6xeyjK6GA59dy2KPF91b0";
+
+#[cfg(test)]
+thread_local! {
+    static VERIFICATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
 
 // NOTE: Using a plain string is vulnerable to hacks that allow reading memory
 // consider using a secure string in the future.
@@ -28,7 +38,7 @@ pub fn hash_password(cleartext_password: &str) -> Option<String> {
             return None;
         }
     };
-    return Some(encoded_password);
+    Some(encoded_password)
 }
 
 // NOTE: Using a plain string is vulnerable to hacks that allow reading memory
@@ -41,7 +51,79 @@ pub fn check_password(hash: &str, cleartext_password: &str) -> bool {
             return false;
         }
     };
+    #[cfg(test)]
+    VERIFICATIONS.with(|v| v.set(v.get() + 1));
     argon2::Argon2::default()
         .verify_password(cleartext_password.as_bytes(), &parsed_hash)
         .is_ok()
 }
+
+/// Checks a login attempt against `hash`, the user's password hash or `None`
+/// for an unknown username. Both cases run exactly one verification so the
+/// response time does not reveal which usernames exist.
+pub fn check_login_password(hash: Option<&str>, cleartext_password: &str) -> bool {
+    match hash {
+        Some(h) => check_password(h, cleartext_password),
+        None => {
+            check_password(UNKNOWN_USER_HASH, cleartext_password);
+            false
+        }
+    }
+}
+
+/// Hashes a high-entropy random token (not a user password) for storage.
+/// A fast digest is enough here because the token cannot be guessed.
+pub fn hash_token(token: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::PasswordHash;
+
+    fn verifications() -> usize {
+        VERIFICATIONS.with(|v| v.get())
+    }
+
+    #[test]
+    fn unknown_user_hash_uses_the_real_parameters() {
+        let real = hash_password("a real password").unwrap();
+        let real = PasswordHash::new(&real).unwrap();
+        let unknown = PasswordHash::new(UNKNOWN_USER_HASH).unwrap();
+        assert_eq!(unknown.algorithm, real.algorithm);
+        assert_eq!(unknown.version, real.version);
+        assert_eq!(unknown.params, real.params);
+        assert_eq!(unknown.hash.map(|h| h.len()), real.hash.map(|h| h.len()));
+    }
+
+    #[test]
+    fn unknown_and_known_users_do_the_same_work() {
+        let hash = hash_password("correct horse").unwrap();
+
+        let before = verifications();
+        assert!(!check_login_password(None, "correct horse"));
+        let unknown_user = verifications() - before;
+
+        let before = verifications();
+        assert!(!check_login_password(Some(&hash), "wrong horse"));
+        let wrong_password = verifications() - before;
+
+        let before = verifications();
+        assert!(check_login_password(Some(&hash), "correct horse"));
+        let right_password = verifications() - before;
+
+        assert_eq!(unknown_user, 1);
+        assert_eq!(wrong_password, 1);
+        assert_eq!(right_password, 1);
+    }
+
+    #[test]
+    fn unknown_user_never_logs_in() {
+        assert!(!check_login_password(
+            None,
+            "unknown user placeholder password"
+        ));
+        assert!(!check_login_password(None, ""));
+    }
+}
@@ -0,0 +1,200 @@
+use std::{fmt, io::ErrorKind, path::Path};
+
+use sha1::{Digest, Sha1};
+use tracing::warn;
+
+use crate::types::Config;
+
+/// A way a new password falls short of the policy.
+#[derive(Debug, PartialEq)]
+pub enum PasswordViolation {
+    /// Shorter than the minimum length, which is included.
+    TooShort(usize),
+    /// Below the minimum strength estimate, with a hint when there is one.
+    TooWeak(Option<String>),
+    ContainsUsername,
+    /// Found in the breached password dataset.
+    Breached,
+}
+
+impl fmt::Display for PasswordViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordViolation::TooShort(min) => {
+                write!(f, "Passwords must be at least {} characters.", min)
+            }
+            PasswordViolation::TooWeak(Some(hint)) => {
+                write!(f, "That password is too easy to guess. {}", hint)
+            }
+            PasswordViolation::TooWeak(None) => write!(f, "That password is too easy to guess."),
+            PasswordViolation::ContainsUsername => {
+                write!(f, "Passwords must not contain the username.")
+            }
+            PasswordViolation::Breached => write!(
+                f,
+                "That password has appeared in a data breach. Choose a different one."
+            ),
+        }
+    }
+}
+
+/// Fails startup if the breached password dataset is configured but
+/// missing, rather than silently skipping the check.
+pub fn check_dataset(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    match &config.password_breach_dataset {
+        Some(dir) if !Path::new(dir).is_dir() => {
+            Err(format!("PASSWORD_BREACH_DATASET {} is not a directory", dir).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks a password being set for `username` against the configured
+/// policy, returning every rule it breaks.
+pub async fn check(
+    config: &Config,
+    username: &str,
+    password: &str,
+) -> Result<(), Vec<PasswordViolation>> {
+    let mut violations = vec![];
+    if password.chars().count() < config.password_min_length {
+        violations.push(PasswordViolation::TooShort(config.password_min_length));
+    }
+    let username = username.trim().to_lowercase();
+    if !username.is_empty() && password.to_lowercase().contains(&username) {
+        violations.push(PasswordViolation::ContainsUsername);
+    }
+    let estimate = zxcvbn::zxcvbn(password, &[&username]);
+    if u8::from(estimate.score()) < config.password_min_strength {
+        let hint = estimate
+            .feedback()
+            .and_then(|f| f.warning())
+            .map(|w| w.to_string());
+        violations.push(PasswordViolation::TooWeak(hint));
+    }
+    if let Some(dataset) = &config.password_breach_dataset {
+        match is_breached(dataset, password).await {
+            Ok(true) => violations.push(PasswordViolation::Breached),
+            Ok(false) => (),
+            Err(e) => warn!("Failed to check breached passwords: {}", e),
+        }
+    }
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+/// Looks `password` up in a local copy of the Have I Been Pwned range
+/// files. Each file is named after the first five hex digits of a SHA-1
+/// (`21BD1.txt`) and lists the rest of each hash as `SUFFIX:COUNT`, so only
+/// one small file is read per check.
+async fn is_breached(dataset: &str, password: &str) -> Result<bool, std::io::Error> {
+    let digest: String = Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    let (prefix, suffix) = digest.split_at(5);
+    let path = Path::new(dataset).join(format!("{}.txt", prefix));
+    let range = match tokio::fs::read_to_string(&path).await {
+        Ok(r) => r,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    Ok(range.lines().any(|line| {
+        line.split(':')
+            .next()
+            .is_some_and(|s| s.trim().eq_ignore_ascii_case(suffix))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATASET: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/breached");
+    const BREACHED: &str = "Snazzy fellas wear purple hats";
+    const NOT_BREACHED: &str = "Snazzy fellas wear orange hats";
+
+    fn config() -> Config {
+        Config {
+            password_min_length: 10,
+            password_min_strength: 3,
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn accepts_a_strong_password() {
+        assert_eq!(check(&config(), "dapper", NOT_BREACHED).await, Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn rejects_short_passwords() {
+        let config = Config {
+            password_min_strength: 0,
+            ..config()
+        };
+        assert_eq!(
+            check(&config, "dapper", "x7#Kq2!").await,
+            Err(vec![PasswordViolation::TooShort(10)])
+        );
+        assert_eq!(check(&config, "dapper", "x7#Kq2!pZ9").await, Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn rejects_passwords_containing_the_username() {
+        let config = Config {
+            password_min_strength: 0,
+            ..config()
+        };
+        let violations = check(&config, " Dapper ", "my-dAPPer-secret-91")
+            .await
+            .unwrap_err();
+        assert_eq!(violations, vec![PasswordViolation::ContainsUsername]);
+    }
+
+    #[actix_web::test]
+    async fn rejects_passwords_below_the_strength_cut_off() {
+        let violations = check(&config(), "dapper", "password123").await.unwrap_err();
+        assert!(matches!(
+            violations.as_slice(),
+            [PasswordViolation::TooWeak(_)]
+        ));
+        let lenient = Config {
+            password_min_strength: 0,
+            ..config()
+        };
+        assert_eq!(check(&lenient, "dapper", "password123").await, Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn finds_breached_passwords_in_the_range_files() {
+        assert!(is_breached(DATASET, BREACHED).await.unwrap());
+        assert!(!is_breached(DATASET, NOT_BREACHED).await.unwrap());
+        let config = Config {
+            password_breach_dataset: Some(DATASET.to_owned()),
+            ..config()
+        };
+        assert_eq!(
+            check(&config, "dapper", BREACHED).await,
+            Err(vec![PasswordViolation::Breached])
+        );
+        assert_eq!(check(&config, "dapper", NOT_BREACHED).await, Ok(()));
+    }
+
+    #[test]
+    fn missing_dataset_fails_the_startup_check() {
+        let config = Config {
+            password_breach_dataset: Some(format!("{}/missing", DATASET)),
+            ..Default::default()
+        };
+        assert!(check_dataset(&config).is_err());
+        let config = Config {
+            password_breach_dataset: Some(DATASET.to_owned()),
+            ..Default::default()
+        };
+        assert!(check_dataset(&config).is_ok());
+    }
+}
@@ -0,0 +1,438 @@
+use std::cmp::Ordering;
+
+use bson::oid::ObjectId;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    claims,
+    db::Database,
+    types::{
+        DbApplication, DbUser, PolicyCondition, PolicyDecision, PolicyEffect, PolicyOperator,
+        PolicyRule, UserAuthorization,
+    },
+};
+
+/// Whether the user may log in to the application.
+pub async fn has_access(
+    database: &Database,
+    application: &DbApplication,
+    user_id: ObjectId,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if !application.restrict_access || application.allowed_users.contains(&user_id) {
+        return Ok(true);
+    }
+    if application.allowed_groups.is_empty() {
+        return Ok(false);
+    }
+    let groups = database.groups_for_user(user_id).await?;
+    Ok(groups.iter().any(|g| {
+        g.id.is_some_and(|id| application.allowed_groups.contains(&id))
+    }))
+}
+
+/// Works out the groups a user is in and the roles and permissions they
+/// hold in an application.
+pub async fn resolve(
+    database: &Database,
+    user_id: ObjectId,
+    application_id: ObjectId,
+) -> Result<UserAuthorization, Box<dyn std::error::Error>> {
+    let groups = database.groups_for_user(user_id).await?;
+    let group_ids: Vec<ObjectId> = groups.iter().filter_map(|g| g.id).collect();
+    let roles = database
+        .roles_for_user(application_id, user_id, &group_ids)
+        .await?;
+    let mut permissions: Vec<String> = roles
+        .iter()
+        .flat_map(|r| r.permissions.iter().cloned())
+        .collect();
+    permissions.sort();
+    permissions.dedup();
+    Ok(UserAuthorization {
+        groups: groups.into_iter().map(|g| g.name).collect(),
+        roles: roles.into_iter().map(|r| r.name).collect(),
+        permissions,
+    })
+}
+
+/// The `groups`, `roles` and `permissions` claims released for `scopes`.
+/// Nothing is looked up unless one of those scopes was granted.
+pub async fn authorization_claims(
+    database: &Database,
+    user_id: ObjectId,
+    application_id: ObjectId,
+    scopes: &[String],
+) -> Result<Map<String, Value>, Box<dyn std::error::Error>> {
+    if !claims::requests_authorization(scopes) {
+        return Ok(Map::new());
+    }
+    let authorization = resolve(database, user_id, application_id).await?;
+    Ok(claims::authorization_claims(&authorization, scopes))
+}
+
+/// What policy conditions can refer to as `subject.*`: the user's profile,
+/// custom attributes, and what they hold in the application.
+pub async fn subject_attributes(
+    database: &Database,
+    user: &DbUser,
+    application_id: ObjectId,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let user_id = user.id.ok_or("User has no ID")?;
+    let authorization = resolve(database, user_id, application_id).await?;
+    Ok(json!({
+        "id": user_id.to_hex(),
+        "username": user.username,
+        "email": user.profile.email,
+        "email_verified": user.profile.email_verified,
+        "groups": authorization.groups,
+        "roles": authorization.roles,
+        "permissions": authorization.permissions,
+        "attributes": user.profile.custom_attributes,
+    }))
+}
+
+/// The document rules are evaluated against. The resource's attributes sit
+/// next to its `id`.
+pub fn policy_request(
+    subject: Value,
+    action: &str,
+    resource_id: &str,
+    resource_attributes: &Map<String, Value>,
+    context: &Map<String, Value>,
+) -> Value {
+    let mut resource = resource_attributes.clone();
+    resource.insert("id".to_owned(), Value::from(resource_id));
+    json!({
+        "subject": subject,
+        "action": action,
+        "resource": resource,
+        "context": context,
+    })
+}
+
+/// Evaluates a request built by `policy_request`. A matching deny rule
+/// overrides any allow; with neither, the request is denied.
+pub fn evaluate(rules: &[PolicyRule], request: &Value) -> PolicyDecision {
+    let mut allowed_by = None;
+    for (index, rule) in rules.iter().enumerate() {
+        if !matches(rule, request) {
+            continue;
+        }
+        match rule.effect {
+            PolicyEffect::Deny => {
+                return PolicyDecision {
+                    allowed: false,
+                    rule: Some(index),
+                }
+            }
+            PolicyEffect::Allow => {
+                allowed_by.get_or_insert(index);
+            }
+        }
+    }
+    PolicyDecision {
+        allowed: allowed_by.is_some(),
+        rule: allowed_by,
+    }
+}
+
+fn matches(rule: &PolicyRule, request: &Value) -> bool {
+    let action = request["action"].as_str().unwrap_or_default();
+    let resource = request["resource"]["id"].as_str().unwrap_or_default();
+    rule.actions.iter().any(|p| matches_pattern(p, action))
+        && rule.resources.iter().any(|p| matches_pattern(p, resource))
+        && holds_any(&rule.roles, &request["subject"]["roles"])
+        && holds_any(&rule.permissions, &request["subject"]["permissions"])
+        && rule.conditions.iter().all(|c| condition_holds(c, request))
+}
+
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => value.starts_with(prefix),
+        None => pattern == value,
+    }
+}
+
+/// Whether `held` names one of `required`, which is satisfied when empty.
+fn holds_any(required: &[String], held: &Value) -> bool {
+    required.is_empty()
+        || held
+            .as_array()
+            .is_some_and(|held| held.iter().any(|h| required.iter().any(|r| h == r)))
+}
+
+fn lookup<'a>(request: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(request, |value, key| value.get(key))
+        .filter(|value| !value.is_null())
+}
+
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Conditions on attributes the request doesn't have never hold, except
+/// for `not_exists`.
+fn condition_holds(condition: &PolicyCondition, request: &Value) -> bool {
+    let actual = lookup(request, &condition.attribute);
+    match condition.operator {
+        PolicyOperator::Exists => return actual.is_some(),
+        PolicyOperator::NotExists => return actual.is_none(),
+        _ => {}
+    }
+    let expected = match &condition.value_from {
+        Some(path) => lookup(request, path),
+        None => condition.value.as_ref(),
+    };
+    let (Some(actual), Some(expected)) = (actual, expected) else {
+        return false;
+    };
+    let within = |list: &Value| {
+        list.as_array()
+            .is_some_and(|list| list.iter().any(|v| equal(v, actual)))
+    };
+    match condition.operator {
+        PolicyOperator::Equals => equal(actual, expected),
+        PolicyOperator::NotEquals => !equal(actual, expected),
+        PolicyOperator::In => within(expected),
+        PolicyOperator::NotIn => expected.is_array() && !within(expected),
+        PolicyOperator::Contains => match (actual, expected) {
+            (Value::Array(items), _) => items.iter().any(|v| equal(v, expected)),
+            (Value::String(text), Value::String(part)) => text.contains(part.as_str()),
+            _ => false,
+        },
+        PolicyOperator::StartsWith => match (actual, expected) {
+            (Value::String(text), Value::String(prefix)) => text.starts_with(prefix.as_str()),
+            _ => false,
+        },
+        PolicyOperator::GreaterThan => compare(actual, expected) == Some(Ordering::Greater),
+        PolicyOperator::GreaterThanOrEqual => matches!(
+            compare(actual, expected),
+            Some(Ordering::Greater | Ordering::Equal)
+        ),
+        PolicyOperator::LessThan => compare(actual, expected) == Some(Ordering::Less),
+        PolicyOperator::LessThanOrEqual => matches!(
+            compare(actual, expected),
+            Some(Ordering::Less | Ordering::Equal)
+        ),
+        PolicyOperator::Exists | PolicyOperator::NotExists => unreachable!(),
+    }
+}
+
+/// Why a rule can't be stored, if it is malformed.
+pub fn validate_rule(rule: &PolicyRule) -> Result<(), String> {
+    if rule.name.trim().is_empty() {
+        return Err("A rule needs a name".to_owned());
+    }
+    if rule.actions.is_empty() || rule.resources.is_empty() {
+        return Err("A rule needs at least one action and one resource".to_owned());
+    }
+    for condition in &rule.conditions {
+        let needs_value = !matches!(
+            condition.operator,
+            PolicyOperator::Exists | PolicyOperator::NotExists
+        );
+        let has_value = condition.value.is_some() || condition.value_from.is_some();
+        if needs_value != has_value {
+            return Err(format!(
+                "The condition on {} needs {} value or value_from",
+                condition.attribute,
+                if needs_value { "a" } else { "no" }
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs policy fixtures: a set of `rules` and the `cases` expected of them.
+///
+/// ```json
+/// {
+///   "rules": [...],
+///   "cases": [{
+///     "name": "...", "subject": {...}, "action": "...",
+///     "resource": { "id": "...", ... }, "context": {...},
+///     "expect": "allow", "rule": "name of the deciding rule"
+///   }]
+/// }
+/// ```
+#[cfg(test)]
+pub mod harness {
+    use serde::Deserialize;
+    use serde_json::{Map, Value};
+
+    use super::{evaluate, policy_request};
+    use crate::types::{PolicyEffect, PolicyRule};
+
+    #[derive(Deserialize)]
+    pub struct PolicyCase {
+        pub name: String,
+        #[serde(default)]
+        pub subject: Value,
+        pub action: String,
+        pub resource: Map<String, Value>,
+        #[serde(default)]
+        pub context: Map<String, Value>,
+        pub expect: PolicyEffect,
+        /// Name of the rule expected to decide the case, if any.
+        pub rule: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct PolicyFixture {
+        pub rules: Vec<PolicyRule>,
+        pub cases: Vec<PolicyCase>,
+    }
+
+    /// Describes how the case went wrong, if it did.
+    pub fn check(rules: &[PolicyRule], case: &PolicyCase) -> Option<String> {
+        let mut resource = case.resource.clone();
+        let id = match resource.remove("id") {
+            Some(Value::String(id)) => id,
+            _ => return Some(format!("{}: the resource needs a string id", case.name)),
+        };
+        let request = policy_request(
+            case.subject.clone(),
+            &case.action,
+            &id,
+            &resource,
+            &case.context,
+        );
+        let decision = evaluate(rules, &request);
+        let effect = if decision.allowed {
+            PolicyEffect::Allow
+        } else {
+            PolicyEffect::Deny
+        };
+        let rule = decision.rule.map(|i| rules[i].name.as_str());
+        if effect != case.expect || rule != case.rule.as_deref() {
+            return Some(format!(
+                "{}: expected {:?} by {:?}, got {:?} by {:?}",
+                case.name, case.expect, case.rule, effect, rule
+            ));
+        }
+        None
+    }
+
+    /// Panics listing every case in the fixture that isn't decided as
+    /// expected.
+    pub fn run(fixture: &str) {
+        let fixture: PolicyFixture = serde_json::from_str(fixture).unwrap();
+        for rule in &fixture.rules {
+            if let Err(e) = super::validate_rule(rule) {
+                panic!("{}: {}", rule.name, e);
+            }
+        }
+        let failures: Vec<String> = fixture
+            .cases
+            .iter()
+            .filter_map(|case| check(&fixture.rules, case))
+            .collect();
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENTS: &str = include_str!("../tests/fixtures/policies/documents.json");
+    const OPERATORS: &str = include_str!("../tests/fixtures/policies/operators.json");
+
+    fn rule(effect: PolicyEffect, actions: &[&str], resources: &[&str]) -> PolicyRule {
+        PolicyRule {
+            name: format!("{:?}", effect),
+            effect,
+            actions: actions.iter().map(|a| a.to_string()).collect(),
+            resources: resources.iter().map(|r| r.to_string()).collect(),
+            roles: vec![],
+            permissions: vec![],
+            conditions: vec![],
+        }
+    }
+
+    fn request(action: &str, resource: &str) -> Value {
+        policy_request(json!({}), action, resource, &Map::new(), &Map::new())
+    }
+
+    #[test]
+    fn documents_policy() {
+        harness::run(DOCUMENTS);
+    }
+
+    #[test]
+    fn operators_policy() {
+        harness::run(OPERATORS);
+    }
+
+    #[test]
+    fn denies_without_rules() {
+        let decision = evaluate(&[], &request("read", "doc:1"));
+        assert_eq!(
+            decision,
+            PolicyDecision {
+                allowed: false,
+                rule: None
+            }
+        );
+    }
+
+    #[test]
+    fn deny_overrides_earlier_allow() {
+        let rules = [
+            rule(PolicyEffect::Allow, &["*"], &["*"]),
+            rule(PolicyEffect::Deny, &["delete"], &["doc:*"]),
+        ];
+        assert_eq!(
+            evaluate(&rules, &request("delete", "doc:1")),
+            PolicyDecision {
+                allowed: false,
+                rule: Some(1)
+            }
+        );
+        assert_eq!(
+            evaluate(&rules, &request("read", "doc:1")),
+            PolicyDecision {
+                allowed: true,
+                rule: Some(0)
+            }
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches_pattern("*", "anything"));
+        assert!(matches_pattern("doc:*", "doc:42"));
+        assert!(!matches_pattern("doc:*", "folder:42"));
+        assert!(matches_pattern("read", "read"));
+        assert!(!matches_pattern("read", "reader"));
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        let mut bad = rule(PolicyEffect::Allow, &["read"], &[]);
+        assert!(validate_rule(&bad).is_err());
+        bad.resources = vec!["*".to_owned()];
+        assert!(validate_rule(&bad).is_ok());
+        bad.conditions.push(PolicyCondition {
+            attribute: "resource.owner".to_owned(),
+            operator: PolicyOperator::Equals,
+            value: None,
+            value_from: None,
+        });
+        assert!(validate_rule(&bad).is_err());
+        bad.conditions[0].value_from = Some("subject.id".to_owned());
+        assert!(validate_rule(&bad).is_ok());
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    web, Error, HttpResponse,
+};
+use async_trait::async_trait;
+use futures_util::future::LocalBoxFuture;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    client_auth,
+    db::Database,
+    routes,
+    types::{Config, RateLimitKey, RateLimitRule},
+};
+
+/// The in-memory backend drops full buckets once it tracks this many.
+const MEMORY_STORE_PRUNE_THRESHOLD: usize = 10_000;
+
+/// Storage for token buckets.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket `key`, creating it full if needed.
+    /// Returns how many seconds until a token is available when it is
+    /// empty.
+    async fn take(
+        &self,
+        key: &str,
+        rule: &RateLimitRule,
+    ) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+fn refill_rate_per_second(rule: &RateLimitRule) -> f64 {
+    f64::from(rule.capacity) / rule.period_seconds.max(1) as f64
+}
+
+/// Seconds until a bucket holding `tokens` has a whole token again.
+fn seconds_until_token(tokens: f64, rule: &RateLimitRule) -> u64 {
+    ((1.0 - tokens) / refill_rate_per_second(rule))
+        .ceil()
+        .max(1.0) as u64
+}
+
+struct MemoryBucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// Time after which the bucket is full again whatever it held, from
+    /// the rule that created it.
+    full_after: Duration,
+}
+
+/// Buckets kept in this process. Only suitable for a single instance.
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, MemoryBucket>>,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn take(
+        &self,
+        key: &str,
+        rule: &RateLimitRule,
+    ) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        self.take_at(key, rule, Instant::now())
+    }
+}
+
+impl MemoryStore {
+    fn take_at(
+        &self,
+        key: &str,
+        rule: &RateLimitRule,
+        now: Instant,
+    ) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        let capacity = f64::from(rule.capacity);
+        let rate = refill_rate_per_second(rule);
+        let mut buckets = self.buckets.lock().map_err(|e| e.to_string())?;
+        if buckets.len() > MEMORY_STORE_PRUNE_THRESHOLD {
+            // A bucket that would be full again is the same as no bucket.
+            buckets.retain(|_, b| now.duration_since(b.updated_at) < b.full_after);
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(MemoryBucket {
+            tokens: capacity,
+            updated_at: now,
+            full_after: Duration::from_secs(rule.period_seconds),
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(None)
+        } else {
+            Ok(Some(seconds_until_token(bucket.tokens, rule)))
+        }
+    }
+}
+
+/// Buckets shared through MongoDB, for running several instances.
+pub struct MongoStore {
+    database: Database,
+}
+
+impl MongoStore {
+    pub fn new(database: Database) -> MongoStore {
+        MongoStore { database }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MongoStore {
+    async fn take(
+        &self,
+        key: &str,
+        rule: &RateLimitRule,
+    ) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+        let now = SystemTime::now();
+        let now_ms = now.duration_since(UNIX_EPOCH)?.as_millis() as i64;
+        let expires_at =
+            bson::DateTime::from_system_time(now + Duration::from_secs(rule.period_seconds));
+        let bucket = self
+            .database
+            .take_rate_limit_token(
+                key,
+                f64::from(rule.capacity),
+                refill_rate_per_second(rule) / 1000.0,
+                now_ms,
+                expires_at,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        if bucket.allowed {
+            Ok(None)
+        } else {
+            Ok(Some(seconds_until_token(bucket.tokens, rule)))
+        }
+    }
+}
+
+pub fn store_from_config(
+    config: &Config,
+    database: Database,
+) -> Result<Arc<dyn RateLimitStore>, Box<dyn std::error::Error>> {
+    match config.rate_limit_backend.as_str() {
+        "memory" => Ok(Arc::new(MemoryStore::default())),
+        "mongo" => Ok(Arc::new(MongoStore::new(database))),
+        other => Err(format!("Unknown RATE_LIMIT_BACKEND {}", other).into()),
+    }
+}
+
+/// Form fields some rules are keyed on.
+#[derive(Deserialize, Default)]
+struct KeyFields {
+    username: Option<String>,
+    client_id: Option<String>,
+}
+
+/// Value of `key` for this request, if it has one.
+fn key_value(
+    config: &Config,
+    req: &ServiceRequest,
+    fields: &KeyFields,
+    key: RateLimitKey,
+) -> Option<String> {
+    let ip = routes::client_ip(config, req.request());
+    let identifier = match key {
+        RateLimitKey::Ip => return Some(ip),
+        RateLimitKey::Username => fields.username.as_ref().map(|u| u.trim().to_lowercase()),
+        RateLimitKey::ClientId => fields
+            .client_id
+            .clone()
+            .or_else(|| client_auth::basic_credentials(req.request()).map(|(id, _)| id)),
+    };
+    identifier
+        .filter(|i| !i.is_empty())
+        .map(|i| format!("{} {}", ip, i))
+}
+
+fn rule_matches(rule: &RateLimitRule, method: &str, path: &str) -> bool {
+    let method_matches = rule.method == "*" || rule.method == method;
+    let path_matches = match rule.path.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => rule.path == path,
+    };
+    method_matches && path_matches
+}
+
+/// Middleware applying the configured per-route token bucket limits.
+/// Requests over a limit get `429 Too Many Requests` with `Retry-After`.
+pub struct RateLimiter {
+    config: Rc<Config>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(config: Config, store: Arc<dyn RateLimitStore>) -> RateLimiter {
+        RateLimiter {
+            config: Rc::new(config),
+            store,
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware {
+            service: Rc::new(service),
+            config: self.config.clone(),
+            store: self.store.clone(),
+        }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    config: Rc<Config>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let store = self.store.clone();
+        let config = self.config.clone();
+        let rule = self
+            .config
+            .rate_limits
+            .iter()
+            .find(|r| rule_matches(r, req.method().as_str(), req.path()))
+            .cloned();
+        Box::pin(async move {
+            let rule = match rule {
+                Some(r) => r,
+                None => return Ok(service.call(req).await?.map_into_left_body()),
+            };
+            let mut fields = KeyFields::default();
+            let needs_body = rule.keys.iter().any(|k| *k != RateLimitKey::Ip);
+            if needs_body {
+                // Read the form to find the keys, then put it back for the
+                // handler.
+                let body = req.extract::<web::Bytes>().await?;
+                fields = serde_urlencoded::from_bytes(&body).unwrap_or_default();
+                let stream = futures_util::stream::once(async move { Ok(body) });
+                req.set_payload(Payload::Stream {
+                    payload: Box::pin(stream),
+                });
+            }
+            let mut retry_after = None;
+            for key in &rule.keys {
+                let value = match key_value(&config, &req, &fields, *key) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let bucket = format!("{} {} {:?}:{}", rule.method, rule.path, key, value);
+                match store.take(&bucket, &rule).await {
+                    Ok(wait) => retry_after = retry_after.max(wait),
+                    // Failing open keeps a storage outage from taking
+                    // logins down with it.
+                    Err(e) => warn!("Failed to check rate limit: {}", e),
+                }
+            }
+            if let Some(seconds) = retry_after {
+                info!("Rate limited {} {}", rule.method, rule.path);
+                let response = HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, seconds.to_string()))
+                    .body("Too many requests");
+                return Ok(req.into_response(response).map_into_right_body());
+            }
+            Ok(service.call(req).await?.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test as actix_test, App};
+
+    fn rule(method: &str, path: &str, capacity: u32, period_seconds: u64) -> RateLimitRule {
+        RateLimitRule {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            capacity,
+            period_seconds,
+            keys: vec![RateLimitKey::Ip],
+        }
+    }
+
+    #[test]
+    fn buckets_empty_and_refill() {
+        let store = MemoryStore::default();
+        let rule = rule("POST", "/login", 2, 2);
+        let start = Instant::now();
+        assert_eq!(store.take_at("a", &rule, start).unwrap(), None);
+        assert_eq!(store.take_at("a", &rule, start).unwrap(), None);
+        assert_eq!(store.take_at("a", &rule, start).unwrap(), Some(1));
+        // Other keys have their own bucket.
+        assert_eq!(store.take_at("b", &rule, start).unwrap(), None);
+        let later = start + Duration::from_secs(1);
+        assert_eq!(store.take_at("a", &rule, later).unwrap(), None);
+        assert_eq!(store.take_at("a", &rule, later).unwrap(), Some(1));
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(store.take_at("a", &rule, much_later).unwrap(), None);
+        assert_eq!(store.take_at("a", &rule, much_later).unwrap(), None);
+        assert_eq!(store.take_at("a", &rule, much_later).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn pruning_keeps_buckets_of_longer_rules() {
+        let store = MemoryStore::default();
+        let short = rule("POST", "/login", 1, 1);
+        let long = rule("POST", "/signup", 1, 300);
+        let start = Instant::now();
+        assert_eq!(store.take_at("long", &long, start).unwrap(), None);
+        for i in 0..MEMORY_STORE_PRUNE_THRESHOLD {
+            store.take_at(&i.to_string(), &short, start).unwrap();
+        }
+        let later = start + Duration::from_secs(10);
+        store.take_at("prune", &short, later).unwrap();
+        assert_eq!(store.buckets.lock().unwrap().len(), 2);
+        assert_eq!(store.take_at("long", &long, later).unwrap(), Some(290));
+    }
+
+    #[test]
+    fn rules_match_methods_and_path_prefixes() {
+        let exact = rule("POST", "/register", 1, 1);
+        assert!(rule_matches(&exact, "POST", "/register"));
+        assert!(!rule_matches(&exact, "GET", "/register"));
+        assert!(!rule_matches(&exact, "POST", "/register/abc"));
+        let prefix = rule("*", "/register/*", 1, 1);
+        assert!(rule_matches(&prefix, "PUT", "/register/abc"));
+        assert!(rule_matches(&prefix, "DELETE", "/register/abc"));
+        assert!(!rule_matches(&prefix, "POST", "/register"));
+    }
+
+    #[actix_web::test]
+    async fn identifier_buckets_are_kept_per_address() {
+        let mut login = rule("POST", "/login", 1, 60);
+        login.keys = vec![RateLimitKey::Username];
+        let config = Config {
+            rate_limits: vec![login],
+            ..Default::default()
+        };
+        let app = actix_test::init_service(
+            App::new()
+                .wrap(RateLimiter::new(config, Arc::new(MemoryStore::default())))
+                .route("/login", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |peer: &str| {
+            actix_test::TestRequest::post()
+                .uri("/login")
+                .peer_addr(peer.parse().unwrap())
+                .set_form([("username", "victim"), ("password", "guess")])
+                .to_request()
+        };
+        let attacker = "203.0.113.7:4000";
+        let response = actix_test::call_service(&app, request(attacker)).await;
+        assert_eq!(response.status(), 200);
+        let response = actix_test::call_service(&app, request(attacker)).await;
+        assert_eq!(response.status(), 429);
+        // The victim's own logins are not held up by the attacker's.
+        let response = actix_test::call_service(&app, request("198.51.100.2:4000")).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn limited_requests_get_429_with_retry_after() {
+        let config = Config {
+            rate_limits: vec![rule("POST", "/login", 1, 60)],
+            ..Default::default()
+        };
+        let app = actix_test::init_service(
+            App::new()
+                .wrap(RateLimiter::new(config, Arc::new(MemoryStore::default())))
+                .route("/login", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = || {
+            actix_test::TestRequest::post()
+                .uri("/login")
+                .peer_addr("203.0.113.7:4000".parse().unwrap())
+                .to_request()
+        };
+        let response = actix_test::call_service(&app, request()).await;
+        assert_eq!(response.status(), 200);
+        let response = actix_test::call_service(&app, request()).await;
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "60");
+    }
+}
@@ -0,0 +1,248 @@
+use std::str::FromStr;
+
+use jsonwebtoken::{self, Algorithm, Validation};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    client_auth::{decode_with_any, decoding_keys, is_hmac, keeps_secret_values},
+    types::{self, DbApplication},
+};
+
+/// Authorization parameters carried in a signed request object (RFC 9101).
+/// `iss`, `aud` and `exp` are checked during decoding and are not kept.
+#[derive(Deserialize)]
+pub struct RequestObjectClaims {
+    pub client_id: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub acr_values: Option<String>,
+    pub state: Option<String>,
+}
+
+impl RequestObjectClaims {
+    /// Signed values take precedence over the plain query parameters.
+    pub fn apply_to(self, request: &mut types::AuthorizationParameters) {
+        if let Some(redirect_uri) = self.redirect_uri {
+            request.redirect_uri = Some(redirect_uri);
+        }
+        if let Some(scope) = self.scope {
+            request.scope = Some(scope);
+        }
+        if let Some(acr_values) = self.acr_values {
+            request.acr_values = Some(acr_values);
+        }
+        if let Some(state) = self.state {
+            request.state = Some(state);
+        }
+        request.request = None;
+    }
+}
+
+/// Verifies a request object sent by `client_id` and returns its claims.
+/// The object must be issued by the client, addressed to this server and
+/// unexpired.
+pub fn verify(
+    app: &DbApplication,
+    client_id: &str,
+    request_object: &str,
+    audience: &str,
+) -> Option<RequestObjectClaims> {
+    let header = match jsonwebtoken::decode_header(request_object) {
+        Ok(h) => h,
+        Err(e) => {
+            warn!("verify(..) - failed to decode request object header: {}", e);
+            return None;
+        }
+    };
+    if let Some(alg) = &app.request_object_signing_alg {
+        if Algorithm::from_str(alg).ok() != Some(header.alg) {
+            warn!(
+                "verify(..) - request object signed with {:?}, but {} is registered",
+                header.alg, alg
+            );
+            return None;
+        }
+    }
+    if is_hmac(header.alg) && !keeps_secret_values(app) {
+        // Secrets are otherwise stored hashed and cannot key an HMAC.
+        warn!(
+            "verify(..) - {} must register an HS request_object_signing_alg to sign request objects with its secret",
+            client_id
+        );
+        return None;
+    }
+    let keys = decoding_keys(app, header.alg, header.kid.as_deref());
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[client_id]);
+    validation.set_audience(&[audience]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+    let claims = match decode_with_any::<RequestObjectClaims>(request_object, &keys, &validation) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("verify(..) - request object rejected: {}", e);
+            return None;
+        }
+    };
+    if claims.client_id.as_deref().is_some_and(|c| c != client_id) {
+        warn!("verify(..) - request object client_id does not match query");
+        return None;
+    }
+    Some(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::Engine;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{client_auth::new_secret, routes::auth::now};
+
+    const CLIENT_ID: &str = "client";
+    const AUDIENCE: &str = "https://auth.example.com";
+    const PRIVATE_KEY: &str = include_str!("../tests/fixtures/jwks/client.pem");
+    const JWKS: &str = include_str!("../tests/fixtures/jwks/client.json");
+
+    fn application(request_object_signing_alg: Option<&str>) -> DbApplication {
+        serde_json::from_value(json!({
+            "client_id": CLIENT_ID,
+            "name": "Client",
+            "redirect_uris": ["https://client.example.com/callback"],
+            "request_object_signing_alg": request_object_signing_alg,
+        }))
+        .unwrap()
+    }
+
+    fn application_with_jwks(request_object_signing_alg: Option<&str>) -> DbApplication {
+        let mut app = application(request_object_signing_alg);
+        app.jwks = Some(serde_json::from_str(JWKS).unwrap());
+        app
+    }
+
+    /// Adds a freshly issued client secret to `app`, stored as the admin
+    /// panel and registration endpoint store it.
+    fn add_secret(app: &mut DbApplication) -> String {
+        let (secret, stored) = new_secret(app, "Test", None);
+        app.secrets.push(stored);
+        secret
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": CLIENT_ID,
+            "aud": AUDIENCE,
+            "exp": now() + 60,
+            "scope": "openid profile",
+        })
+    }
+
+    fn sign_hmac(alg: Algorithm, secret: &str, claims: &Value) -> String {
+        jsonwebtoken::encode(
+            &Header::new(alg),
+            claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn sign_rsa(claims: &Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("client-key".to_owned());
+        let key = EncodingKey::from_rsa_pem(PRIVATE_KEY.as_bytes()).unwrap();
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    #[test]
+    fn verifies_rsa_request_objects_with_the_client_jwks() {
+        let app = application_with_jwks(Some("RS256"));
+        let request_object = sign_rsa(&claims());
+        let verified = verify(&app, CLIENT_ID, &request_object, AUDIENCE).unwrap();
+        assert_eq!(verified.scope.as_deref(), Some("openid profile"));
+    }
+
+    #[test]
+    fn rejects_the_wrong_audience() {
+        let app = application_with_jwks(Some("RS256"));
+        let mut claims = claims();
+        claims["aud"] = json!("https://other.example.com");
+        assert!(verify(&app, CLIENT_ID, &sign_rsa(&claims), AUDIENCE).is_none());
+    }
+
+    #[test]
+    fn rejects_expired_request_objects() {
+        let app = application_with_jwks(Some("RS256"));
+        let mut claims = claims();
+        claims["exp"] = json!(now() - 3600);
+        assert!(verify(&app, CLIENT_ID, &sign_rsa(&claims), AUDIENCE).is_none());
+    }
+
+    #[test]
+    fn rejects_another_clients_request_objects() {
+        let app = application_with_jwks(Some("RS256"));
+        let mut claims = claims();
+        claims["client_id"] = json!("other");
+        assert!(verify(&app, CLIENT_ID, &sign_rsa(&claims), AUDIENCE).is_none());
+    }
+
+    #[test]
+    fn rejects_unsigned_request_objects() {
+        let app = application_with_jwks(None);
+        let encode = |value: Value| {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(value.to_string())
+        };
+        let request_object = format!(
+            "{}.{}.",
+            encode(json!({ "alg": "none", "typ": "JWT" })),
+            encode(claims())
+        );
+        assert!(verify(&app, CLIENT_ID, &request_object, AUDIENCE).is_none());
+    }
+
+    #[test]
+    fn rejects_hmac_signed_with_the_public_key() {
+        // Using the published JWKS as an HMAC secret must not pass for an
+        // RSA signature, whether or not an algorithm is registered.
+        let request_object = sign_hmac(Algorithm::HS256, JWKS, &claims());
+        let app = application_with_jwks(Some("RS256"));
+        assert!(verify(&app, CLIENT_ID, &request_object, AUDIENCE).is_none());
+        let app = application_with_jwks(None);
+        assert!(verify(&app, CLIENT_ID, &request_object, AUDIENCE).is_none());
+    }
+
+    #[test]
+    fn rejects_rsa_when_hmac_is_registered() {
+        let mut app = application_with_jwks(Some("HS256"));
+        add_secret(&mut app);
+        assert!(verify(&app, CLIENT_ID, &sign_rsa(&claims()), AUDIENCE).is_none());
+    }
+
+    #[test]
+    fn verifies_hmac_request_objects_after_secrets_are_hashed() {
+        let mut app = application(Some("HS256"));
+        let secret = add_secret(&mut app);
+        assert_ne!(app.secrets[0].hash, secret);
+        let request_object = sign_hmac(Algorithm::HS256, &secret, &claims());
+        let verified = verify(&app, CLIENT_ID, &request_object, AUDIENCE).unwrap();
+        assert_eq!(verified.scope.as_deref(), Some("openid profile"));
+    }
+
+    #[test]
+    fn rejects_hmac_request_objects_without_a_registered_algorithm() {
+        // The secret is only stored hashed, so it cannot verify an HMAC.
+        let mut app = application(None);
+        let secret = add_secret(&mut app);
+        assert!(app.secrets[0].value.is_none());
+        let request_object = sign_hmac(Algorithm::HS256, &secret, &claims());
+        assert!(verify(&app, CLIENT_ID, &request_object, AUDIENCE).is_none());
+    }
+
+    #[test]
+    fn rejects_algorithms_other_than_the_registered_one() {
+        let mut app = application(Some("HS256"));
+        let secret = add_secret(&mut app);
+        let request_object = sign_hmac(Algorithm::HS512, &secret, &claims());
+        assert!(verify(&app, CLIENT_ID, &request_object, AUDIENCE).is_none());
+    }
+}
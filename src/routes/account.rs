@@ -0,0 +1,114 @@
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use tracing::{info, warn};
+
+use crate::{
+    browser_session, claims, csrf, lockout, password,
+    routes::{
+        auth::{begin_second_factor, complete_login, needs_second_factor},
+        client_ip, render,
+    },
+    types::{self, AccountTemplate, DbUser},
+};
+
+/// Account page for the logged in `user`, or its login form when `None`.
+pub async fn account_form(
+    state: &types::AppState,
+    session: &Session,
+    user: Option<&DbUser>,
+    error: Option<String>,
+) -> HttpResponse {
+    let user = match user {
+        Some(u) => u,
+        None => {
+            return render(AccountTemplate {
+                csrf_token: csrf::token(session),
+                username: None,
+                totp_enabled: false,
+                passkey_names: vec![],
+                error,
+            })
+        }
+    };
+    let passkey_names = match state
+        .database
+        .webauthn_credentials_for_user(user.id.unwrap())
+        .await
+    {
+        Ok(credentials) => credentials.into_iter().map(|c| c.name).collect(),
+        Err(e) => {
+            warn!("Failed to load passkeys: {}", e);
+            vec![]
+        }
+    };
+    render(AccountTemplate {
+        csrf_token: csrf::token(session),
+        username: Some(user.username.clone()),
+        totp_enabled: user.totp.as_ref().is_some_and(|t| t.confirmed),
+        passkey_names,
+        error,
+    })
+}
+
+pub async fn account(state: web::Data<types::AppState>, session: Session) -> HttpResponse {
+    let user = browser_session::current_user(&state, &session).await;
+    account_form(&state, &session, user.as_ref(), None).await
+}
+
+/// Logs in to the account page itself, with no application involved.
+pub async fn login(
+    req: HttpRequest,
+    state: web::Data<types::AppState>,
+    session: Session,
+    request: web::Form<types::AccountLoginRequest>,
+) -> HttpResponse {
+    let client_ip = client_ip(&state.config, &req);
+    if let Some(wait) = lockout::retry_after(&state, &request.username, &client_ip).await {
+        info!("Account login throttled for {} more seconds", wait);
+        let error = "Too many failed login attempts. Please try again later.".to_owned();
+        return account_form(&state, &session, None, Some(error)).await;
+    }
+    let user = state.database.user_by_username(&request.username).await;
+    let password_matches = password::check_login_password(
+        user.as_ref().map(|u| u.password_hash.as_str()),
+        &request.password,
+    );
+    let user = match user {
+        Some(u) if password_matches => u,
+        _ => {
+            info!("User entered invalid credentials on the account page");
+            lockout::record_failure(&state, &request.username, &client_ip).await;
+            return account_form(
+                &state,
+                &session,
+                None,
+                Some("Invalid username or password.".to_owned()),
+            )
+            .await;
+        }
+    };
+    if user.disabled {
+        info!("Disabled user {} tried to log in", user.username);
+        let error = "This account has been disabled.".to_owned();
+        return account_form(&state, &session, None, Some(error)).await;
+    }
+    if needs_second_factor(&state, &user).await {
+        return begin_second_factor(&state, &session, &user, None)
+            .await
+            .respond_to(&req)
+            .map_into_boxed_body();
+    }
+    lockout::record_success(&state, &user.username).await;
+    let authentication = claims::authentication_context(&[claims::AMR_PASSWORD]);
+    complete_login(&state, &session, &user, authentication, None)
+        .await
+        .respond_to(&req)
+        .map_into_boxed_body()
+}
+
+pub async fn logout(session: Session) -> HttpResponse {
+    browser_session::log_out(&session);
+    HttpResponse::SeeOther()
+        .insert_header(("Location", "/account"))
+        .finish()
+}
@@ -1,17 +1,151 @@
 use crate::{
-    password,
-    types::{self, AdminPanelTemplate, DbApplication, DbUser},
+    browser_session, client_auth, csrf,
+    db::{self, UserConflict},
+    lockout, password, password_policy,
+    routes::{
+        auth::{now, ISSUER},
+        render,
+        verify_email::send_verification_email,
+    },
+    types::{
+        self, AdminPanelTemplate, DbApplication, DbInvite, DbUser, MessageTemplate, UserProfile,
+    },
 };
+use actix_session::Session;
 use actix_web::{http::header::ContentType, web, HttpResponse};
 use askama::Template;
+use jsonwebtoken::jwk::JwkSet;
 use rand::prelude::*;
-use tracing::error;
+use tracing::{error, info, warn};
 
-pub async fn panel(state: web::Data<types::AppState>) -> HttpResponse {
+/// Client ID of the application the admin panel signs in through.
+pub const ADMIN_CLIENT_ID: &str = "admin-panel";
+
+fn admin_redirect_uri() -> String {
+    format!("{}/admin/callback", ISSUER)
+}
+
+/// Creates the admin panel's own application if it does not exist yet.
+pub async fn ensure_admin_application(
+    database: &crate::db::Database,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if database.app_by_client_id(ADMIN_CLIENT_ID).await.is_some() {
+        return Ok(());
+    }
+    let application = DbApplication {
+        id: None,
+        client_id: ADMIN_CLIENT_ID.to_owned(),
+        name: "Admin panel".to_owned(),
+        // Grants are redeemed in-process, so no secret is needed.
+        secrets: vec![],
+        redirect_uris: vec![admin_redirect_uri()],
+        jwks: None,
+        logo_uri: None,
+        grant_types: vec![],
+        token_endpoint_auth_method: None,
+        request_object_signing_alg: None,
+        registration_access_token_hash: None,
+        client_id_issued_at: None,
+        require_verified_email: false,
+        allowed_scopes: vec![],
+        access_token_lifetime: None,
+        id_token_lifetime: None,
+        permissions: vec![],
+        restrict_access: false,
+        allowed_users: vec![],
+        allowed_groups: vec![],
+    };
+    database.insert_application(&application).await?;
+    Ok(())
+}
+
+/// Starts signing in to the admin panel through the normal login flow.
+pub async fn login(state: web::Data<types::AppState>, session: Session) -> HttpResponse {
     if !state.config.admin_panel_enabled {
         return HttpResponse::Forbidden().body("Admin panel is not enabled");
     }
-    let rendering = match (AdminPanelTemplate {}).render() {
+    let location = format!(
+        "/auth?client_id={}&redirect_uri={}&scope=openid&state={}",
+        ADMIN_CLIENT_ID,
+        urlencoding::encode(&admin_redirect_uri()),
+        browser_session::begin_admin_login(&session)
+    );
+    HttpResponse::SeeOther()
+        .insert_header(("Location", location))
+        .finish()
+}
+
+/// Redeems the admin panel's authorization code and remembers the
+/// administrator in the browser session.
+pub async fn callback(
+    state: web::Data<types::AppState>,
+    session: Session,
+    query: web::Query<types::AdminCallbackQuery>,
+) -> HttpResponse {
+    if !state.config.admin_panel_enabled {
+        return HttpResponse::Forbidden().body("Admin panel is not enabled");
+    }
+    // The login has to have been started from this browser.
+    if browser_session::take_admin_login_state(&session).as_deref() != Some(query.state.as_str()) {
+        info!("Admin panel callback with an unexpected state");
+        return HttpResponse::BadRequest().body("Sign-in expired, please try again");
+    }
+    let admin_app_id = match state.database.app_by_client_id(ADMIN_CLIENT_ID).await {
+        Some(a) => a.id.unwrap(),
+        None => return HttpResponse::InternalServerError().body("Failed to sign in"),
+    };
+    // Only the admin panel's own codes are redeemed, so other
+    // applications' codes cannot be burned here.
+    let grant = match state
+        .database
+        .take_application_grant(&query.code, admin_app_id)
+        .await
+    {
+        Ok(Some(g)) => g,
+        Ok(None) => {
+            info!("Admin panel callback with an unknown code");
+            return HttpResponse::BadRequest().body("No such grant");
+        }
+        Err(e) => {
+            warn!("Failed to get application grant: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to sign in");
+        }
+    };
+    let user = match state.database.user_by_id(grant.user_id).await {
+        Some(u) => u,
+        None => return HttpResponse::BadRequest().body("No such grant"),
+    };
+    if !user.is_admin {
+        info!("{} tried to sign in to the admin panel", user.username);
+        return HttpResponse::Forbidden().body("This account is not an administrator");
+    }
+    browser_session::set_admin_user_id(&session, grant.user_id);
+    HttpResponse::SeeOther()
+        .insert_header(("Location", "/admin"))
+        .finish()
+}
+
+pub async fn logout(session: Session) -> HttpResponse {
+    browser_session::clear_admin_user_id(&session);
+    HttpResponse::SeeOther()
+        .insert_header(("Location", "/"))
+        .finish()
+}
+
+pub async fn panel(state: web::Data<types::AppState>, session: Session) -> HttpResponse {
+    let locked_logins = match state.database.locked_login_throttles(now()).await {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to load locked logins: {}", e);
+            vec![]
+        }
+    };
+    let rendering = match (AdminPanelTemplate {
+        csrf_token: csrf::token(&session),
+        locked_logins,
+    })
+    .render()
+    {
         Ok(p) => p,
         Err(e) => {
             error!("Template rendering failed: {}", e);
@@ -27,8 +161,16 @@ pub async fn create_user(
     state: web::Data<types::AppState>,
     request: web::Form<types::AdminCreateUserRequest>,
 ) -> HttpResponse {
-    if !state.config.admin_panel_enabled {
-        return HttpResponse::Forbidden().body("Admin panel is not enabled");
+    if let Err(violations) =
+        password_policy::check(&state.config, &request.username, &request.password).await
+    {
+        let items: String = violations
+            .iter()
+            .map(|v| format!("<li>{}</li>", v))
+            .collect();
+        return HttpResponse::BadRequest()
+            .content_type(ContentType::html())
+            .body(format!("Password rejected:<ul>{}</ul>", items));
     }
     let hashed_password = match password::hash_password(&request.password) {
         Some(s) => s,
@@ -36,42 +178,170 @@ pub async fn create_user(
             return HttpResponse::InternalServerError().body("Failed to hash password.");
         }
     };
-    let user = DbUser {
+    let mut user = DbUser {
         id: None,
         username: request.username.clone(),
         password_hash: hashed_password,
+        profile: UserProfile {
+            email: non_empty(&request.email),
+            ..Default::default()
+        },
+        must_verify_email: false,
+        totp: None,
+        is_admin: request.is_admin.is_some(),
+        disabled: false,
+        sessions_valid_after: 0,
+        external_id: None,
     };
     match state.database.insert_user(&user).await {
-        Ok(_) => (),
+        Ok(id) => user.id = Some(id),
         Err(e) => {
-            return HttpResponse::InternalServerError().body(format!("Failed to create user: {e}"));
+            let message = match db::user_conflict(e.as_ref()) {
+                Some(UserConflict::Username) => "That username is taken.",
+                Some(UserConflict::Email) => "An account already uses that email address.",
+                None => {
+                    return HttpResponse::InternalServerError()
+                        .body(format!("Failed to create user: {e}"))
+                }
+            };
+            return HttpResponse::BadRequest()
+                .content_type(ContentType::html())
+                .body(message);
         }
     };
+    if let Err(e) = send_verification_email(&state.database, &*state.mailer, &user).await {
+        error!("Failed to send verification email: {}", e);
+        return HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body("Created user, but the verification email could not be sent.");
+    }
     HttpResponse::Ok()
         .content_type(ContentType::html())
         .body("Created user.")
 }
 
+pub async fn create_invite(state: web::Data<types::AppState>) -> HttpResponse {
+    let code = random_string(16);
+    let invite = DbInvite {
+        code_hash: password::hash_token(&code),
+        created_at: bson::DateTime::now(),
+    };
+    if let Err(e) = state.database.insert_invite(&invite).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to create invite: {e}"));
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!("Created invite.<br />Invite code: {}", code))
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
+
+pub async fn update_user_profile(
+    state: web::Data<types::AppState>,
+    request: web::Form<types::AdminUpdateUserProfileRequest>,
+) -> HttpResponse {
+    let mut user = match state.database.user_by_username(&request.username).await {
+        Some(u) => u,
+        None => {
+            return HttpResponse::NotFound().body("No such user.");
+        }
+    };
+    match request.custom_attributes.as_deref().map(str::trim) {
+        None => (),
+        Some("") => user.profile.custom_attributes = Default::default(),
+        Some(json) => match serde_json::from_str(json) {
+            Ok(a) => user.profile.custom_attributes = a,
+            Err(e) => {
+                return HttpResponse::BadRequest()
+                    .body(format!("Custom attributes must be a JSON object: {e}"));
+            }
+        },
+    }
+    let old_email = user.profile.email.clone();
+    let profile = &mut user.profile;
+    for (field, submitted) in [
+        (&mut profile.name, &request.name),
+        (&mut profile.given_name, &request.given_name),
+        (&mut profile.family_name, &request.family_name),
+        (&mut profile.email, &request.email),
+        (&mut profile.picture, &request.picture),
+        (&mut profile.locale, &request.locale),
+        (&mut profile.zoneinfo, &request.zoneinfo),
+    ] {
+        if let Some(value) = submitted {
+            *field = non_empty(value);
+        }
+    }
+    profile.email_verified = request.email_verified.is_some();
+    profile.updated_at = Some(now());
+    let email_changed = user.profile.email != old_email;
+    if let Err(e) = state.database.update_user(&user).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to update user: {e}"));
+    }
+    if email_changed && !user.profile.email_verified {
+        if let Err(e) = send_verification_email(&state.database, &*state.mailer, &user).await {
+            error!("Failed to send verification email: {}", e);
+            return HttpResponse::Ok()
+                .content_type(ContentType::html())
+                .body("Updated user profile, but the verification email could not be sent.");
+        }
+    }
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body("Updated user profile.")
+}
+
 pub async fn create_application(
     state: web::Data<types::AppState>,
     request: web::Form<types::AdminCreateApplicationRequest>,
 ) -> HttpResponse {
-    if !state.config.admin_panel_enabled {
-        return HttpResponse::Forbidden().body("Admin panel is not enabled");
-    }
-    let secret = random_string(128);
-    let application = DbApplication {
+    let jwks = if request.jwks.trim().is_empty() {
+        None
+    } else {
+        match serde_json::from_str::<JwkSet>(&request.jwks) {
+            Ok(j) => Some(j),
+            Err(e) => {
+                return HttpResponse::BadRequest().body(format!("Invalid JWKS: {e}"));
+            }
+        }
+    };
+    let mut application = DbApplication {
         id: None,
+        client_id: random_string(32),
         name: request.app_name.clone(),
-        secret,
+        secrets: vec![],
         redirect_uris: request
             .redirect_uris
             .split(",")
             .map(|url| url.trim().to_owned())
             .collect::<Vec<_>>(),
+        jwks,
+        logo_uri: None,
+        grant_types: vec![],
+        token_endpoint_auth_method: None,
+        request_object_signing_alg: None,
+        registration_access_token_hash: None,
+        client_id_issued_at: None,
+        require_verified_email: request.require_verified_email.is_some(),
+        allowed_scopes: vec![],
+        access_token_lifetime: None,
+        id_token_lifetime: None,
+        permissions: vec![],
+        restrict_access: false,
+        allowed_users: vec![],
+        allowed_groups: vec![],
     };
-    let app_id = match state.database.insert_application(&application).await {
-        Ok(i) => i,
+    let (secret, stored) = client_auth::new_secret(&application, "Initial secret", None);
+    application.secrets.push(stored);
+    match state.database.insert_application(&application).await {
+        Ok(_) => (),
         Err(e) => {
             return HttpResponse::InternalServerError()
                 .body(format!("Failed to create application: {e}"));
@@ -81,7 +351,7 @@ pub async fn create_application(
         .content_type(ContentType::html())
         .body(format!(
             "Created application.<br />Client ID: {}<br />Client secret: {}",
-            app_id, application.secret,
+            application.client_id, secret,
         ))
 }
 
@@ -98,3 +368,15 @@ fn random_string(len: usize) -> String {
     result.into_iter().collect::<String>()
 }
 
+pub async fn unlock_login(
+    state: web::Data<types::AppState>,
+    request: web::Form<types::AdminUnlockRequest>,
+) -> HttpResponse {
+    if let Err(e) = lockout::unlock(&state, &request.key).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to unlock: {e}"));
+    }
+    render(MessageTemplate {
+        title: "Unlock login".to_owned(),
+        message: format!("Unlocked {}.", request.key),
+    })
+}
@@ -0,0 +1,1247 @@
+use actix_web::{
+    error::{InternalError, JsonPayloadError, QueryPayloadError},
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use bson::oid::ObjectId;
+use tracing::error;
+
+use crate::{
+    audit, client_auth,
+    db::{self, UserConflict},
+    password, password_policy, permissions,
+    routes::{
+        admin::ADMIN_CLIENT_ID,
+        admin_users::end_sessions,
+        auth::{generate_random_code, now, GRANT_TYPE_AUTHORIZATION_CODE},
+        permissions::SCOPE_CHECK,
+        register::SUPPORTED_GRANT_TYPES,
+        scim::SCOPE_SCIM,
+        verify_email::send_verification_email,
+    },
+    types::{
+        self, ApiApplication, ApiApplicationRequest, ApiClient, ApiClientSecret,
+        ApiCreatedApplication, ApiCreatedSecret, ApiErrorResponse, ApiGroup, ApiGroupRequest,
+        ApiPolicyRule, ApiSession, ApiUser, DbApplication, DbGroup, DbPolicyRule, DbUser,
+        PolicyRule,
+    },
+};
+
+/// Base path of this version of the API, which manages users,
+/// applications, sessions and groups. Tokens are signed with the single
+/// `JWT_SECRET`, so there are no signing keys to manage here.
+pub const API_PATH: &str = "/api/admin/v1";
+
+pub const SCOPE_USERS: &str = "admin:users";
+pub const SCOPE_APPLICATIONS: &str = "admin:applications";
+pub const SCOPE_SESSIONS: &str = "admin:sessions";
+pub const SCOPE_GROUPS: &str = "admin:groups";
+
+/// Scopes that open up these APIs rather than user data. A client can only
+/// give an application the ones it holds itself.
+const SERVICE_SCOPES: [&str; 6] = [
+    SCOPE_USERS,
+    SCOPE_APPLICATIONS,
+    SCOPE_SESSIONS,
+    SCOPE_GROUPS,
+    SCOPE_SCIM,
+    SCOPE_CHECK,
+];
+
+const DEFAULT_PAGE_SIZE: u64 = 50;
+const MAX_PAGE_SIZE: u64 = 200;
+
+pub fn api_error(status: StatusCode, error: &str, message: impl Into<String>) -> HttpResponse {
+    HttpResponse::build(status).json(ApiErrorResponse {
+        error: error.to_owned(),
+        message: message.into(),
+    })
+}
+
+pub fn bad_request(message: impl Into<String>) -> HttpResponse {
+    api_error(StatusCode::BAD_REQUEST, "invalid_request", message)
+}
+
+fn not_found(what: &str) -> HttpResponse {
+    api_error(
+        StatusCode::NOT_FOUND,
+        "not_found",
+        format!("No such {}", what),
+    )
+}
+
+pub fn server_error(action: &str, e: Box<dyn std::error::Error>) -> HttpResponse {
+    error!("API failed to {}: {}", action, e);
+    api_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "server_error",
+        format!("Failed to {}", action),
+    )
+}
+
+/// A conflict when saving a user collided with another user, otherwise a
+/// server error.
+fn user_save_error(action: &str, e: Box<dyn std::error::Error>) -> HttpResponse {
+    let message = match db::user_conflict(e.as_ref()) {
+        Some(UserConflict::Username) => "A user with that username already exists",
+        Some(UserConflict::Email) => "A user with that email address already exists",
+        None => return server_error(action, e),
+    };
+    api_error(StatusCode::CONFLICT, "already_exists", message)
+}
+
+/// Answers malformed JSON bodies in the API's error format.
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = bad_request(err.to_string());
+    InternalError::from_response(err, response).into()
+}
+
+/// Answers malformed query strings in the API's error format.
+pub fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = bad_request(err.to_string());
+    InternalError::from_response(err, response).into()
+}
+
+pub fn require_scope(client: &ApiClient, scope: &str) -> Result<(), HttpResponse> {
+    if client.scopes.iter().any(|s| s == scope) {
+        Ok(())
+    } else {
+        Err(api_error(
+            StatusCode::FORBIDDEN,
+            "insufficient_scope",
+            format!("This requires the {} scope", scope),
+        ))
+    }
+}
+
+fn actor(client: &ApiClient) -> String {
+    format!("by API client {}", client.client_id)
+}
+
+fn api_user(user: DbUser) -> ApiUser {
+    ApiUser {
+        id: user.id.map(|id| id.to_hex()).unwrap_or_default(),
+        username: user.username,
+        profile: user.profile,
+        must_verify_email: user.must_verify_email,
+        totp_enabled: user.totp.as_ref().is_some_and(|t| t.confirmed),
+        is_admin: user.is_admin,
+        disabled: user.disabled,
+    }
+}
+
+fn api_secret(secret: &types::DbClientSecret) -> ApiClientSecret {
+    ApiClientSecret {
+        id: secret.id.clone(),
+        label: secret.label.clone(),
+        created_at: secret.created_at,
+        expires_at: secret.expires_at,
+    }
+}
+
+fn api_application(app: &DbApplication) -> ApiApplication {
+    ApiApplication {
+        id: app.id.map(|id| id.to_hex()).unwrap_or_default(),
+        client_id: app.client_id.clone(),
+        name: app.name.clone(),
+        redirect_uris: app.redirect_uris.clone(),
+        logo_uri: app.logo_uri.clone(),
+        grant_types: app.grant_types.clone(),
+        allowed_scopes: app.allowed_scopes.clone(),
+        token_endpoint_auth_method: app.token_endpoint_auth_method.clone(),
+        request_object_signing_alg: app.request_object_signing_alg.clone(),
+        access_token_lifetime: app.access_token_lifetime,
+        id_token_lifetime: app.id_token_lifetime,
+        require_verified_email: app.require_verified_email,
+        jwks: app.jwks.clone(),
+        secrets: app.secrets.iter().map(api_secret).collect(),
+    }
+}
+
+fn api_session(session: types::DbSession) -> ApiSession {
+    ApiSession {
+        id: session.id.map(|id| id.to_hex()).unwrap_or_default(),
+        user_id: session.user_id.to_hex(),
+        application_id: session.client_id.to_hex(),
+        scopes: session.scopes,
+        amr: session.authentication.amr,
+        auth_time: session.authentication.auth_time,
+        expires_at: session.expires_at,
+    }
+}
+
+async fn path_user(state: &types::AppState, id: &str) -> Result<DbUser, HttpResponse> {
+    let user = match ObjectId::parse_str(id) {
+        Ok(id) => state.database.user_by_id(id).await,
+        Err(_) => None,
+    };
+    user.ok_or_else(|| not_found("user"))
+}
+
+async fn path_application(
+    state: &types::AppState,
+    id: &str,
+) -> Result<DbApplication, HttpResponse> {
+    let application = match ObjectId::parse_str(id) {
+        Ok(id) => state.database.app_by_id(id).await,
+        Err(_) => None,
+    };
+    application.ok_or_else(|| not_found("application"))
+}
+
+async fn check_password(
+    state: &types::AppState,
+    username: &str,
+    password: &str,
+) -> Result<(), HttpResponse> {
+    password_policy::check(&state.config, username, password)
+        .await
+        .map_err(|violations| {
+            let reasons: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+            api_error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "password_rejected",
+                reasons.join(" "),
+            )
+        })
+}
+
+pub async fn list_users(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    query: web::Query<types::ApiListQuery>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_USERS) {
+        return response;
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    match state
+        .database
+        .list_users(query.q.trim(), query.skip, limit as i64)
+        .await
+    {
+        Ok((users, total)) => HttpResponse::Ok().json(types::ApiUserList {
+            users: users.into_iter().map(api_user).collect(),
+            total,
+        }),
+        Err(e) => server_error("list users", e),
+    }
+}
+
+pub async fn create_user(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    request: web::Json<types::ApiCreateUserRequest>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_USERS) {
+        return response;
+    }
+    let request = request.into_inner();
+    let username = request.username.trim();
+    if username.is_empty() {
+        return bad_request("username is required");
+    }
+    if state.database.user_by_username(username).await.is_some() {
+        return api_error(
+            StatusCode::CONFLICT,
+            "already_exists",
+            "A user with that username already exists",
+        );
+    }
+    if let Err(response) = check_password(&state, username, &request.password).await {
+        return response;
+    }
+    let password_hash = match password::hash_password(&request.password) {
+        Some(h) => h,
+        None => return server_error("hash the password", "hashing failed".into()),
+    };
+    let mut user = DbUser {
+        id: None,
+        username: username.to_owned(),
+        password_hash,
+        profile: types::UserProfile {
+            updated_at: Some(now()),
+            ..request.profile
+        },
+        must_verify_email: false,
+        totp: None,
+        is_admin: request.is_admin,
+        disabled: false,
+        sessions_valid_after: 0,
+        external_id: None,
+    };
+    match state.database.insert_user(&user).await {
+        Ok(id) => user.id = Some(id),
+        Err(e) => return user_save_error("create the user", e),
+    }
+    if !user.profile.email_verified {
+        if let Err(e) = send_verification_email(&state.database, &*state.mailer, &user).await {
+            error!("Failed to send verification email: {}", e);
+        }
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_CREATED,
+        &user.username,
+        actor(&client),
+    )
+    .await;
+    let id = user.id.unwrap().to_hex();
+    HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("{}/users/{}", API_PATH, id)))
+        .json(api_user(user))
+}
+
+pub async fn get_user(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_USERS) {
+        return response;
+    }
+    match path_user(&state, &id).await {
+        Ok(user) => HttpResponse::Ok().json(api_user(user)),
+        Err(response) => response,
+    }
+}
+
+pub async fn update_user(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    request: web::Json<types::ApiUpdateUserRequest>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_USERS) {
+        return response;
+    }
+    let request = request.into_inner();
+    let mut user = match path_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    // Changing the password or disabling the user ends their sessions, as in
+    // the admin panel.
+    let mut end_user_sessions = false;
+    if let Some(new_password) = &request.password {
+        if let Err(response) = check_password(&state, &user.username, new_password).await {
+            return response;
+        }
+        user.password_hash = match password::hash_password(new_password) {
+            Some(h) => h,
+            None => return server_error("hash the password", "hashing failed".into()),
+        };
+        end_user_sessions = true;
+    }
+    let mut send_verification = false;
+    if let Some(profile) = request.profile {
+        send_verification = profile.email != user.profile.email && !profile.email_verified;
+        user.profile = types::UserProfile {
+            updated_at: Some(now()),
+            ..profile
+        };
+    }
+    if let Some(is_admin) = request.is_admin {
+        user.is_admin = is_admin;
+    }
+    if let Some(disabled) = request.disabled {
+        end_user_sessions |= disabled && !user.disabled;
+        user.disabled = disabled;
+    }
+    let result = if end_user_sessions {
+        end_sessions(&state, &mut user).await
+    } else {
+        state.database.update_user(&user).await
+    };
+    if let Err(e) = result {
+        return user_save_error("update the user", e);
+    }
+    if request.password.is_some() {
+        if let Err(e) = state
+            .database
+            .remove_password_resets_for_user(user.id.unwrap())
+            .await
+        {
+            error!("Failed to remove outstanding password resets: {}", e);
+        }
+    }
+    if send_verification {
+        if let Err(e) = send_verification_email(&state.database, &*state.mailer, &user).await {
+            error!("Failed to send verification email: {}", e);
+        }
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_UPDATED,
+        &user.username,
+        actor(&client),
+    )
+    .await;
+    HttpResponse::Ok().json(api_user(user))
+}
+
+pub async fn delete_user(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_USERS) {
+        return response;
+    }
+    let user = match path_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.database.delete_user(user.id.unwrap()).await {
+        return server_error("delete the user", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_DELETED,
+        &user.username,
+        actor(&client),
+    )
+    .await;
+    HttpResponse::NoContent().finish()
+}
+
+pub async fn user_sessions(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_SESSIONS) {
+        return response;
+    }
+    let user = match path_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    match state.database.sessions_for_user(user.id.unwrap()).await {
+        Ok(sessions) => HttpResponse::Ok().json(
+            sessions
+                .into_iter()
+                .map(api_session)
+                .collect::<Vec<ApiSession>>(),
+        ),
+        Err(e) => server_error("load sessions", e),
+    }
+}
+
+/// Logs the user out of every browser and application.
+pub async fn end_user_sessions(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_SESSIONS) {
+        return response;
+    }
+    let mut user = match path_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    if let Err(e) = end_sessions(&state, &mut user).await {
+        return server_error("end sessions", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_LOGGED_OUT,
+        &user.username,
+        actor(&client),
+    )
+    .await;
+    HttpResponse::NoContent().finish()
+}
+
+pub async fn delete_session(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_SESSIONS) {
+        return response;
+    }
+    let id = match ObjectId::parse_str(id.as_str()) {
+        Ok(id) => id,
+        Err(_) => return not_found("session"),
+    };
+    match state.database.remove_session(id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => not_found("session"),
+        Err(e) => server_error("delete the session", e),
+    }
+}
+
+/// Applies `request` to `app`, checking the result is a usable client and
+/// that `client` is not granting service scopes it does not hold.
+fn apply_application(
+    client: &ApiClient,
+    app: &mut DbApplication,
+    request: ApiApplicationRequest,
+) -> Result<(), HttpResponse> {
+    if let Some(name) = request.name {
+        app.name = name.trim().to_owned();
+    }
+    if let Some(redirect_uris) = request.redirect_uris {
+        app.redirect_uris = redirect_uris;
+    }
+    if let Some(logo_uri) = request.logo_uri {
+        app.logo_uri = (!logo_uri.is_empty()).then_some(logo_uri);
+    }
+    if let Some(grant_types) = request.grant_types {
+        app.grant_types = grant_types;
+    }
+    if let Some(allowed_scopes) = request.allowed_scopes {
+        let escalated = allowed_scopes.iter().find(|s| {
+            SERVICE_SCOPES.contains(&s.as_str())
+                && !app.allowed_scopes.contains(s)
+                && !client.scopes.contains(s)
+        });
+        if let Some(scope) = escalated {
+            return Err(api_error(
+                StatusCode::FORBIDDEN,
+                "insufficient_scope",
+                format!("Only a client holding the {} scope can grant it", scope),
+            ));
+        }
+        app.allowed_scopes = allowed_scopes;
+    }
+    if let Some(method) = request.token_endpoint_auth_method {
+        app.token_endpoint_auth_method = (!method.is_empty()).then_some(method);
+    }
+    if let Some(alg) = request.request_object_signing_alg {
+        app.request_object_signing_alg = (!alg.is_empty()).then_some(alg);
+    }
+    // 0 goes back to the server default.
+    if let Some(lifetime) = request.access_token_lifetime {
+        app.access_token_lifetime = (lifetime > 0).then_some(lifetime);
+    }
+    if let Some(lifetime) = request.id_token_lifetime {
+        app.id_token_lifetime = (lifetime > 0).then_some(lifetime);
+    }
+    if let Some(require_verified_email) = request.require_verified_email {
+        app.require_verified_email = require_verified_email;
+    }
+    if let Some(jwks) = request.jwks {
+        app.jwks = Some(jwks);
+    }
+
+    if app.name.is_empty() {
+        return Err(bad_request("name is required"));
+    }
+    if let Some(unsupported) = app
+        .grant_types
+        .iter()
+        .find(|g| !SUPPORTED_GRANT_TYPES.contains(&g.as_str()))
+    {
+        return Err(bad_request(format!(
+            "Unsupported grant type {}",
+            unsupported
+        )));
+    }
+    let uses_redirects = app.grant_types.is_empty()
+        || app
+            .grant_types
+            .iter()
+            .any(|g| g == GRANT_TYPE_AUTHORIZATION_CODE);
+    if uses_redirects && app.redirect_uris.is_empty() {
+        return Err(bad_request("At least one redirect URI is required"));
+    }
+    if let Some(method) = &app.token_endpoint_auth_method {
+        if !client_auth::SUPPORTED_AUTH_METHODS.contains(&method.as_str()) {
+            return Err(bad_request(format!(
+                "Unsupported token_endpoint_auth_method {}",
+                method
+            )));
+        }
+        if method == client_auth::PRIVATE_KEY_JWT && app.jwks.is_none() {
+            return Err(bad_request("private_key_jwt requires jwks"));
+        }
+    }
+    if let Some(alg) = &app.request_object_signing_alg {
+        client_auth::check_request_object_signing_alg(alg, app.jwks.is_some())
+            .map_err(bad_request)?;
+    }
+    if !client_auth::keeps_secret_values(app) {
+        for secret in &mut app.secrets {
+            secret.value = None;
+        }
+    }
+    Ok(())
+}
+
+pub async fn list_applications(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    match state.database.list_applications().await {
+        Ok(applications) => HttpResponse::Ok().json(
+            applications
+                .iter()
+                .map(api_application)
+                .collect::<Vec<ApiApplication>>(),
+        ),
+        Err(e) => server_error("list applications", e),
+    }
+}
+
+pub async fn create_application(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    request: web::Json<ApiApplicationRequest>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    let mut application = DbApplication {
+        id: None,
+        client_id: generate_random_code(32),
+        name: String::new(),
+        secrets: vec![],
+        redirect_uris: vec![],
+        jwks: None,
+        logo_uri: None,
+        grant_types: vec![],
+        token_endpoint_auth_method: None,
+        request_object_signing_alg: None,
+        registration_access_token_hash: None,
+        client_id_issued_at: Some(now()),
+        require_verified_email: false,
+        allowed_scopes: vec![],
+        access_token_lifetime: None,
+        id_token_lifetime: None,
+        permissions: vec![],
+        restrict_access: false,
+        allowed_users: vec![],
+        allowed_groups: vec![],
+    };
+    if let Err(response) = apply_application(&client, &mut application, request.into_inner()) {
+        return response;
+    }
+    let (client_secret, stored) = client_auth::new_secret(&application, "Initial secret", None);
+    application.secrets.push(stored);
+    match state.database.insert_application(&application).await {
+        Ok(id) => application.id = Some(id),
+        Err(e) => return server_error("create the application", e),
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_CREATED,
+        &application.client_id,
+        actor(&client),
+    )
+    .await;
+    let id = application.id.unwrap().to_hex();
+    HttpResponse::Created()
+        .insert_header((
+            header::LOCATION,
+            format!("{}/applications/{}", API_PATH, id),
+        ))
+        .json(ApiCreatedApplication {
+            application: api_application(&application),
+            client_secret,
+        })
+}
+
+pub async fn get_application(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    match path_application(&state, &id).await {
+        Ok(application) => HttpResponse::Ok().json(api_application(&application)),
+        Err(response) => response,
+    }
+}
+
+pub async fn update_application(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    request: web::Json<ApiApplicationRequest>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    if application.client_id == ADMIN_CLIENT_ID {
+        return api_error(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "The admin panel's application cannot be changed",
+        );
+    }
+    let previous_scopes = application.allowed_scopes.clone();
+    if let Err(response) = apply_application(&client, &mut application, request.into_inner()) {
+        return response;
+    }
+    if let Err(e) = state.database.update_application(&application).await {
+        return server_error("update the application", e);
+    }
+    if previous_scopes
+        .iter()
+        .any(|s| !application.allowed_scopes.contains(s))
+    {
+        if let Err(e) = state
+            .database
+            .remove_service_tokens(application.id.unwrap())
+            .await
+        {
+            return server_error("revoke access tokens", e);
+        }
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_UPDATED,
+        &application.client_id,
+        actor(&client),
+    )
+    .await;
+    HttpResponse::Ok().json(api_application(&application))
+}
+
+/// Deletes the application and revokes every session and grant it has.
+pub async fn delete_application(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    let application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    if application.client_id == ADMIN_CLIENT_ID {
+        return api_error(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "The admin panel's application cannot be deleted",
+        );
+    }
+    if let Err(e) = state
+        .database
+        .delete_application(application.id.unwrap())
+        .await
+    {
+        return server_error("delete the application", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_DELETED,
+        &application.client_id,
+        actor(&client),
+    )
+    .await;
+    HttpResponse::NoContent().finish()
+}
+
+pub async fn create_secret(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    request: web::Json<types::ApiCreateSecretRequest>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    if application.client_id == ADMIN_CLIENT_ID {
+        return api_error(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            "The admin panel's application has no secrets",
+        );
+    }
+    if request.expires_at.is_some_and(|at| at <= now()) {
+        return bad_request("expires_at must be in the future");
+    }
+    let label = match request.label.trim() {
+        "" => "Unnamed secret",
+        label => label,
+    };
+    let (client_secret, stored) = client_auth::new_secret(&application, label, request.expires_at);
+    let secret = api_secret(&stored);
+    application.secrets.push(stored);
+    if let Err(e) = state.database.update_application(&application).await {
+        return server_error("add the secret", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_SECRET_ADDED,
+        &application.client_id,
+        format!("{} {}", label, actor(&client)),
+    )
+    .await;
+    HttpResponse::Created().json(ApiCreatedSecret {
+        secret,
+        client_secret,
+    })
+}
+
+pub async fn delete_secret(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    let (id, secret_id) = path.into_inner();
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let position = match application.secrets.iter().position(|s| s.id == secret_id) {
+        Some(p) => p,
+        None => return not_found("secret"),
+    };
+    let removed = application.secrets.remove(position);
+    if let Err(e) = state.database.update_application(&application).await {
+        return server_error("delete the secret", e);
+    }
+    // Any token could have been issued with the deleted secret.
+    if let Err(e) = state
+        .database
+        .remove_service_tokens(application.id.unwrap())
+        .await
+    {
+        return server_error("revoke access tokens", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_SECRET_DELETED,
+        &application.client_id,
+        format!("{} {}", removed.label, actor(&client)),
+    )
+    .await;
+    HttpResponse::NoContent().finish()
+}
+
+fn api_group(group: DbGroup) -> ApiGroup {
+    ApiGroup {
+        id: group.id.map(|id| id.to_hex()).unwrap_or_default(),
+        name: group.name,
+        external_id: group.external_id,
+        members: group.members.into_iter().map(ObjectId::to_hex).collect(),
+        created_at: group.created_at,
+        updated_at: group.updated_at,
+    }
+}
+
+async fn path_group(state: &types::AppState, id: &str) -> Result<DbGroup, HttpResponse> {
+    let group = match ObjectId::parse_str(id) {
+        Ok(id) => match state.database.group_by_id(id).await {
+            Ok(group) => group,
+            Err(e) => return Err(server_error("load the group", e)),
+        },
+        Err(_) => None,
+    };
+    group.ok_or_else(|| not_found("group"))
+}
+
+/// Applies `request` to `group`, checking the name is free and every
+/// member exists.
+async fn apply_group(
+    state: &types::AppState,
+    group: &mut DbGroup,
+    request: ApiGroupRequest,
+) -> Result<(), HttpResponse> {
+    if let Some(name) = request.name {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(bad_request("name is required"));
+        }
+        if name != group.name {
+            match state.database.group_by_name(name).await {
+                Ok(None) => (),
+                Ok(Some(_)) => {
+                    return Err(api_error(
+                        StatusCode::CONFLICT,
+                        "already_exists",
+                        "A group with that name already exists",
+                    ))
+                }
+                Err(e) => return Err(server_error("load the group", e)),
+            }
+            group.name = name.to_owned();
+        }
+    }
+    if let Some(members) = request.members {
+        let mut ids = Vec::with_capacity(members.len());
+        for member in &members {
+            let user = path_user(state, member)
+                .await
+                .map_err(|_| bad_request(format!("No such user {}", member)))?;
+            let id = user.id.unwrap();
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        group.members = ids;
+    }
+    if group.name.is_empty() {
+        return Err(bad_request("name is required"));
+    }
+    group.updated_at = now();
+    Ok(())
+}
+
+pub async fn list_groups(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_GROUPS) {
+        return response;
+    }
+    match state.database.list_groups().await {
+        Ok(groups) => {
+            HttpResponse::Ok().json(groups.into_iter().map(api_group).collect::<Vec<ApiGroup>>())
+        }
+        Err(e) => server_error("list groups", e),
+    }
+}
+
+pub async fn create_group(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    request: web::Json<ApiGroupRequest>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_GROUPS) {
+        return response;
+    }
+    let mut group = DbGroup {
+        id: None,
+        name: String::new(),
+        external_id: None,
+        members: vec![],
+        created_at: now(),
+        updated_at: now(),
+    };
+    if let Err(response) = apply_group(&state, &mut group, request.into_inner()).await {
+        return response;
+    }
+    match state.database.insert_group(&group).await {
+        Ok(id) => group.id = Some(id),
+        Err(e) => return server_error("create the group", e),
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_GROUP_CREATED,
+        &group.name,
+        actor(&client),
+    )
+    .await;
+    let id = group.id.unwrap().to_hex();
+    HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("{}/groups/{}", API_PATH, id)))
+        .json(api_group(group))
+}
+
+pub async fn get_group(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_GROUPS) {
+        return response;
+    }
+    match path_group(&state, &id).await {
+        Ok(group) => HttpResponse::Ok().json(api_group(group)),
+        Err(response) => response,
+    }
+}
+
+pub async fn update_group(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    request: web::Json<ApiGroupRequest>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_GROUPS) {
+        return response;
+    }
+    let mut group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    if let Err(response) = apply_group(&state, &mut group, request.into_inner()).await {
+        return response;
+    }
+    if let Err(e) = state.database.update_group(&group).await {
+        return server_error("update the group", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_GROUP_UPDATED,
+        &group.name,
+        actor(&client),
+    )
+    .await;
+    HttpResponse::Ok().json(api_group(group))
+}
+
+/// Deletes the group. Its members lose the roles and access it gave.
+pub async fn delete_group(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_GROUPS) {
+        return response;
+    }
+    let group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.database.delete_group(group.id.unwrap()).await {
+        return server_error("delete the group", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_GROUP_DELETED,
+        &group.name,
+        actor(&client),
+    )
+    .await;
+    HttpResponse::NoContent().finish()
+}
+
+pub async fn add_group_member(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_GROUPS) {
+        return response;
+    }
+    let (id, user_id) = path.into_inner();
+    let mut group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    let user = match path_user(&state, &user_id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    if !group.members.contains(&user.id.unwrap()) {
+        group.members.push(user.id.unwrap());
+        group.updated_at = now();
+        if let Err(e) = state.database.update_group(&group).await {
+            return server_error("update the group", e);
+        }
+        audit::record(
+            &state.database,
+            audit::EVENT_GROUP_UPDATED,
+            &group.name,
+            format!("added {} {}", user.username, actor(&client)),
+        )
+        .await;
+    }
+    HttpResponse::NoContent().finish()
+}
+
+pub async fn remove_group_member(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_GROUPS) {
+        return response;
+    }
+    let (id, user_id) = path.into_inner();
+    let mut group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    let position = match ObjectId::parse_str(&user_id)
+        .ok()
+        .and_then(|user_id| group.members.iter().position(|m| *m == user_id))
+    {
+        Some(p) => p,
+        None => return not_found("member"),
+    };
+    group.members.remove(position);
+    group.updated_at = now();
+    if let Err(e) = state.database.update_group(&group).await {
+        return server_error("update the group", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_GROUP_UPDATED,
+        &group.name,
+        format!("removed {} {}", user_id, actor(&client)),
+    )
+    .await;
+    HttpResponse::NoContent().finish()
+}
+
+fn api_policy_rule(rule: DbPolicyRule) -> ApiPolicyRule {
+    ApiPolicyRule {
+        id: rule.id.map(|id| id.to_hex()).unwrap_or_default(),
+        rule: rule.rule,
+    }
+}
+
+/// Loads a policy rule of `application` by the ID in the path.
+async fn path_policy_rule(
+    state: &types::AppState,
+    application: &DbApplication,
+    id: &str,
+) -> Result<DbPolicyRule, HttpResponse> {
+    let rule = match ObjectId::parse_str(id) {
+        Ok(id) => match state.database.policy_rule_by_id(id).await {
+            Ok(rule) => rule,
+            Err(e) => return Err(server_error("load the policy rule", e)),
+        },
+        Err(_) => None,
+    };
+    rule.filter(|r| Some(r.application_id) == application.id)
+        .ok_or_else(|| not_found("policy rule"))
+}
+
+/// The application's policy rules in evaluation order.
+pub async fn list_policy_rules(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    let application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    match state
+        .database
+        .policy_rules_for_application(application.id.unwrap())
+        .await
+    {
+        Ok(rules) => HttpResponse::Ok().json(
+            rules
+                .into_iter()
+                .map(api_policy_rule)
+                .collect::<Vec<ApiPolicyRule>>(),
+        ),
+        Err(e) => server_error("list the policy rules", e),
+    }
+}
+
+pub async fn create_policy_rule(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    request: web::Json<PolicyRule>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    let application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let rule = request.into_inner();
+    if let Err(message) = permissions::validate_rule(&rule) {
+        return bad_request(message);
+    }
+    let mut rule = DbPolicyRule {
+        id: None,
+        application_id: application.id.unwrap(),
+        rule,
+    };
+    match state.database.insert_policy_rule(&rule).await {
+        Ok(id) => rule.id = Some(id),
+        Err(e) => return server_error("create the policy rule", e),
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_POLICY_RULE_CREATED,
+        &application.client_id,
+        format!("{} {}", rule.rule.name, actor(&client)),
+    )
+    .await;
+    HttpResponse::Created().json(api_policy_rule(rule))
+}
+
+pub async fn update_policy_rule(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    path: web::Path<(String, String)>,
+    request: web::Json<PolicyRule>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    let (id, rule_id) = path.into_inner();
+    let application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let mut rule = match path_policy_rule(&state, &application, &rule_id).await {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+    if let Err(message) = permissions::validate_rule(&request) {
+        return bad_request(message);
+    }
+    rule.rule = request.into_inner();
+    if let Err(e) = state.database.update_policy_rule(&rule).await {
+        return server_error("update the policy rule", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_POLICY_RULE_UPDATED,
+        &application.client_id,
+        format!("{} {}", rule.rule.name, actor(&client)),
+    )
+    .await;
+    HttpResponse::Ok().json(api_policy_rule(rule))
+}
+
+pub async fn delete_policy_rule(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_APPLICATIONS) {
+        return response;
+    }
+    let (id, rule_id) = path.into_inner();
+    let application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let rule = match path_policy_rule(&state, &application, &rule_id).await {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.database.delete_policy_rule(rule.id.unwrap()).await {
+        return server_error("delete the policy rule", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_POLICY_RULE_DELETED,
+        &application.client_id,
+        format!("{} {}", rule.rule.name, actor(&client)),
+    )
+    .await;
+    HttpResponse::NoContent().finish()
+}
@@ -0,0 +1,393 @@
+use actix_session::Session;
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use bson::oid::ObjectId;
+use jsonwebtoken::jwk::JwkSet;
+use tracing::{error, info};
+
+use crate::{
+    audit, client_auth, csrf,
+    routes::{
+        admin::ADMIN_CLIENT_ID,
+        auth::now,
+        permissions::{group_choices, members},
+        register::SUPPORTED_GRANT_TYPES,
+        render,
+    },
+    types::{
+        self, AdminApplicationTemplate, AdminApplicationsTemplate, AdminClientSecret,
+        AdminIdentity, DbApplication,
+    },
+};
+
+/// Loads the application named by the `{id}` path segment.
+pub async fn path_application(
+    state: &types::AppState,
+    id: &str,
+) -> Result<DbApplication, HttpResponse> {
+    let application = match ObjectId::parse_str(id) {
+        Ok(id) => state.database.app_by_id(id).await,
+        Err(_) => None,
+    };
+    application.ok_or_else(|| HttpResponse::NotFound().body("No such application."))
+}
+
+pub async fn detail_page(
+    state: &types::AppState,
+    session: &Session,
+    application: DbApplication,
+    error: Option<String>,
+) -> HttpResponse {
+    let app_id = application.id.unwrap();
+    let session_count = match state.database.count_sessions_for_application(app_id).await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to count sessions: {}", e);
+            0
+        }
+    };
+    let roles = match state.database.roles_for_application(app_id).await {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Failed to load roles: {}", e);
+            vec![]
+        }
+    };
+    let allowed_users = members(state, &application.allowed_users).await;
+    let (allowed_groups, other_groups) = group_choices(state, &application.allowed_groups).await;
+    let jwks = match &application.jwks {
+        Some(jwks) => serde_json::to_string_pretty(jwks).unwrap_or_default(),
+        None => String::new(),
+    };
+    let secrets = application
+        .secrets
+        .iter()
+        .map(|s| AdminClientSecret {
+            id: s.id.clone(),
+            label: s.label.clone(),
+            created_at: s.created_at,
+            expires_at: s.expires_at,
+            expired: !client_auth::secret_active(s),
+        })
+        .collect();
+    render(AdminApplicationTemplate {
+        csrf_token: csrf::token(session),
+        app_id: app_id.to_hex(),
+        redirect_uris: application.redirect_uris.join("\n"),
+        jwks,
+        session_count,
+        secrets,
+        roles,
+        allowed_users,
+        allowed_groups,
+        other_groups,
+        error,
+        application,
+    })
+}
+
+/// Parses an optional lifetime in seconds. Empty means the server default.
+fn parse_lifetime(value: &str, name: &str) -> Result<Option<u64>, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.parse::<u64>() {
+        Ok(0) | Err(_) => Err(format!("{} must be a positive number of seconds.", name)),
+        Ok(seconds) => Ok(Some(seconds)),
+    }
+}
+
+/// Applies the edit form to `application`, or says what is wrong with it.
+fn apply_update(
+    application: &mut DbApplication,
+    request: &types::AdminUpdateApplicationRequest,
+) -> Result<(), String> {
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err("The application needs a name.".to_owned());
+    }
+    let redirect_uris: Vec<String> = request
+        .redirect_uris
+        .split(['\n', ','])
+        .map(|uri| uri.trim().to_owned())
+        .filter(|uri| !uri.is_empty())
+        .collect();
+    if redirect_uris.is_empty() {
+        return Err("At least one redirect URI is required.".to_owned());
+    }
+    let grant_types: Vec<String> = request
+        .grant_types
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect();
+    if let Some(unsupported) = grant_types
+        .iter()
+        .find(|g| !SUPPORTED_GRANT_TYPES.contains(&g.as_str()))
+    {
+        return Err(format!("Unsupported grant type {}.", unsupported));
+    }
+    let access_token_lifetime =
+        parse_lifetime(&request.access_token_lifetime, "Access token lifetime")?;
+    let id_token_lifetime = parse_lifetime(&request.id_token_lifetime, "ID token lifetime")?;
+    let jwks = if request.jwks.trim().is_empty() {
+        None
+    } else {
+        match serde_json::from_str::<JwkSet>(&request.jwks) {
+            Ok(j) => Some(j),
+            Err(e) => return Err(format!("Invalid JWKS: {e}")),
+        }
+    };
+    let logo_uri = request.logo_uri.trim();
+
+    application.name = name.to_owned();
+    application.redirect_uris = redirect_uris;
+    application.logo_uri = (!logo_uri.is_empty()).then(|| logo_uri.to_owned());
+    application.grant_types = grant_types;
+    application.allowed_scopes = request
+        .allowed_scopes
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect();
+    application.access_token_lifetime = access_token_lifetime;
+    application.id_token_lifetime = id_token_lifetime;
+    application.jwks = jwks;
+    application.require_verified_email = request.require_verified_email.is_some();
+    Ok(())
+}
+
+pub async fn list(state: web::Data<types::AppState>) -> HttpResponse {
+    let applications = match state.database.list_applications().await {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Failed to list applications: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to list applications");
+        }
+    };
+    render(AdminApplicationsTemplate { applications })
+}
+
+pub async fn detail(
+    state: web::Data<types::AppState>,
+    session: Session,
+    id: web::Path<String>,
+) -> HttpResponse {
+    match path_application(&state, &id).await {
+        Ok(application) => detail_page(&state, &session, application, None).await,
+        Err(response) => response,
+    }
+}
+
+pub async fn update(
+    state: web::Data<types::AppState>,
+    session: Session,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminUpdateApplicationRequest>,
+) -> HttpResponse {
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let previous_scopes = application.allowed_scopes.clone();
+    if let Err(message) = apply_update(&mut application, &request) {
+        return detail_page(&state, &session, application, Some(message)).await;
+    }
+    if let Err(e) = state.database.update_application(&application).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to update application: {e}"));
+    }
+    if previous_scopes
+        .iter()
+        .any(|s| !application.allowed_scopes.contains(s))
+    {
+        if let Err(response) = revoke_service_tokens(&state, &application).await {
+            return response;
+        }
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_UPDATED,
+        &application.client_id,
+        format!("by {}", admin.username),
+    )
+    .await;
+    back_to_application(&id)
+}
+
+/// Revokes the application's `client_credentials` tokens after its scopes
+/// are cut or a secret stops working. Tokens are not tied to the secret
+/// they were issued for, so all of them go.
+async fn revoke_service_tokens(
+    state: &types::AppState,
+    application: &DbApplication,
+) -> Result<(), HttpResponse> {
+    state
+        .database
+        .remove_service_tokens(application.id.unwrap())
+        .await
+        .map_err(|e| {
+            HttpResponse::InternalServerError().body(format!("Failed to revoke access tokens: {e}"))
+        })
+}
+
+pub fn back_to_application(id: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header(("Location", format!("/admin/applications/{}", id)))
+        .finish()
+}
+
+/// Issues an additional client secret and shows it once.
+pub async fn add_secret(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminAddSecretRequest>,
+) -> HttpResponse {
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    if application.client_id == ADMIN_CLIENT_ID {
+        return HttpResponse::BadRequest().body("The admin panel's application has no secrets.");
+    }
+    let expires_at = match request.expires_in_days.trim() {
+        "" => None,
+        days => match days.parse::<u64>() {
+            Ok(days) if days > 0 => Some(now() + days.saturating_mul(24 * 60 * 60)),
+            _ => return HttpResponse::BadRequest().body("Invalid expiry."),
+        },
+    };
+    let label = match request.label.trim() {
+        "" => "Unnamed secret",
+        label => label,
+    };
+    let (secret, stored) = client_auth::new_secret(&application, label, expires_at);
+    application.secrets.push(stored);
+    if let Err(e) = state.database.update_application(&application).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to add secret: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_SECRET_ADDED,
+        &application.client_id,
+        format!("{} by {}", label, admin.username),
+    )
+    .await;
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            "Added secret. It will not be shown again.<br />Client ID: {}<br />Client secret: {}<br /><a href=\"/admin/applications/{}\">Back</a>",
+            application.client_id, secret, id,
+        ))
+}
+
+/// Stops accepting a secret after the grace period, or at once when it is 0.
+pub async fn expire_secret(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String)>,
+    request: web::Form<types::AdminExpireSecretRequest>,
+) -> HttpResponse {
+    let (id, secret_id) = path.into_inner();
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let expires_at = now() + request.grace_period_hours.saturating_mul(60 * 60);
+    let label = match application.secrets.iter_mut().find(|s| s.id == secret_id) {
+        Some(secret) => {
+            // Never extend a secret that would expire sooner anyway.
+            secret.expires_at = Some(
+                secret
+                    .expires_at
+                    .map_or(expires_at, |at| at.min(expires_at)),
+            );
+            secret.label.clone()
+        }
+        None => return HttpResponse::NotFound().body("No such secret."),
+    };
+    if let Err(e) = state.database.update_application(&application).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to expire secret: {e}"));
+    }
+    if request.grace_period_hours == 0 {
+        if let Err(response) = revoke_service_tokens(&state, &application).await {
+            return response;
+        }
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_SECRET_EXPIRED,
+        &application.client_id,
+        format!(
+            "{} in {} hours by {}",
+            label, request.grace_period_hours, admin.username
+        ),
+    )
+    .await;
+    back_to_application(&id)
+}
+
+pub async fn delete_secret(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (id, secret_id) = path.into_inner();
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let position = match application.secrets.iter().position(|s| s.id == secret_id) {
+        Some(p) => p,
+        None => return HttpResponse::NotFound().body("No such secret."),
+    };
+    let removed = application.secrets.remove(position);
+    if let Err(e) = state.database.update_application(&application).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to delete secret: {e}"));
+    }
+    if let Err(response) = revoke_service_tokens(&state, &application).await {
+        return response;
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_SECRET_DELETED,
+        &application.client_id,
+        format!("{} by {}", removed.label, admin.username),
+    )
+    .await;
+    back_to_application(&id)
+}
+
+/// Deletes the application and revokes every session and grant it has.
+pub async fn delete(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    if application.client_id == ADMIN_CLIENT_ID {
+        return HttpResponse::BadRequest().body("The admin panel's application cannot be deleted.");
+    }
+    if let Err(e) = state
+        .database
+        .delete_application(application.id.unwrap())
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to delete application: {e}"));
+    }
+    info!("Deleted application {}", application.client_id);
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_DELETED,
+        &application.client_id,
+        format!("{} by {}", application.name, admin.username),
+    )
+    .await;
+    HttpResponse::SeeOther()
+        .insert_header(("Location", "/admin/applications"))
+        .finish()
+}
@@ -0,0 +1,274 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use actix_session::Session;
+use actix_web::{http::header::ContentType, web, HttpResponse};
+use bson::oid::ObjectId;
+use tracing::{error, info};
+
+use crate::{
+    audit, csrf, password, password_policy,
+    routes::{auth::now, render},
+    types::{self, AdminIdentity, AdminUserSession, AdminUserTemplate, AdminUsersTemplate, DbUser},
+};
+
+const USERS_PER_PAGE: u64 = 25;
+
+/// Loads the user named by the `{id}` path segment.
+async fn path_user(state: &types::AppState, id: &str) -> Result<DbUser, HttpResponse> {
+    let user = match ObjectId::parse_str(id) {
+        Ok(id) => state.database.user_by_id(id).await,
+        Err(_) => None,
+    };
+    user.ok_or_else(|| HttpResponse::NotFound().body("No such user."))
+}
+
+fn back_to_user(id: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header(("Location", format!("/admin/users/{}", id)))
+        .finish()
+}
+
+/// Ends every session the user has: browser logins, application sessions
+/// and unredeemed authorization codes.
+pub async fn end_sessions(
+    state: &types::AppState,
+    user: &mut DbUser,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let user_id = user.id.ok_or("User has no ID")?;
+    user.sessions_valid_after = now();
+    state.database.update_user(user).await?;
+    state.database.remove_sessions_for_user(user_id).await?;
+    state.database.remove_grants_for_user(user_id).await?;
+    Ok(())
+}
+
+pub async fn list(
+    state: web::Data<types::AppState>,
+    query: web::Query<types::AdminUsersQuery>,
+) -> HttpResponse {
+    let search = query.q.trim();
+    let page = query.page.unwrap_or(1).max(1);
+    let skip = (page - 1) * USERS_PER_PAGE;
+    let (users, total) = match state
+        .database
+        .list_users(search, skip, USERS_PER_PAGE as i64)
+        .await
+    {
+        Ok(u) => u,
+        Err(e) => {
+            error!("Failed to list users: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to list users");
+        }
+    };
+    render(AdminUsersTemplate {
+        query: search.to_owned(),
+        users,
+        total,
+        page,
+        has_next: skip + USERS_PER_PAGE < total,
+    })
+}
+
+pub async fn detail(
+    state: web::Data<types::AppState>,
+    session: Session,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let user = match path_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    let user_id = user.id.unwrap();
+    let db_sessions = match state.database.sessions_for_user(user_id).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to load sessions: {}", e);
+            vec![]
+        }
+    };
+    let mut app_names: HashMap<ObjectId, String> = HashMap::new();
+    let mut sessions = vec![];
+    for s in db_sessions {
+        if let Entry::Vacant(entry) = app_names.entry(s.client_id) {
+            let name = match state.database.app_by_id(s.client_id).await {
+                Some(app) => app.name,
+                None => "(deleted application)".to_owned(),
+            };
+            entry.insert(name);
+        }
+        sessions.push(AdminUserSession {
+            app_name: app_names[&s.client_id].clone(),
+            scopes: s.scopes.join(" "),
+            amr: s.authentication.amr.join(", "),
+            auth_time: s.authentication.auth_time,
+        });
+    }
+    let mut granted_apps: Vec<String> = app_names.into_values().collect();
+    granted_apps.sort();
+    let passkey_count = match state.database.webauthn_credentials_for_user(user_id).await {
+        Ok(c) => c.len(),
+        Err(e) => {
+            error!("Failed to load passkeys: {}", e);
+            0
+        }
+    };
+    let custom_attributes = if user.profile.custom_attributes.is_empty() {
+        String::new()
+    } else {
+        serde_json::to_string_pretty(&user.profile.custom_attributes).unwrap_or_default()
+    };
+    render(AdminUserTemplate {
+        csrf_token: csrf::token(&session),
+        user_id: user_id.to_hex(),
+        user,
+        custom_attributes,
+        sessions,
+        granted_apps,
+        passkey_count,
+    })
+}
+
+pub async fn set_password(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminSetPasswordRequest>,
+) -> HttpResponse {
+    let mut user = match path_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    if let Err(violations) =
+        password_policy::check(&state.config, &user.username, &request.password).await
+    {
+        let items: String = violations
+            .iter()
+            .map(|v| format!("<li>{}</li>", v))
+            .collect();
+        return HttpResponse::BadRequest()
+            .content_type(ContentType::html())
+            .body(format!("Password rejected:<ul>{}</ul>", items));
+    }
+    user.password_hash = match password::hash_password(&request.password) {
+        Some(h) => h,
+        None => return HttpResponse::InternalServerError().body("Failed to hash password."),
+    };
+    if let Err(e) = end_sessions(&state, &mut user).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to set password: {e}"));
+    }
+    if let Err(e) = state
+        .database
+        .remove_password_resets_for_user(user.id.unwrap())
+        .await
+    {
+        error!("Failed to remove outstanding password resets: {}", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_PASSWORD_SET,
+        &user.username,
+        format!("by {}", admin.username),
+    )
+    .await;
+    back_to_user(&id)
+}
+
+pub async fn disable(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let mut user = match path_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    if user.id == Some(admin.id) {
+        return HttpResponse::BadRequest().body("You cannot disable your own account.");
+    }
+    user.disabled = true;
+    if let Err(e) = end_sessions(&state, &mut user).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to disable user: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_DISABLED,
+        &user.username,
+        format!("by {}", admin.username),
+    )
+    .await;
+    back_to_user(&id)
+}
+
+pub async fn enable(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let mut user = match path_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    user.disabled = false;
+    if let Err(e) = state.database.update_user(&user).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to enable user: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_ENABLED,
+        &user.username,
+        format!("by {}", admin.username),
+    )
+    .await;
+    back_to_user(&id)
+}
+
+/// Logs the user out of every browser and application.
+pub async fn logout(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let mut user = match path_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    if let Err(e) = end_sessions(&state, &mut user).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to log out user: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_LOGGED_OUT,
+        &user.username,
+        format!("by {}", admin.username),
+    )
+    .await;
+    back_to_user(&id)
+}
+
+pub async fn delete(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let user = match path_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    if user.id == Some(admin.id) {
+        return HttpResponse::BadRequest().body("You cannot delete your own account.");
+    }
+    if let Err(e) = state.database.delete_user(user.id.unwrap()).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to delete user: {e}"));
+    }
+    info!("Deleted user {}", user.username);
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_DELETED,
+        &user.username,
+        format!("by {}", admin.username),
+    )
+    .await;
+    HttpResponse::SeeOther()
+        .insert_header(("Location", "/admin/users"))
+        .finish()
+}
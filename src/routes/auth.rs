@@ -1,74 +1,496 @@
-use actix_web::{http::header, web, HttpRequest, HttpResponse, Responder};
+use actix_session::Session;
+use actix_web::{
+    http::header::{self, ContentType},
+    web, HttpRequest, HttpResponse, Responder,
+};
+use askama::Template;
 use hmac::{Hmac, Mac};
 use jwt::SignWithKey;
 use rand::Rng;
 use sha2::Sha256;
-use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::{info, warn};
 
-use crate::{password, types};
+use crate::{
+    browser_session, claims, client_auth, csrf, lockout, password, permissions, request_object,
+    routes::{
+        self,
+        register::{oauth_error, SUPPORTED_GRANT_TYPES},
+    },
+    types,
+};
 
-pub async fn auth(request: web::Query<types::AuthRequest>) -> impl Responder {
-    return types::AuthTemplate {
-        redirect_uri: request.redirect_uri.clone(),
-        client_id: request.client_id.clone(),
+pub const ISSUER: &str = "https://auth.snazzyfellas.com";
+pub const GRANT_TYPE_AUTHORIZATION_CODE: &str = "authorization_code";
+pub const GRANT_TYPE_CLIENT_CREDENTIALS: &str = "client_credentials";
+/// Thirty days, for applications that do not set their own lifetime.
+pub const DEFAULT_ACCESS_TOKEN_LIFETIME_SECONDS: u64 = 60 * 60 * 24 * 30;
+pub const DEFAULT_ID_TOKEN_LIFETIME_SECONDS: u64 = 60 * 60;
+/// How long the login page for an authorization request can be used.
+const AUTHORIZATION_REQUEST_LIFETIME_SECONDS: u64 = 60 * 60;
+const PUSHED_REQUEST_LIFETIME_SECONDS: u64 = 90;
+/// How long an authorization code can be redeemed for, as RFC 6749
+/// section 4.1.2 recommends.
+const AUTHORIZATION_CODE_LIFETIME_SECONDS: u64 = 10 * 60;
+/// Prefix of the `request_uri` values given out for pushed requests.
+const REQUEST_URI_PREFIX: &str = "urn:ietf:params:oauth:request_uri:";
+
+/// Checks the client, redirect URI and any signed request object of an
+/// authorization request.
+async fn verify_authorization(
+    state: &types::AppState,
+    mut parameters: types::AuthorizationParameters,
+) -> Result<types::LoginAuthorization, &'static str> {
+    let app = match state.database.app_by_client_id(&parameters.client_id).await {
+        Some(a) => a,
+        None => {
+            warn!(
+                "Failed to find application for client ID {}",
+                &parameters.client_id
+            );
+            return Err("Unknown client");
+        }
     };
+    if let Some(signed_request) = parameters.request.take() {
+        let claims = request_object::verify(&app, &app.client_id, &signed_request, ISSUER)
+            .ok_or("Invalid request object")?;
+        claims.apply_to(&mut parameters);
+    }
+    let redirect_uri = parameters.redirect_uri.ok_or("Missing redirect_uri")?;
+    if !app.redirect_uris.contains(&redirect_uri) {
+        warn!("Application redirect uri invalid");
+        return Err("Unregistered redirect_uri");
+    }
+    let scopes = claims::allowed_scopes(&app, parameters.scope.as_deref().unwrap_or_default());
+    if scopes.is_empty() {
+        info!("Client {} requested no allowed scopes", app.client_id);
+        return Err("None of the requested scopes are allowed");
+    }
+    Ok(types::LoginAuthorization {
+        request_id: String::new(),
+        client_id: app.client_id,
+        redirect_uri,
+        scope: scopes.join(" "),
+        acr_values: parameters.acr_values.unwrap_or_default(),
+        state: parameters.state.unwrap_or_default(),
+    })
+}
+
+/// Stores a verified authorization request under a new ID. Requests for
+/// the login page know their own ID as `request_id`.
+async fn store_authorization(
+    state: &types::AppState,
+    mut authorization: types::LoginAuthorization,
+    pushed: bool,
+) -> Result<types::DbAuthorizationRequest, Box<dyn std::error::Error>> {
+    let id = generate_random_code(32);
+    let lifetime = if pushed {
+        PUSHED_REQUEST_LIFETIME_SECONDS
+    } else {
+        authorization.request_id = id.clone();
+        AUTHORIZATION_REQUEST_LIFETIME_SECONDS
+    };
+    let stored = types::DbAuthorizationRequest {
+        id,
+        pushed,
+        authorization,
+        expires_at: bson::DateTime::from_millis(((now() + lifetime) * 1000) as i64),
+    };
+    state.database.insert_authorization_request(&stored).await?;
+    Ok(stored)
+}
+
+/// The stored authorization request a login form was shown for.
+pub async fn stored_authorization(
+    state: &types::AppState,
+    id: &str,
+) -> Option<types::LoginAuthorization> {
+    match state.database.authorization_request(id).await {
+        Ok(stored) => stored.map(|s| s.authorization),
+        Err(e) => {
+            warn!("Failed to load authorization request: {}", e);
+            None
+        }
+    }
+}
+
+/// The authorization request the login page is for: the one it is
+/// retrying, a pushed one, or a new one from the query, which is stored.
+async fn requested_authorization(
+    state: &types::AppState,
+    request: types::AuthRequest,
+) -> Result<types::LoginAuthorization, HttpResponse> {
+    if let Some(id) = &request.authorization {
+        return stored_authorization(state, id).await.ok_or_else(|| {
+            HttpResponse::BadRequest()
+                .body("This login request has expired. Go back to the application and try again.")
+        });
+    }
+    let authorization = match &request.request_uri {
+        Some(request_uri) => {
+            let id = request_uri
+                .strip_prefix(REQUEST_URI_PREFIX)
+                .unwrap_or_default();
+            match state
+                .database
+                .take_pushed_authorization_request(id, &request.parameters.client_id)
+                .await
+            {
+                Ok(Some(stored)) => stored.authorization,
+                Ok(None) => {
+                    info!("Unknown or expired request_uri");
+                    return Err(HttpResponse::BadRequest().body("Invalid request_uri"));
+                }
+                Err(e) => {
+                    warn!("Failed to load pushed authorization request: {}", e);
+                    return Err(HttpResponse::InternalServerError()
+                        .body("Failed to load authorization request"));
+                }
+            }
+        }
+        None => verify_authorization(state, request.parameters)
+            .await
+            .map_err(|e| HttpResponse::BadRequest().body(e))?,
+    };
+    match store_authorization(state, authorization, false).await {
+        Ok(stored) => Ok(stored.authorization),
+        Err(e) => {
+            warn!("Failed to save authorization request: {}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to save authorization request"))
+        }
+    }
+}
+
+/// Pushed authorization request endpoint (RFC 9126). Stores the verified
+/// request and returns the `request_uri` to send the browser to `/auth`
+/// with.
+pub async fn pushed_authorization(
+    req: HttpRequest,
+    request: web::Form<types::PushedAuthorizationRequest>,
+    state: web::Data<types::AppState>,
+) -> HttpResponse {
+    let request = request.into_inner();
+    let app = match client_auth::authenticate(&req, &request.client, &state.database).await {
+        Some(a) => a,
+        None => {
+            info!("Client authentication failed");
+            return HttpResponse::Unauthorized().json(oauth_error(
+                "invalid_client",
+                "Client authentication failed",
+            ));
+        }
+    };
+    let parameters = types::AuthorizationParameters {
+        client_id: app.client_id,
+        redirect_uri: request.redirect_uri,
+        scope: request.scope,
+        acr_values: request.acr_values,
+        state: request.state,
+        request: request.request,
+    };
+    let authorization = match verify_authorization(&state, parameters).await {
+        Ok(a) => a,
+        Err(e) => return HttpResponse::BadRequest().json(oauth_error("invalid_request", e)),
+    };
+    match store_authorization(&state, authorization, true).await {
+        Ok(stored) => HttpResponse::Created().json(types::PushedAuthorizationResponse {
+            request_uri: format!("{}{}", REQUEST_URI_PREFIX, stored.id),
+            expires_in: PUSHED_REQUEST_LIFETIME_SECONDS,
+        }),
+        Err(e) => {
+            warn!("Failed to save pushed authorization request: {}", e);
+            HttpResponse::InternalServerError()
+                .json(oauth_error("server_error", "Failed to save the request"))
+        }
+    }
+}
+
+pub async fn auth(
+    req: HttpRequest,
+    session: Session,
+    request: web::Query<types::AuthRequest>,
+    state: web::Data<types::AppState>,
+) -> HttpResponse {
+    let request = request.into_inner();
+    let error = login_error(&request);
+    let force_login = request
+        .prompt
+        .as_deref()
+        .is_some_and(|p| p.split_whitespace().any(|v| v == "login"));
+    let authorization = match requested_authorization(&state, request).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    // Error flags mean the login page sent the user back here, so only reuse
+    // the browser session on a fresh request.
+    if error.is_none() && !force_login {
+        if let Some(response) = resume_session(&state, &session, &authorization).await {
+            return response.respond_to(&req).map_into_boxed_body();
+        }
+    }
+    let rendering = match (types::AuthTemplate {
+        csrf_token: csrf::token(&session),
+        authorization: authorization.request_id,
+        error,
+        signup_enabled: state.config.signup_enabled,
+    })
+    .render()
+    {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("Template rendering failed: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to render template");
+        }
+    };
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(rendering)
+}
+
+fn login_error(request: &types::AuthRequest) -> Option<&'static str> {
+    if request.locked.is_some() {
+        Some("Too many failed login attempts. Please try again later.")
+    } else if request.disabled.is_some() {
+        Some("This account has been disabled.")
+    } else if request.invalid_creds.is_some() {
+        Some("Invalid username or password.")
+    } else if request.email_unverified.is_some() {
+        Some("Verify your email address before logging in.")
+    } else if request.no_access.is_some() {
+        Some("You don't have access to this application. Ask an administrator for access, or log in with another account.")
+    } else if request.mfa_required.is_some() {
+        Some("This application requires two-factor authentication. Set it up from your account page.")
+    } else if request.invalid_config.is_some() {
+        Some("This application is not configured correctly.")
+    } else {
+        None
+    }
 }
 
 pub async fn login(
+    req: HttpRequest,
     request: web::Form<types::LoginRequest>,
     state: web::Data<types::AppState>,
+    session: Session,
 ) -> impl Responder {
-    // TODO Encode query params
-    let invalid_password_uri = format!(
-        "/auth?client_id={}&redirect_uri={}&invalid_creds=1",
-        request.client_id, request.redirect_uri
-    );
-    let invalid_config_uri = format!(
-        "/auth?client_id={}&redirect_uri={}&invalid_config=1",
-        request.client_id, request.redirect_uri
-    );
-    let user = match state.database.user_by_username(&request.username).await {
-        Some(u) => u,
+    let authorization = match stored_authorization(&state, &request.authorization).await {
+        Some(a) => a,
+        // The login page explains that the request has expired.
         None => {
-            info!("User tried to login as a user that does not exist");
+            return web::Redirect::to(format!(
+                "/auth?authorization={}",
+                urlencoding::encode(&request.authorization)
+            ))
+            .see_other()
+        }
+    };
+    let invalid_password_uri = auth_retry_uri(&authorization, "invalid_creds=1");
+    let client_ip = routes::client_ip(&state.config, &req);
+    if let Some(wait) = lockout::retry_after(&state, &request.username, &client_ip).await {
+        info!("Login throttled for {} more seconds", wait);
+        return web::Redirect::to(auth_retry_uri(&authorization, "locked=1")).see_other();
+    }
+    // Unknown usernames and wrong passwords take the same path so neither
+    // the response nor its timing shows which usernames exist.
+    let user = state.database.user_by_username(&request.username).await;
+    let password_matches = password::check_login_password(
+        user.as_ref().map(|u| u.password_hash.as_str()),
+        &request.password,
+    );
+    let user = match user {
+        Some(u) if password_matches => u,
+        _ => {
+            info!("User entered invalid credentials");
+            lockout::record_failure(&state, &request.username, &client_ip).await;
             return web::Redirect::to(invalid_password_uri).see_other();
         }
     };
-    if !password::check_password(&user.password_hash, &request.password) {
-        info!("User entered invalid password");
-        return web::Redirect::to(invalid_password_uri);
+    if user.disabled {
+        info!("Disabled user {} tried to log in", user.username);
+        return web::Redirect::to(auth_retry_uri(&authorization, "disabled=1")).see_other();
+    }
+    // Failures are only forgiven once every factor has been checked.
+    if needs_second_factor(&state, &user).await {
+        return begin_second_factor(&state, &session, &user, Some(authorization)).await;
+    }
+    lockout::record_success(&state, &user.username).await;
+    let authentication = claims::authentication_context(&[claims::AMR_PASSWORD]);
+    complete_login(&state, &session, &user, authentication, Some(authorization)).await
+}
+
+/// Authorizes the application straight away when the browser is already
+/// logged in strongly enough for it.
+async fn resume_session(
+    state: &types::AppState,
+    session: &Session,
+    authorization: &types::LoginAuthorization,
+) -> Option<web::Redirect> {
+    let user = browser_session::current_user(state, session).await?;
+    let authentication = browser_session::authentication(session)?;
+    if !claims::satisfies_acr_values(&authentication, &authorization.acr_values) {
+        info!("Browser session does not meet requested acr_values, logging in again");
+        return None;
+    }
+    Some(
+        complete_login(
+            state,
+            session,
+            &user,
+            authentication,
+            Some(authorization.clone()),
+        )
+        .await,
+    )
+}
+
+/// Whether the user has enrolled a second factor that password logins must
+/// be followed by.
+pub async fn needs_second_factor(state: &types::AppState, user: &types::DbUser) -> bool {
+    if user.totp.as_ref().is_some_and(|t| t.confirmed) {
+        return true;
+    }
+    match state
+        .database
+        .webauthn_credentials_for_user(user.id.unwrap())
+        .await
+    {
+        Ok(credentials) => !credentials.is_empty(),
+        Err(e) => {
+            // Failing closed keeps a database hiccup from skipping 2FA.
+            warn!("Failed to load passkeys: {}", e);
+            true
+        }
     }
-    let app = match state.database.app_by_name(&request.client_id).await {
+}
+
+/// Remembers a login that passed the password check and sends the browser
+/// to the second factor page.
+pub async fn begin_second_factor(
+    state: &types::AppState,
+    session: &Session,
+    user: &types::DbUser,
+    authorization: Option<types::LoginAuthorization>,
+) -> web::Redirect {
+    // Without a pending login the next page says the login has expired.
+    if let Err(e) =
+        browser_session::begin_pending_login(state, session, user.id.unwrap(), authorization).await
+    {
+        warn!("Failed to save pending login: {}", e);
+    }
+    web::Redirect::to("/login/second-factor").see_other()
+}
+
+/// Finishes a login once every required factor has been checked. Logs the
+/// browser in and, when logging in to an application, issues it a code.
+pub async fn complete_login(
+    state: &types::AppState,
+    session: &Session,
+    user: &types::DbUser,
+    authentication: types::AuthenticationContext,
+    authorization: Option<types::LoginAuthorization>,
+) -> web::Redirect {
+    // However this ends, the pending login cannot be used again.
+    browser_session::clear_pending_login(state, session).await;
+    if user.disabled {
+        info!("Disabled user {} tried to log in", user.username);
+        return match &authorization {
+            Some(a) => web::Redirect::to(auth_retry_uri(a, "disabled=1")).see_other(),
+            None => web::Redirect::to("/account").see_other(),
+        };
+    }
+    let authorization = match authorization {
+        Some(a) => a,
+        None => {
+            browser_session::log_in(session, user.id.unwrap(), &authentication);
+            return web::Redirect::to("/account").see_other();
+        }
+    };
+    let invalid_config_uri = auth_retry_uri(&authorization, "invalid_config=1");
+    let app = match state
+        .database
+        .app_by_client_id(&authorization.client_id)
+        .await
+    {
         Some(a) => a,
         None => {
             warn!(
-                "Failed to find application for app name {}",
-                &request.client_id
+                "Failed to find application for client ID {}",
+                &authorization.client_id
             );
-            return web::Redirect::to(invalid_config_uri);
+            return web::Redirect::to(invalid_config_uri).see_other();
         }
     };
-    if !app.redirect_uris.contains(&request.redirect_uri) {
+    if !app.redirect_uris.contains(&authorization.redirect_uri) {
         warn!("Application redirect uri invalid");
         return web::Redirect::to(invalid_config_uri).see_other();
     }
-    // TODO: Support state parameter
-    // TODO Make application grants expire
+    match permissions::has_access(&state.database, &app, user.id.unwrap()).await {
+        Ok(true) => (),
+        Ok(false) => {
+            info!("{} has no access to {}", user.username, app.client_id);
+            return web::Redirect::to(auth_retry_uri(&authorization, "no_access=1")).see_other();
+        }
+        Err(e) => {
+            warn!("Failed to check application access: {}", e);
+            return web::Redirect::to(invalid_config_uri).see_other();
+        }
+    }
+    let needs_verified_email = app.require_verified_email || user.must_verify_email;
+    if needs_verified_email && !user.profile.email_verified {
+        info!("User without verified email tried to log in");
+        return web::Redirect::to(auth_retry_uri(&authorization, "email_unverified=1")).see_other();
+    }
+    if !claims::satisfies_acr_values(&authentication, &authorization.acr_values) {
+        info!("User without a second factor tried to log in to an app requiring one");
+        return web::Redirect::to(auth_retry_uri(&authorization, "mfa_required=1")).see_other();
+    }
+    // The allowlist may have changed since the request was verified.
+    let scopes = claims::allowed_scopes(&app, &authorization.scope);
+    browser_session::log_in(session, user.id.unwrap(), &authentication);
     let code = generate_random_code(128);
     let grant = types::DbApplicationGrant {
         client_id: app.id.unwrap(),
         code,
         user_id: user.id.unwrap(),
+        scopes,
+        authentication,
+        redirect_uri: authorization.redirect_uri.clone(),
+        expires_at: bson::DateTime::from_millis(
+            ((now() + AUTHORIZATION_CODE_LIFETIME_SECONDS) * 1000) as i64,
+        ),
     };
     if let Err(e) = state.database.insert_application_grant(&grant).await {
         warn!("Failed to insert application grant: {}", e);
-        return web::Redirect::to(invalid_config_uri);
+        return web::Redirect::to(invalid_config_uri).see_other();
     }
-    web::Redirect::to(format!("{}?code={}", &request.redirect_uri, &grant.code)).see_other()
+    let mut location = format!("{}?code={}", &authorization.redirect_uri, &grant.code);
+    if !authorization.state.is_empty() {
+        location.push_str(&format!(
+            "&state={}",
+            urlencoding::encode(&authorization.state)
+        ));
+    }
+    web::Redirect::to(location).see_other()
 }
 
-fn generate_random_code(len: usize) -> String {
+/// Login page URI for retrying `authorization`, with `flag` telling the page
+/// why.
+pub fn auth_retry_uri(authorization: &types::LoginAuthorization, flag: &str) -> String {
+    format!(
+        "/auth?authorization={}&{}",
+        urlencoding::encode(&authorization.request_id),
+        flag
+    )
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn generate_random_code(len: usize) -> String {
     let mut chars = Vec::<char>::new();
     chars.append(&mut ('a'..='z').collect());
     chars.append(&mut ('A'..='Z').collect());
@@ -83,10 +505,37 @@ fn generate_random_code(len: usize) -> String {
 }
 
 pub async fn token(
+    req: HttpRequest,
     request: web::Form<types::TokenRequest>,
     state: web::Data<types::AppState>,
 ) -> HttpResponse {
-    let grant = match state.database.get_application_grant(&request.code).await {
+    let app = match client_auth::authenticate(&req, &request.client, &state.database).await {
+        Some(a) => a,
+        None => {
+            info!("Client authentication failed");
+            return HttpResponse::Unauthorized().body("Client authentication failed");
+        }
+    };
+    // Client credentials are never implied by an empty grant_types, since
+    // they let the client act without a user.
+    let grant_type_allowed = app.grant_types.contains(&request.grant_type)
+        || (app.grant_types.is_empty() && request.grant_type == GRANT_TYPE_AUTHORIZATION_CODE);
+    if !grant_type_allowed || !SUPPORTED_GRANT_TYPES.contains(&request.grant_type.as_str()) {
+        info!(
+            "Client {} used grant type {}",
+            app.client_id, request.grant_type
+        );
+        return HttpResponse::BadRequest().body("Unsupported grant type");
+    }
+    if request.grant_type == GRANT_TYPE_CLIENT_CREDENTIALS {
+        return client_credentials_token(&state, &app, request.scope.as_deref()).await;
+    }
+    // Taking the grant redeems the code, so it cannot be used twice.
+    let grant = match state
+        .database
+        .take_application_grant(&request.code, app.id.unwrap())
+        .await
+    {
         Ok(g) => g,
         Err(e) => {
             warn!("Failed to get application grant: {}", e);
@@ -97,10 +546,20 @@ pub async fn token(
     let grant = match grant {
         Some(g) => g,
         None => {
-            info!("Non existant grant requested");
+            info!(
+                "Client {} redeemed an unknown, expired or used code",
+                app.client_id
+            );
             return HttpResponse::BadRequest().body("No such grant");
         }
     };
+    if request.redirect_uri != grant.redirect_uri {
+        info!(
+            "Client {} redeemed a code with a different redirect URI",
+            app.client_id
+        );
+        return HttpResponse::BadRequest().body("Redirect URI does not match");
+    }
     let jwt_key: Hmac<Sha256> = match Hmac::new_from_slice(state.config.jwt_secret.as_bytes()) {
         Ok(k) => k,
         Err(e) => {
@@ -108,12 +567,58 @@ pub async fn token(
             return HttpResponse::InternalServerError().body("Crypto failed");
         }
     };
-    let mut claims = BTreeMap::new();
-    claims.insert("sub", grant.user_id.to_string());
-    claims.insert("iss", "https://auth.snazzyfellas.com".to_owned());
+    let user = match state.database.user_by_id(grant.user_id).await {
+        Some(u) if !u.disabled => u,
+        _ => {
+            warn!("User for grant no longer exists or is disabled");
+            return HttpResponse::BadRequest().body("No such grant");
+        }
+    };
+    // Access may have been taken away since the code was issued.
+    match permissions::has_access(&state.database, &app, grant.user_id).await {
+        Ok(true) => (),
+        Ok(false) => {
+            info!(
+                "User lost access to {} before redeeming a grant",
+                app.client_id
+            );
+            return HttpResponse::BadRequest().body("No such grant");
+        }
+        Err(e) => {
+            warn!("Failed to check application access: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to check access");
+        }
+    }
+    let mut claims = claims::user_claims(&user, &grant.scopes);
+    match permissions::authorization_claims(
+        &state.database,
+        grant.user_id,
+        grant.client_id,
+        &grant.scopes,
+    )
+    .await
+    {
+        Ok(c) => claims.extend(c),
+        Err(e) => {
+            warn!("Failed to resolve permissions: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to resolve permissions");
+        }
+    }
+    claims.insert("sub".to_owned(), grant.user_id.to_string().into());
+    claims.insert("iss".to_owned(), ISSUER.to_owned().into());
+    claims.insert(
+        "auth_time".to_owned(),
+        grant.authentication.auth_time.into(),
+    );
+    claims.insert("acr".to_owned(), claims::acr(&grant.authentication).into());
+    claims.insert("amr".to_owned(), grant.authentication.amr.clone().into());
+    let issued_at = now();
+    let id_token_lifetime = app
+        .id_token_lifetime
+        .unwrap_or(DEFAULT_ID_TOKEN_LIFETIME_SECONDS);
+    claims.insert("iat".to_owned(), issued_at.into());
+    claims.insert("exp".to_owned(), (issued_at + id_token_lifetime).into());
     // claims.insert("aud", "TODO: Provide correct audience claim");
-    // claims.insert("exp", "TODO: Provide expire time to JWT");
-    // claims.insert("iat", "TODO: Provide issued at time");
     // claims.insert("nbf", "TODO: Provide not before time");
     // claims.insert("jti", "TODO: Unique identifier to prevent JWT replay");
     let id_token = match claims.sign_with_key(&jwt_key) {
@@ -123,13 +628,19 @@ pub async fn token(
             return HttpResponse::InternalServerError().body("Failed to sign JWT");
         }
     };
-    // TODO Make expiration actually do something
+    let access_token_lifetime = app
+        .access_token_lifetime
+        .unwrap_or(DEFAULT_ACCESS_TOKEN_LIFETIME_SECONDS);
     let session = types::DbSession {
+        id: None,
         user_id: grant.user_id,
-        expires: 60 * 24 * 30, // + current time
+        expires: access_token_lifetime,
+        expires_at: issued_at + access_token_lifetime,
         client_id: grant.client_id,
         session_key: generate_random_code(512),
         id_token: id_token.clone(),
+        scopes: grant.scopes,
+        authentication: grant.authentication,
     };
     match state.database.insert_session(&session).await {
         Ok(_) => (),
@@ -138,12 +649,56 @@ pub async fn token(
             return HttpResponse::InternalServerError().body("Failed to save session to database");
         }
     };
-    return HttpResponse::Ok().json(web::Json(types::TokenResponse {
+    HttpResponse::Ok().json(web::Json(types::TokenResponse {
         token_type: "Bearer".to_owned(),
-        expires_in: session.expires, // 1 month
+        expires_in: session.expires,
         access_token: session.session_key,
-        id_token,
-    }));
+        id_token: Some(id_token),
+        scope: None,
+    }))
+}
+
+/// Issues an access token to the application itself. It can only carry
+/// scopes explicitly allowed for the application.
+async fn client_credentials_token(
+    state: &types::AppState,
+    app: &types::DbApplication,
+    scope: Option<&str>,
+) -> HttpResponse {
+    let requested = claims::parse_scopes(scope.unwrap_or_default());
+    let scopes: Vec<String> = if requested.is_empty() {
+        app.allowed_scopes.clone()
+    } else {
+        requested
+            .into_iter()
+            .filter(|s| app.allowed_scopes.contains(s))
+            .collect()
+    };
+    if scopes.is_empty() {
+        info!("Client {} requested no allowed scopes", app.client_id);
+        return HttpResponse::BadRequest().body("invalid_scope");
+    }
+    let lifetime = app
+        .access_token_lifetime
+        .unwrap_or(DEFAULT_ACCESS_TOKEN_LIFETIME_SECONDS);
+    let access_token = generate_random_code(128);
+    let token = types::DbServiceToken {
+        token_hash: password::hash_token(&access_token),
+        client_id: app.id.unwrap(),
+        scopes,
+        expires_at: bson::DateTime::from_millis(((now() + lifetime) * 1000) as i64),
+    };
+    if let Err(e) = state.database.insert_service_token(&token).await {
+        warn!("Failed to save service token: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to save token to database");
+    }
+    HttpResponse::Ok().json(web::Json(types::TokenResponse {
+        token_type: "Bearer".to_owned(),
+        expires_in: lifetime,
+        access_token,
+        id_token: None,
+        scope: Some(token.scopes.join(" ")),
+    }))
 }
 
 pub async fn user_info(req: HttpRequest, state: web::Data<types::AppState>) -> HttpResponse {
@@ -175,8 +730,56 @@ pub async fn user_info(req: HttpRequest, state: web::Data<types::AppState>) -> H
             return HttpResponse::Unauthorized().body("Invalid session");
         }
     };
+    if session.expires_at != 0 && session.expires_at <= now() {
+        info!("Expired access token used");
+        return HttpResponse::Unauthorized().body("Invalid session");
+    }
+    let user = match state.database.user_by_id(session.user_id).await {
+        Some(u) if !u.disabled => u,
+        _ => {
+            warn!("User for session no longer exists or is disabled");
+            return HttpResponse::Unauthorized().body("Invalid session");
+        }
+    };
+    let app = match state.database.app_by_id(session.client_id).await {
+        Some(a) => a,
+        None => {
+            warn!("Application for session no longer exists");
+            return HttpResponse::Unauthorized().body("Invalid session");
+        }
+    };
+    // Access may have been taken away since the token was issued.
+    match permissions::has_access(&state.database, &app, session.user_id).await {
+        Ok(true) => (),
+        Ok(false) => {
+            info!(
+                "User lost access to {} after a token was issued",
+                app.client_id
+            );
+            return HttpResponse::Unauthorized().body("Invalid session");
+        }
+        Err(e) => {
+            warn!("Failed to check application access: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to check access");
+        }
+    }
+    let mut claims = claims::user_claims(&user, &session.scopes);
+    match permissions::authorization_claims(
+        &state.database,
+        session.user_id,
+        session.client_id,
+        &session.scopes,
+    )
+    .await
+    {
+        Ok(c) => claims.extend(c),
+        Err(e) => {
+            warn!("Failed to resolve permissions: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to resolve permissions");
+        }
+    }
     HttpResponse::Ok().json(types::UserInfoResponse {
         sub: session.user_id.to_string(),
+        claims,
     })
 }
-
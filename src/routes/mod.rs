@@ -1,4 +1,107 @@
+pub mod account;
 pub mod admin;
+pub mod admin_api;
+pub mod admin_apps;
+pub mod admin_users;
 pub mod auth;
-//pub mod permissions;
+pub mod passkey;
+pub mod permissions;
+pub mod register;
+pub mod reset;
+pub mod scim;
+pub mod signup;
+pub mod totp;
+pub mod verify_email;
 
+use std::net::IpAddr;
+
+use actix_web::{http::header::ContentType, HttpRequest, HttpResponse};
+use askama::Template;
+use tracing::warn;
+
+use crate::types::Config;
+
+/// Renders an askama template as an HTML response.
+pub fn render(template: impl Template) -> HttpResponse {
+    match template.render() {
+        Ok(p) => HttpResponse::Ok().content_type(ContentType::html()).body(p),
+        Err(e) => {
+            warn!("Template rendering failed: {}", e);
+            HttpResponse::InternalServerError().body("Failed to render template")
+        }
+    }
+}
+
+/// Address of the client. `X-Forwarded-For` is only believed as far back
+/// as the chain of `trusted_proxies` goes, since anyone else can put
+/// whatever they like in it.
+pub fn client_ip(config: &Config, req: &HttpRequest) -> String {
+    forwarded_client_ip(&config.trusted_proxies, req)
+}
+
+fn forwarded_client_ip(trusted_proxies: &[IpAddr], req: &HttpRequest) -> String {
+    let mut ip = match req.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return String::new(),
+    };
+    let forwarded_for: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect();
+    // Each proxy appends the address it got the request from, so walk back
+    // from the end until an address that is not one of ours.
+    for hop in forwarded_for.iter().rev() {
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+        match hop.trim().parse::<IpAddr>() {
+            Ok(hop) => ip = hop,
+            Err(_) => break,
+        }
+    }
+    ip.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn request(peer: &str, forwarded_for: &[&str]) -> HttpRequest {
+        let mut request = TestRequest::default().peer_addr(peer.parse().unwrap());
+        for value in forwarded_for {
+            request = request.append_header(("X-Forwarded-For", *value));
+        }
+        request.to_http_request()
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_without_trusted_proxies() {
+        let req = request("203.0.113.7:4000", &["198.51.100.1"]);
+        assert_eq!(forwarded_client_ip(&[], &req), "203.0.113.7");
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let trusted = ["10.0.0.1".parse().unwrap()];
+        let req = request("203.0.113.7:4000", &["198.51.100.1"]);
+        assert_eq!(forwarded_client_ip(&trusted, &req), "203.0.113.7");
+    }
+
+    #[test]
+    fn trusted_proxies_are_walked_back_to_the_client() {
+        let trusted = ["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        // The client made up the first entry; the proxies appended the rest.
+        let req = request("10.0.0.1:4000", &["192.0.2.9, 198.51.100.1", "10.0.0.2"]);
+        assert_eq!(forwarded_client_ip(&trusted, &req), "198.51.100.1");
+    }
+
+    #[test]
+    fn unparseable_hops_stop_the_walk() {
+        let trusted = ["10.0.0.1".parse().unwrap()];
+        let req = request("10.0.0.1:4000", &["198.51.100.1, unknown"]);
+        assert_eq!(forwarded_client_ip(&trusted, &req), "10.0.0.1");
+    }
+}
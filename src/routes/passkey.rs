@@ -0,0 +1,380 @@
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{
+    browser_session::{self, Ceremony},
+    claims, lockout,
+    routes::{
+        account::account_form,
+        auth::{auth_retry_uri, complete_login, now, stored_authorization},
+        client_ip,
+        totp::{expired_login, second_factor_form},
+    },
+    types::{self, DbWebauthnChallenge, DbWebauthnCredential},
+    webauthn::{self, RelyingParty},
+};
+
+const CEREMONY_TIMEOUT_MILLISECONDS: u64 = 120_000;
+const RP_NAME: &str = "Snazzy Fellas";
+
+async fn user_credentials(
+    state: &types::AppState,
+    user_id: bson::oid::ObjectId,
+) -> Vec<DbWebauthnCredential> {
+    match state.database.webauthn_credentials_for_user(user_id).await {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Failed to load passkeys: {}", e);
+            vec![]
+        }
+    }
+}
+
+fn credential_descriptors(credentials: &[DbWebauthnCredential]) -> Vec<serde_json::Value> {
+    credentials
+        .iter()
+        .map(|c| json!({ "type": "public-key", "id": c.credential_id }))
+        .collect()
+}
+
+/// `PublicKeyCredentialCreationOptions` for adding a passkey to the logged
+/// in account.
+pub async fn registration_options(
+    state: web::Data<types::AppState>,
+    session: Session,
+) -> HttpResponse {
+    let user = browser_session::current_user(&state, &session).await;
+    let user = match user {
+        Some(u) => u,
+        None => return HttpResponse::Unauthorized().body("Log in first"),
+    };
+    let user_id = user.id.unwrap();
+    let challenge = webauthn::generate_challenge();
+    if let Err(e) = browser_session::begin_webauthn_challenge(
+        &state,
+        &session,
+        Ceremony::Registration,
+        challenge.clone(),
+        None,
+    )
+    .await
+    {
+        warn!("Failed to save passkey challenge: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to start passkey registration");
+    }
+    let existing = user_credentials(&state, user_id).await;
+    HttpResponse::Ok().json(json!({
+        "challenge": challenge,
+        "rp": { "id": state.config.webauthn_rp_id, "name": RP_NAME },
+        "user": {
+            "id": webauthn::base64url_encode(&user_id.bytes()),
+            "name": user.username,
+            "displayName": user.profile.name.as_deref().unwrap_or(&user.username),
+        },
+        "pubKeyCredParams": [{ "type": "public-key", "alg": webauthn::COSE_ALG_ES256 }],
+        "excludeCredentials": credential_descriptors(&existing),
+        "authenticatorSelection": {
+            "residentKey": "required",
+            "userVerification": "preferred",
+        },
+        "attestation": "none",
+        "timeout": CEREMONY_TIMEOUT_MILLISECONDS,
+    }))
+}
+
+pub async fn register(
+    state: web::Data<types::AppState>,
+    session: Session,
+    request: web::Form<types::PasskeyRegisterRequest>,
+) -> HttpResponse {
+    let user = browser_session::current_user(&state, &session).await;
+    let user = match user {
+        Some(u) => u,
+        None => return account_form(&state, &session, None, None).await,
+    };
+    let challenge =
+        match browser_session::take_webauthn_challenge(&state, &session, Ceremony::Registration)
+            .await
+        {
+            Some(c) => c,
+            None => {
+                let error = "The passkey request expired. Please try again.".to_owned();
+                return account_form(&state, &session, Some(&user), Some(error)).await;
+            }
+        };
+    let client_data_json = webauthn::base64url_decode(&request.client_data_json);
+    let attestation_object = webauthn::base64url_decode(&request.attestation_object);
+    let registered = match (client_data_json, attestation_object) {
+        (Some(client_data_json), Some(attestation_object)) => webauthn::verify_registration(
+            &RelyingParty::from_config(&state.config),
+            &challenge.challenge,
+            &client_data_json,
+            &attestation_object,
+        ),
+        _ => Err(webauthn::WebauthnError::Malformed("request encoding")),
+    };
+    let registered = match registered {
+        Ok(r) => r,
+        Err(e) => {
+            info!("Passkey registration failed: {}", e);
+            let error = "That passkey could not be registered.".to_owned();
+            return account_form(&state, &session, Some(&user), Some(error)).await;
+        }
+    };
+    let name = request.name.trim();
+    let credential = DbWebauthnCredential {
+        id: None,
+        user_id: user.id.unwrap(),
+        credential_id: registered.credential_id,
+        public_key: registered.public_key,
+        sign_count: registered.sign_count,
+        name: if name.is_empty() { "Passkey" } else { name }.to_owned(),
+        created_at: now(),
+    };
+    if let Err(e) = state.database.insert_webauthn_credential(&credential).await {
+        warn!("Failed to save passkey: {}", e);
+        let error = "That passkey could not be registered.".to_owned();
+        return account_form(&state, &session, Some(&user), Some(error)).await;
+    }
+    account_form(&state, &session, Some(&user), None).await
+}
+
+/// `PublicKeyCredentialRequestOptions` for logging in. During a pending
+/// login the user's own passkeys are offered as a second factor; otherwise
+/// any discoverable passkey may be used in place of the password.
+pub async fn login_options(
+    state: web::Data<types::AppState>,
+    session: Session,
+    request: web::Form<types::PasskeyOptionsRequest>,
+) -> HttpResponse {
+    let challenge = webauthn::generate_challenge();
+    let (allow_credentials, user_verification, authorization) =
+        match browser_session::pending_login(&state, &session).await {
+            Some(pending) => {
+                let credentials = user_credentials(&state, pending.user_id).await;
+                (credential_descriptors(&credentials), "preferred", None)
+            }
+            None if request.authorization.is_empty() => (vec![], "required", None),
+            None => match stored_authorization(&state, &request.authorization).await {
+                Some(authorization) => (vec![], "required", Some(authorization)),
+                None => return HttpResponse::BadRequest().body("Login request expired"),
+            },
+        };
+    if let Err(e) = browser_session::begin_webauthn_challenge(
+        &state,
+        &session,
+        Ceremony::Login,
+        challenge.clone(),
+        authorization,
+    )
+    .await
+    {
+        warn!("Failed to save passkey challenge: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to start passkey login");
+    }
+    HttpResponse::Ok().json(json!({
+        "challenge": challenge,
+        "rpId": state.config.webauthn_rp_id,
+        "allowCredentials": allow_credentials,
+        "userVerification": user_verification,
+        "timeout": CEREMONY_TIMEOUT_MILLISECONDS,
+    }))
+}
+
+/// Checks an assertion made with `credential`, updating its sign count.
+async fn verify_assertion(
+    state: &types::AppState,
+    challenge: &DbWebauthnChallenge,
+    request: &types::PasskeyLoginRequest,
+    credential: &DbWebauthnCredential,
+    require_user_verification: bool,
+) -> bool {
+    let decoded = (
+        webauthn::base64url_decode(&request.client_data_json),
+        webauthn::base64url_decode(&request.authenticator_data),
+        webauthn::base64url_decode(&request.signature),
+    );
+    let (client_data_json, authenticator_data, signature) = match decoded {
+        (Some(c), Some(a), Some(s)) => (c, a, s),
+        _ => {
+            info!("Passkey login sent a malformed assertion");
+            return false;
+        }
+    };
+    let assertion = match webauthn::verify_authentication(
+        &RelyingParty::from_config(&state.config),
+        &challenge.challenge,
+        credential,
+        &client_data_json,
+        &authenticator_data,
+        &signature,
+        require_user_verification,
+    ) {
+        Ok(a) => a,
+        Err(e) => {
+            info!("Passkey login failed: {}", e);
+            return false;
+        }
+    };
+    if let Err(e) = state
+        .database
+        .update_webauthn_sign_count(&credential.credential_id, assertion.sign_count)
+        .await
+    {
+        warn!("Failed to update passkey sign count: {}", e);
+        return false;
+    }
+    true
+}
+
+/// Answers a passwordless login that did not succeed, `flag` telling the
+/// login page why.
+async fn passwordless_failure(
+    state: &types::AppState,
+    session: &Session,
+    challenge: &DbWebauthnChallenge,
+    flag: &str,
+    error: &str,
+) -> HttpResponse {
+    match &challenge.authorization {
+        Some(authorization) => HttpResponse::SeeOther()
+            .insert_header(("Location", auth_retry_uri(authorization, flag)))
+            .finish(),
+        None => account_form(state, session, None, Some(error.to_owned())).await,
+    }
+}
+
+pub async fn login(
+    req: HttpRequest,
+    state: web::Data<types::AppState>,
+    session: Session,
+    request: web::Form<types::PasskeyLoginRequest>,
+) -> HttpResponse {
+    let challenge =
+        browser_session::take_webauthn_challenge(&state, &session, Ceremony::Login).await;
+    let pending = browser_session::pending_login(&state, &session).await;
+    let challenge = match challenge {
+        Some(c) => c,
+        None => return expired_login(),
+    };
+    let client_ip = client_ip(&state.config, &req);
+    let credential = state
+        .database
+        .webauthn_credential_by_credential_id(&request.credential_id)
+        .await;
+    // Passkeys go through the same lockout as passwords, so a locked
+    // account cannot sign in with one either.
+    match pending {
+        Some(pending) => {
+            let user = match state.database.user_by_id(pending.user_id).await {
+                Some(u) => u,
+                None => return expired_login(),
+            };
+            if lockout::retry_after(&state, &user.username, &client_ip)
+                .await
+                .is_some()
+            {
+                let error = "Too many failed login attempts. Please try again later.".to_owned();
+                return second_factor_form(&state, &session, &user, Some(error)).await;
+            }
+            let verified = match &credential {
+                Some(c) if c.user_id == pending.user_id => {
+                    verify_assertion(&state, &challenge, &request, c, false).await
+                }
+                _ => {
+                    info!("Passkey does not belong to the user of the pending login");
+                    false
+                }
+            };
+            if !verified {
+                lockout::record_failure(&state, &user.username, &client_ip).await;
+                if !browser_session::record_second_factor_failure(&state, &session, &pending).await
+                {
+                    return expired_login();
+                }
+                let error = "That passkey could not be verified.".to_owned();
+                return second_factor_form(&state, &session, &user, Some(error)).await;
+            }
+            lockout::record_success(&state, &user.username).await;
+            let authentication = claims::authentication_context(&[
+                claims::AMR_PASSWORD,
+                claims::AMR_HARDWARE_KEY,
+                claims::AMR_MULTI_FACTOR,
+            ]);
+            complete_login(
+                &state,
+                &session,
+                &user,
+                authentication,
+                pending.authorization,
+            )
+            .await
+            .respond_to(&req)
+            .map_into_boxed_body()
+        }
+        None => {
+            let invalid = "That passkey could not be verified.";
+            let owner = match &credential {
+                Some(c) => state.database.user_by_id(c.user_id).await,
+                None => None,
+            };
+            let (credential, user) = match (credential, owner) {
+                (Some(c), Some(u)) => (c, u),
+                _ => {
+                    info!("Passwordless login with an unknown passkey");
+                    return passwordless_failure(
+                        &state,
+                        &session,
+                        &challenge,
+                        "invalid_creds=1",
+                        invalid,
+                    )
+                    .await;
+                }
+            };
+            if let Some(wait) = lockout::retry_after(&state, &user.username, &client_ip).await {
+                info!("Passkey login throttled for {} more seconds", wait);
+                return passwordless_failure(
+                    &state,
+                    &session,
+                    &challenge,
+                    "locked=1",
+                    "Too many failed login attempts. Please try again later.",
+                )
+                .await;
+            }
+            // Without a password, the passkey has to prove who is holding
+            // it too.
+            if !verify_assertion(&state, &challenge, &request, &credential, true).await {
+                lockout::record_failure(&state, &user.username, &client_ip).await;
+                return passwordless_failure(
+                    &state,
+                    &session,
+                    &challenge,
+                    "invalid_creds=1",
+                    invalid,
+                )
+                .await;
+            }
+            lockout::record_success(&state, &user.username).await;
+            // User verification on the authenticator makes a passkey
+            // multi-factor on its own.
+            let authentication = claims::authentication_context(&[
+                claims::AMR_HARDWARE_KEY,
+                claims::AMR_MULTI_FACTOR,
+            ]);
+            complete_login(
+                &state,
+                &session,
+                &user,
+                authentication,
+                challenge.authorization,
+            )
+            .await
+            .respond_to(&req)
+            .map_into_boxed_body()
+        }
+    }
+}
@@ -1 +1,1008 @@
+use std::collections::HashMap;
 
+use actix_session::Session;
+use actix_web::{http::StatusCode, web, HttpResponse};
+use bson::oid::ObjectId;
+use serde_json::Value;
+use tracing::error;
+
+use crate::{
+    audit, csrf, permissions,
+    routes::{
+        admin::ADMIN_CLIENT_ID,
+        admin_api::{api_error, bad_request, require_scope, server_error},
+        admin_apps::{back_to_application, detail_page, path_application},
+        auth::now,
+        render,
+    },
+    types::{
+        self, AdminGroupTemplate, AdminGroupsTemplate, AdminIdentity, AdminMember,
+        AdminRoleTemplate, ApiClient, ApiMatchedRule, ApiPolicyBatchCheck, ApiPolicyBatchDecision,
+        ApiPolicyCheck, ApiPolicyDecision, DbApplication, DbGroup, DbPermission, DbPolicyRule,
+        DbRole, PolicyEffect, PolicyRule,
+    },
+};
+
+fn back_to_group(id: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header(("Location", format!("/admin/groups/{}", id)))
+        .finish()
+}
+
+fn back_to_role(app_id: &str, role_id: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((
+            "Location",
+            format!("/admin/applications/{}/roles/{}", app_id, role_id),
+        ))
+        .finish()
+}
+
+/// Lists users by name for a group, role or application page.
+pub async fn members(state: &types::AppState, ids: &[ObjectId]) -> Vec<AdminMember> {
+    let users = match state.database.users_by_ids(ids).await {
+        Ok(u) => u,
+        Err(e) => {
+            error!("Failed to load users: {}", e);
+            vec![]
+        }
+    };
+    let mut members: Vec<AdminMember> = users
+        .into_iter()
+        .filter_map(|u| {
+            Some(AdminMember {
+                id: u.id?.to_hex(),
+                name: u.username,
+            })
+        })
+        .collect();
+    members.sort_by(|a, b| a.name.cmp(&b.name));
+    members
+}
+
+/// Splits all groups into the `selected` ones and the rest, for pages that
+/// add and remove groups.
+pub async fn group_choices(
+    state: &types::AppState,
+    selected: &[ObjectId],
+) -> (Vec<AdminMember>, Vec<AdminMember>) {
+    let groups = match state.database.list_groups().await {
+        Ok(g) => g,
+        Err(e) => {
+            error!("Failed to list groups: {}", e);
+            vec![]
+        }
+    };
+    let (chosen, others): (Vec<AdminMember>, Vec<AdminMember>) = groups
+        .into_iter()
+        .filter_map(|g| Some((g.id?, g.name)))
+        .map(|(id, name)| AdminMember {
+            id: id.to_hex(),
+            name,
+        })
+        .partition(|g| selected.iter().any(|id| id.to_hex() == g.id));
+    (chosen, others)
+}
+
+async fn groups_page(
+    state: &types::AppState,
+    session: &Session,
+    error: Option<String>,
+) -> HttpResponse {
+    let groups = match state.database.list_groups().await {
+        Ok(g) => g,
+        Err(e) => {
+            error!("Failed to list groups: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to list groups");
+        }
+    };
+    render(AdminGroupsTemplate {
+        csrf_token: csrf::token(session),
+        groups,
+        error,
+    })
+}
+
+async fn group_page(
+    state: &types::AppState,
+    session: &Session,
+    group: DbGroup,
+    error: Option<String>,
+) -> HttpResponse {
+    render(AdminGroupTemplate {
+        csrf_token: csrf::token(session),
+        group_id: group.id.unwrap().to_hex(),
+        members: members(state, &group.members).await,
+        group,
+        error,
+    })
+}
+
+/// Loads the group named by the `{id}` path segment.
+async fn path_group(state: &types::AppState, id: &str) -> Result<DbGroup, HttpResponse> {
+    let id = match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return Err(HttpResponse::NotFound().body("No such group.")),
+    };
+    match state.database.group_by_id(id).await {
+        Ok(Some(g)) => Ok(g),
+        Ok(None) => Err(HttpResponse::NotFound().body("No such group.")),
+        Err(e) => {
+            error!("Failed to load group: {}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to load group"))
+        }
+    }
+}
+
+/// Checks a new group name is usable, or says what is wrong with it.
+async fn check_group_name(state: &types::AppState, name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("The group needs a name.".to_owned());
+    }
+    match state.database.group_by_name(name).await {
+        Ok(None) => Ok(()),
+        Ok(Some(_)) => Err(format!("There is already a group called {}.", name)),
+        Err(e) => {
+            error!("Failed to load group: {}", e);
+            Err("Failed to check the group name.".to_owned())
+        }
+    }
+}
+
+async fn save_group(
+    state: &types::AppState,
+    admin: &AdminIdentity,
+    group: &mut DbGroup,
+    change: String,
+) -> HttpResponse {
+    group.updated_at = now();
+    if let Err(e) = state.database.update_group(group).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to update group: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_GROUP_UPDATED,
+        &group.name,
+        format!("{} by {}", change, admin.username),
+    )
+    .await;
+    back_to_group(&group.id.unwrap().to_hex())
+}
+
+pub async fn list_groups(state: web::Data<types::AppState>, session: Session) -> HttpResponse {
+    groups_page(&state, &session, None).await
+}
+
+pub async fn create_group(
+    state: web::Data<types::AppState>,
+    session: Session,
+    admin: web::ReqData<AdminIdentity>,
+    request: web::Form<types::AdminGroupRequest>,
+) -> HttpResponse {
+    let name = request.name.trim();
+    if let Err(message) = check_group_name(&state, name).await {
+        return groups_page(&state, &session, Some(message)).await;
+    }
+    let group = DbGroup {
+        id: None,
+        name: name.to_owned(),
+        external_id: None,
+        members: vec![],
+        created_at: now(),
+        updated_at: now(),
+    };
+    let id = match state.database.insert_group(&group).await {
+        Ok(id) => id,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .body(format!("Failed to create group: {e}"));
+        }
+    };
+    audit::record(
+        &state.database,
+        audit::EVENT_GROUP_CREATED,
+        &group.name,
+        format!("by {}", admin.username),
+    )
+    .await;
+    back_to_group(&id.to_hex())
+}
+
+pub async fn group_detail(
+    state: web::Data<types::AppState>,
+    session: Session,
+    id: web::Path<String>,
+) -> HttpResponse {
+    match path_group(&state, &id).await {
+        Ok(group) => group_page(&state, &session, group, None).await,
+        Err(response) => response,
+    }
+}
+
+pub async fn rename_group(
+    state: web::Data<types::AppState>,
+    session: Session,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminGroupRequest>,
+) -> HttpResponse {
+    let mut group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    let name = request.name.trim();
+    if name == group.name {
+        return back_to_group(&id);
+    }
+    if let Err(message) = check_group_name(&state, name).await {
+        return group_page(&state, &session, group, Some(message)).await;
+    }
+    let change = format!("renamed from {}", group.name);
+    group.name = name.to_owned();
+    save_group(&state, &admin, &mut group, change).await
+}
+
+pub async fn add_member(
+    state: web::Data<types::AppState>,
+    session: Session,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminMemberRequest>,
+) -> HttpResponse {
+    let mut group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    let user = match state
+        .database
+        .user_by_username(request.username.trim())
+        .await
+    {
+        Some(u) => u,
+        None => {
+            return group_page(&state, &session, group, Some("No such user.".to_owned())).await;
+        }
+    };
+    let user_id = user.id.unwrap();
+    if group.members.contains(&user_id) {
+        return back_to_group(&id);
+    }
+    group.members.push(user_id);
+    save_group(
+        &state,
+        &admin,
+        &mut group,
+        format!("added {}", user.username),
+    )
+    .await
+}
+
+pub async fn remove_member(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (id, user_id) = path.into_inner();
+    let mut group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    let position = match ObjectId::parse_str(&user_id)
+        .ok()
+        .and_then(|user_id| group.members.iter().position(|m| *m == user_id))
+    {
+        Some(p) => p,
+        None => return HttpResponse::NotFound().body("No such member."),
+    };
+    group.members.remove(position);
+    save_group(&state, &admin, &mut group, format!("removed {}", user_id)).await
+}
+
+/// Deletes the group. Its members lose the roles it held.
+pub async fn delete_group(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    let group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.database.delete_group(group.id.unwrap()).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to delete group: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_GROUP_DELETED,
+        &group.name,
+        format!("by {}", admin.username),
+    )
+    .await;
+    HttpResponse::SeeOther()
+        .insert_header(("Location", "/admin/groups"))
+        .finish()
+}
+
+/// Adds a permission roles in the application can grant.
+pub async fn define_permission(
+    state: web::Data<types::AppState>,
+    session: Session,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminPermissionRequest>,
+) -> HttpResponse {
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let name = request.name.trim();
+    let error = if name.is_empty() || name.contains(char::is_whitespace) {
+        Some("Permission names cannot be empty or contain spaces.".to_owned())
+    } else if application.permissions.iter().any(|p| p.name == name) {
+        Some(format!("{} is already defined.", name))
+    } else {
+        None
+    };
+    if error.is_some() {
+        return detail_page(&state, &session, application, error).await;
+    }
+    application.permissions.push(DbPermission {
+        name: name.to_owned(),
+        description: request.description.trim().to_owned(),
+    });
+    if let Err(e) = state.database.update_application(&application).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to define permission: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_UPDATED,
+        &application.client_id,
+        format!("defined permission {} by {}", name, admin.username),
+    )
+    .await;
+    back_to_application(&id)
+}
+
+/// Removes a permission from the application and from its roles.
+pub async fn delete_permission(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminPermissionRequest>,
+) -> HttpResponse {
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let position = match application
+        .permissions
+        .iter()
+        .position(|p| p.name == request.name)
+    {
+        Some(p) => p,
+        None => return HttpResponse::NotFound().body("No such permission."),
+    };
+    let removed = application.permissions.remove(position);
+    if let Err(e) = state.database.update_application(&application).await {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to delete permission: {e}"));
+    }
+    if let Err(e) = state
+        .database
+        .remove_permission_from_roles(application.id.unwrap(), &removed.name)
+        .await
+    {
+        return HttpResponse::InternalServerError()
+            .body(format!("Failed to remove permission from roles: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_UPDATED,
+        &application.client_id,
+        format!("deleted permission {} by {}", removed.name, admin.username),
+    )
+    .await;
+    back_to_application(&id)
+}
+
+/// Checks a role name is free in the application, or says what is wrong.
+async fn check_role_name(
+    state: &types::AppState,
+    application_id: ObjectId,
+    name: &str,
+) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("The role needs a name.".to_owned());
+    }
+    match state.database.roles_for_application(application_id).await {
+        Ok(roles) if roles.iter().any(|r| r.name == name) => {
+            Err(format!("There is already a role called {}.", name))
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to load roles: {}", e);
+            Err("Failed to check the role name.".to_owned())
+        }
+    }
+}
+
+pub async fn create_role(
+    state: web::Data<types::AppState>,
+    session: Session,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminRoleRequest>,
+) -> HttpResponse {
+    let application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let name = request.name.trim();
+    let application_id = application.id.unwrap();
+    if let Err(message) = check_role_name(&state, application_id, name).await {
+        return detail_page(&state, &session, application, Some(message)).await;
+    }
+    let role = DbRole {
+        id: None,
+        application_id,
+        name: name.to_owned(),
+        description: String::new(),
+        permissions: vec![],
+        users: vec![],
+        groups: vec![],
+    };
+    let role_id = match state.database.insert_role(&role).await {
+        Ok(id) => id,
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Failed to create role: {e}"));
+        }
+    };
+    audit::record(
+        &state.database,
+        audit::EVENT_ROLE_CREATED,
+        &application.client_id,
+        format!("{} by {}", role.name, admin.username),
+    )
+    .await;
+    back_to_role(&id, &role_id.to_hex())
+}
+
+/// Loads the application and role named by the `{id}` and `{role_id}` path
+/// segments.
+async fn path_role(
+    state: &types::AppState,
+    id: &str,
+    role_id: &str,
+) -> Result<(DbApplication, DbRole), HttpResponse> {
+    let application = path_application(state, id).await?;
+    let role = match ObjectId::parse_str(role_id) {
+        Ok(role_id) => state.database.role_by_id(role_id).await,
+        Err(_) => Ok(None),
+    };
+    match role {
+        Ok(Some(role)) if Some(role.application_id) == application.id => Ok((application, role)),
+        Ok(_) => Err(HttpResponse::NotFound().body("No such role.")),
+        Err(e) => {
+            error!("Failed to load role: {}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to load role"))
+        }
+    }
+}
+
+async fn role_page(
+    state: &types::AppState,
+    session: &Session,
+    application: DbApplication,
+    role: DbRole,
+    error: Option<String>,
+) -> HttpResponse {
+    let (groups, other_groups) = group_choices(state, &role.groups).await;
+    render(AdminRoleTemplate {
+        csrf_token: csrf::token(session),
+        app_id: application.id.unwrap().to_hex(),
+        app_name: application.name,
+        role_id: role.id.unwrap().to_hex(),
+        defined_permissions: application.permissions,
+        users: members(state, &role.users).await,
+        groups,
+        other_groups,
+        role,
+        error,
+    })
+}
+
+async fn save_role(
+    state: &types::AppState,
+    admin: &AdminIdentity,
+    application: &DbApplication,
+    role: &DbRole,
+    change: String,
+) -> HttpResponse {
+    if let Err(e) = state.database.update_role(role).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to update role: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_ROLE_UPDATED,
+        &application.client_id,
+        format!("{} {} by {}", role.name, change, admin.username),
+    )
+    .await;
+    back_to_role(
+        &application.id.unwrap().to_hex(),
+        &role.id.unwrap().to_hex(),
+    )
+}
+
+pub async fn role_detail(
+    state: web::Data<types::AppState>,
+    session: Session,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (id, role_id) = path.into_inner();
+    match path_role(&state, &id, &role_id).await {
+        Ok((application, role)) => role_page(&state, &session, application, role, None).await,
+        Err(response) => response,
+    }
+}
+
+pub async fn update_role(
+    state: web::Data<types::AppState>,
+    session: Session,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String)>,
+    request: web::Form<types::AdminUpdateRoleRequest>,
+) -> HttpResponse {
+    let (id, role_id) = path.into_inner();
+    let (application, mut role) = match path_role(&state, &id, &role_id).await {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+    let name = request.name.trim();
+    if name != role.name {
+        if let Err(message) = check_role_name(&state, role.application_id, name).await {
+            return role_page(&state, &session, application, role, Some(message)).await;
+        }
+    }
+    let mut permissions: Vec<String> = vec![];
+    for permission in request.permissions.split_whitespace() {
+        if !application.permissions.iter().any(|p| p.name == permission) {
+            let message = format!("{} is not defined by this application.", permission);
+            return role_page(&state, &session, application, role, Some(message)).await;
+        }
+        if !permissions.iter().any(|p| p == permission) {
+            permissions.push(permission.to_owned());
+        }
+    }
+    role.name = name.to_owned();
+    role.description = request.description.trim().to_owned();
+    role.permissions = permissions;
+    save_role(&state, &admin, &application, &role, "edited".to_owned()).await
+}
+
+pub async fn add_role_user(
+    state: web::Data<types::AppState>,
+    session: Session,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String)>,
+    request: web::Form<types::AdminMemberRequest>,
+) -> HttpResponse {
+    let (id, role_id) = path.into_inner();
+    let (application, mut role) = match path_role(&state, &id, &role_id).await {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+    let user = match state
+        .database
+        .user_by_username(request.username.trim())
+        .await
+    {
+        Some(u) => u,
+        None => {
+            let message = Some("No such user.".to_owned());
+            return role_page(&state, &session, application, role, message).await;
+        }
+    };
+    let user_id = user.id.unwrap();
+    if role.users.contains(&user_id) {
+        return back_to_role(&id, &role_id);
+    }
+    role.users.push(user_id);
+    let change = format!("given to {}", user.username);
+    save_role(&state, &admin, &application, &role, change).await
+}
+
+pub async fn remove_role_user(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String, String)>,
+) -> HttpResponse {
+    let (id, role_id, user_id) = path.into_inner();
+    let (application, mut role) = match path_role(&state, &id, &role_id).await {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+    let position = match ObjectId::parse_str(&user_id)
+        .ok()
+        .and_then(|user_id| role.users.iter().position(|u| *u == user_id))
+    {
+        Some(p) => p,
+        None => return HttpResponse::NotFound().body("No such user."),
+    };
+    role.users.remove(position);
+    let change = format!("taken from user {}", user_id);
+    save_role(&state, &admin, &application, &role, change).await
+}
+
+pub async fn add_role_group(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String)>,
+    request: web::Form<types::AdminAddGroupRequest>,
+) -> HttpResponse {
+    let (id, role_id) = path.into_inner();
+    let (application, mut role) = match path_role(&state, &id, &role_id).await {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+    let group = match path_group(&state, &request.group_id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    let group_id = group.id.unwrap();
+    if role.groups.contains(&group_id) {
+        return back_to_role(&id, &role_id);
+    }
+    role.groups.push(group_id);
+    let change = format!("given to group {}", group.name);
+    save_role(&state, &admin, &application, &role, change).await
+}
+
+pub async fn remove_role_group(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String, String)>,
+) -> HttpResponse {
+    let (id, role_id, group_id) = path.into_inner();
+    let (application, mut role) = match path_role(&state, &id, &role_id).await {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+    let position = match ObjectId::parse_str(&group_id)
+        .ok()
+        .and_then(|group_id| role.groups.iter().position(|g| *g == group_id))
+    {
+        Some(p) => p,
+        None => return HttpResponse::NotFound().body("No such group."),
+    };
+    role.groups.remove(position);
+    let change = format!("taken from group {}", group_id);
+    save_role(&state, &admin, &application, &role, change).await
+}
+
+pub async fn delete_role(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (id, role_id) = path.into_inner();
+    let (application, role) = match path_role(&state, &id, &role_id).await {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.database.delete_role(role.id.unwrap()).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to delete role: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_ROLE_DELETED,
+        &application.client_id,
+        format!("{} by {}", role.name, admin.username),
+    )
+    .await;
+    back_to_application(&id)
+}
+
+async fn save_access(
+    state: &types::AppState,
+    admin: &AdminIdentity,
+    application: &DbApplication,
+    change: String,
+) -> HttpResponse {
+    if let Err(e) = state.database.update_application(application).await {
+        return HttpResponse::InternalServerError().body(format!("Failed to update access: {e}"));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_APPLICATION_UPDATED,
+        &application.client_id,
+        format!("{} by {}", change, admin.username),
+    )
+    .await;
+    back_to_application(&application.id.unwrap().to_hex())
+}
+
+/// Turns the application's allowlist on or off.
+pub async fn set_access(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminAccessRequest>,
+) -> HttpResponse {
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    if application.client_id == ADMIN_CLIENT_ID {
+        return HttpResponse::BadRequest()
+            .body("Access to the admin panel is controlled by the administrator flag.");
+    }
+    application.restrict_access = request.restrict_access.is_some();
+    let change = if application.restrict_access {
+        "restricted access"
+    } else {
+        "opened access to everyone"
+    };
+    save_access(&state, &admin, &application, change.to_owned()).await
+}
+
+pub async fn add_allowed_user(
+    state: web::Data<types::AppState>,
+    session: Session,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminMemberRequest>,
+) -> HttpResponse {
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let user = match state
+        .database
+        .user_by_username(request.username.trim())
+        .await
+    {
+        Some(u) => u,
+        None => {
+            let message = Some("No such user.".to_owned());
+            return detail_page(&state, &session, application, message).await;
+        }
+    };
+    let user_id = user.id.unwrap();
+    if application.allowed_users.contains(&user_id) {
+        return back_to_application(&id);
+    }
+    application.allowed_users.push(user_id);
+    let change = format!("allowed {}", user.username);
+    save_access(&state, &admin, &application, change).await
+}
+
+pub async fn remove_allowed_user(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (id, user_id) = path.into_inner();
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let position = match ObjectId::parse_str(&user_id)
+        .ok()
+        .and_then(|user_id| application.allowed_users.iter().position(|u| *u == user_id))
+    {
+        Some(p) => p,
+        None => return HttpResponse::NotFound().body("No such user."),
+    };
+    application.allowed_users.remove(position);
+    let change = format!("disallowed user {}", user_id);
+    save_access(&state, &admin, &application, change).await
+}
+
+pub async fn add_allowed_group(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    id: web::Path<String>,
+    request: web::Form<types::AdminAddGroupRequest>,
+) -> HttpResponse {
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let group = match path_group(&state, &request.group_id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    let group_id = group.id.unwrap();
+    if application.allowed_groups.contains(&group_id) {
+        return back_to_application(&id);
+    }
+    application.allowed_groups.push(group_id);
+    let change = format!("allowed group {}", group.name);
+    save_access(&state, &admin, &application, change).await
+}
+
+pub async fn remove_allowed_group(
+    state: web::Data<types::AppState>,
+    admin: web::ReqData<AdminIdentity>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (id, group_id) = path.into_inner();
+    let mut application = match path_application(&state, &id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let position = match ObjectId::parse_str(&group_id).ok().and_then(|group_id| {
+        application
+            .allowed_groups
+            .iter()
+            .position(|g| *g == group_id)
+    }) {
+        Some(p) => p,
+        None => return HttpResponse::NotFound().body("No such group."),
+    };
+    application.allowed_groups.remove(position);
+    let change = format!("disallowed group {}", group_id);
+    save_access(&state, &admin, &application, change).await
+}
+
+/// Base path of the policy check API.
+pub const CHECK_PATH: &str = "/permissions";
+
+pub const SCOPE_CHECK: &str = "permissions:check";
+
+const MAX_BATCH_CHECKS: usize = 100;
+
+/// The calling application's ID and policy rules.
+async fn caller_rules(
+    state: &types::AppState,
+    client: &ApiClient,
+) -> Result<(ObjectId, Vec<DbPolicyRule>), HttpResponse> {
+    let application = match state.database.app_by_client_id(&client.client_id).await {
+        Some(a) => a,
+        None => {
+            return Err(api_error(
+                StatusCode::UNAUTHORIZED,
+                "invalid_client",
+                "No such client",
+            ))
+        }
+    };
+    let application_id = application.id.unwrap();
+    match state
+        .database
+        .policy_rules_for_application(application_id)
+        .await
+    {
+        Ok(rules) => Ok((application_id, rules)),
+        Err(e) => Err(server_error("load the policy rules", e)),
+    }
+}
+
+/// The subject's attributes, or why the request is denied without
+/// evaluating any rule.
+async fn check_subject(
+    state: &types::AppState,
+    application_id: ObjectId,
+    subject: &str,
+) -> Result<Result<Value, &'static str>, HttpResponse> {
+    let user = match ObjectId::parse_str(subject) {
+        Ok(id) => state.database.user_by_id(id).await,
+        Err(_) => None,
+    };
+    let user = match user {
+        Some(u) if u.disabled => return Ok(Err("disabled_subject")),
+        Some(u) => u,
+        None => return Ok(Err("unknown_subject")),
+    };
+    match permissions::subject_attributes(&state.database, &user, application_id).await {
+        Ok(attributes) => Ok(Ok(attributes)),
+        Err(e) => Err(server_error("resolve the subject", e)),
+    }
+}
+
+/// `policy` holds the rules of `rules`, in the same order.
+fn decide(
+    rules: &[DbPolicyRule],
+    policy: &[PolicyRule],
+    check: &ApiPolicyCheck,
+    subject: &Result<Value, &'static str>,
+) -> ApiPolicyDecision {
+    let subject = match subject {
+        Ok(s) => s,
+        Err(reason) => {
+            return ApiPolicyDecision {
+                allowed: false,
+                decision: PolicyEffect::Deny,
+                matched_rule: None,
+                reason: Some(reason),
+            }
+        }
+    };
+    let request = permissions::policy_request(
+        subject.clone(),
+        &check.action,
+        &check.resource.id,
+        &check.resource.attributes,
+        &check.context,
+    );
+    let decision = permissions::evaluate(policy, &request);
+    ApiPolicyDecision {
+        allowed: decision.allowed,
+        decision: if decision.allowed {
+            PolicyEffect::Allow
+        } else {
+            PolicyEffect::Deny
+        },
+        matched_rule: decision.rule.map(|i| ApiMatchedRule {
+            id: rules[i].id.map(|id| id.to_hex()).unwrap_or_default(),
+            name: rules[i].rule.name.clone(),
+            effect: rules[i].rule.effect,
+        }),
+        reason: decision.rule.is_none().then_some("no_matching_rule"),
+    }
+}
+
+/// Whether the subject may perform the action on the resource, under the
+/// calling application's policy rules.
+pub async fn check(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    request: web::Json<ApiPolicyCheck>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_CHECK) {
+        return response;
+    }
+    let (application_id, rules) = match caller_rules(&state, &client).await {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+    let subject = match check_subject(&state, application_id, &request.subject).await {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    let policy: Vec<PolicyRule> = rules.iter().map(|r| r.rule.clone()).collect();
+    HttpResponse::Ok().json(decide(&rules, &policy, &request, &subject))
+}
+
+/// Evaluates up to 100 checks at once, answering them in order. Each
+/// subject is only looked up once.
+pub async fn check_batch(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    request: web::Json<ApiPolicyBatchCheck>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client, SCOPE_CHECK) {
+        return response;
+    }
+    if request.checks.len() > MAX_BATCH_CHECKS {
+        return bad_request(format!(
+            "At most {} checks can be made at once",
+            MAX_BATCH_CHECKS
+        ));
+    }
+    let (application_id, rules) = match caller_rules(&state, &client).await {
+        Ok(r) => r,
+        Err(response) => return response,
+    };
+    let policy: Vec<PolicyRule> = rules.iter().map(|r| r.rule.clone()).collect();
+    let mut subjects: HashMap<&str, Result<Value, &'static str>> = HashMap::new();
+    let mut results = Vec::with_capacity(request.checks.len());
+    for check in &request.checks {
+        if !subjects.contains_key(check.subject.as_str()) {
+            let subject = match check_subject(&state, application_id, &check.subject).await {
+                Ok(s) => s,
+                Err(response) => return response,
+            };
+            subjects.insert(&check.subject, subject);
+        }
+        results.push(decide(
+            &rules,
+            &policy,
+            check,
+            &subjects[check.subject.as_str()],
+        ));
+    }
+    HttpResponse::Ok().json(ApiPolicyBatchDecision { results })
+}
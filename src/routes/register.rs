@@ -0,0 +1,417 @@
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use subtle::ConstantTimeEq;
+use tracing::{info, warn};
+use url::{Host, Url};
+
+use crate::{
+    client_auth, password,
+    routes::auth::{
+        generate_random_code, now, GRANT_TYPE_AUTHORIZATION_CODE, GRANT_TYPE_CLIENT_CREDENTIALS,
+        ISSUER,
+    },
+    types::{self, ClientMetadata, Config, DbApplication},
+};
+
+pub const SUPPORTED_GRANT_TYPES: [&str; 2] =
+    [GRANT_TYPE_AUTHORIZATION_CODE, GRANT_TYPE_CLIENT_CREDENTIALS];
+
+pub fn oauth_error(error: &str, description: &str) -> types::OAuthErrorResponse {
+    types::OAuthErrorResponse {
+        error: error.to_owned(),
+        error_description: description.to_owned(),
+    }
+}
+
+fn invalid_metadata(description: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(oauth_error("invalid_client_metadata", description))
+}
+
+fn invalid_token() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Bearer error=\"invalid_token\""))
+        .json(oauth_error("invalid_token", "Invalid access token"))
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.as_bytes().ct_eq(given.as_bytes()).into()
+}
+
+/// Whether a redirect URI is absolute, has no fragment and uses HTTPS, or
+/// plain HTTP back to the client's own machine (RFC 7591 section 2,
+/// RFC 8252 section 7.3).
+fn valid_redirect_uri(uri: &str) -> bool {
+    let url = match Url::parse(uri) {
+        Ok(u) => u,
+        Err(_) => return false,
+    };
+    if url.fragment().is_some() {
+        return false;
+    }
+    match (url.scheme(), url.host()) {
+        ("https", Some(_)) => true,
+        ("http", Some(Host::Domain(domain))) => domain == "localhost",
+        ("http", Some(Host::Ipv4(ip))) => ip.is_loopback(),
+        ("http", Some(Host::Ipv6(ip))) => ip.is_loopback(),
+        _ => false,
+    }
+}
+
+/// Scopes a registered client may be granted: those it asked for that
+/// registration allows, or all of those when it did not ask. Never empty,
+/// since an application with no allowed scopes may be granted any.
+fn registered_scopes(config: &Config, requested: Option<&str>) -> Result<String, String> {
+    let allowed = &config.registration_allowed_scopes;
+    let scopes: Vec<&str> = match requested {
+        Some(requested) => requested
+            .split_whitespace()
+            .filter(|s| allowed.iter().any(|a| a == s))
+            .collect(),
+        None => allowed.iter().map(String::as_str).collect(),
+    };
+    if scopes.is_empty() {
+        return Err("None of the requested scopes can be registered".to_owned());
+    }
+    Ok(scopes.join(" "))
+}
+
+/// Checks the requested metadata and fills in defaults for omitted values.
+fn validate_metadata(config: &Config, metadata: &mut ClientMetadata) -> Result<(), HttpResponse> {
+    if metadata.redirect_uris.is_empty() {
+        return Err(HttpResponse::BadRequest().json(oauth_error(
+            "invalid_redirect_uri",
+            "At least one redirect_uri is required",
+        )));
+    }
+    if let Some(invalid) = metadata
+        .redirect_uris
+        .iter()
+        .find(|u| !valid_redirect_uri(u))
+    {
+        return Err(HttpResponse::BadRequest().json(oauth_error(
+            "invalid_redirect_uri",
+            &format!(
+                "{invalid} must be an absolute https URI, or http on a loopback address, without a fragment"
+            ),
+        )));
+    }
+    if metadata.grant_types.is_empty() {
+        metadata.grant_types = vec![GRANT_TYPE_AUTHORIZATION_CODE.to_owned()];
+    }
+    if let Some(unsupported) = metadata
+        .grant_types
+        .iter()
+        .find(|g| !SUPPORTED_GRANT_TYPES.contains(&g.as_str()))
+    {
+        return Err(invalid_metadata(&format!(
+            "Unsupported grant type {unsupported}"
+        )));
+    }
+    // Service clients need scopes only an administrator can grant.
+    if metadata
+        .grant_types
+        .iter()
+        .any(|g| g == GRANT_TYPE_CLIENT_CREDENTIALS)
+    {
+        return Err(invalid_metadata(
+            "client_credentials clients cannot be registered dynamically",
+        ));
+    }
+    let scope =
+        registered_scopes(config, metadata.scope.as_deref()).map_err(|e| invalid_metadata(&e))?;
+    metadata.scope = Some(scope);
+    let auth_method = metadata
+        .token_endpoint_auth_method
+        .get_or_insert_with(|| client_auth::CLIENT_SECRET_BASIC.to_owned());
+    if !client_auth::SUPPORTED_AUTH_METHODS.contains(&auth_method.as_str()) {
+        return Err(invalid_metadata(&format!(
+            "Unsupported token_endpoint_auth_method {auth_method}"
+        )));
+    }
+    if auth_method == client_auth::PRIVATE_KEY_JWT && metadata.jwks.is_none() {
+        return Err(invalid_metadata("private_key_jwt requires jwks"));
+    }
+    if let Some(alg) = &metadata.request_object_signing_alg {
+        client_auth::check_request_object_signing_alg(alg, metadata.jwks.is_some())
+            .map_err(|e| invalid_metadata(&e))?;
+    }
+    Ok(())
+}
+
+/// Applies `metadata` to `app`. Returns a newly issued client secret when
+/// the change needs one.
+fn apply_metadata(app: &mut DbApplication, metadata: &ClientMetadata) -> Option<String> {
+    if let Some(name) = &metadata.client_name {
+        app.name = name.clone();
+    }
+    app.redirect_uris = metadata.redirect_uris.clone();
+    app.grant_types = metadata.grant_types.clone();
+    app.token_endpoint_auth_method = metadata.token_endpoint_auth_method.clone();
+    app.request_object_signing_alg = metadata.request_object_signing_alg.clone();
+    app.allowed_scopes = metadata
+        .scope
+        .as_deref()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect();
+    app.logo_uri = metadata.logo_uri.clone();
+    app.jwks = metadata.jwks.clone();
+    if !client_auth::keeps_secret_values(app) {
+        for secret in &mut app.secrets {
+            secret.value = None;
+        }
+        return None;
+    }
+    // Hashed secrets cannot key an HMAC, so switching to client_secret_jwt
+    // or HMAC signed request objects needs a new secret.
+    let usable = app
+        .secrets
+        .iter()
+        .any(|s| s.value.is_some() && client_auth::secret_active(s));
+    if usable {
+        return None;
+    }
+    let (secret, stored) = client_auth::new_secret(app, "Dynamic registration", None);
+    app.secrets.push(stored);
+    Some(secret)
+}
+
+fn registration_response(
+    app: &DbApplication,
+    client_secret: Option<String>,
+    registration_access_token: Option<String>,
+) -> types::ClientRegistrationResponse {
+    types::ClientRegistrationResponse {
+        client_id: app.client_id.clone(),
+        client_secret,
+        client_id_issued_at: app.client_id_issued_at.unwrap_or(0),
+        client_secret_expires_at: 0,
+        registration_access_token,
+        registration_client_uri: format!(
+            "{}/register/{}",
+            ISSUER,
+            urlencoding::encode(&app.client_id)
+        ),
+        metadata: ClientMetadata {
+            redirect_uris: app.redirect_uris.clone(),
+            client_name: Some(app.name.clone()),
+            grant_types: app.grant_types.clone(),
+            token_endpoint_auth_method: app.token_endpoint_auth_method.clone(),
+            request_object_signing_alg: app.request_object_signing_alg.clone(),
+            scope: Some(app.allowed_scopes.join(" ")),
+            logo_uri: app.logo_uri.clone(),
+            jwks: app.jwks.clone(),
+            client_id: None,
+        },
+    }
+}
+
+/// Loads the client managed by the registration access token in `req`.
+async fn registered_client(
+    req: &HttpRequest,
+    state: &types::AppState,
+    client_id: &str,
+) -> Result<DbApplication, HttpResponse> {
+    let token = match bearer_token(req) {
+        Some(t) => t,
+        None => return Err(invalid_token()),
+    };
+    let app = match state.database.app_by_client_id(client_id).await {
+        Some(a) => a,
+        None => {
+            info!("Registration access attempted for unknown client");
+            return Err(invalid_token());
+        }
+    };
+    let authorized = app
+        .registration_access_token_hash
+        .as_deref()
+        .is_some_and(|hash| tokens_match(hash, &password::hash_token(token)));
+    if !authorized {
+        info!("Invalid registration access token for {}", client_id);
+        return Err(invalid_token());
+    }
+    Ok(app)
+}
+
+pub async fn register(
+    req: HttpRequest,
+    state: web::Data<types::AppState>,
+    metadata: web::Json<ClientMetadata>,
+) -> HttpResponse {
+    let initial_access_token = match &state.config.registration_initial_access_token {
+        Some(t) => t,
+        None => {
+            return HttpResponse::Forbidden().json(oauth_error(
+                "access_denied",
+                "Dynamic client registration is not enabled",
+            ));
+        }
+    };
+    if !bearer_token(&req).is_some_and(|t| tokens_match(initial_access_token, t)) {
+        return invalid_token();
+    }
+    let mut metadata = metadata.into_inner();
+    if let Err(response) = validate_metadata(&state.config, &mut metadata) {
+        return response;
+    }
+    let client_id = generate_random_code(32);
+    let registration_access_token = generate_random_code(64);
+    let mut app = DbApplication {
+        id: None,
+        name: metadata
+            .client_name
+            .clone()
+            .unwrap_or_else(|| client_id.clone()),
+        client_id,
+        secrets: vec![],
+        redirect_uris: vec![],
+        jwks: None,
+        logo_uri: None,
+        grant_types: vec![],
+        token_endpoint_auth_method: None,
+        request_object_signing_alg: None,
+        registration_access_token_hash: Some(password::hash_token(&registration_access_token)),
+        client_id_issued_at: Some(now()),
+        require_verified_email: false,
+        allowed_scopes: vec![],
+        access_token_lifetime: None,
+        id_token_lifetime: None,
+        permissions: vec![],
+        restrict_access: false,
+        allowed_users: vec![],
+        allowed_groups: vec![],
+    };
+    let client_secret = match apply_metadata(&mut app, &metadata) {
+        Some(secret) => secret,
+        None => {
+            let (secret, stored) = client_auth::new_secret(&app, "Dynamic registration", None);
+            app.secrets.push(stored);
+            secret
+        }
+    };
+    if let Err(e) = state.database.insert_application(&app).await {
+        warn!("Failed to register application: {}", e);
+        return HttpResponse::InternalServerError()
+            .json(oauth_error("server_error", "Failed to register client"));
+    }
+    HttpResponse::Created().json(registration_response(
+        &app,
+        Some(client_secret),
+        Some(registration_access_token),
+    ))
+}
+
+pub async fn read_client(
+    req: HttpRequest,
+    state: web::Data<types::AppState>,
+    client_id: web::Path<String>,
+) -> HttpResponse {
+    match registered_client(&req, &state, &client_id).await {
+        Ok(app) => HttpResponse::Ok().json(registration_response(&app, None, None)),
+        Err(response) => response,
+    }
+}
+
+pub async fn update_client(
+    req: HttpRequest,
+    state: web::Data<types::AppState>,
+    client_id: web::Path<String>,
+    metadata: web::Json<ClientMetadata>,
+) -> HttpResponse {
+    let mut app = match registered_client(&req, &state, &client_id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let mut metadata = metadata.into_inner();
+    if metadata.client_id.as_deref() != Some(client_id.as_str()) {
+        return invalid_metadata("client_id must match the registration");
+    }
+    if let Err(response) = validate_metadata(&state.config, &mut metadata) {
+        return response;
+    }
+    let client_secret = apply_metadata(&mut app, &metadata);
+    if let Err(e) = state.database.update_application(&app).await {
+        warn!("Failed to update application: {}", e);
+        return HttpResponse::InternalServerError()
+            .json(oauth_error("server_error", "Failed to update client"));
+    }
+    HttpResponse::Ok().json(registration_response(&app, client_secret, None))
+}
+
+pub async fn delete_client(
+    req: HttpRequest,
+    state: web::Data<types::AppState>,
+    client_id: web::Path<String>,
+) -> HttpResponse {
+    let app = match registered_client(&req, &state, &client_id).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let id = match app.id {
+        Some(i) => i,
+        None => return HttpResponse::InternalServerError().finish(),
+    };
+    if let Err(e) = state.database.delete_application(id).await {
+        warn!("Failed to delete application: {}", e);
+        return HttpResponse::InternalServerError()
+            .json(oauth_error("server_error", "Failed to delete client"));
+    }
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirect_uris_must_be_https_or_loopback() {
+        for uri in [
+            "https://client.example.com/callback",
+            "https://client.example.com:8443/cb?tenant=1",
+            "http://localhost:8080/callback",
+            "http://127.0.0.1/callback",
+            "http://[::1]:3000/callback",
+        ] {
+            assert!(valid_redirect_uri(uri), "{}", uri);
+        }
+        for uri in [
+            "/callback",
+            "client.example.com/callback",
+            "http://client.example.com/callback",
+            "https://client.example.com/callback#fragment",
+            "https://client.example.com/callback#",
+            "javascript:alert(1)",
+            "com.example.app:/callback",
+        ] {
+            assert!(!valid_redirect_uri(uri), "{}", uri);
+        }
+    }
+
+    #[test]
+    fn registered_scopes_are_limited_to_the_allowlist() {
+        let config = Config {
+            registration_allowed_scopes: vec!["openid".to_owned(), "profile".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            registered_scopes(&config, Some("openid groups profile")),
+            Ok("openid profile".to_owned())
+        );
+        assert_eq!(
+            registered_scopes(&config, None),
+            Ok("openid profile".to_owned())
+        );
+        // An empty list would allow every scope.
+        assert!(registered_scopes(&config, Some("groups permissions")).is_err());
+        assert!(registered_scopes(&config, Some("")).is_err());
+    }
+}
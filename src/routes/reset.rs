@@ -0,0 +1,200 @@
+use actix_session::Session;
+use actix_web::{web, HttpResponse};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::{
+    csrf,
+    mailer::Email,
+    password, password_policy,
+    routes::{
+        admin_users::end_sessions,
+        auth::{generate_random_code, ISSUER},
+        render,
+    },
+    types::{self, ResetConfirmTemplate, ResetRequestTemplate},
+};
+
+const RESET_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+pub async fn request_page(session: Session) -> HttpResponse {
+    render(ResetRequestTemplate {
+        csrf_token: csrf::token(&session),
+        sent: false,
+    })
+}
+
+pub async fn request_reset(
+    state: web::Data<types::AppState>,
+    session: Session,
+    request: web::Form<types::PasswordResetRequest>,
+) -> HttpResponse {
+    // The response is the same whether or not the account exists so this
+    // page cannot be used to discover usernames.
+    let sent_page = render(ResetRequestTemplate {
+        csrf_token: csrf::token(&session),
+        sent: true,
+    });
+    let user = match state.database.user_by_username(&request.username).await {
+        Some(u) => Some(u),
+        None => state.database.user_by_email(&request.username).await,
+    };
+    let user = match user {
+        Some(u) => u,
+        None => {
+            info!("Password reset requested for unknown account");
+            return sent_page;
+        }
+    };
+    // Only a verified address is known to belong to the user.
+    let email = match &user.profile.email {
+        Some(e) if user.profile.email_verified => e.clone(),
+        _ => {
+            info!("Password reset requested for account without a verified email");
+            return sent_page;
+        }
+    };
+    let token = generate_random_code(64);
+    let reset = types::DbPasswordReset {
+        user_id: user.id.unwrap(),
+        token_hash: password::hash_token(&token),
+        expires_at: bson::DateTime::from_system_time(
+            std::time::SystemTime::now() + RESET_TOKEN_LIFETIME,
+        ),
+    };
+    if let Err(e) = state.database.insert_password_reset(&reset).await {
+        warn!("Failed to save password reset: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to start password reset");
+    }
+    let link = format!(
+        "{}/reset/confirm?token={}",
+        ISSUER,
+        urlencoding::encode(&token)
+    );
+    let message = Email {
+        to: email,
+        subject: "Reset your Snazzy Fellas password".to_owned(),
+        body: format!(
+            "Someone asked to reset the password for {}.\n\n\
+             Follow this link within an hour to choose a new password:\n{}\n\n\
+             If this wasn't you, you can ignore this email.",
+            user.username, link
+        ),
+    };
+    if let Err(e) = state.mailer.send(&message).await {
+        warn!("Failed to send password reset email: {}", e);
+    }
+    sent_page
+}
+
+pub async fn confirm_page(
+    state: web::Data<types::AppState>,
+    session: Session,
+    query: web::Query<types::PasswordResetQuery>,
+) -> HttpResponse {
+    let token_hash = password::hash_token(&query.token);
+    let message = match state
+        .database
+        .password_reset_by_token_hash(&token_hash)
+        .await
+    {
+        Ok(Some(_)) => None,
+        Ok(None) => Some("This reset link is invalid or has expired.".to_owned()),
+        Err(e) => {
+            warn!("Failed to load password reset: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to load password reset");
+        }
+    };
+    render(ResetConfirmTemplate {
+        csrf_token: csrf::token(&session),
+        token: query.token.clone(),
+        message,
+        password_errors: vec![],
+    })
+}
+
+pub async fn confirm_reset(
+    state: web::Data<types::AppState>,
+    session: Session,
+    request: web::Form<types::PasswordResetConfirmRequest>,
+) -> HttpResponse {
+    let token_hash = password::hash_token(&request.token);
+    let invalid_link = || {
+        render(ResetConfirmTemplate {
+            csrf_token: csrf::token(&session),
+            token: request.token.clone(),
+            message: Some("This reset link is invalid or has expired.".to_owned()),
+            password_errors: vec![],
+        })
+    };
+    // Look the reset up without consuming it so a rejected password can be
+    // retried with the same link.
+    let reset = match state
+        .database
+        .password_reset_by_token_hash(&token_hash)
+        .await
+    {
+        Ok(Some(r)) => r,
+        Ok(None) => {
+            info!("Invalid or expired password reset token used");
+            return invalid_link();
+        }
+        Err(e) => {
+            warn!("Failed to load password reset: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to load password reset");
+        }
+    };
+    let mut user = match state.database.user_by_id(reset.user_id).await {
+        Some(u) => u,
+        None => {
+            warn!("Password reset for a user that no longer exists");
+            return HttpResponse::BadRequest().body("No such user");
+        }
+    };
+    if let Err(violations) =
+        password_policy::check(&state.config, &user.username, &request.password).await
+    {
+        return render(ResetConfirmTemplate {
+            csrf_token: csrf::token(&session),
+            token: request.token.clone(),
+            message: None,
+            password_errors: violations.iter().map(|v| v.to_string()).collect(),
+        });
+    }
+    match state.database.take_password_reset(&token_hash).await {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            info!("Password reset token used twice");
+            return invalid_link();
+        }
+        Err(e) => {
+            warn!("Failed to load password reset: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to load password reset");
+        }
+    }
+    user.password_hash = match password::hash_password(&request.password) {
+        Some(h) => h,
+        None => {
+            return HttpResponse::InternalServerError().body("Failed to hash password.");
+        }
+    };
+    // Saves the new password and logs the user out everywhere, browser
+    // logins included.
+    if let Err(e) = end_sessions(&state, &mut user).await {
+        warn!("Failed to save new password and end sessions: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to save new password");
+    }
+    if let Err(e) = state
+        .database
+        .remove_password_resets_for_user(reset.user_id)
+        .await
+    {
+        warn!("Failed to remove outstanding password resets: {}", e);
+    }
+    render(ResetConfirmTemplate {
+        csrf_token: csrf::token(&session),
+        token: String::new(),
+        message: Some("Your password has been changed.".to_owned()),
+        password_errors: vec![],
+    })
+}
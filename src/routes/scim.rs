@@ -0,0 +1,1324 @@
+use std::collections::HashMap;
+
+use actix_web::{
+    error::{InternalError, JsonPayloadError, QueryPayloadError},
+    http::{header, StatusCode},
+    web, HttpRequest, HttpResponse,
+};
+use bson::{doc, oid::ObjectId, Document};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use tracing::{error, warn};
+
+use crate::{
+    audit,
+    db::{self, UserConflict},
+    password, password_policy,
+    routes::{
+        admin_users::end_sessions,
+        auth::{generate_random_code, now, ISSUER},
+        verify_email::send_verification_email,
+    },
+    scim::{self, ScimProblem, SCHEMA_GROUP, SCHEMA_USER},
+    types::{
+        self, ApiClient, DbGroup, DbUser, ScimGroup, ScimListQuery, ScimListResponse, ScimMeta,
+        ScimMultiValue, ScimName, ScimReference, ScimResourceQuery, ScimUser, UserProfile,
+    },
+};
+
+/// Base path of the SCIM endpoints.
+pub const SCIM_PATH: &str = "/scim/v2";
+/// Scope a client-credentials token needs to use SCIM.
+pub const SCOPE_SCIM: &str = "scim";
+
+const SCHEMA_LIST_RESPONSE: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const SCHEMA_ERROR: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const SCHEMA_SERVICE_PROVIDER_CONFIG: &str =
+    "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+const SCHEMA_RESOURCE_TYPE: &str = "urn:ietf:params:scim:schemas:core:2.0:ResourceType";
+const SCHEMA_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Schema";
+
+const CONTENT_TYPE: &str = "application/scim+json";
+
+const DEFAULT_COUNT: usize = 100;
+const MAX_COUNT: usize = 200;
+
+fn scim_response(status: StatusCode, body: &impl Serialize) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type(CONTENT_TYPE)
+        .json(body)
+}
+
+fn scim_error(
+    status: StatusCode,
+    scim_type: Option<&str>,
+    detail: impl Into<String>,
+) -> HttpResponse {
+    scim_response(
+        status,
+        &types::ScimError {
+            schemas: vec![SCHEMA_ERROR.to_owned()],
+            status: status.as_u16().to_string(),
+            scim_type: scim_type.map(str::to_owned),
+            detail: detail.into(),
+        },
+    )
+}
+
+fn problem_response(problem: ScimProblem) -> HttpResponse {
+    scim_error(
+        StatusCode::BAD_REQUEST,
+        Some(problem.scim_type()),
+        problem.to_string(),
+    )
+}
+
+fn not_found(what: &str) -> HttpResponse {
+    scim_error(StatusCode::NOT_FOUND, None, format!("No such {}", what))
+}
+
+fn server_error(action: &str, e: Box<dyn std::error::Error>) -> HttpResponse {
+    error!("SCIM failed to {}: {}", action, e);
+    scim_error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        None,
+        format!("Failed to {}", action),
+    )
+}
+
+/// A uniqueness error when saving a user collided with another user,
+/// otherwise a server error.
+fn user_save_error(action: &str, e: Box<dyn std::error::Error>) -> HttpResponse {
+    let detail = match db::user_conflict(e.as_ref()) {
+        Some(UserConflict::Username) => "A user with that userName already exists",
+        Some(UserConflict::Email) => "A user with that email address already exists",
+        None => return server_error(action, e),
+    };
+    scim_error(StatusCode::CONFLICT, Some("uniqueness"), detail)
+}
+
+/// Answers malformed JSON bodies with a SCIM error.
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = scim_error(
+        StatusCode::BAD_REQUEST,
+        Some("invalidSyntax"),
+        err.to_string(),
+    );
+    InternalError::from_response(err, response).into()
+}
+
+/// Answers malformed query strings with a SCIM error.
+pub fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = scim_error(StatusCode::BAD_REQUEST, None, err.to_string());
+    InternalError::from_response(err, response).into()
+}
+
+fn require_scope(client: &ApiClient) -> Result<(), HttpResponse> {
+    if client.scopes.iter().any(|s| s == SCOPE_SCIM) {
+        Ok(())
+    } else {
+        Err(scim_error(
+            StatusCode::FORBIDDEN,
+            None,
+            format!("This requires the {} scope", SCOPE_SCIM),
+        ))
+    }
+}
+
+fn actor(client: &ApiClient) -> String {
+    format!("by SCIM client {}", client.client_id)
+}
+
+/// RFC 3339 form of a time in seconds since the epoch.
+fn timestamp(seconds: u64) -> String {
+    bson::DateTime::from_millis((seconds as i64).saturating_mul(1000))
+        .try_to_rfc3339_string()
+        .unwrap_or_default()
+}
+
+fn location(endpoint: &str, id: &str) -> String {
+    format!("{}{}/{}/{}", ISSUER, SCIM_PATH, endpoint, id)
+}
+
+fn to_value(resource: &impl Serialize) -> Value {
+    serde_json::to_value(resource).unwrap_or(Value::Null)
+}
+
+/// Reads a resource sent by the client.
+fn parse_resource<T: DeserializeOwned>(mut value: Value) -> Result<T, HttpResponse> {
+    scim::normalize(&mut value);
+    serde_json::from_value(value).map_err(|e| {
+        scim_error(
+            StatusCode::BAD_REQUEST,
+            Some("invalidSyntax"),
+            e.to_string(),
+        )
+    })
+}
+
+fn parse_filter(query: &ScimListQuery) -> Result<Option<scim::Filter>, HttpResponse> {
+    query
+        .filter
+        .as_deref()
+        .filter(|f| !f.trim().is_empty())
+        .map(scim::parse_filter)
+        .transpose()
+        .map_err(problem_response)
+}
+
+/// A stored field resources can be looked up by: the attribute path in
+/// lowercase, the field, and whether the field holds ObjectIds.
+type QueryField = (&'static str, &'static str, bool);
+
+const USER_QUERY_FIELDS: &[QueryField] = &[
+    ("id", "_id", true),
+    ("username", "username", false),
+    ("externalid", "external_id", false),
+    ("emails", "email", false),
+    ("emails.value", "email", false),
+];
+
+const GROUP_QUERY_FIELDS: &[QueryField] = &[
+    ("id", "_id", true),
+    ("displayname", "name", false),
+    ("externalid", "external_id", false),
+    ("members", "members", true),
+    ("members.value", "members", true),
+];
+
+/// Narrows a filter to a database query, so that a lookup such as
+/// `userName eq "alice"` loads one resource rather than all of them. Only
+/// `eq` comparisons of `fields` are translated. The query matches at least
+/// the resources the filter does, which `list_response` then filters
+/// exactly; `None` means nothing could be narrowed.
+fn database_query(
+    filter: &scim::Filter,
+    prefix: &[String],
+    fields: &[QueryField],
+) -> Option<Document> {
+    match filter {
+        scim::Filter::And(a, b) => match (
+            database_query(a, prefix, fields),
+            database_query(b, prefix, fields),
+        ) {
+            (Some(a), Some(b)) => Some(doc! { "$and": [a, b] }),
+            (a, b) => a.or(b),
+        },
+        scim::Filter::Or(a, b) => Some(doc! { "$or": [
+            database_query(a, prefix, fields)?,
+            database_query(b, prefix, fields)?,
+        ] }),
+        scim::Filter::ValuePath(path, filter) => {
+            let prefix: Vec<String> = prefix.iter().chain(path).cloned().collect();
+            database_query(filter, &prefix, fields)
+        }
+        scim::Filter::Compare(path, scim::CompareOp::Eq, Value::String(value)) => {
+            let attribute = prefix
+                .iter()
+                .chain(path)
+                .map(|n| n.to_ascii_lowercase())
+                .collect::<Vec<String>>()
+                .join(".");
+            let (_, field, is_id) = fields.iter().find(|(a, _, _)| *a == attribute)?;
+            let mut query = Document::new();
+            if *is_id {
+                query.insert(*field, ObjectId::parse_str(value).ok()?);
+            } else {
+                query.insert(*field, value.as_str());
+            }
+            Some(query)
+        }
+        _ => None,
+    }
+}
+
+/// Filters, pages and projects resources into a ListResponse.
+fn list_response(
+    resources: Vec<Value>,
+    filter: Option<scim::Filter>,
+    query: &ScimListQuery,
+) -> HttpResponse {
+    let matching: Vec<Value> = resources
+        .into_iter()
+        .filter(|r| filter.as_ref().is_none_or(|f| f.matches(r)))
+        .collect();
+    let start_index = query.start_index.unwrap_or(1).max(1) as usize;
+    let count = query
+        .count
+        .map_or(DEFAULT_COUNT, |c| (c as usize).min(MAX_COUNT));
+    let total_results = matching.len();
+    let page: Vec<Value> = matching
+        .into_iter()
+        .skip(start_index - 1)
+        .take(count)
+        .map(|mut r| {
+            scim::project(
+                &mut r,
+                query.attributes.as_deref(),
+                query.excluded_attributes.as_deref(),
+            );
+            r
+        })
+        .collect();
+    scim_response(
+        StatusCode::OK,
+        &ScimListResponse {
+            schemas: vec![SCHEMA_LIST_RESPONSE.to_owned()],
+            total_results,
+            start_index,
+            items_per_page: page.len(),
+            resources: page,
+        },
+    )
+}
+
+fn resource_response(
+    status: StatusCode,
+    resource: &impl Serialize,
+    location: String,
+    query: Option<&ScimResourceQuery>,
+) -> HttpResponse {
+    let mut value = to_value(resource);
+    if let Some(query) = query {
+        scim::project(
+            &mut value,
+            query.attributes.as_deref(),
+            query.excluded_attributes.as_deref(),
+        );
+    }
+    HttpResponse::build(status)
+        .content_type(CONTENT_TYPE)
+        .insert_header((header::LOCATION, location))
+        .json(value)
+}
+
+fn primary_or_first(values: &[ScimMultiValue]) -> Option<String> {
+    values
+        .iter()
+        .find(|v| v.primary == Some(true))
+        .or(values.first())
+        .map(|v| v.value.clone())
+}
+
+fn group_reference(group: &DbGroup) -> ScimReference {
+    let id = group.id.unwrap().to_hex();
+    ScimReference {
+        reference: Some(location("Groups", &id)),
+        value: id,
+        display: Some(group.name.clone()),
+        kind: Some("direct".to_owned()),
+    }
+}
+
+fn scim_user(user: &DbUser, groups: &[&DbGroup]) -> ScimUser {
+    let id = user.id.unwrap();
+    let created = (id.timestamp().timestamp_millis() / 1000) as u64;
+    let profile = &user.profile;
+    let name = if profile.name.is_some()
+        || profile.given_name.is_some()
+        || profile.family_name.is_some()
+    {
+        Some(ScimName {
+            formatted: profile.name.clone(),
+            given_name: profile.given_name.clone(),
+            family_name: profile.family_name.clone(),
+        })
+    } else {
+        None
+    };
+    ScimUser {
+        schemas: vec![SCHEMA_USER.to_owned()],
+        id: Some(id.to_hex()),
+        external_id: user.external_id.clone(),
+        user_name: user.username.clone(),
+        name,
+        display_name: profile.name.clone(),
+        emails: profile
+            .email
+            .iter()
+            .map(|email| ScimMultiValue {
+                value: email.clone(),
+                kind: None,
+                primary: Some(true),
+            })
+            .collect(),
+        photos: profile
+            .picture
+            .iter()
+            .map(|picture| ScimMultiValue {
+                value: picture.clone(),
+                kind: Some("photo".to_owned()),
+                primary: Some(true),
+            })
+            .collect(),
+        locale: profile.locale.clone(),
+        timezone: profile.zoneinfo.clone(),
+        active: Some(!user.disabled),
+        password: None,
+        groups: groups.iter().map(|g| group_reference(g)).collect(),
+        meta: Some(ScimMeta {
+            resource_type: "User".to_owned(),
+            created: timestamp(created),
+            last_modified: timestamp(profile.updated_at.unwrap_or(created)),
+            location: location("Users", &id.to_hex()),
+        }),
+    }
+}
+
+fn scim_group(group: &DbGroup, usernames: &HashMap<ObjectId, String>) -> ScimGroup {
+    let id = group.id.unwrap().to_hex();
+    ScimGroup {
+        schemas: vec![SCHEMA_GROUP.to_owned()],
+        external_id: group.external_id.clone(),
+        display_name: group.name.clone(),
+        members: group
+            .members
+            .iter()
+            .map(|member| ScimReference {
+                value: member.to_hex(),
+                display: usernames.get(member).cloned(),
+                reference: Some(location("Users", &member.to_hex())),
+                kind: Some("User".to_owned()),
+            })
+            .collect(),
+        meta: Some(ScimMeta {
+            resource_type: "Group".to_owned(),
+            created: timestamp(group.created_at),
+            last_modified: timestamp(group.updated_at),
+            location: location("Groups", &id),
+        }),
+        id: Some(id),
+    }
+}
+
+/// What saving a user has to follow up on.
+struct UserChanges {
+    password_changed: bool,
+    email_changed: bool,
+    deactivated: bool,
+}
+
+/// Copies a SCIM user onto `user`. Attributes with no place in `DbUser`
+/// are ignored.
+async fn apply_user(
+    state: &types::AppState,
+    user: &mut DbUser,
+    scim: ScimUser,
+) -> Result<UserChanges, HttpResponse> {
+    let username = scim.user_name.trim();
+    if username.is_empty() {
+        return Err(scim_error(
+            StatusCode::BAD_REQUEST,
+            Some("invalidValue"),
+            "userName is required",
+        ));
+    }
+    if username != user.username && state.database.user_by_username(username).await.is_some() {
+        return Err(scim_error(
+            StatusCode::CONFLICT,
+            Some("uniqueness"),
+            "A user with that userName already exists",
+        ));
+    }
+    let password_changed = match &scim.password {
+        Some(new_password) => {
+            if let Err(violations) =
+                password_policy::check(&state.config, username, new_password).await
+            {
+                let reasons: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                return Err(scim_error(
+                    StatusCode::BAD_REQUEST,
+                    Some("invalidValue"),
+                    reasons.join(" "),
+                ));
+            }
+            user.password_hash = match password::hash_password(new_password) {
+                Some(h) => h,
+                None => return Err(server_error("hash the password", "hashing failed".into())),
+            };
+            true
+        }
+        None => false,
+    };
+    let email = primary_or_first(&scim.emails);
+    let email_changed = email != user.profile.email;
+    let name = scim.name.unwrap_or_default();
+    user.username = username.to_owned();
+    user.external_id = scim.external_id;
+    user.profile = UserProfile {
+        name: scim.display_name.or(name.formatted),
+        given_name: name.given_name,
+        family_name: name.family_name,
+        email_verified: user.profile.email_verified && !email_changed,
+        email,
+        picture: primary_or_first(&scim.photos),
+        locale: scim.locale,
+        zoneinfo: scim.timezone,
+        updated_at: Some(now()),
+        custom_attributes: std::mem::take(&mut user.profile.custom_attributes),
+    };
+    let deactivated = scim.active == Some(false) && !user.disabled;
+    if let Some(active) = scim.active {
+        user.disabled = !active;
+    }
+    Ok(UserChanges {
+        password_changed,
+        email_changed,
+        deactivated,
+    })
+}
+
+/// Saves an existing user. A new password or deactivation ends their
+/// sessions, as in the admin panel.
+async fn save_user(
+    state: &types::AppState,
+    user: &mut DbUser,
+    changes: UserChanges,
+) -> Result<(), HttpResponse> {
+    let result = if changes.password_changed || changes.deactivated {
+        end_sessions(state, user).await
+    } else {
+        state.database.update_user(user).await
+    };
+    if let Err(e) = result {
+        return Err(user_save_error("update the user", e));
+    }
+    if changes.password_changed {
+        if let Err(e) = state
+            .database
+            .remove_password_resets_for_user(user.id.unwrap())
+            .await
+        {
+            error!("Failed to remove outstanding password resets: {}", e);
+        }
+    }
+    if changes.email_changed {
+        if let Err(e) = send_verification_email(&state.database, &*state.mailer, user).await {
+            error!("Failed to send verification email: {}", e);
+        }
+    }
+    Ok(())
+}
+
+async fn path_user(state: &types::AppState, id: &str) -> Result<DbUser, HttpResponse> {
+    let user = match ObjectId::parse_str(id) {
+        Ok(id) => state.database.user_by_id(id).await,
+        Err(_) => None,
+    };
+    user.ok_or_else(|| not_found("user"))
+}
+
+/// Loads a user the client is about to change. Administrators can only be
+/// changed in the admin panel, so a SCIM client cannot take one over.
+async fn changeable_user(state: &types::AppState, id: &str) -> Result<DbUser, HttpResponse> {
+    let user = path_user(state, id).await?;
+    if user.is_admin {
+        warn!(
+            "SCIM client tried to change administrator {}",
+            user.username
+        );
+        return Err(scim_error(
+            StatusCode::FORBIDDEN,
+            None,
+            "Administrators cannot be changed over SCIM",
+        ));
+    }
+    Ok(user)
+}
+
+async fn user_groups(state: &types::AppState, user: &DbUser) -> Vec<DbGroup> {
+    match state.database.groups_for_user(user.id.unwrap()).await {
+        Ok(g) => g,
+        Err(e) => {
+            error!("Failed to load groups: {}", e);
+            vec![]
+        }
+    }
+}
+
+async fn user_response(
+    state: &types::AppState,
+    status: StatusCode,
+    user: &DbUser,
+    query: Option<&ScimResourceQuery>,
+) -> HttpResponse {
+    let groups = user_groups(state, user).await;
+    let groups: Vec<&DbGroup> = groups.iter().collect();
+    let resource = scim_user(user, &groups);
+    let location = location("Users", &user.id.unwrap().to_hex());
+    resource_response(status, &resource, location, query)
+}
+
+pub async fn list_users(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    query: web::Query<ScimListQuery>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    let filter = match parse_filter(&query) {
+        Ok(f) => f,
+        Err(response) => return response,
+    };
+    let lookup = filter
+        .as_ref()
+        .and_then(|f| database_query(f, &[], USER_QUERY_FIELDS));
+    let narrowed = lookup.is_some();
+    let users = match state
+        .database
+        .users_matching(lookup.unwrap_or_default())
+        .await
+    {
+        Ok(u) => u,
+        Err(e) => return server_error("list users", e),
+    };
+    let groups = if narrowed {
+        let ids: Vec<ObjectId> = users.iter().filter_map(|u| u.id).collect();
+        state.database.groups_with_members(&ids).await
+    } else {
+        state.database.list_groups().await
+    };
+    let groups = match groups {
+        Ok(g) => g,
+        Err(e) => return server_error("list groups", e),
+    };
+    let mut memberships: HashMap<ObjectId, Vec<&DbGroup>> = HashMap::new();
+    for group in &groups {
+        for member in &group.members {
+            memberships.entry(*member).or_default().push(group);
+        }
+    }
+    let resources = users
+        .iter()
+        .map(|user| {
+            let groups = memberships
+                .get(&user.id.unwrap())
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            to_value(&scim_user(user, groups))
+        })
+        .collect();
+    list_response(resources, filter, &query)
+}
+
+pub async fn create_user(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    body: web::Json<Value>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    let scim = match parse_resource::<ScimUser>(body.into_inner()) {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    // Provisioned users without a password set one through a reset.
+    let password_hash = match password::hash_password(&generate_random_code(64)) {
+        Some(h) => h,
+        None => return server_error("hash the password", "hashing failed".into()),
+    };
+    let mut user = DbUser {
+        id: None,
+        username: String::new(),
+        password_hash,
+        profile: UserProfile::default(),
+        must_verify_email: false,
+        totp: None,
+        is_admin: false,
+        disabled: false,
+        sessions_valid_after: 0,
+        external_id: None,
+    };
+    let changes = match apply_user(&state, &mut user, scim).await {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    match state.database.insert_user(&user).await {
+        Ok(id) => user.id = Some(id),
+        Err(e) => return user_save_error("create the user", e),
+    }
+    if changes.email_changed {
+        if let Err(e) = send_verification_email(&state.database, &*state.mailer, &user).await {
+            error!("Failed to send verification email: {}", e);
+        }
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_CREATED,
+        &user.username,
+        actor(&client),
+    )
+    .await;
+    user_response(&state, StatusCode::CREATED, &user, None).await
+}
+
+pub async fn get_user(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    query: web::Query<ScimResourceQuery>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    match path_user(&state, &id).await {
+        Ok(user) => user_response(&state, StatusCode::OK, &user, Some(&query)).await,
+        Err(response) => response,
+    }
+}
+
+pub async fn replace_user(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    body: web::Json<Value>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    let mut user = match changeable_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    let scim = match parse_resource::<ScimUser>(body.into_inner()) {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    let changes = match apply_user(&state, &mut user, scim).await {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    if let Err(response) = save_user(&state, &mut user, changes).await {
+        return response;
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_UPDATED,
+        &user.username,
+        actor(&client),
+    )
+    .await;
+    user_response(&state, StatusCode::OK, &user, None).await
+}
+
+pub async fn patch_user(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    request: web::Json<types::ScimPatchRequest>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    let mut user = match changeable_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    let groups = user_groups(&state, &user).await;
+    let groups: Vec<&DbGroup> = groups.iter().collect();
+    let mut resource = to_value(&scim_user(&user, &groups));
+    for operation in &request.operations {
+        if let Err(problem) = scim::apply_operation(&mut resource, operation) {
+            return problem_response(problem);
+        }
+    }
+    let scim = match parse_resource::<ScimUser>(resource) {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    let changes = match apply_user(&state, &mut user, scim).await {
+        Ok(c) => c,
+        Err(response) => return response,
+    };
+    if let Err(response) = save_user(&state, &mut user, changes).await {
+        return response;
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_UPDATED,
+        &user.username,
+        actor(&client),
+    )
+    .await;
+    user_response(&state, StatusCode::OK, &user, None).await
+}
+
+pub async fn delete_user(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    let user = match changeable_user(&state, &id).await {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.database.delete_user(user.id.unwrap()).await {
+        return server_error("delete the user", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_USER_DELETED,
+        &user.username,
+        actor(&client),
+    )
+    .await;
+    HttpResponse::NoContent().finish()
+}
+
+/// Copies a SCIM group onto `group`. Members must be existing users.
+async fn apply_group(
+    state: &types::AppState,
+    group: &mut DbGroup,
+    scim: ScimGroup,
+) -> Result<(), HttpResponse> {
+    let name = scim.display_name.trim();
+    if name.is_empty() {
+        return Err(scim_error(
+            StatusCode::BAD_REQUEST,
+            Some("invalidValue"),
+            "displayName is required",
+        ));
+    }
+    if name != group.name {
+        match state.database.group_by_name(name).await {
+            Ok(None) => (),
+            Ok(Some(_)) => {
+                return Err(scim_error(
+                    StatusCode::CONFLICT,
+                    Some("uniqueness"),
+                    "A group with that displayName already exists",
+                ))
+            }
+            Err(e) => return Err(server_error("load groups", e)),
+        }
+    }
+    let mut members = vec![];
+    for member in &scim.members {
+        match ObjectId::parse_str(&member.value) {
+            Ok(id) if !members.contains(&id) => members.push(id),
+            Ok(_) => (),
+            Err(_) => {
+                return Err(scim_error(
+                    StatusCode::BAD_REQUEST,
+                    Some("invalidValue"),
+                    format!("No such user {}", member.value),
+                ))
+            }
+        }
+    }
+    let found = match state.database.users_by_ids(&members).await {
+        Ok(u) => u.len(),
+        Err(e) => return Err(server_error("load members", e)),
+    };
+    if found != members.len() {
+        return Err(scim_error(
+            StatusCode::BAD_REQUEST,
+            Some("invalidValue"),
+            "Members must be existing users",
+        ));
+    }
+    group.name = name.to_owned();
+    group.external_id = scim.external_id;
+    group.members = members;
+    group.updated_at = now();
+    Ok(())
+}
+
+async fn usernames(
+    state: &types::AppState,
+    ids: &[ObjectId],
+) -> Result<HashMap<ObjectId, String>, HttpResponse> {
+    match state.database.users_by_ids(ids).await {
+        Ok(users) => Ok(users
+            .into_iter()
+            .filter_map(|u| Some((u.id?, u.username)))
+            .collect()),
+        Err(e) => Err(server_error("load members", e)),
+    }
+}
+
+async fn path_group(state: &types::AppState, id: &str) -> Result<DbGroup, HttpResponse> {
+    let id = match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return Err(not_found("group")),
+    };
+    match state.database.group_by_id(id).await {
+        Ok(Some(g)) => Ok(g),
+        Ok(None) => Err(not_found("group")),
+        Err(e) => Err(server_error("load the group", e)),
+    }
+}
+
+async fn group_response(
+    state: &types::AppState,
+    status: StatusCode,
+    group: &DbGroup,
+    query: Option<&ScimResourceQuery>,
+) -> HttpResponse {
+    let names = match usernames(state, &group.members).await {
+        Ok(n) => n,
+        Err(response) => return response,
+    };
+    let location = location("Groups", &group.id.unwrap().to_hex());
+    resource_response(status, &scim_group(group, &names), location, query)
+}
+
+pub async fn list_groups(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    query: web::Query<ScimListQuery>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    let filter = match parse_filter(&query) {
+        Ok(f) => f,
+        Err(response) => return response,
+    };
+    let lookup = filter
+        .as_ref()
+        .and_then(|f| database_query(f, &[], GROUP_QUERY_FIELDS));
+    let groups = match state
+        .database
+        .groups_matching(lookup.unwrap_or_default())
+        .await
+    {
+        Ok(g) => g,
+        Err(e) => return server_error("list groups", e),
+    };
+    let mut members: Vec<ObjectId> = groups.iter().flat_map(|g| g.members.clone()).collect();
+    members.sort();
+    members.dedup();
+    let names = match usernames(&state, &members).await {
+        Ok(n) => n,
+        Err(response) => return response,
+    };
+    let resources = groups
+        .iter()
+        .map(|g| to_value(&scim_group(g, &names)))
+        .collect();
+    list_response(resources, filter, &query)
+}
+
+pub async fn create_group(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    body: web::Json<Value>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    let scim = match parse_resource::<ScimGroup>(body.into_inner()) {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    let mut group = DbGroup {
+        id: None,
+        name: String::new(),
+        external_id: None,
+        members: vec![],
+        created_at: now(),
+        updated_at: now(),
+    };
+    if let Err(response) = apply_group(&state, &mut group, scim).await {
+        return response;
+    }
+    match state.database.insert_group(&group).await {
+        Ok(id) => group.id = Some(id),
+        Err(e) => return server_error("create the group", e),
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_GROUP_CREATED,
+        &group.name,
+        actor(&client),
+    )
+    .await;
+    group_response(&state, StatusCode::CREATED, &group, None).await
+}
+
+pub async fn get_group(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    query: web::Query<ScimResourceQuery>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    match path_group(&state, &id).await {
+        Ok(group) => group_response(&state, StatusCode::OK, &group, Some(&query)).await,
+        Err(response) => response,
+    }
+}
+
+async fn save_group(
+    state: &types::AppState,
+    client: &ApiClient,
+    group: &DbGroup,
+) -> Result<(), HttpResponse> {
+    if let Err(e) = state.database.update_group(group).await {
+        return Err(server_error("update the group", e));
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_GROUP_UPDATED,
+        &group.name,
+        actor(client),
+    )
+    .await;
+    Ok(())
+}
+
+pub async fn replace_group(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    body: web::Json<Value>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    let mut group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    let scim = match parse_resource::<ScimGroup>(body.into_inner()) {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    if let Err(response) = apply_group(&state, &mut group, scim).await {
+        return response;
+    }
+    if let Err(response) = save_group(&state, &client, &group).await {
+        return response;
+    }
+    group_response(&state, StatusCode::OK, &group, None).await
+}
+
+pub async fn patch_group(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+    request: web::Json<types::ScimPatchRequest>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    let mut group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    // Member names are not needed to apply operations.
+    let mut resource = to_value(&scim_group(&group, &HashMap::new()));
+    for operation in &request.operations {
+        if let Err(problem) = scim::apply_operation(&mut resource, operation) {
+            return problem_response(problem);
+        }
+    }
+    let scim = match parse_resource::<ScimGroup>(resource) {
+        Ok(s) => s,
+        Err(response) => return response,
+    };
+    if let Err(response) = apply_group(&state, &mut group, scim).await {
+        return response;
+    }
+    if let Err(response) = save_group(&state, &client, &group).await {
+        return response;
+    }
+    group_response(&state, StatusCode::OK, &group, None).await
+}
+
+pub async fn delete_group(
+    state: web::Data<types::AppState>,
+    client: web::ReqData<ApiClient>,
+    id: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = require_scope(&client) {
+        return response;
+    }
+    let group = match path_group(&state, &id).await {
+        Ok(g) => g,
+        Err(response) => return response,
+    };
+    if let Err(e) = state.database.delete_group(group.id.unwrap()).await {
+        return server_error("delete the group", e);
+    }
+    audit::record(
+        &state.database,
+        audit::EVENT_GROUP_DELETED,
+        &group.name,
+        actor(&client),
+    )
+    .await;
+    HttpResponse::NoContent().finish()
+}
+
+pub async fn service_provider_config() -> HttpResponse {
+    scim_response(
+        StatusCode::OK,
+        &json!({
+            "schemas": [SCHEMA_SERVICE_PROVIDER_CONFIG],
+            "patch": { "supported": true },
+            "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+            "filter": { "supported": true, "maxResults": MAX_COUNT },
+            "changePassword": { "supported": true },
+            "sort": { "supported": false },
+            "etag": { "supported": false },
+            "authenticationSchemes": [{
+                "type": "oauthbearertoken",
+                "name": "OAuth Bearer Token",
+                "description": format!(
+                    "Access token from the client_credentials grant with the {} scope",
+                    SCOPE_SCIM
+                ),
+                "primary": true,
+            }],
+            "meta": {
+                "resourceType": "ServiceProviderConfig",
+                "location": format!("{}{}/ServiceProviderConfig", ISSUER, SCIM_PATH),
+            },
+        }),
+    )
+}
+
+fn resource_types() -> Vec<Value> {
+    [
+        ("User", "/Users", SCHEMA_USER),
+        ("Group", "/Groups", SCHEMA_GROUP),
+    ]
+    .into_iter()
+    .map(|(name, endpoint, schema)| {
+        json!({
+            "schemas": [SCHEMA_RESOURCE_TYPE],
+            "id": name,
+            "name": name,
+            "endpoint": endpoint,
+            "schema": schema,
+            "meta": {
+                "resourceType": "ResourceType",
+                "location": location("ResourceTypes", name),
+            },
+        })
+    })
+    .collect()
+}
+
+fn attribute(name: &str, kind: &str, multi_valued: bool, mutability: &str) -> Value {
+    json!({
+        "name": name,
+        "type": kind,
+        "multiValued": multi_valued,
+        "required": false,
+        "caseExact": false,
+        "mutability": mutability,
+        "returned": "default",
+        "uniqueness": "none",
+    })
+}
+
+fn complex(name: &str, multi_valued: bool, mutability: &str, sub_attributes: Vec<Value>) -> Value {
+    let mut attribute = attribute(name, "complex", multi_valued, mutability);
+    attribute["subAttributes"] = Value::Array(sub_attributes);
+    attribute
+}
+
+fn reference(reference_type: &str, mutability: &str) -> Value {
+    let mut attribute = attribute("$ref", "reference", false, mutability);
+    attribute["referenceTypes"] = json!([reference_type]);
+    attribute
+}
+
+/// Unique, required attribute such as `userName`.
+fn identifier(name: &str) -> Value {
+    let mut attribute = attribute(name, "string", false, "readWrite");
+    attribute["required"] = true.into();
+    attribute["uniqueness"] = "server".into();
+    attribute
+}
+
+fn schemas() -> Vec<Value> {
+    let multi_value = |name: &str| {
+        complex(
+            name,
+            true,
+            "readWrite",
+            vec![
+                attribute("value", "string", false, "readWrite"),
+                attribute("type", "string", false, "readWrite"),
+                attribute("primary", "boolean", false, "readWrite"),
+            ],
+        )
+    };
+    let mut password = attribute("password", "string", false, "writeOnly");
+    password["returned"] = "never".into();
+    let user = vec![
+        identifier("userName"),
+        attribute("externalId", "string", false, "readWrite"),
+        complex(
+            "name",
+            false,
+            "readWrite",
+            vec![
+                attribute("formatted", "string", false, "readWrite"),
+                attribute("givenName", "string", false, "readWrite"),
+                attribute("familyName", "string", false, "readWrite"),
+            ],
+        ),
+        attribute("displayName", "string", false, "readWrite"),
+        multi_value("emails"),
+        multi_value("photos"),
+        attribute("locale", "string", false, "readWrite"),
+        attribute("timezone", "string", false, "readWrite"),
+        attribute("active", "boolean", false, "readWrite"),
+        password,
+        complex(
+            "groups",
+            true,
+            "readOnly",
+            vec![
+                attribute("value", "string", false, "readOnly"),
+                reference("Group", "readOnly"),
+                attribute("display", "string", false, "readOnly"),
+                attribute("type", "string", false, "readOnly"),
+            ],
+        ),
+    ];
+    let group = vec![
+        identifier("displayName"),
+        attribute("externalId", "string", false, "readWrite"),
+        complex(
+            "members",
+            true,
+            "readWrite",
+            vec![
+                attribute("value", "string", false, "immutable"),
+                reference("User", "immutable"),
+                attribute("display", "string", false, "readOnly"),
+                attribute("type", "string", false, "immutable"),
+            ],
+        ),
+    ];
+    [
+        (SCHEMA_USER, "User", "User Account", user),
+        (SCHEMA_GROUP, "Group", "Group", group),
+    ]
+    .into_iter()
+    .map(|(id, name, description, attributes)| {
+        json!({
+            "schemas": [SCHEMA_SCHEMA],
+            "id": id,
+            "name": name,
+            "description": description,
+            "attributes": attributes,
+            "meta": {
+                "resourceType": "Schema",
+                "location": location("Schemas", id),
+            },
+        })
+    })
+    .collect()
+}
+
+fn discovery_list(resources: Vec<Value>) -> HttpResponse {
+    scim_response(
+        StatusCode::OK,
+        &ScimListResponse {
+            schemas: vec![SCHEMA_LIST_RESPONSE.to_owned()],
+            total_results: resources.len(),
+            start_index: 1,
+            items_per_page: resources.len(),
+            resources,
+        },
+    )
+}
+
+fn discovery_entry(resources: Vec<Value>, id: &str, what: &str) -> HttpResponse {
+    match resources.into_iter().find(|r| r["id"] == id) {
+        Some(r) => scim_response(StatusCode::OK, &r),
+        None => not_found(what),
+    }
+}
+
+pub async fn list_resource_types() -> HttpResponse {
+    discovery_list(resource_types())
+}
+
+pub async fn get_resource_type(id: web::Path<String>) -> HttpResponse {
+    discovery_entry(resource_types(), &id, "resource type")
+}
+
+pub async fn list_schemas() -> HttpResponse {
+    discovery_list(schemas())
+}
+
+pub async fn get_schema(id: web::Path<String>) -> HttpResponse {
+    discovery_entry(schemas(), &id, "schema")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_query(filter: &str) -> Option<Document> {
+        database_query(&scim::parse_filter(filter).unwrap(), &[], USER_QUERY_FIELDS)
+    }
+
+    #[test]
+    fn translates_equality_lookups() {
+        assert_eq!(
+            user_query(r#"userName eq "alice""#),
+            Some(doc! { "username": "alice" })
+        );
+        assert_eq!(
+            user_query(r#"EXTERNALID eq "x1""#),
+            Some(doc! { "external_id": "x1" })
+        );
+        assert_eq!(
+            user_query(r#"emails[value eq "a@example.com"]"#),
+            Some(doc! { "email": "a@example.com" })
+        );
+        let id = ObjectId::new();
+        assert_eq!(
+            user_query(&format!(r#"id eq "{}""#, id.to_hex())),
+            Some(doc! { "_id": id })
+        );
+        assert_eq!(
+            database_query(
+                &scim::parse_filter(&format!(r#"members[value eq "{}"]"#, id.to_hex())).unwrap(),
+                &[],
+                GROUP_QUERY_FIELDS,
+            ),
+            Some(doc! { "members": id })
+        );
+    }
+
+    #[test]
+    fn narrows_only_when_every_match_is_covered() {
+        // One side of `and` is enough to narrow by.
+        assert_eq!(
+            user_query(r#"userName eq "alice" and active eq true"#),
+            Some(doc! { "username": "alice" })
+        );
+        assert_eq!(
+            user_query(r#"userName eq "a" or externalId eq "b""#),
+            Some(doc! { "$or": [{ "username": "a" }, { "external_id": "b" }] })
+        );
+        for filter in [
+            r#"userName eq "a" or active eq true"#,
+            r#"not (userName eq "alice")"#,
+            r#"userName sw "al""#,
+            r#"userName ne "alice""#,
+            r#"id eq "not an id""#,
+            r#"emails[type eq "work"]"#,
+            "userName pr",
+        ] {
+            assert_eq!(user_query(filter), None, "{}", filter);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse};
+use tracing::{info, warn};
+
+use crate::{
+    csrf,
+    db::{self, UserConflict},
+    password, password_policy,
+    routes::{client_ip, render, verify_email::send_verification_email},
+    types::{self, Config, DbUser, MessageTemplate, SignupTemplate, UserProfile},
+};
+
+fn signup_form(state: &types::AppState, session: &Session, error: Option<String>) -> HttpResponse {
+    render(SignupTemplate {
+        csrf_token: csrf::token(session),
+        invite_only: state.config.signup_invite_only,
+        captcha_widget: state.captcha.widget(),
+        error,
+        password_errors: vec![],
+    })
+}
+
+fn check_username(config: &Config, username: &str) -> Result<(), String> {
+    let length = username.chars().count();
+    if length < config.signup_username_min_length || length > config.signup_username_max_length {
+        return Err(format!(
+            "Usernames must be between {} and {} characters.",
+            config.signup_username_min_length, config.signup_username_max_length
+        ));
+    }
+    let allowed =
+        |c: char| c.is_ascii_alphanumeric() || config.signup_username_extra_chars.contains(c);
+    if !username.chars().all(allowed) {
+        return Err(format!(
+            "Usernames may only contain letters, digits and {}",
+            config.signup_username_extra_chars
+        ));
+    }
+    Ok(())
+}
+
+fn check_email(config: &Config, email: &str) -> Result<(), String> {
+    let domain = match email.rsplit_once('@') {
+        Some((local, domain)) if !local.is_empty() && domain.contains('.') => domain,
+        _ => return Err("Enter a valid email address.".to_owned()),
+    };
+    let allowed_domains = &config.signup_allowed_email_domains;
+    if !allowed_domains.is_empty() && !allowed_domains.contains(&domain.to_lowercase()) {
+        return Err("Sign-ups are not open for that email domain.".to_owned());
+    }
+    Ok(())
+}
+
+pub async fn signup_page(state: web::Data<types::AppState>, session: Session) -> HttpResponse {
+    if !state.config.signup_enabled {
+        return HttpResponse::NotFound().body("Sign-up is not enabled");
+    }
+    signup_form(&state, &session, None)
+}
+
+pub async fn signup(
+    req: HttpRequest,
+    state: web::Data<types::AppState>,
+    session: Session,
+    request: web::Form<types::SignupRequest>,
+) -> HttpResponse {
+    if !state.config.signup_enabled {
+        return HttpResponse::NotFound().body("Sign-up is not enabled");
+    }
+    let client_ip = client_ip(&state.config, &req);
+    if !state
+        .captcha
+        .verify(&client_ip, &request.captcha_response)
+        .await
+    {
+        info!("Sign-up from {} failed the CAPTCHA", client_ip);
+        return signup_form(
+            &state,
+            &session,
+            Some("Please complete the CAPTCHA.".to_owned()),
+        );
+    }
+    let username = request.username.trim();
+    let email = request.email.trim();
+    if let Err(e) = check_username(&state.config, username) {
+        return signup_form(&state, &session, Some(e));
+    }
+    if let Err(e) = check_email(&state.config, email) {
+        return signup_form(&state, &session, Some(e));
+    }
+    if let Err(violations) =
+        password_policy::check(&state.config, username, &request.password).await
+    {
+        return render(SignupTemplate {
+            csrf_token: csrf::token(&session),
+            invite_only: state.config.signup_invite_only,
+            captcha_widget: state.captcha.widget(),
+            error: None,
+            password_errors: violations.iter().map(|v| v.to_string()).collect(),
+        });
+    }
+    if state.database.user_by_username(username).await.is_some() {
+        return signup_form(&state, &session, Some("That username is taken.".to_owned()));
+    }
+    if state.database.user_by_email(email).await.is_some() {
+        return signup_form(
+            &state,
+            &session,
+            Some("An account already uses that email address.".to_owned()),
+        );
+    }
+    if state.config.signup_invite_only {
+        let invite_hash = password::hash_token(request.invite_code.trim());
+        match state.database.take_invite(&invite_hash).await {
+            Ok(Some(_)) => (),
+            Ok(None) => {
+                return signup_form(
+                    &state,
+                    &session,
+                    Some("That invite code is not valid.".to_owned()),
+                );
+            }
+            Err(e) => {
+                warn!("Failed to check invite code: {}", e);
+                return HttpResponse::InternalServerError().body("Failed to check invite code");
+            }
+        }
+    }
+    let password_hash = match password::hash_password(&request.password) {
+        Some(h) => h,
+        None => {
+            return HttpResponse::InternalServerError().body("Failed to hash password.");
+        }
+    };
+    let mut user = DbUser {
+        id: None,
+        username: username.to_owned(),
+        password_hash,
+        profile: UserProfile {
+            email: Some(email.to_owned()),
+            ..Default::default()
+        },
+        must_verify_email: true,
+        totp: None,
+        is_admin: false,
+        disabled: false,
+        sessions_valid_after: 0,
+        external_id: None,
+    };
+    match state.database.insert_user(&user).await {
+        Ok(id) => user.id = Some(id),
+        // Someone else signed up with the same details since the checks above.
+        Err(e) => match db::user_conflict(e.as_ref()) {
+            Some(UserConflict::Username) => {
+                return signup_form(&state, &session, Some("That username is taken.".to_owned()));
+            }
+            Some(UserConflict::Email) => {
+                return signup_form(
+                    &state,
+                    &session,
+                    Some("An account already uses that email address.".to_owned()),
+                );
+            }
+            None => {
+                warn!("Failed to create user: {}", e);
+                return HttpResponse::InternalServerError().body("Failed to create account");
+            }
+        },
+    }
+    info!("New account signed up from {}", client_ip);
+    if let Err(e) = send_verification_email(&state.database, &*state.mailer, &user).await {
+        warn!("Failed to send verification email: {}", e);
+    }
+    render(MessageTemplate {
+        title: "Sign up".to_owned(),
+        message: format!(
+            "Welcome, {}! We sent a link to {} to verify your email address. \
+             Follow it before logging in.",
+            user.username, email
+        ),
+    })
+}
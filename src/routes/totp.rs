@@ -0,0 +1,339 @@
+use actix_session::Session;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use qrcode::{render::svg, QrCode};
+use tracing::{info, warn};
+
+use crate::{
+    browser_session, claims, csrf, lockout, password,
+    routes::{
+        auth::{complete_login, now},
+        client_ip, render,
+    },
+    totp,
+    types::{self, DbTotp, DbUser, MessageTemplate, SecondFactorTemplate, TotpEnrollTemplate},
+};
+
+const TOTP_ISSUER: &str = "Snazzy Fellas";
+const RECOVERY_CODE_COUNT: usize = 10;
+
+pub fn expired_login() -> HttpResponse {
+    render(MessageTemplate {
+        title: "Login".to_owned(),
+        message: "Your login has expired. Please start again.".to_owned(),
+    })
+}
+
+/// Checks a TOTP code for a user, consuming its time step in the database
+/// so the same code cannot be used again, even by a concurrent request.
+async fn check_code(state: &types::AppState, user: &mut DbUser, code: &str) -> bool {
+    let user_id = match user.id {
+        Some(id) => id,
+        None => return false,
+    };
+    let totp = match &mut user.totp {
+        Some(t) => t,
+        None => return false,
+    };
+    let secret =
+        match totp::decrypt_secret(&state.config.mfa_encryption_key, &totp.encrypted_secret) {
+            Some(s) => s,
+            None => return false,
+        };
+    let step = match totp::verify(&secret, code, now()) {
+        Some(step) => step,
+        None => return false,
+    };
+    match state.database.use_totp_step(user_id, step).await {
+        Ok(true) => {
+            totp.last_used_step = step;
+            true
+        }
+        Ok(false) => {
+            info!("TOTP code was replayed");
+            false
+        }
+        Err(e) => {
+            warn!("Failed to save used TOTP code: {}", e);
+            false
+        }
+    }
+}
+
+/// Checks a recovery code and removes it from the user in the database if
+/// it matches, so it can only be redeemed once.
+async fn use_recovery_code(state: &types::AppState, user: &DbUser, code: &str) -> bool {
+    let (user_id, totp) = match (user.id, &user.totp) {
+        (Some(id), Some(t)) => (id, t),
+        _ => return false,
+    };
+    let code = totp::normalize_recovery_code(code);
+    let hash = match totp
+        .recovery_code_hashes
+        .iter()
+        .find(|hash| password::check_password(hash, &code))
+    {
+        Some(h) => h,
+        None => return false,
+    };
+    match state.database.use_recovery_code(user_id, hash).await {
+        Ok(true) => true,
+        Ok(false) => {
+            info!("Recovery code was already used");
+            false
+        }
+        Err(e) => {
+            warn!("Failed to remove used recovery code: {}", e);
+            false
+        }
+    }
+}
+
+/// Page offering the second factors the user of a pending login has.
+pub async fn second_factor_form(
+    state: &types::AppState,
+    session: &Session,
+    user: &DbUser,
+    error: Option<String>,
+) -> HttpResponse {
+    let passkey_enabled = match state
+        .database
+        .webauthn_credentials_for_user(user.id.unwrap())
+        .await
+    {
+        Ok(credentials) => !credentials.is_empty(),
+        Err(e) => {
+            warn!("Failed to load passkeys: {}", e);
+            false
+        }
+    };
+    render(SecondFactorTemplate {
+        csrf_token: csrf::token(session),
+        totp_enabled: user.totp.as_ref().is_some_and(|t| t.confirmed),
+        passkey_enabled,
+        error,
+    })
+}
+
+pub async fn second_factor_page(
+    state: web::Data<types::AppState>,
+    session: Session,
+) -> HttpResponse {
+    let pending = match browser_session::pending_login(&state, &session).await {
+        Some(p) => p,
+        None => return expired_login(),
+    };
+    match state.database.user_by_id(pending.user_id).await {
+        Some(user) => second_factor_form(&state, &session, &user, None).await,
+        None => expired_login(),
+    }
+}
+
+pub async fn login(
+    req: HttpRequest,
+    state: web::Data<types::AppState>,
+    session: Session,
+    request: web::Form<types::SecondFactorRequest>,
+) -> HttpResponse {
+    let pending = match browser_session::pending_login(&state, &session).await {
+        Some(p) => p,
+        None => return expired_login(),
+    };
+    let mut user = match state.database.user_by_id(pending.user_id).await {
+        Some(u) => u,
+        None => {
+            browser_session::clear_pending_login(&state, &session).await;
+            return expired_login();
+        }
+    };
+    let client_ip = client_ip(&state.config, &req);
+    if let Some(wait) = lockout::retry_after(&state, &user.username, &client_ip).await {
+        info!("Second factor throttled for {} more seconds", wait);
+        let error = "Too many failed login attempts. Please try again later.".to_owned();
+        return second_factor_form(&state, &session, &user, Some(error)).await;
+    }
+    let (verified, method) = if !request.recovery_code.trim().is_empty() {
+        (
+            use_recovery_code(&state, &user, &request.recovery_code).await,
+            claims::AMR_RECOVERY_CODE,
+        )
+    } else {
+        (
+            check_code(&state, &mut user, &request.code).await,
+            claims::AMR_OTP,
+        )
+    };
+    if !verified {
+        info!("User entered an invalid second factor");
+        lockout::record_failure(&state, &user.username, &client_ip).await;
+        if !browser_session::record_second_factor_failure(&state, &session, &pending).await {
+            return expired_login();
+        }
+        return second_factor_form(
+            &state,
+            &session,
+            &user,
+            Some("That code is not valid.".to_owned()),
+        )
+        .await;
+    }
+    lockout::record_success(&state, &user.username).await;
+    let authentication =
+        claims::authentication_context(&[claims::AMR_PASSWORD, method, claims::AMR_MULTI_FACTOR]);
+    complete_login(
+        &state,
+        &session,
+        &user,
+        authentication,
+        pending.authorization,
+    )
+    .await
+    .respond_to(&req)
+    .map_into_boxed_body()
+}
+
+async fn account_user(state: &types::AppState, session: &Session) -> Option<DbUser> {
+    browser_session::current_user(state, session).await
+}
+
+fn enroll_form(
+    config: &types::Config,
+    session: &Session,
+    user: &DbUser,
+    error: Option<String>,
+) -> HttpResponse {
+    let secret = user
+        .totp
+        .as_ref()
+        .and_then(|t| totp::decrypt_secret(&config.mfa_encryption_key, &t.encrypted_secret));
+    let secret = match secret {
+        Some(s) => s,
+        None => return HttpResponse::InternalServerError().body("Failed to load TOTP secret"),
+    };
+    let otpauth_uri = totp::otpauth_uri(TOTP_ISSUER, &user.username, &secret);
+    let qr_svg = match QrCode::new(otpauth_uri.as_bytes()) {
+        Ok(code) => code.render::<svg::Color>().min_dimensions(200, 200).build(),
+        Err(e) => {
+            warn!("Failed to render TOTP QR code: {}", e);
+            String::new()
+        }
+    };
+    render(TotpEnrollTemplate {
+        csrf_token: csrf::token(session),
+        otpauth_uri,
+        secret: totp::base32_encode(&secret),
+        qr_svg,
+        error,
+    })
+}
+
+fn already_enabled() -> HttpResponse {
+    render(MessageTemplate {
+        title: "Two-factor authentication".to_owned(),
+        message: "Two-factor authentication is already enabled.".to_owned(),
+    })
+}
+
+/// Shows the enrollment in progress, if any. Starting one is a POST so that
+/// loading this page never replaces the secret.
+pub async fn enroll_page(state: web::Data<types::AppState>, session: Session) -> HttpResponse {
+    let user = match account_user(&state, &session).await {
+        Some(u) => u,
+        None => {
+            return HttpResponse::SeeOther()
+                .insert_header(("Location", "/account"))
+                .finish()
+        }
+    };
+    match &user.totp {
+        Some(t) if t.confirmed => already_enabled(),
+        Some(_) => enroll_form(&state.config, &session, &user, None),
+        None => HttpResponse::SeeOther()
+            .insert_header(("Location", "/account"))
+            .finish(),
+    }
+}
+
+/// Generates a new TOTP secret for the user and shows it for enrollment.
+pub async fn start_enrollment(state: web::Data<types::AppState>, session: Session) -> HttpResponse {
+    let mut user = match account_user(&state, &session).await {
+        Some(u) => u,
+        None => {
+            return HttpResponse::SeeOther()
+                .insert_header(("Location", "/account"))
+                .finish()
+        }
+    };
+    if user.totp.as_ref().is_some_and(|t| t.confirmed) {
+        return already_enabled();
+    }
+    let encrypted_secret =
+        match totp::encrypt_secret(&state.config.mfa_encryption_key, &totp::generate_secret()) {
+            Some(s) => s,
+            None => {
+                return HttpResponse::InternalServerError().body("Failed to create TOTP secret")
+            }
+        };
+    user.totp = Some(DbTotp {
+        encrypted_secret,
+        confirmed: false,
+        last_used_step: 0,
+        recovery_code_hashes: vec![],
+    });
+    if let Err(e) = state.database.update_user(&user).await {
+        warn!("Failed to save TOTP secret: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to save TOTP secret");
+    }
+    enroll_form(&state.config, &session, &user, None)
+}
+
+pub async fn confirm_enrollment(
+    state: web::Data<types::AppState>,
+    session: Session,
+    request: web::Form<types::TotpConfirmRequest>,
+) -> HttpResponse {
+    let mut user = match account_user(&state, &session).await {
+        Some(u) => u,
+        None => {
+            return HttpResponse::SeeOther()
+                .insert_header(("Location", "/account"))
+                .finish()
+        }
+    };
+    if user.totp.as_ref().is_none_or(|t| t.confirmed) {
+        return HttpResponse::BadRequest().body("No TOTP enrollment in progress");
+    }
+    if !check_code(&state, &mut user, &request.code).await {
+        return enroll_form(
+            &state.config,
+            &session,
+            &user,
+            Some("That code is not valid. Check your device's clock and try again.".to_owned()),
+        );
+    }
+    let recovery_codes = totp::generate_recovery_codes(RECOVERY_CODE_COUNT);
+    let mut recovery_code_hashes = Vec::with_capacity(recovery_codes.len());
+    for code in &recovery_codes {
+        match password::hash_password(&totp::normalize_recovery_code(code)) {
+            Some(h) => recovery_code_hashes.push(h),
+            None => {
+                return HttpResponse::InternalServerError().body("Failed to hash recovery codes")
+            }
+        }
+    }
+    if let Some(totp) = &mut user.totp {
+        totp.confirmed = true;
+        totp.recovery_code_hashes = recovery_code_hashes;
+    }
+    if let Err(e) = state.database.update_user(&user).await {
+        warn!("Failed to enable TOTP: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to enable TOTP");
+    }
+    render(MessageTemplate {
+        title: "Two-factor authentication".to_owned(),
+        message: format!(
+            "Two-factor authentication is on. Keep these single-use recovery codes \
+             somewhere safe, they will not be shown again: {}",
+            recovery_codes.join(", ")
+        ),
+    })
+}
@@ -0,0 +1,104 @@
+use actix_web::{web, HttpResponse};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::{
+    db::Database,
+    mailer::{Email, Mailer},
+    password,
+    routes::{
+        auth::{generate_random_code, ISSUER},
+        render,
+    },
+    types::{self, DbUser, MessageTemplate},
+};
+
+const VERIFICATION_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24);
+
+fn render_message(title: &str, message: &str) -> HttpResponse {
+    let template = MessageTemplate {
+        title: title.to_owned(),
+        message: message.to_owned(),
+    };
+    render(template)
+}
+
+/// Emails `user` a link confirming they own their current email address.
+/// Does nothing for users without an email address.
+pub async fn send_verification_email(
+    database: &Database,
+    mailer: &dyn Mailer,
+    user: &DbUser,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let email = match &user.profile.email {
+        Some(e) => e.clone(),
+        None => return Ok(()),
+    };
+    let token = generate_random_code(64);
+    let verification = types::DbEmailVerification {
+        user_id: user.id.ok_or("User has no ID")?,
+        email: email.clone(),
+        token_hash: password::hash_token(&token),
+        expires_at: bson::DateTime::from_system_time(
+            SystemTime::now() + VERIFICATION_TOKEN_LIFETIME,
+        ),
+    };
+    database.insert_email_verification(&verification).await?;
+    let link = format!(
+        "{}/verify-email?token={}",
+        ISSUER,
+        urlencoding::encode(&token)
+    );
+    mailer
+        .send(&Email {
+            to: email,
+            subject: "Verify your Snazzy Fellas email address".to_owned(),
+            body: format!(
+                "Hi {},\n\nConfirm this is your email address by following this link \
+                 within a day:\n{}\n\nIf you don't have an account, you can ignore this email.",
+                user.username, link
+            ),
+        })
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?;
+    Ok(())
+}
+
+pub async fn verify_email(
+    state: web::Data<types::AppState>,
+    query: web::Query<types::VerifyEmailQuery>,
+) -> HttpResponse {
+    let invalid_link = "This verification link is invalid or has expired.";
+    let verification = match state
+        .database
+        .take_email_verification(&password::hash_token(&query.token))
+        .await
+    {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            info!("Invalid or expired email verification token used");
+            return render_message("Email verification", invalid_link);
+        }
+        Err(e) => {
+            warn!("Failed to load email verification: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to verify email");
+        }
+    };
+    let mut user = match state.database.user_by_id(verification.user_id).await {
+        Some(u) => u,
+        None => return render_message("Email verification", invalid_link),
+    };
+    if user.profile.email.as_deref() != Some(verification.email.as_str()) {
+        info!("Email verification for an address no longer on the account");
+        return render_message("Email verification", invalid_link);
+    }
+    user.profile.email_verified = true;
+    if let Err(e) = state.database.update_user(&user).await {
+        warn!("Failed to mark email verified: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to verify email");
+    }
+    render_message(
+        "Email verification",
+        "Thanks, your email address is verified.",
+    )
+}
@@ -0,0 +1,1153 @@
+//! SCIM 2.0 (RFC 7644) filters and PATCH operations, applied to resources in
+//! their JSON form.
+
+use std::{cmp::Ordering, fmt};
+
+use serde_json::{Map, Value};
+
+use crate::types::ScimPatchOperation;
+
+pub const SCHEMA_USER: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const SCHEMA_GROUP: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+
+/// Attribute names as the resource types spell them. Clients may use any
+/// case, so names are matched against these.
+const ATTRIBUTE_NAMES: &[&str] = &[
+    "schemas",
+    "id",
+    "externalId",
+    "userName",
+    "name",
+    "formatted",
+    "givenName",
+    "familyName",
+    "displayName",
+    "emails",
+    "photos",
+    "value",
+    "type",
+    "primary",
+    "display",
+    "locale",
+    "timezone",
+    "active",
+    "password",
+    "groups",
+    "members",
+    "meta",
+    "$ref",
+];
+
+/// Boolean attributes some clients send as `"True"` or `"False"`.
+const BOOLEAN_ATTRIBUTES: &[&str] = &["active", "primary"];
+
+/// Why a filter, path or operation cannot be used. Each variant is a SCIM
+/// `scimType` error.
+#[derive(Debug, PartialEq)]
+pub enum ScimProblem {
+    InvalidFilter(String),
+    InvalidPath(String),
+    InvalidValue(String),
+    NoTarget(String),
+}
+
+impl ScimProblem {
+    pub fn scim_type(&self) -> &'static str {
+        match self {
+            ScimProblem::InvalidFilter(_) => "invalidFilter",
+            ScimProblem::InvalidPath(_) => "invalidPath",
+            ScimProblem::InvalidValue(_) => "invalidValue",
+            ScimProblem::NoTarget(_) => "noTarget",
+        }
+    }
+}
+
+impl fmt::Display for ScimProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScimProblem::InvalidFilter(detail)
+            | ScimProblem::InvalidPath(detail)
+            | ScimProblem::InvalidValue(detail)
+            | ScimProblem::NoTarget(detail) => write!(f, "{}", detail),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Co,
+    Sw,
+    Ew,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl CompareOp {
+    fn parse(word: &str) -> Option<CompareOp> {
+        Some(match word.to_ascii_lowercase().as_str() {
+            "eq" => CompareOp::Eq,
+            "ne" => CompareOp::Ne,
+            "co" => CompareOp::Co,
+            "sw" => CompareOp::Sw,
+            "ew" => CompareOp::Ew,
+            "gt" => CompareOp::Gt,
+            "ge" => CompareOp::Ge,
+            "lt" => CompareOp::Lt,
+            "le" => CompareOp::Le,
+            _ => return None,
+        })
+    }
+}
+
+/// A parsed `filter` expression.
+#[derive(Debug, PartialEq)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Present(Vec<String>),
+    Compare(Vec<String>, CompareOp, Value),
+    /// `emails[type eq "work"]`: some entry of the attribute matches.
+    ValuePath(Vec<String>, Box<Filter>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+    Word(String),
+    Literal(Value),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ScimProblem> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '[' | ']' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '[' => Token::OpenBracket,
+                    _ => Token::CloseBracket,
+                });
+            }
+            '"' => {
+                chars.next();
+                let mut end = None;
+                let mut escaped = false;
+                for (i, c) in chars.by_ref() {
+                    match c {
+                        '\\' if !escaped => escaped = true,
+                        '"' if !escaped => {
+                            end = Some(i);
+                            break;
+                        }
+                        _ => escaped = false,
+                    }
+                }
+                let end = end
+                    .ok_or_else(|| ScimProblem::InvalidFilter("Unterminated string".to_owned()))?;
+                // SCIM strings use JSON escapes.
+                let literal = serde_json::from_str(&input[start..=end])
+                    .map_err(|e| ScimProblem::InvalidFilter(format!("Invalid string: {e}")))?;
+                tokens.push(Token::Literal(literal));
+            }
+            _ => {
+                let mut end = input.len();
+                while let Some(&(i, c)) = chars.peek() {
+                    if c.is_whitespace() || "()[]\"".contains(c) {
+                        end = i;
+                        break;
+                    }
+                    chars.next();
+                }
+                tokens.push(Token::Word(input[start..end].to_owned()));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Splits an attribute path such as `name.givenName` or
+/// `urn:ietf:params:scim:schemas:core:2.0:User:userName` into its names.
+fn attribute_path(word: &str) -> Result<Vec<String>, ScimProblem> {
+    let path = if word.to_ascii_lowercase().starts_with("urn:") {
+        word.rsplit(':').next().unwrap_or_default()
+    } else {
+        word
+    };
+    let names: Vec<String> = path.split('.').map(str::to_owned).collect();
+    if names.len() > 2 || names.iter().any(|n| n.is_empty()) {
+        return Err(ScimProblem::InvalidPath(format!(
+            "Invalid attribute path {}",
+            word
+        )));
+    }
+    Ok(names)
+}
+
+/// How deeply `not`, parentheses and value paths may nest in a filter.
+/// Parsing, matching and dropping a filter all recurse over its nesting.
+const MAX_FILTER_DEPTH: usize = 32;
+
+/// How many attribute expressions a filter may hold. Chains of `and` and
+/// `or` nest one level per term.
+const MAX_FILTER_TERMS: usize = 64;
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+    terms: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>) -> Parser {
+        Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+            terms: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.position += 1;
+        self.tokens.get(self.position - 1)
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), ScimProblem> {
+        match self.next() {
+            Some(t) if *t == token => Ok(()),
+            _ => Err(ScimProblem::InvalidFilter(format!("Expected {:?}", token))),
+        }
+    }
+
+    /// Parses a nested filter, closed by `close`.
+    fn nested(&mut self, close: Token) -> Result<Filter, ScimProblem> {
+        if self.depth == MAX_FILTER_DEPTH {
+            return Err(ScimProblem::InvalidFilter(
+                "The filter is nested too deeply".to_owned(),
+            ));
+        }
+        self.depth += 1;
+        let filter = self.or()?;
+        self.expect(close)?;
+        self.depth -= 1;
+        Ok(filter)
+    }
+
+    fn or(&mut self) -> Result<Filter, ScimProblem> {
+        let mut filter = self.and()?;
+        while self.peek_keyword("or") {
+            self.next();
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, ScimProblem> {
+        let mut filter = self.unary()?;
+        while self.peek_keyword("and") {
+            self.next();
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn unary(&mut self) -> Result<Filter, ScimProblem> {
+        if self.peek_keyword("not") {
+            self.next();
+            self.expect(Token::Open)?;
+            let filter = self.nested(Token::Close)?;
+            return Ok(Filter::Not(Box::new(filter)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.next();
+            return self.nested(Token::Close);
+        }
+        self.attribute_expression()
+    }
+
+    fn attribute_expression(&mut self) -> Result<Filter, ScimProblem> {
+        self.terms += 1;
+        if self.terms > MAX_FILTER_TERMS {
+            return Err(ScimProblem::InvalidFilter(
+                "The filter has too many terms".to_owned(),
+            ));
+        }
+        let path = match self.next() {
+            Some(Token::Word(w)) => attribute_path(w)
+                .map_err(|_| ScimProblem::InvalidFilter(format!("Invalid attribute {}", w)))?,
+            _ => {
+                return Err(ScimProblem::InvalidFilter(
+                    "Expected an attribute".to_owned(),
+                ))
+            }
+        };
+        if self.peek() == Some(&Token::OpenBracket) {
+            self.next();
+            let filter = self.nested(Token::CloseBracket)?;
+            return Ok(Filter::ValuePath(path, Box::new(filter)));
+        }
+        let operator = match self.next() {
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("pr") => {
+                return Ok(Filter::Present(path))
+            }
+            Some(Token::Word(w)) => CompareOp::parse(w)
+                .ok_or_else(|| ScimProblem::InvalidFilter(format!("Unknown operator {}", w)))?,
+            _ => {
+                return Err(ScimProblem::InvalidFilter(
+                    "Expected an operator".to_owned(),
+                ))
+            }
+        };
+        let value = match self.next() {
+            Some(Token::Literal(v)) => v.clone(),
+            // true, false, null and numbers are written bare.
+            Some(Token::Word(w)) => match serde_json::from_str::<Value>(w) {
+                Ok(v) if !v.is_string() => v,
+                _ => return Err(ScimProblem::InvalidFilter(format!("Invalid value {}", w))),
+            },
+            _ => return Err(ScimProblem::InvalidFilter("Expected a value".to_owned())),
+        };
+        Ok(Filter::Compare(path, operator, value))
+    }
+}
+
+/// Parses a `filter` query parameter.
+pub fn parse_filter(input: &str) -> Result<Filter, ScimProblem> {
+    let mut parser = Parser::new(tokenize(input)?);
+    let filter = parser.or()?;
+    if parser.peek().is_some() {
+        return Err(ScimProblem::InvalidFilter(
+            "Unexpected text after the filter".to_owned(),
+        ));
+    }
+    Ok(filter)
+}
+
+fn get<'a>(value: &'a Value, name: &str) -> Option<&'a Value> {
+    value
+        .as_object()?
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v)
+}
+
+fn get_mut<'a>(object: &'a mut Map<String, Value>, name: &str) -> Option<&'a mut Value> {
+    object
+        .iter_mut()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v)
+}
+
+fn canonical_name(name: &str) -> String {
+    ATTRIBUTE_NAMES
+        .iter()
+        .find(|n| n.eq_ignore_ascii_case(name))
+        .map_or_else(|| name.to_owned(), |n| (*n).to_owned())
+}
+
+/// Sets `name` on `object`, keeping the spelling of an existing key.
+fn set(object: &mut Map<String, Value>, name: &str, value: Value) {
+    match get_mut(object, name) {
+        Some(existing) => *existing = value,
+        None => {
+            object.insert(canonical_name(name), value);
+        }
+    }
+}
+
+fn remove(object: &mut Map<String, Value>, name: &str) {
+    object.retain(|k, _| !k.eq_ignore_ascii_case(name));
+}
+
+/// The entries of a multi-valued attribute, or the value itself.
+fn entries(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(items) => items.iter().collect(),
+        other => vec![other],
+    }
+}
+
+/// Everything at `path`, with multi-valued attributes flattened.
+fn resolve<'a>(resource: &'a Value, path: &[String]) -> Vec<&'a Value> {
+    let mut current = vec![resource];
+    for name in path {
+        current = current
+            .into_iter()
+            .flat_map(entries)
+            .filter_map(|v| get(v, name))
+            .collect();
+    }
+    current.into_iter().flat_map(entries).collect()
+}
+
+fn compare(actual: &Value, operator: CompareOp, expected: &Value) -> bool {
+    // Complex entries such as emails compare by their `value`.
+    let actual = match actual {
+        Value::Object(_) => match get(actual, "value") {
+            Some(v) => v,
+            None => return false,
+        },
+        other => other,
+    };
+    let ordering = match (actual, expected) {
+        (Value::String(a), Value::String(b)) => {
+            // Attributes here are case-insensitive, as is the SCIM default.
+            let (a, b) = (a.to_lowercase(), b.to_lowercase());
+            match operator {
+                CompareOp::Co => return a.contains(&b),
+                CompareOp::Sw => return a.starts_with(&b),
+                CompareOp::Ew => return a.ends_with(&b),
+                _ => a.cmp(&b),
+            }
+        }
+        (Value::Number(a), Value::Number(b)) => match a.as_f64().partial_cmp(&b.as_f64()) {
+            Some(o) => o,
+            None => return false,
+        },
+        (Value::Bool(a), Value::Bool(b)) => match operator {
+            CompareOp::Eq | CompareOp::Ne => a.cmp(b),
+            _ => return false,
+        },
+        _ => return false,
+    };
+    match operator {
+        CompareOp::Eq | CompareOp::Ne => ordering == Ordering::Equal,
+        CompareOp::Gt => ordering == Ordering::Greater,
+        CompareOp::Ge => ordering != Ordering::Less,
+        CompareOp::Lt => ordering == Ordering::Less,
+        CompareOp::Le => ordering != Ordering::Greater,
+        CompareOp::Co | CompareOp::Sw | CompareOp::Ew => false,
+    }
+}
+
+fn is_present(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        _ => true,
+    }
+}
+
+impl Filter {
+    /// Whether the resource, in its JSON form, matches the filter.
+    pub fn matches(&self, resource: &Value) -> bool {
+        match self {
+            Filter::And(a, b) => a.matches(resource) && b.matches(resource),
+            Filter::Or(a, b) => a.matches(resource) || b.matches(resource),
+            Filter::Not(f) => !f.matches(resource),
+            Filter::Present(path) => resolve(resource, path).into_iter().any(is_present),
+            Filter::Compare(path, CompareOp::Ne, expected) => !resolve(resource, path)
+                .into_iter()
+                .any(|v| compare(v, CompareOp::Eq, expected)),
+            Filter::Compare(path, operator, expected) => resolve(resource, path)
+                .into_iter()
+                .any(|v| compare(v, *operator, expected)),
+            Filter::ValuePath(path, filter) => resolve(resource, path)
+                .into_iter()
+                .any(|entry| filter.matches(entry)),
+        }
+    }
+}
+
+/// The target of a PATCH operation: an attribute, optionally narrowed to
+/// the entries matching a filter, and optionally one of their
+/// sub-attributes.
+struct PatchPath {
+    attribute: String,
+    filter: Option<Filter>,
+    sub_attribute: Option<String>,
+}
+
+fn parse_path(input: &str) -> Result<PatchPath, ScimProblem> {
+    let invalid = || ScimProblem::InvalidPath(format!("Invalid path {}", input));
+    let tokens = tokenize(input).map_err(|_| invalid())?;
+    let mut parser = Parser::new(tokens);
+    let mut names = match parser.next() {
+        Some(Token::Word(w)) => attribute_path(w)?,
+        _ => return Err(invalid()),
+    };
+    if parser.peek() != Some(&Token::OpenBracket) {
+        if parser.peek().is_some() {
+            return Err(invalid());
+        }
+        let sub_attribute = if names.len() == 2 { names.pop() } else { None };
+        return Ok(PatchPath {
+            attribute: names.remove(0),
+            filter: None,
+            sub_attribute,
+        });
+    }
+    if names.len() != 1 {
+        return Err(invalid());
+    }
+    parser.next();
+    let filter = parser.or()?;
+    parser.expect(Token::CloseBracket)?;
+    let sub_attribute = match parser.next() {
+        None => None,
+        Some(Token::Word(w)) => match w.strip_prefix('.') {
+            Some(name) if !name.is_empty() && !name.contains('.') => Some(name.to_owned()),
+            _ => return Err(invalid()),
+        },
+        Some(_) => return Err(invalid()),
+    };
+    if parser.peek().is_some() {
+        return Err(invalid());
+    }
+    Ok(PatchPath {
+        attribute: names.remove(0),
+        filter: Some(filter),
+        sub_attribute,
+    })
+}
+
+fn same_entry(a: &Value, b: &Value) -> bool {
+    match (get(a, "value"), get(b, "value")) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Adds or replaces the value at `path`. Adding to a multi-valued attribute
+/// appends to it.
+fn add_or_replace(
+    resource: &mut Map<String, Value>,
+    path: &PatchPath,
+    value: Value,
+    add: bool,
+) -> Result<(), ScimProblem> {
+    let filter = match &path.filter {
+        Some(f) => f,
+        None => {
+            match (&path.sub_attribute, get_mut(resource, &path.attribute)) {
+                (Some(sub), Some(Value::Object(parent))) => set(parent, sub, value),
+                (Some(sub), Some(Value::Null) | None) => {
+                    let mut parent = Map::new();
+                    set(&mut parent, sub, value);
+                    set(resource, &path.attribute, Value::Object(parent));
+                }
+                (Some(_), Some(_)) => {
+                    return Err(ScimProblem::InvalidPath(format!(
+                        "{} has no sub-attributes",
+                        path.attribute
+                    )))
+                }
+                (None, Some(Value::Array(existing))) if add => {
+                    let new = match value {
+                        Value::Array(items) => items,
+                        other => vec![other],
+                    };
+                    for entry in new {
+                        if !existing.iter().any(|e| same_entry(e, &entry)) {
+                            existing.push(entry);
+                        }
+                    }
+                }
+                (None, _) => set(resource, &path.attribute, value),
+            }
+            return Ok(());
+        }
+    };
+    if !matches!(get_mut(resource, &path.attribute), Some(Value::Array(_))) {
+        set(resource, &path.attribute, Value::Array(vec![]));
+    }
+    let Some(Value::Array(entries)) = get_mut(resource, &path.attribute) else {
+        unreachable!()
+    };
+    let mut matched = false;
+    for entry in entries.iter_mut().filter(|e| filter.matches(e)) {
+        matched = true;
+        match (&path.sub_attribute, entry) {
+            (Some(sub), Value::Object(entry)) => set(entry, sub, value.clone()),
+            (None, Value::Object(entry)) if add => {
+                if let Value::Object(fields) = &value {
+                    for (k, v) in fields {
+                        set(entry, k, v.clone());
+                    }
+                }
+            }
+            (None, entry) => *entry = value.clone(),
+            (Some(_), _) => {
+                return Err(ScimProblem::InvalidPath(format!(
+                    "{} has no sub-attributes",
+                    path.attribute
+                )))
+            }
+        }
+    }
+    if matched {
+        return Ok(());
+    }
+    // Clients such as Azure AD set `emails[type eq "work"].value` before
+    // the entry exists, so an equality filter creates it.
+    match filter {
+        Filter::Compare(names, CompareOp::Eq, expected) if names.len() == 1 => {
+            let mut entry = Map::new();
+            set(&mut entry, &names[0], expected.clone());
+            match (&path.sub_attribute, value) {
+                (Some(sub), value) => set(&mut entry, sub, value),
+                (None, Value::Object(fields)) => {
+                    for (k, v) in fields {
+                        set(&mut entry, &k, v);
+                    }
+                }
+                (None, _) => {
+                    return Err(ScimProblem::InvalidValue(
+                        "Entries of a multi-valued attribute must be objects".to_owned(),
+                    ))
+                }
+            }
+            entries.push(Value::Object(entry));
+            Ok(())
+        }
+        _ => Err(ScimProblem::NoTarget(format!(
+            "Nothing in {} matches the filter",
+            path.attribute
+        ))),
+    }
+}
+
+fn remove_at(
+    resource: &mut Map<String, Value>,
+    path: &PatchPath,
+    value: Option<&Value>,
+) -> Result<(), ScimProblem> {
+    match (&path.filter, &path.sub_attribute) {
+        (None, None) => match (get_mut(resource, &path.attribute), value) {
+            // Removing listed entries, as Azure AD does for group members.
+            (Some(Value::Array(existing)), Some(Value::Array(removed))) => {
+                existing.retain(|e| !removed.iter().any(|r| same_entry(e, r)));
+            }
+            _ => remove(resource, &path.attribute),
+        },
+        (None, Some(sub)) => match get_mut(resource, &path.attribute) {
+            Some(Value::Object(parent)) => remove(parent, sub),
+            Some(Value::Array(entries)) => {
+                for entry in entries.iter_mut().filter_map(Value::as_object_mut) {
+                    remove(entry, sub);
+                }
+            }
+            _ => (),
+        },
+        (Some(filter), sub) => {
+            if let Some(Value::Array(entries)) = get_mut(resource, &path.attribute) {
+                match sub {
+                    None => entries.retain(|e| !filter.matches(e)),
+                    Some(sub) => {
+                        for entry in entries.iter_mut().filter(|e| filter.matches(e)) {
+                            if let Value::Object(entry) = entry {
+                                remove(entry, sub);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies one PATCH operation to a resource in its JSON form.
+pub fn apply_operation(
+    resource: &mut Value,
+    operation: &ScimPatchOperation,
+) -> Result<(), ScimProblem> {
+    let resource = match resource {
+        Value::Object(r) => r,
+        _ => return Err(ScimProblem::InvalidValue("Not a resource".to_owned())),
+    };
+    let op = operation.op.to_ascii_lowercase();
+    match op.as_str() {
+        "add" | "replace" => {
+            let value = operation
+                .value
+                .clone()
+                .ok_or_else(|| ScimProblem::InvalidValue(format!("{} needs a value", op)))?;
+            match &operation.path {
+                Some(path) => add_or_replace(resource, &parse_path(path)?, value, op == "add"),
+                // Without a path the value holds the attributes to change.
+                None => match value {
+                    Value::Object(fields) => {
+                        for (name, value) in fields {
+                            add_or_replace(resource, &parse_path(&name)?, value, op == "add")?;
+                        }
+                        Ok(())
+                    }
+                    _ => Err(ScimProblem::InvalidValue(format!(
+                        "{} without a path needs an object",
+                        op
+                    ))),
+                },
+            }
+        }
+        "remove" => match &operation.path {
+            Some(path) => remove_at(resource, &parse_path(path)?, operation.value.as_ref()),
+            None => Err(ScimProblem::NoTarget("remove needs a path".to_owned())),
+        },
+        _ => Err(ScimProblem::InvalidValue(format!(
+            "Unsupported operation {}",
+            operation.op
+        ))),
+    }
+}
+
+/// Brings client input into the shape the resource types deserialize:
+/// attribute names in their usual case and booleans as booleans.
+pub fn normalize(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            let fields = std::mem::take(object);
+            for (name, mut field) in fields {
+                let name = canonical_name(&name);
+                if BOOLEAN_ATTRIBUTES.contains(&name.as_str()) {
+                    if let Value::String(s) = &field {
+                        if let Ok(b) = s.to_ascii_lowercase().parse::<bool>() {
+                            field = Value::Bool(b);
+                        }
+                    }
+                }
+                normalize(&mut field);
+                object.insert(name, field);
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(normalize),
+        _ => (),
+    }
+}
+
+/// Applies the `attributes` and `excludedAttributes` parameters to a
+/// resource. Only top-level attributes are selected; `id` is always kept.
+pub fn project(resource: &mut Value, attributes: Option<&str>, excluded: Option<&str>) {
+    let resource = match resource {
+        Value::Object(r) => r,
+        _ => return,
+    };
+    let top_level = |list: &str| -> Vec<String> {
+        list.split(',')
+            .filter_map(|a| attribute_path(a.trim()).ok())
+            .map(|mut names| names.remove(0))
+            .collect()
+    };
+    if let Some(attributes) = attributes.filter(|a| !a.trim().is_empty()) {
+        let keep = top_level(attributes);
+        resource.retain(|k, _| {
+            ["id", "schemas", "meta"].contains(&k.as_str())
+                || keep.iter().any(|a| a.eq_ignore_ascii_case(k))
+        });
+    }
+    if let Some(excluded) = excluded {
+        let drop = top_level(excluded);
+        resource.retain(|k, _| k == "id" || !drop.iter().any(|a| a.eq_ignore_ascii_case(k)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn user() -> Value {
+        json!({
+            "schemas": [SCHEMA_USER],
+            "id": "65f0c0ffee0000000000beef",
+            "userName": "Alice",
+            "name": {"givenName": "Alice", "familyName": "Liddell"},
+            "emails": [
+                {"value": "alice@example.com", "type": "work", "primary": true},
+                {"value": "alice@home.example", "type": "home"}
+            ],
+            "active": true,
+            "loginCount": 7
+        })
+    }
+
+    fn filter(input: &str) -> Filter {
+        parse_filter(input).unwrap_or_else(|e| panic!("{}: {}", input, e))
+    }
+
+    fn operation(op: &str, path: Option<&str>, value: Option<Value>) -> ScimPatchOperation {
+        ScimPatchOperation {
+            op: op.to_owned(),
+            path: path.map(str::to_owned),
+            value,
+        }
+    }
+
+    fn attribute(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| (*n).to_owned()).collect()
+    }
+
+    #[test]
+    fn parses_comparisons_and_logic() {
+        assert_eq!(
+            filter(r#"userName eq "alice""#),
+            Filter::Compare(attribute(&["userName"]), CompareOp::Eq, json!("alice"))
+        );
+        assert_eq!(
+            filter("name.givenName pr"),
+            Filter::Present(attribute(&["name", "givenName"]))
+        );
+        assert_eq!(
+            filter("urn:ietf:params:scim:schemas:core:2.0:User:userName EQ \"a\""),
+            Filter::Compare(attribute(&["userName"]), CompareOp::Eq, json!("a"))
+        );
+        assert_eq!(
+            filter("active eq true and loginCount gt 3"),
+            Filter::And(
+                Box::new(Filter::Compare(
+                    attribute(&["active"]),
+                    CompareOp::Eq,
+                    json!(true)
+                )),
+                Box::new(Filter::Compare(
+                    attribute(&["loginCount"]),
+                    CompareOp::Gt,
+                    json!(3)
+                )),
+            )
+        );
+        // `and` binds tighter than `or`.
+        assert!(matches!(
+            filter("id pr or id pr and id pr"),
+            Filter::Or(_, right) if matches!(*right, Filter::And(_, _))
+        ));
+        assert!(matches!(filter("not (id pr)"), Filter::Not(_)));
+        assert!(matches!(
+            filter(r#"emails[type eq "work"]"#),
+            Filter::ValuePath(path, _) if path == attribute(&["emails"])
+        ));
+        assert_eq!(
+            filter(r#"userName eq "quote \" here""#),
+            Filter::Compare(
+                attribute(&["userName"]),
+                CompareOp::Eq,
+                json!("quote \" here")
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        for input in [
+            "",
+            "userName",
+            r#"userName eq "alice"#,
+            r#"userName is "alice""#,
+            "userName eq alice",
+            r#"(userName eq "alice""#,
+            r#"userName eq "alice")"#,
+            r#"emails[type eq "work""#,
+            "a.b.c pr",
+            "not id pr",
+            "id pr and",
+        ] {
+            assert!(
+                matches!(parse_filter(input), Err(ScimProblem::InvalidFilter(_))),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn limits_filter_nesting() {
+        let nested = |depth: usize| format!("{}id pr{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse_filter(&nested(MAX_FILTER_DEPTH)).is_ok());
+        assert!(matches!(
+            parse_filter(&nested(MAX_FILTER_DEPTH + 1)),
+            Err(ScimProblem::InvalidFilter(_))
+        ));
+        let negated = format!("{}id pr{}", "not (".repeat(100_000), ")".repeat(100_000));
+        assert!(matches!(
+            parse_filter(&negated),
+            Err(ScimProblem::InvalidFilter(_))
+        ));
+        let brackets = format!("{}id pr{}", "emails[".repeat(100), "]".repeat(100));
+        assert!(matches!(
+            parse_filter(&brackets),
+            Err(ScimProblem::InvalidFilter(_))
+        ));
+    }
+
+    #[test]
+    fn limits_filter_terms() {
+        let chain = |terms: usize| vec!["id pr"; terms].join(" and ");
+        assert!(parse_filter(&chain(MAX_FILTER_TERMS)).is_ok());
+        assert!(matches!(
+            parse_filter(&chain(MAX_FILTER_TERMS + 1)),
+            Err(ScimProblem::InvalidFilter(_))
+        ));
+    }
+
+    #[test]
+    fn matches_each_operator() {
+        let user = user();
+        let cases = [
+            (r#"userName eq "alice""#, true),
+            (r#"userName eq "bob""#, false),
+            (r#"userName ne "bob""#, true),
+            (r#"userName ne "ALICE""#, false),
+            (r#"userName co "lic""#, true),
+            (r#"userName co "bob""#, false),
+            (r#"userName sw "al""#, true),
+            (r#"userName sw "ce""#, false),
+            (r#"userName ew "CE""#, true),
+            (r#"userName ew "al""#, false),
+            (r#"userName gt "aaa""#, true),
+            (r#"userName gt "alice""#, false),
+            (r#"userName ge "alice""#, true),
+            (r#"userName lt "b""#, true),
+            (r#"userName lt "alice""#, false),
+            (r#"userName le "alice""#, true),
+            ("loginCount gt 6", true),
+            ("loginCount ge 8", false),
+            ("loginCount lt 7.5", true),
+            ("loginCount le 6", false),
+            ("active eq true", true),
+            ("active ne true", false),
+            ("active gt false", false),
+            ("name.givenName pr", true),
+            ("name.middleName pr", false),
+            ("title pr", false),
+            (r#"emails co "home.example""#, true),
+            (r#"emails.value eq "alice@example.com""#, true),
+            (r#"emails.type eq "other""#, false),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(filter(input).matches(&user), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn matches_logic_and_value_paths() {
+        let user = user();
+        let cases = [
+            (r#"userName eq "alice" and active eq true"#, true),
+            (r#"userName eq "alice" and active eq false"#, false),
+            (r#"userName eq "bob" or active eq true"#, true),
+            (r#"userName eq "bob" or active eq false"#, false),
+            (r#"not (userName eq "bob")"#, true),
+            (r#"not (userName eq "alice")"#, false),
+            (r#"emails[type eq "work" and value co "example.com"]"#, true),
+            // Both conditions must hold for the same entry.
+            (
+                r#"emails[type eq "home" and value co "example.com"]"#,
+                false,
+            ),
+            (r#"emails[primary eq true]"#, true),
+            (
+                r#"(userName eq "bob" or userName eq "alice") and emails pr"#,
+                true,
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(filter(input).matches(&user), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn adds_and_replaces_attributes() {
+        let mut user = user();
+        apply_operation(
+            &mut user,
+            &operation("replace", Some("name.familyName"), Some(json!("Pleasance"))),
+        )
+        .unwrap();
+        apply_operation(
+            &mut user,
+            &operation(
+                "Replace",
+                None,
+                Some(json!({"active": false, "title": "Ms"})),
+            ),
+        )
+        .unwrap();
+        apply_operation(
+            &mut user,
+            &operation(
+                "add",
+                Some("emails"),
+                Some(json!([
+                    {"value": "alice@example.com", "type": "work"},
+                    {"value": "a@other.example", "type": "other"}
+                ])),
+            ),
+        )
+        .unwrap();
+        assert_eq!(user["name"]["familyName"], "Pleasance");
+        assert_eq!(user["name"]["givenName"], "Alice");
+        assert_eq!(user["active"], false);
+        assert_eq!(user["title"], "Ms");
+        // Adding appends, skipping entries that are already there.
+        assert_eq!(user["emails"].as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn changes_entries_through_value_paths() {
+        let mut user = user();
+        apply_operation(
+            &mut user,
+            &operation(
+                "replace",
+                Some(r#"emails[type eq "work"].value"#),
+                Some(json!("alice@work.example")),
+            ),
+        )
+        .unwrap();
+        assert_eq!(user["emails"][0]["value"], "alice@work.example");
+        assert_eq!(user["emails"][1]["value"], "alice@home.example");
+
+        apply_operation(
+            &mut user,
+            &operation(
+                "add",
+                Some(r#"emails[type eq "home"]"#),
+                Some(json!({"primary": false})),
+            ),
+        )
+        .unwrap();
+        assert_eq!(user["emails"][1]["primary"], false);
+        assert_eq!(user["emails"][1]["value"], "alice@home.example");
+    }
+
+    #[test]
+    fn creates_a_missing_entry_for_an_equality_value_path() {
+        let mut user = json!({"userName": "alice"});
+        apply_operation(
+            &mut user,
+            &operation(
+                "replace",
+                Some(r#"emails[type eq "work"].value"#),
+                Some(json!("alice@example.com")),
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            user["emails"],
+            json!([{"type": "work", "value": "alice@example.com"}])
+        );
+    }
+
+    #[test]
+    fn removes_attributes_and_entries() {
+        let mut user = user();
+        apply_operation(
+            &mut user,
+            &operation("remove", Some("name.givenName"), None),
+        )
+        .unwrap();
+        apply_operation(
+            &mut user,
+            &operation("remove", Some(r#"emails[type eq "home"]"#), None),
+        )
+        .unwrap();
+        apply_operation(
+            &mut user,
+            &operation("remove", Some(r#"emails[type eq "work"].primary"#), None),
+        )
+        .unwrap();
+        apply_operation(&mut user, &operation("remove", Some("ACTIVE"), None)).unwrap();
+        assert_eq!(user["name"], json!({"familyName": "Liddell"}));
+        assert_eq!(
+            user["emails"],
+            json!([{"value": "alice@example.com", "type": "work"}])
+        );
+        assert!(user.get("active").is_none());
+    }
+
+    #[test]
+    fn removes_listed_members() {
+        let mut group = json!({
+            "displayName": "staff",
+            "members": [{"value": "a"}, {"value": "b"}, {"value": "c"}]
+        });
+        apply_operation(
+            &mut group,
+            &operation(
+                "remove",
+                Some("members"),
+                Some(json!([{"value": "a"}, {"value": "c"}])),
+            ),
+        )
+        .unwrap();
+        assert_eq!(group["members"], json!([{"value": "b"}]));
+    }
+
+    #[test]
+    fn rejects_unusable_operations() {
+        let cases = [
+            (operation("replace", Some("userName"), None), "invalidValue"),
+            (operation("add", None, Some(json!("alice"))), "invalidValue"),
+            (
+                operation("copy", Some("userName"), Some(json!("x"))),
+                "invalidValue",
+            ),
+            (operation("remove", None, None), "noTarget"),
+            (operation("remove", Some("a.b.c"), None), "invalidPath"),
+            (operation("remove", Some("emails]"), None), "invalidPath"),
+            (
+                operation(
+                    "replace",
+                    Some(r#"emails[type eq "work"].a.b"#),
+                    Some(json!(1)),
+                ),
+                "invalidPath",
+            ),
+            (
+                operation("replace", Some("userName.first"), Some(json!("x"))),
+                "invalidPath",
+            ),
+            (
+                operation(
+                    "replace",
+                    Some(r#"emails[value co "zzz"]"#),
+                    Some(json!({})),
+                ),
+                "noTarget",
+            ),
+            (
+                operation("add", Some(r#"emails[type eq "other"]"#), Some(json!("x"))),
+                "invalidValue",
+            ),
+        ];
+        for (operation, scim_type) in cases {
+            let result = apply_operation(&mut user(), &operation);
+            assert_eq!(
+                result.map_err(|p| p.scim_type()),
+                Err(scim_type),
+                "{} {:?}",
+                operation.op,
+                operation.path
+            );
+        }
+        assert!(matches!(
+            apply_operation(&mut json!([]), &operation("remove", Some("id"), None)),
+            Err(ScimProblem::InvalidValue(_))
+        ));
+    }
+}
@@ -1,64 +1,592 @@
 use askama::Template;
 use bson;
+use jsonwebtoken::jwk::JwkSet;
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc};
 
-use crate::db::Database;
+use crate::{captcha::Captcha, db::Database, mailer::Mailer};
 
 #[derive(Serialize, Deserialize)]
 pub struct AdminCreateUserRequest {
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub email: String,
+    pub is_admin: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminCallbackQuery {
+    pub code: String,
+    #[serde(default)]
+    pub state: String,
+}
+
+/// Administrator making the request, set by `admin_guard::RequireAdmin`.
+#[derive(Clone)]
+pub struct AdminIdentity {
+    pub id: bson::oid::ObjectId,
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminUsersQuery {
+    /// Matched against usernames and email addresses.
+    #[serde(default)]
+    pub q: String,
+    /// One-based page number.
+    pub page: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminSetPasswordRequest {
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminUpdateApplicationRequest {
+    pub name: String,
+    /// One per line or separated by commas.
+    #[serde(default)]
+    pub redirect_uris: String,
+    #[serde(default)]
+    pub logo_uri: String,
+    /// Space-separated.
+    #[serde(default)]
+    pub grant_types: String,
+    /// Space-separated. Any scope when empty.
+    #[serde(default)]
+    pub allowed_scopes: String,
+    /// Seconds, or empty for the default.
+    #[serde(default)]
+    pub access_token_lifetime: String,
+    #[serde(default)]
+    pub id_token_lifetime: String,
+    #[serde(default)]
+    pub jwks: String,
+    pub require_verified_email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminExpireSecretRequest {
+    /// How long the secret keeps working. It stops at once when 0.
+    #[serde(default)]
+    pub grace_period_hours: u64,
+}
+
+#[derive(Deserialize)]
+pub struct AdminAddSecretRequest {
+    #[serde(default)]
+    pub label: String,
+    /// Days until the secret expires, or empty for never.
+    #[serde(default)]
+    pub expires_in_days: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin_applications.html")]
+pub struct AdminApplicationsTemplate {
+    pub applications: Vec<DbApplication>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_application.html")]
+pub struct AdminApplicationTemplate {
+    pub csrf_token: String,
+    pub app_id: String,
+    pub application: DbApplication,
+    pub redirect_uris: String,
+    pub jwks: String,
+    pub session_count: u64,
+    pub secrets: Vec<AdminClientSecret>,
+    pub roles: Vec<DbRole>,
+    pub allowed_users: Vec<AdminMember>,
+    /// Groups allowed to log in, and the ones that could be added.
+    pub allowed_groups: Vec<AdminMember>,
+    pub other_groups: Vec<AdminMember>,
+    pub error: Option<String>,
+}
+
+/// A client secret as shown in the admin panel.
+pub struct AdminClientSecret {
+    pub id: String,
+    pub label: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub expired: bool,
+}
+
+/// A user's session with an application, as shown in the admin panel.
+pub struct AdminUserSession {
+    pub app_name: String,
+    pub scopes: String,
+    pub amr: String,
+    pub auth_time: u64,
+}
+
+#[derive(Template)]
+#[template(path = "admin_users.html")]
+pub struct AdminUsersTemplate {
+    pub query: String,
+    pub users: Vec<DbUser>,
+    pub total: u64,
+    pub page: u64,
+    pub has_next: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_user.html")]
+pub struct AdminUserTemplate {
+    pub csrf_token: String,
+    pub user_id: String,
+    pub user: DbUser,
+    /// Custom attributes as JSON, for editing.
+    pub custom_attributes: String,
+    pub sessions: Vec<AdminUserSession>,
+    pub granted_apps: Vec<String>,
+    pub passkey_count: usize,
+}
+
+/// Fields left out of the form keep their stored value; fields submitted
+/// empty are cleared.
+#[derive(Serialize, Deserialize)]
+pub struct AdminUpdateUserProfileRequest {
+    pub username: String,
+    pub name: Option<String>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub email: Option<String>,
+    /// Checkbox value, present only when checked.
+    pub email_verified: Option<String>,
+    pub picture: Option<String>,
+    pub locale: Option<String>,
+    pub zoneinfo: Option<String>,
+    /// JSON object of custom attributes.
+    pub custom_attributes: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct AdminCreateApplicationRequest {
     pub app_name: String,
     pub redirect_uris: String,
+    #[serde(default)]
+    pub jwks: String,
+    /// Checkbox value, present only when checked.
+    pub require_verified_email: Option<String>,
+}
+
+/// Form fields a client authenticates with, when not using HTTP Basic.
+#[derive(Serialize, Deserialize)]
+pub struct ClientCredentials {
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TokenRequest {
     pub grant_type: String,
+    /// Only used by the `authorization_code` grant.
+    #[serde(default)]
     pub code: String,
+    #[serde(default)]
     pub redirect_uri: String,
+    /// Space-separated scopes wanted by the `client_credentials` grant.
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(flatten)]
+    pub client: ClientCredentials,
+}
+
+/// Pushed authorization request (RFC 9126). The authorization parameters
+/// are those of `/auth`; the client is the one authenticating.
+#[derive(Serialize, Deserialize)]
+pub struct PushedAuthorizationRequest {
+    #[serde(flatten)]
+    pub client: ClientCredentials,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    pub acr_values: Option<String>,
+    pub state: Option<String>,
+    pub request: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PushedAuthorizationResponse {
+    pub request_uri: String,
+    pub expires_in: u64,
 }
 
 #[derive(Serialize)]
 pub struct TokenResponse {
     pub access_token: String,
     pub token_type: String,
-    pub id_token: String,
+    /// Not issued for the `client_credentials` grant, which has no user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
     pub expires_in: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
 }
 
+/// Parameters of an authorization request, as sent to `/auth` or pushed.
 #[derive(Serialize, Deserialize)]
-pub struct AuthRequest {
-    pub redirect_uri: String,
+pub struct AuthorizationParameters {
+    #[serde(default)]
     pub client_id: String,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+    /// Space-separated authentication context classes the client wants.
+    pub acr_values: Option<String>,
+    /// Opaque value returned to the client with the code.
+    pub state: Option<String>,
+    /// Signed request object (RFC 9101) overriding the plain parameters.
+    pub request: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthRequest {
+    #[serde(flatten)]
+    pub parameters: AuthorizationParameters,
+    /// Reference to a pushed authorization request (RFC 9126).
+    pub request_uri: Option<String>,
+    /// ID of the stored authorization request the login page is retrying.
+    pub authorization: Option<String>,
+    /// Set by `/login` when sending the user back to the login page.
+    pub invalid_creds: Option<String>,
+    pub invalid_config: Option<String>,
+    pub email_unverified: Option<String>,
+    pub mfa_required: Option<String>,
+    pub locked: Option<String>,
+    pub disabled: Option<String>,
+    pub no_access: Option<String>,
+    /// `login` forces the user to log in again even with a browser session.
+    pub prompt: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
-    pub redirect_uri: String,
-    pub client_id: String,
+    /// ID of the stored authorization request being logged in to.
+    pub authorization: String,
 }
 
 #[derive(Template)]
 #[template(path = "auth.html")]
 pub struct AuthTemplate {
-    pub redirect_uri: String,
+    pub csrf_token: String,
+    pub authorization: String,
+    pub error: Option<&'static str>,
+    pub signup_enabled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswordResetRequest {
+    /// Username or email address of the account.
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswordResetQuery {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasswordResetConfirmRequest {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Template)]
+#[template(path = "reset_request.html")]
+pub struct ResetRequestTemplate {
+    pub csrf_token: String,
+    pub sent: bool,
+}
+
+#[derive(Template)]
+#[template(path = "reset_confirm.html")]
+pub struct ResetConfirmTemplate {
+    pub csrf_token: String,
+    pub token: String,
+    pub message: Option<String>,
+    pub password_errors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VerifyEmailQuery {
+    pub token: String,
+}
+
+#[derive(Template)]
+#[template(path = "message.html")]
+pub struct MessageTemplate {
+    pub title: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignupRequest {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+    #[serde(default)]
+    pub invite_code: String,
+    #[serde(default)]
+    pub captcha_response: String,
+}
+
+#[derive(Template)]
+#[template(path = "signup.html")]
+pub struct SignupTemplate {
+    pub csrf_token: String,
+    pub invite_only: bool,
+    pub captcha_widget: Option<String>,
+    pub error: Option<String>,
+    pub password_errors: Vec<String>,
+}
+
+/// Validated parameters of the authorization request a login is for.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LoginAuthorization {
+    /// ID of the stored request the login page was shown for.
+    #[serde(default)]
+    pub request_id: String,
     pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    #[serde(default)]
+    pub acr_values: String,
+    #[serde(default)]
+    pub state: String,
+}
+
+/// An authorization request checked by `/auth` or pushed by a client, kept
+/// on the server so the login page only has to carry its ID.
+#[derive(Serialize, Deserialize)]
+pub struct DbAuthorizationRequest {
+    #[serde(rename = "_id")]
+    pub id: String,
+    /// Pushed requests are used once, through `request_uri`.
+    pub pushed: bool,
+    pub authorization: LoginAuthorization,
+    pub expires_at: bson::DateTime,
+}
+
+/// How and when a user authenticated, as reported in ID tokens.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct AuthenticationContext {
+    /// Authentication method references (RFC 8176), e.g. `pwd`, `otp`.
+    pub amr: Vec<String>,
+    pub auth_time: u64,
+}
+
+/// A login that passed the password check and is waiting for a second
+/// factor. Only its random ID is kept in the browser session, so the
+/// attempt count cannot be reset by replaying an older cookie.
+#[derive(Serialize, Deserialize)]
+pub struct DbPendingLogin {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub user_id: bson::oid::ObjectId,
+    #[serde(default)]
+    pub failed_attempts: u32,
+    /// Absent when logging in to the account page rather than an app.
+    pub authorization: Option<LoginAuthorization>,
+    pub expires_at: bson::DateTime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SecondFactorRequest {
+    #[serde(default)]
+    pub code: String,
+    #[serde(default)]
+    pub recovery_code: String,
+}
+
+/// WebAuthn challenge waiting for the browser's response. The browser
+/// session only holds its ID, and it is deleted when answered, so replaying
+/// an older cookie cannot answer the same challenge twice.
+#[derive(Serialize, Deserialize)]
+pub struct DbWebauthnChallenge {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub ceremony: String,
+    pub challenge: String,
+    pub expires_at: bson::DateTime,
+    /// Application a passwordless login was started for.
+    #[serde(default)]
+    pub authorization: Option<LoginAuthorization>,
+}
+
+/// Starts a passkey login. `authorization` is empty when not logging in to
+/// an application.
+#[derive(Serialize, Deserialize)]
+pub struct PasskeyOptionsRequest {
+    #[serde(default)]
+    pub authorization: String,
+}
+
+/// Assertion from `navigator.credentials.get`, fields base64url encoded.
+#[derive(Serialize, Deserialize)]
+pub struct PasskeyLoginRequest {
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+/// Attestation from `navigator.credentials.create`, fields base64url
+/// encoded.
+#[derive(Serialize, Deserialize)]
+pub struct PasskeyRegisterRequest {
+    pub client_data_json: String,
+    pub attestation_object: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AccountLoginRequest {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TotpConfirmRequest {
+    pub code: String,
+}
+
+#[derive(Template)]
+#[template(path = "second_factor.html")]
+pub struct SecondFactorTemplate {
+    pub csrf_token: String,
+    pub totp_enabled: bool,
+    pub passkey_enabled: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "totp_enroll.html")]
+pub struct TotpEnrollTemplate {
+    pub csrf_token: String,
+    pub otpauth_uri: String,
+    pub secret: String,
+    pub qr_svg: String,
+    pub error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "account.html")]
+pub struct AccountTemplate {
+    pub csrf_token: String,
+    pub username: Option<String>,
+    pub totp_enabled: bool,
+    pub passkey_names: Vec<String>,
+    pub error: Option<String>,
 }
 
 #[derive(Template)]
 #[template(path = "admin.html")]
-pub struct AdminPanelTemplate {}
+pub struct AdminPanelTemplate {
+    pub csrf_token: String,
+    pub locked_logins: Vec<DbLoginThrottle>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminUnlockRequest {
+    pub key: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin_groups.html")]
+pub struct AdminGroupsTemplate {
+    pub csrf_token: String,
+    pub groups: Vec<DbGroup>,
+    pub error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_group.html")]
+pub struct AdminGroupTemplate {
+    pub csrf_token: String,
+    pub group_id: String,
+    pub group: DbGroup,
+    pub members: Vec<AdminMember>,
+    pub error: Option<String>,
+}
+
+/// A user or group listed on a group or role page.
+pub struct AdminMember {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin_role.html")]
+pub struct AdminRoleTemplate {
+    pub csrf_token: String,
+    pub app_id: String,
+    pub app_name: String,
+    pub role_id: String,
+    pub role: DbRole,
+    pub defined_permissions: Vec<DbPermission>,
+    pub users: Vec<AdminMember>,
+    /// Groups holding the role, and the ones that could be added.
+    pub groups: Vec<AdminMember>,
+    pub other_groups: Vec<AdminMember>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminGroupRequest {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminMemberRequest {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminPermissionRequest {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminRoleRequest {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminUpdateRoleRequest {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// Permission names separated by spaces.
+    #[serde(default)]
+    pub permissions: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminAddGroupRequest {
+    pub group_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminAccessRequest {
+    pub restrict_access: Option<String>,
+}
 
 pub struct AppState {
     pub database: Database,
     pub config: Config,
+    pub mailer: Arc<dyn Mailer>,
+    pub captcha: Arc<dyn Captcha>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -67,6 +595,99 @@ pub struct DbUser {
     pub id: Option<bson::oid::ObjectId>,
     pub username: String,
     pub password_hash: String,
+    #[serde(flatten)]
+    pub profile: UserProfile,
+    /// Self-registered users cannot log in until their email is verified.
+    #[serde(default)]
+    pub must_verify_email: bool,
+    #[serde(default)]
+    pub totp: Option<DbTotp>,
+    /// May sign in to the admin panel.
+    #[serde(default)]
+    pub is_admin: bool,
+    /// Disabled users cannot log in.
+    #[serde(default)]
+    pub disabled: bool,
+    /// Browser sessions from logins before this time (seconds since the
+    /// epoch) are no longer accepted. A login in the same second is kept, so
+    /// the user can sign straight back in after a reset.
+    #[serde(default)]
+    pub sessions_valid_after: u64,
+    /// Identifier given to the user by the SCIM client that provisioned it.
+    #[serde(default)]
+    pub external_id: Option<String>,
+}
+
+/// A named set of users.
+#[derive(Serialize, Deserialize)]
+pub struct DbGroup {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<bson::oid::ObjectId>,
+    /// Unique across groups.
+    pub name: String,
+    #[serde(default)]
+    pub external_id: Option<String>,
+    #[serde(default)]
+    pub members: Vec<bson::oid::ObjectId>,
+    /// Seconds since the epoch.
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// TOTP second factor for a user. The secret is encrypted with the MFA
+/// encryption key; recovery codes are stored hashed and removed once used.
+#[derive(Serialize, Deserialize)]
+pub struct DbTotp {
+    pub encrypted_secret: String,
+    /// Enrollment is finished once the user has entered a valid code.
+    pub confirmed: bool,
+    /// Last time step accepted, so a code cannot be used twice.
+    #[serde(default)]
+    pub last_used_step: u64,
+    #[serde(default)]
+    pub recovery_code_hashes: Vec<String>,
+}
+
+/// A passkey registered to a user.
+#[derive(Serialize, Deserialize)]
+pub struct DbWebauthnCredential {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<bson::oid::ObjectId>,
+    pub user_id: bson::oid::ObjectId,
+    /// Base64url credential ID chosen by the authenticator.
+    pub credential_id: String,
+    /// Base64url SEC1 encoded P-256 public key.
+    pub public_key: String,
+    pub sign_count: u32,
+    pub name: String,
+    pub created_at: u64,
+}
+
+/// Standard OpenID Connect claims describing a user, plus custom
+/// attributes. Released to applications according to granted scopes.
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct UserProfile {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub given_name: Option<String>,
+    #[serde(default)]
+    pub family_name: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub picture: Option<String>,
+    #[serde(default)]
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub zoneinfo: Option<String>,
+    /// Seconds since the epoch when the profile was last changed.
+    #[serde(default)]
+    pub updated_at: Option<u64>,
+    #[serde(default)]
+    pub custom_attributes: BTreeMap<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -74,36 +695,790 @@ pub struct DbApplicationGrant {
     pub client_id: bson::oid::ObjectId,
     pub code: String,
     pub user_id: bson::oid::ObjectId,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub authentication: AuthenticationContext,
+    /// Where the code was sent. `/token` must be given the same URI.
+    pub redirect_uri: String,
+    pub expires_at: bson::DateTime,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DbClientSecret {
+    /// Identifies the secret in the admin panel. Not secret itself.
+    pub id: String,
+    pub label: String,
+    /// `password::hash_token` of the secret.
+    pub hash: String,
+    /// The secret itself, kept only for apps that authenticate with
+    /// `client_secret_jwt` since verifying an HMAC needs the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    pub created_at: u64,
+    /// Seconds since the epoch. Never expires when unset.
+    #[serde(default)]
+    pub expires_at: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DbApplication {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<bson::oid::ObjectId>,
+    /// Public identifier clients send as `client_id`. Unique across apps.
+    pub client_id: String,
     pub name: String,
-    pub secret: String,
+    /// Client secrets that are currently or were recently accepted.
+    #[serde(default)]
+    pub secrets: Vec<DbClientSecret>,
     pub redirect_uris: Vec<String>,
+    /// Public keys used to verify JWTs signed by the client.
+    #[serde(default)]
+    pub jwks: Option<JwkSet>,
+    #[serde(default)]
+    pub logo_uri: Option<String>,
+    #[serde(default)]
+    pub grant_types: Vec<String>,
+    /// Authentication method the client must use at `/token`. Any supported
+    /// method is accepted when unset.
+    #[serde(default)]
+    pub token_endpoint_auth_method: Option<String>,
+    /// Algorithm the client signs request objects with. Any algorithm its
+    /// keys support is accepted when unset.
+    #[serde(default)]
+    pub request_object_signing_alg: Option<String>,
+    /// Hash of the token that manages a dynamically registered client.
+    #[serde(default)]
+    pub registration_access_token_hash: Option<String>,
+    #[serde(default)]
+    pub client_id_issued_at: Option<u64>,
+    /// Users must have a verified email address to log in to this app.
+    #[serde(default)]
+    pub require_verified_email: bool,
+    /// Scopes the app may be granted. Any scope when empty.
+    #[serde(default)]
+    pub allowed_scopes: Vec<String>,
+    /// Access token lifetime in seconds, or the server default when unset.
+    #[serde(default)]
+    pub access_token_lifetime: Option<u64>,
+    /// ID token lifetime in seconds, or the server default when unset.
+    #[serde(default)]
+    pub id_token_lifetime: Option<u64>,
+    /// Permissions roles in this app can grant.
+    #[serde(default)]
+    pub permissions: Vec<DbPermission>,
+    /// Only `allowed_users` and members of `allowed_groups` may log in.
+    #[serde(default)]
+    pub restrict_access: bool,
+    #[serde(default)]
+    pub allowed_users: Vec<bson::oid::ObjectId>,
+    #[serde(default)]
+    pub allowed_groups: Vec<bson::oid::ObjectId>,
+}
+
+/// A permission an application defines, such as `documents:write`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DbPermission {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// A named set of an application's permissions, held by users directly or
+/// through their groups.
+#[derive(Serialize, Deserialize)]
+pub struct DbRole {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<bson::oid::ObjectId>,
+    pub application_id: bson::oid::ObjectId,
+    /// Unique within the application.
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<bson::oid::ObjectId>,
+    #[serde(default)]
+    pub groups: Vec<bson::oid::ObjectId>,
+}
+
+/// What a user holds in one application, by name.
+pub struct UserAuthorization {
+    pub groups: Vec<String>,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyEffect {
+    Allow,
+    Deny,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyOperator {
+    Equals,
+    NotEquals,
+    In,
+    NotIn,
+    Contains,
+    StartsWith,
+    GreaterThan,
+    GreaterThanOrEqual,
+    LessThan,
+    LessThanOrEqual,
+    Exists,
+    NotExists,
+}
+
+/// Compares the attribute at a dotted path such as `resource.owner` with a
+/// literal `value`, or with the attribute at another path in `value_from`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PolicyCondition {
+    pub attribute: String,
+    pub operator: PolicyOperator,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_from: Option<String>,
+}
+
+/// Applies to a request when the action and resource match one of the
+/// patterns, the subject holds one of `roles` and one of `permissions`
+/// (when given), and every condition holds. In patterns, `*` matches
+/// anything and a trailing `*` matches any suffix.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PolicyRule {
+    pub name: String,
+    pub effect: PolicyEffect,
+    pub actions: Vec<String>,
+    pub resources: Vec<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub conditions: Vec<PolicyCondition>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DbPolicyRule {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<bson::oid::ObjectId>,
+    pub application_id: bson::oid::ObjectId,
+    #[serde(flatten)]
+    pub rule: PolicyRule,
+}
+
+/// The outcome of evaluating a request, with the index of the rule that
+/// decided it. Requests no rule matches are denied.
+#[derive(PartialEq, Eq, Debug)]
+pub struct PolicyDecision {
+    pub allowed: bool,
+    pub rule: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DbSession {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<bson::oid::ObjectId>,
     pub user_id: bson::oid::ObjectId,
     pub client_id: bson::oid::ObjectId,
     pub expires: u64,
+    /// When the access token stops working, in seconds since the epoch.
+    /// Sessions from before lifetimes were enforced have 0 and do not
+    /// expire.
+    #[serde(default)]
+    pub expires_at: u64,
     pub session_key: String,
     pub id_token: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub authentication: AuthenticationContext,
+}
+
+/// An access token issued to an application itself through the
+/// `client_credentials` grant. Only the hash of the token is stored.
+#[derive(Serialize, Deserialize)]
+pub struct DbServiceToken {
+    pub token_hash: String,
+    pub client_id: bson::oid::ObjectId,
+    pub scopes: Vec<String>,
+    pub expires_at: bson::DateTime,
+}
+
+/// A single-use password reset token. Only the hash of the token is stored.
+#[derive(Serialize, Deserialize)]
+pub struct DbPasswordReset {
+    pub user_id: bson::oid::ObjectId,
+    pub token_hash: String,
+    pub expires_at: bson::DateTime,
+}
+
+/// A pending verification of `email` for a user. Only the hash of the token
+/// sent to the address is stored.
+#[derive(Serialize, Deserialize)]
+pub struct DbEmailVerification {
+    pub user_id: bson::oid::ObjectId,
+    pub email: String,
+    pub token_hash: String,
+    pub expires_at: bson::DateTime,
+}
+
+/// Failed login attempts for one username or client IP. Removed by a TTL
+/// index once `expires_at` passes without further failures.
+#[derive(Serialize, Deserialize)]
+pub struct DbLoginThrottle {
+    /// `username:<name>` or `ip:<address>`.
+    pub key: String,
+    pub failures: u32,
+    pub last_failure_at: u64,
+    #[serde(default)]
+    pub locked_until: Option<u64>,
+    pub expires_at: bson::DateTime,
+}
+
+/// Shared token bucket for the Mongo rate limit backend.
+#[derive(Serialize, Deserialize)]
+pub struct DbRateLimitBucket {
+    pub key: String,
+    pub tokens: f64,
+    /// Milliseconds since the Unix epoch when `tokens` was last refilled.
+    pub updated_at: i64,
+    /// Whether the last request took a token.
+    pub allowed: bool,
+    pub expires_at: bson::DateTime,
+}
+
+/// Security relevant event kept for administrators.
+#[derive(Serialize, Deserialize)]
+pub struct DbAuditEvent {
+    pub at: bson::DateTime,
+    pub event: String,
+    /// What the event is about, e.g. a throttle key or username.
+    pub subject: String,
+    pub detail: String,
+}
+
+/// A single-use sign-up invite. Only the hash of the code is stored.
+#[derive(Serialize, Deserialize)]
+pub struct DbInvite {
+    pub code_hash: String,
+    pub created_at: bson::DateTime,
+}
+
+/// A client assertion `jti` seen at the token endpoint, kept until the
+/// assertion expires so it cannot be replayed.
+#[derive(Serialize, Deserialize)]
+pub struct DbClientAssertionJti {
+    pub client_id: String,
+    pub jti: String,
+    pub expires_at: bson::DateTime,
+}
+
+/// Request attribute a rate limit bucket is keyed on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateLimitKey {
+    Ip,
+    /// The client ID, per client address. It is sent before the client has
+    /// authenticated, so anyone could otherwise empty another client's
+    /// bucket.
+    ClientId,
+    /// The submitted username, per client address, for the same reason.
+    /// Guessing one account's password from many addresses is left to the
+    /// login lockout.
+    Username,
 }
 
+/// Token bucket limit for one route. Each key gets its own bucket holding
+/// up to `capacity` requests, refilled evenly over `period_seconds`.
 #[derive(Clone)]
+pub struct RateLimitRule {
+    /// HTTP method, or `*` for any.
+    pub method: String,
+    /// Request path. A trailing `*` covers every path starting with the
+    /// rest, all sharing one bucket per key.
+    pub path: String,
+    pub capacity: u32,
+    pub period_seconds: u64,
+    pub keys: Vec<RateLimitKey>,
+}
+
+#[derive(Clone, Default)]
 pub struct Config {
     pub mongodb_uri: String,
     pub jwt_secret: String,
     pub listen_address: String,
     pub admin_panel_enabled: bool,
+    /// Initial access token required by `/register`. Registration is
+    /// disabled when unset.
+    pub registration_initial_access_token: Option<String>,
+    /// Scopes dynamically registered clients may be granted.
+    pub registration_allowed_scopes: Vec<String>,
+    /// SMTP relay for outgoing mail. Mail is written to `mail_file`
+    /// instead when unset.
+    pub smtp_host: Option<String>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub mail_from: String,
+    pub mail_file: String,
+    /// Whether the public `/signup` page is available.
+    pub signup_enabled: bool,
+    /// Sign-ups need a single-use invite code created in the admin panel.
+    pub signup_invite_only: bool,
+    /// Lowercase email domains allowed to sign up. Any domain when empty.
+    pub signup_allowed_email_domains: Vec<String>,
+    pub signup_username_min_length: usize,
+    pub signup_username_max_length: usize,
+    /// Characters allowed in usernames besides ASCII letters and digits.
+    pub signup_username_extra_chars: String,
+    /// Minimum length of new passwords, in characters.
+    pub password_min_length: usize,
+    /// Minimum zxcvbn score (0-4) of new passwords.
+    pub password_min_strength: u8,
+    /// Directory of Have I Been Pwned SHA-1 range files new passwords are
+    /// checked against. No breach check when unset.
+    pub password_breach_dataset: Option<String>,
+    /// Failed logins allowed before each further attempt has to wait twice
+    /// as long as the last.
+    pub login_backoff_free_attempts: u32,
+    /// Failed logins for one username before it is locked.
+    pub login_lockout_username_threshold: u32,
+    /// Failed logins from one IP before it is locked.
+    pub login_lockout_ip_threshold: u32,
+    /// How long lockouts last, and how long failures are remembered.
+    pub login_lockout_seconds: u64,
+    /// `memory` for a single instance, or `mongo` to share buckets between
+    /// instances.
+    pub rate_limit_backend: String,
+    pub rate_limits: Vec<RateLimitRule>,
+    /// Key used to encrypt TOTP secrets at rest.
+    pub mfa_encryption_key: String,
+    /// Only send the browser session cookie over HTTPS.
+    pub secure_cookies: bool,
+    /// Reverse proxies whose `X-Forwarded-For` is trusted for the client
+    /// address. The connecting address is used as is when empty.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// WebAuthn relying party ID, the domain passkeys are bound to.
+    pub webauthn_rp_id: String,
+    /// Origin the login pages are served from, checked in WebAuthn
+    /// client data.
+    pub webauthn_origin: String,
+}
+
+/// Client metadata accepted by dynamic client registration (RFC 7591).
+#[derive(Serialize, Deserialize)]
+pub struct ClientMetadata {
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    pub client_name: Option<String>,
+    #[serde(default)]
+    pub grant_types: Vec<String>,
+    pub token_endpoint_auth_method: Option<String>,
+    pub request_object_signing_alg: Option<String>,
+    /// Space-separated scopes the client may be granted.
+    pub scope: Option<String>,
+    pub logo_uri: Option<String>,
+    pub jwks: Option<JwkSet>,
+    /// Only meaningful on updates (RFC 7592), where it must match the path.
+    #[serde(skip_serializing)]
+    pub client_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ClientRegistrationResponse {
+    pub client_id: String,
+    /// Only returned when a secret is issued. Secrets are stored hashed, so
+    /// they cannot be read back later.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    pub client_id_issued_at: u64,
+    /// Client secrets do not expire.
+    pub client_secret_expires_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registration_access_token: Option<String>,
+    pub registration_client_uri: String,
+    #[serde(flatten)]
+    pub metadata: ClientMetadata,
+}
+
+#[derive(Serialize)]
+pub struct OAuthErrorResponse {
+    pub error: String,
+    pub error_description: String,
 }
 
 #[derive(Serialize)]
 pub struct UserInfoResponse {
     pub sub: String,
+    #[serde(flatten)]
+    pub claims: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The application calling the admin API, as read by handlers through
+/// `web::ReqData<ApiClient>`.
+#[derive(Clone)]
+pub struct ApiClient {
+    pub client_id: String,
+    pub scopes: Vec<String>,
 }
 
+#[derive(Serialize)]
+pub struct ApiErrorResponse {
+    pub error: String,
+    pub message: String,
+}
+
+#[derive(Deserialize)]
+pub struct ApiListQuery {
+    /// Matched against usernames and email addresses.
+    #[serde(default)]
+    pub q: String,
+    #[serde(default)]
+    pub skip: u64,
+    pub limit: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ApiUser {
+    pub id: String,
+    pub username: String,
+    #[serde(flatten)]
+    pub profile: UserProfile,
+    pub must_verify_email: bool,
+    pub totp_enabled: bool,
+    pub is_admin: bool,
+    pub disabled: bool,
+}
+
+#[derive(Serialize)]
+pub struct ApiUserList {
+    pub users: Vec<ApiUser>,
+    pub total: u64,
+}
+
+#[derive(Deserialize)]
+pub struct ApiCreateUserRequest {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub profile: UserProfile,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+/// Changes to a user. Omitted fields are left alone.
+#[derive(Deserialize)]
+pub struct ApiUpdateUserRequest {
+    pub password: Option<String>,
+    /// Replaces the whole profile.
+    pub profile: Option<UserProfile>,
+    pub is_admin: Option<bool>,
+    pub disabled: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct ApiClientSecret {
+    pub id: String,
+    pub label: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ApiApplication {
+    pub id: String,
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub logo_uri: Option<String>,
+    pub grant_types: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    pub token_endpoint_auth_method: Option<String>,
+    pub request_object_signing_alg: Option<String>,
+    pub access_token_lifetime: Option<u64>,
+    pub id_token_lifetime: Option<u64>,
+    pub require_verified_email: bool,
+    pub jwks: Option<JwkSet>,
+    pub secrets: Vec<ApiClientSecret>,
+}
+
+/// A new application, or changes to one. Omitted fields are left alone,
+/// or take their defaults for a new application.
+#[derive(Deserialize)]
+pub struct ApiApplicationRequest {
+    pub name: Option<String>,
+    pub redirect_uris: Option<Vec<String>>,
+    pub logo_uri: Option<String>,
+    pub grant_types: Option<Vec<String>>,
+    pub allowed_scopes: Option<Vec<String>>,
+    pub token_endpoint_auth_method: Option<String>,
+    pub request_object_signing_alg: Option<String>,
+    pub access_token_lifetime: Option<u64>,
+    pub id_token_lifetime: Option<u64>,
+    pub require_verified_email: Option<bool>,
+    pub jwks: Option<JwkSet>,
+}
+
+#[derive(Serialize)]
+pub struct ApiCreatedApplication {
+    #[serde(flatten)]
+    pub application: ApiApplication,
+    /// Only shown once.
+    pub client_secret: String,
+}
+
+#[derive(Deserialize)]
+pub struct ApiCreateSecretRequest {
+    #[serde(default)]
+    pub label: String,
+    /// Seconds since the epoch. Never expires when omitted.
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ApiCreatedSecret {
+    #[serde(flatten)]
+    pub secret: ApiClientSecret,
+    /// Only shown once.
+    pub client_secret: String,
+}
+
+#[derive(Serialize)]
+pub struct ApiSession {
+    pub id: String,
+    pub user_id: String,
+    /// Database ID of the application, as in `ApiApplication::id`.
+    pub application_id: String,
+    pub scopes: Vec<String>,
+    pub amr: Vec<String>,
+    pub auth_time: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Serialize)]
+pub struct ApiGroup {
+    pub id: String,
+    pub name: String,
+    pub external_id: Option<String>,
+    /// User IDs, as in `ApiUser::id`.
+    pub members: Vec<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+/// A new group, or changes to one. Omitted fields are left alone, or are
+/// empty for a new group.
+#[derive(Deserialize)]
+pub struct ApiGroupRequest {
+    pub name: Option<String>,
+    /// Replaces the whole member list.
+    pub members: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct ApiPolicyRule {
+    pub id: String,
+    #[serde(flatten)]
+    pub rule: PolicyRule,
+}
+
+#[derive(Deserialize)]
+pub struct ApiPolicyResource {
+    pub id: String,
+    #[serde(default)]
+    pub attributes: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiPolicyCheck {
+    /// User ID.
+    pub subject: String,
+    pub action: String,
+    pub resource: ApiPolicyResource,
+    #[serde(default)]
+    pub context: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiPolicyBatchCheck {
+    pub checks: Vec<ApiPolicyCheck>,
+}
+
+#[derive(Serialize)]
+pub struct ApiMatchedRule {
+    pub id: String,
+    pub name: String,
+    pub effect: PolicyEffect,
+}
+
+#[derive(Serialize)]
+pub struct ApiPolicyDecision {
+    pub allowed: bool,
+    pub decision: PolicyEffect,
+    pub matched_rule: Option<ApiMatchedRule>,
+    /// Why the request was denied when no rule decided it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+pub struct ApiPolicyBatchDecision {
+    pub results: Vec<ApiPolicyDecision>,
+}
+
+/// Query parameters of SCIM list requests.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimListQuery {
+    pub filter: Option<String>,
+    /// 1-based.
+    pub start_index: Option<u64>,
+    pub count: Option<u64>,
+    /// Comma-separated top-level attributes to return.
+    pub attributes: Option<String>,
+    /// Comma-separated top-level attributes to leave out.
+    pub excluded_attributes: Option<String>,
+}
+
+/// Query parameters of SCIM requests for a single resource.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimResourceQuery {
+    pub attributes: Option<String>,
+    pub excluded_attributes: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimName {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub formatted: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub given_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family_name: Option<String>,
+}
+
+/// An entry of a multi-valued attribute such as `emails`.
+#[derive(Serialize, Deserialize)]
+pub struct ScimMultiValue {
+    pub value: String,
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary: Option<bool>,
+}
+
+/// A reference to another resource, as in group `members`.
+#[derive(Serialize, Deserialize)]
+pub struct ScimReference {
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+    #[serde(default, rename = "$ref", skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimMeta {
+    pub resource_type: String,
+    pub created: String,
+    pub last_modified: String,
+    pub location: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUser {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    pub user_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<ScimName>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub emails: Vec<ScimMultiValue>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub photos: Vec<ScimMultiValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    /// Write-only.
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// Read-only. Groups are changed through their `members`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<ScimReference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScimMeta>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimGroup {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    pub display_name: String,
+    #[serde(default)]
+    pub members: Vec<ScimReference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScimMeta>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimListResponse {
+    pub schemas: Vec<String>,
+    pub total_results: usize,
+    pub start_index: usize,
+    pub items_per_page: usize,
+    #[serde(rename = "Resources")]
+    pub resources: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimError {
+    pub schemas: Vec<String>,
+    /// The HTTP status code, as a string.
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scim_type: Option<String>,
+    pub detail: String,
+}
+
+#[derive(Deserialize)]
+pub struct ScimPatchRequest {
+    #[serde(rename = "Operations")]
+    pub operations: Vec<ScimPatchOperation>,
+}
+
+#[derive(Deserialize)]
+pub struct ScimPatchOperation {
+    /// `add`, `replace` or `remove`, in any case.
+    pub op: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub value: Option<serde_json::Value>,
+}
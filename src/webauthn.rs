@@ -0,0 +1,476 @@
+use base64::{self, engine::Engine};
+use ciborium::value::Value;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::types::{Config, DbWebauthnCredential};
+
+/// COSE algorithm identifier for ECDSA with P-256 and SHA-256, the only
+/// algorithm we ask authenticators for. Every platform authenticator
+/// supports it.
+pub const COSE_ALG_ES256: i64 = -7;
+const COSE_KEY_TYPE_EC2: i64 = 2;
+const COSE_CURVE_P256: i64 = 1;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+const CHALLENGE_LENGTH: usize = 32;
+/// rpIdHash, flags and signCount.
+const AUTHENTICATOR_DATA_MIN_LENGTH: usize = 37;
+const AAGUID_LENGTH: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum WebauthnError {
+    Malformed(&'static str),
+    WrongCeremony,
+    ChallengeMismatch,
+    OriginMismatch,
+    RpIdMismatch,
+    UserNotPresent,
+    UserNotVerified,
+    UnsupportedKey,
+    InvalidSignature,
+    /// The signature counter went backwards, which suggests a cloned
+    /// authenticator.
+    CounterRegressed,
+}
+
+impl fmt::Display for WebauthnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebauthnError::Malformed(what) => write!(f, "malformed {}", what),
+            WebauthnError::WrongCeremony => write!(f, "client data is for another ceremony"),
+            WebauthnError::ChallengeMismatch => write!(f, "challenge does not match"),
+            WebauthnError::OriginMismatch => write!(f, "origin does not match"),
+            WebauthnError::RpIdMismatch => write!(f, "relying party ID does not match"),
+            WebauthnError::UserNotPresent => write!(f, "user presence flag not set"),
+            WebauthnError::UserNotVerified => write!(f, "user verification flag not set"),
+            WebauthnError::UnsupportedKey => write!(f, "credential key is not ES256"),
+            WebauthnError::InvalidSignature => write!(f, "signature is invalid"),
+            WebauthnError::CounterRegressed => write!(f, "signature counter went backwards"),
+        }
+    }
+}
+
+/// Where passkeys are used: the domain they are bound to and the origin
+/// browsers report.
+pub struct RelyingParty {
+    pub id: String,
+    pub origin: String,
+}
+
+impl RelyingParty {
+    pub fn from_config(config: &Config) -> RelyingParty {
+        RelyingParty {
+            id: config.webauthn_rp_id.clone(),
+            origin: config.webauthn_origin.clone(),
+        }
+    }
+}
+
+/// A credential from a successful registration ceremony.
+pub struct RegisteredCredential {
+    /// Base64url credential ID.
+    pub credential_id: String,
+    /// Base64url SEC1 encoded public key.
+    pub public_key: String,
+    pub sign_count: u32,
+}
+
+/// Result of a successful authentication ceremony.
+pub struct VerifiedAssertion {
+    pub sign_count: u32,
+    pub user_verified: bool,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    /// Credential ID and COSE public key, present on registration.
+    attested_credential: Option<(Vec<u8>, Value)>,
+}
+
+pub fn generate_challenge() -> String {
+    let mut challenge = [0u8; CHALLENGE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    base64url_encode(&challenge)
+}
+
+pub fn base64url_encode(data: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+}
+
+pub fn base64url_decode(data: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(data.trim_end_matches('='))
+        .ok()
+}
+
+fn check_client_data(
+    rp: &RelyingParty,
+    ceremony: &str,
+    challenge: &str,
+    client_data_json: &[u8],
+) -> Result<(), WebauthnError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| WebauthnError::Malformed("client data"))?;
+    if client_data.ceremony != ceremony {
+        return Err(WebauthnError::WrongCeremony);
+    }
+    if client_data.challenge != challenge {
+        return Err(WebauthnError::ChallengeMismatch);
+    }
+    if client_data.origin != rp.origin {
+        return Err(WebauthnError::OriginMismatch);
+    }
+    Ok(())
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, WebauthnError> {
+    if data.len() < AUTHENTICATOR_DATA_MIN_LENGTH {
+        return Err(WebauthnError::Malformed("authenticator data"));
+    }
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+    let mut attested_credential = None;
+    if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+        let rest = &data[AUTHENTICATOR_DATA_MIN_LENGTH..];
+        if rest.len() < AAGUID_LENGTH + 2 {
+            return Err(WebauthnError::Malformed("attested credential data"));
+        }
+        let id_length = u16::from_be_bytes([rest[AAGUID_LENGTH], rest[AAGUID_LENGTH + 1]]) as usize;
+        let rest = &rest[AAGUID_LENGTH + 2..];
+        if rest.len() < id_length {
+            return Err(WebauthnError::Malformed("attested credential data"));
+        }
+        let (credential_id, public_key) = rest.split_at(id_length);
+        // Extensions may follow the key, so only read one CBOR item.
+        let public_key: Value = ciborium::de::from_reader(public_key)
+            .map_err(|_| WebauthnError::Malformed("credential public key"))?;
+        attested_credential = Some((credential_id.to_vec(), public_key));
+    }
+    Ok(AuthenticatorData {
+        rp_id_hash: data[..32].to_vec(),
+        flags,
+        sign_count,
+        attested_credential,
+    })
+}
+
+fn check_authenticator_data(
+    rp: &RelyingParty,
+    data: &AuthenticatorData,
+    require_user_verification: bool,
+) -> Result<(), WebauthnError> {
+    if data.rp_id_hash != Sha256::digest(rp.id.as_bytes()).as_slice() {
+        return Err(WebauthnError::RpIdMismatch);
+    }
+    if data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(WebauthnError::UserNotPresent);
+    }
+    if require_user_verification && data.flags & FLAG_USER_VERIFIED == 0 {
+        return Err(WebauthnError::UserNotVerified);
+    }
+    Ok(())
+}
+
+fn cbor_map_get<'a>(map: &'a [(Value, Value)], key: &str) -> Option<&'a Value> {
+    map.iter()
+        .find(|(k, _)| k.as_text() == Some(key))
+        .map(|(_, v)| v)
+}
+
+fn cose_map_get(map: &[(Value, Value)], label: i64) -> Option<&Value> {
+    map.iter()
+        .find(|(k, _)| k.as_integer() == Some(label.into()))
+        .map(|(_, v)| v)
+}
+
+/// Converts an ES256 COSE key to an uncompressed SEC1 point.
+fn cose_key_to_sec1(key: &Value) -> Result<Vec<u8>, WebauthnError> {
+    let key = key.as_map().ok_or(WebauthnError::UnsupportedKey)?;
+    let integer = |label| cose_map_get(key, label).and_then(|v| v.as_integer());
+    if integer(1) != Some(COSE_KEY_TYPE_EC2.into())
+        || integer(3) != Some(COSE_ALG_ES256.into())
+        || integer(-1) != Some(COSE_CURVE_P256.into())
+    {
+        return Err(WebauthnError::UnsupportedKey);
+    }
+    let coordinate = |label| {
+        cose_map_get(key, label)
+            .and_then(|v| v.as_bytes())
+            .filter(|b| b.len() == 32)
+            .ok_or(WebauthnError::UnsupportedKey)
+    };
+    let mut point = vec![0x04];
+    point.extend(coordinate(-2)?);
+    point.extend(coordinate(-3)?);
+    VerifyingKey::from_sec1_bytes(&point).map_err(|_| WebauthnError::UnsupportedKey)?;
+    Ok(point)
+}
+
+/// Verifies the response to `navigator.credentials.create`. Attestation
+/// statements are not checked since we request `"none"` and do not restrict
+/// authenticator models.
+pub fn verify_registration(
+    rp: &RelyingParty,
+    challenge: &str,
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+) -> Result<RegisteredCredential, WebauthnError> {
+    check_client_data(rp, "webauthn.create", challenge, client_data_json)?;
+    let attestation: Value = ciborium::de::from_reader(attestation_object)
+        .map_err(|_| WebauthnError::Malformed("attestation object"))?;
+    let auth_data = attestation
+        .as_map()
+        .and_then(|m| cbor_map_get(m, "authData"))
+        .and_then(|v| v.as_bytes())
+        .ok_or(WebauthnError::Malformed("attestation object"))?;
+    let auth_data = parse_authenticator_data(auth_data)?;
+    check_authenticator_data(rp, &auth_data, false)?;
+    let (credential_id, public_key) = auth_data
+        .attested_credential
+        .ok_or(WebauthnError::Malformed("attested credential data"))?;
+    Ok(RegisteredCredential {
+        credential_id: base64url_encode(&credential_id),
+        public_key: base64url_encode(&cose_key_to_sec1(&public_key)?),
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// Verifies the response to `navigator.credentials.get` for a stored
+/// credential.
+pub fn verify_authentication(
+    rp: &RelyingParty,
+    challenge: &str,
+    credential: &DbWebauthnCredential,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+    require_user_verification: bool,
+) -> Result<VerifiedAssertion, WebauthnError> {
+    check_client_data(rp, "webauthn.get", challenge, client_data_json)?;
+    let parsed = parse_authenticator_data(authenticator_data)?;
+    check_authenticator_data(rp, &parsed, require_user_verification)?;
+    let public_key =
+        base64url_decode(&credential.public_key).ok_or(WebauthnError::UnsupportedKey)?;
+    let key =
+        VerifyingKey::from_sec1_bytes(&public_key).map_err(|_| WebauthnError::UnsupportedKey)?;
+    let signature = Signature::from_der(signature).map_err(|_| WebauthnError::InvalidSignature)?;
+    let mut signed = authenticator_data.to_vec();
+    signed.extend(Sha256::digest(client_data_json));
+    key.verify(&signed, &signature)
+        .map_err(|_| WebauthnError::InvalidSignature)?;
+    // Authenticators that do not count always report zero.
+    if (parsed.sign_count != 0 || credential.sign_count != 0)
+        && parsed.sign_count <= credential.sign_count
+    {
+        return Err(WebauthnError::CounterRegressed);
+    }
+    Ok(VerifiedAssertion {
+        sign_count: parsed.sign_count,
+        user_verified: parsed.flags & FLAG_USER_VERIFIED != 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ceremonies recorded from a software authenticator using an ES256 key,
+    /// for the relying party `auth.snazzyfellas.com`.
+    const REGISTRATION: &str = include_str!("../tests/fixtures/webauthn/registration.json");
+    const AUTHENTICATION: &str = include_str!("../tests/fixtures/webauthn/authentication.json");
+
+    fn fixture(json: &str) -> serde_json::Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn field(fixture: &serde_json::Value, name: &str) -> Vec<u8> {
+        base64url_decode(fixture[name].as_str().unwrap()).unwrap()
+    }
+
+    fn rp(fixture: &serde_json::Value) -> RelyingParty {
+        RelyingParty {
+            id: fixture["rp_id"].as_str().unwrap().to_owned(),
+            origin: fixture["origin"].as_str().unwrap().to_owned(),
+        }
+    }
+
+    fn stored_credential(fixture: &serde_json::Value, sign_count: u32) -> DbWebauthnCredential {
+        DbWebauthnCredential {
+            id: None,
+            user_id: bson::oid::ObjectId::new(),
+            credential_id: fixture["credential_id"].as_str().unwrap().to_owned(),
+            public_key: fixture["public_key"].as_str().unwrap().to_owned(),
+            sign_count,
+            name: "Test key".to_owned(),
+            created_at: 0,
+        }
+    }
+
+    fn authenticate(
+        fixture: &serde_json::Value,
+        rp: &RelyingParty,
+        challenge: &str,
+        credential: &DbWebauthnCredential,
+        signature: &[u8],
+    ) -> Result<VerifiedAssertion, WebauthnError> {
+        verify_authentication(
+            rp,
+            challenge,
+            credential,
+            &field(fixture, "client_data_json"),
+            &field(fixture, "authenticator_data"),
+            signature,
+            true,
+        )
+    }
+
+    #[test]
+    fn registration_extracts_credential() {
+        let fixture = fixture(REGISTRATION);
+        let credential = verify_registration(
+            &rp(&fixture),
+            fixture["challenge"].as_str().unwrap(),
+            &field(&fixture, "client_data_json"),
+            &field(&fixture, "attestation_object"),
+        )
+        .unwrap();
+        let authentication = self::fixture(AUTHENTICATION);
+        assert_eq!(credential.credential_id, authentication["credential_id"]);
+        assert_eq!(credential.public_key, authentication["public_key"]);
+        assert_eq!(credential.sign_count, 0);
+    }
+
+    #[test]
+    fn registration_rejects_wrong_challenge() {
+        let fixture = fixture(REGISTRATION);
+        let result = verify_registration(
+            &rp(&fixture),
+            &generate_challenge(),
+            &field(&fixture, "client_data_json"),
+            &field(&fixture, "attestation_object"),
+        );
+        assert_eq!(result.err(), Some(WebauthnError::ChallengeMismatch));
+    }
+
+    #[test]
+    fn registration_rejects_wrong_origin_and_rp_id() {
+        let fixture = fixture(REGISTRATION);
+        let challenge = fixture["challenge"].as_str().unwrap();
+        let client_data = field(&fixture, "client_data_json");
+        let attestation = field(&fixture, "attestation_object");
+        let mut other_origin = rp(&fixture);
+        other_origin.origin = "https://evil.example".to_owned();
+        let result = verify_registration(&other_origin, challenge, &client_data, &attestation);
+        assert_eq!(result.err(), Some(WebauthnError::OriginMismatch));
+        let mut other_rp = rp(&fixture);
+        other_rp.id = "evil.example".to_owned();
+        let result = verify_registration(&other_rp, challenge, &client_data, &attestation);
+        assert_eq!(result.err(), Some(WebauthnError::RpIdMismatch));
+    }
+
+    #[test]
+    fn registration_rejects_assertion_client_data() {
+        let registration = fixture(REGISTRATION);
+        let authentication = fixture(AUTHENTICATION);
+        let result = verify_registration(
+            &rp(&registration),
+            authentication["challenge"].as_str().unwrap(),
+            &field(&authentication, "client_data_json"),
+            &field(&registration, "attestation_object"),
+        );
+        assert_eq!(result.err(), Some(WebauthnError::WrongCeremony));
+    }
+
+    #[test]
+    fn authentication_accepts_valid_assertion() {
+        let fixture = fixture(AUTHENTICATION);
+        let assertion = authenticate(
+            &fixture,
+            &rp(&fixture),
+            fixture["challenge"].as_str().unwrap(),
+            &stored_credential(&fixture, 0),
+            &field(&fixture, "signature"),
+        )
+        .unwrap();
+        assert_eq!(assertion.sign_count, 1);
+        assert!(assertion.user_verified);
+    }
+
+    #[test]
+    fn authentication_rejects_tampered_signature() {
+        let fixture = fixture(AUTHENTICATION);
+        let mut signature = field(&fixture, "signature");
+        let last = signature.len() - 1;
+        signature[last] ^= 1;
+        let result = authenticate(
+            &fixture,
+            &rp(&fixture),
+            fixture["challenge"].as_str().unwrap(),
+            &stored_credential(&fixture, 0),
+            &signature,
+        );
+        assert_eq!(result.err(), Some(WebauthnError::InvalidSignature));
+    }
+
+    #[test]
+    fn authentication_rejects_other_key() {
+        let registration = fixture(REGISTRATION);
+        let fixture = fixture(AUTHENTICATION);
+        let mut credential = stored_credential(&fixture, 0);
+        // Not the key that signed the assertion.
+        let mut public_key = base64url_decode(&credential.public_key).unwrap();
+        public_key[64] ^= 1;
+        credential.public_key = base64url_encode(&public_key);
+        let result = authenticate(
+            &fixture,
+            &rp(&registration),
+            fixture["challenge"].as_str().unwrap(),
+            &credential,
+            &field(&fixture, "signature"),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn authentication_rejects_replayed_counter() {
+        let fixture = fixture(AUTHENTICATION);
+        let result = authenticate(
+            &fixture,
+            &rp(&fixture),
+            fixture["challenge"].as_str().unwrap(),
+            &stored_credential(&fixture, 1),
+            &field(&fixture, "signature"),
+        );
+        assert_eq!(result.err(), Some(WebauthnError::CounterRegressed));
+    }
+
+    #[test]
+    fn authentication_rejects_wrong_challenge() {
+        let fixture = fixture(AUTHENTICATION);
+        let result = authenticate(
+            &fixture,
+            &rp(&fixture),
+            &generate_challenge(),
+            &stored_credential(&fixture, 0),
+            &field(&fixture, "signature"),
+        );
+        assert_eq!(result.err(), Some(WebauthnError::ChallengeMismatch));
+    }
+}
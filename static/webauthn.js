@@ -0,0 +1,59 @@
+// Browser side of the passkey ceremonies. The server sends options with
+// base64url strings where WebAuthn wants ArrayBuffers, and expects the
+// responses back base64url encoded in a regular form post.
+
+function base64urlToBuffer(value) {
+  const base64 = value.replace(/-/g, "+").replace(/_/g, "/");
+  const binary = atob(base64 + "=".repeat((4 - (base64.length % 4)) % 4));
+  return Uint8Array.from(binary, (c) => c.charCodeAt(0)).buffer;
+}
+
+function bufferToBase64url(buffer) {
+  const binary = String.fromCharCode(...new Uint8Array(buffer));
+  return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+// `form` supplies the anti-CSRF token for the request.
+async function fetchOptions(url, form, params) {
+  const response = await fetch(url, {
+    method: "POST",
+    headers: {
+      "Content-Type": "application/x-www-form-urlencoded",
+      "X-CSRF-Token": form.csrf_token.value,
+    },
+    body: new URLSearchParams(params || {}),
+  });
+  if (!response.ok) {
+    throw new Error("Failed to start passkey ceremony");
+  }
+  return response.json();
+}
+
+function decodeDescriptors(descriptors) {
+  return descriptors.map((d) => ({ ...d, id: base64urlToBuffer(d.id) }));
+}
+
+// Logs in with a passkey, then posts the assertion with `form`.
+async function passkeyLogin(form, params) {
+  const options = await fetchOptions("/login/passkey/options", form, params);
+  options.challenge = base64urlToBuffer(options.challenge);
+  options.allowCredentials = decodeDescriptors(options.allowCredentials);
+  const credential = await navigator.credentials.get({ publicKey: options });
+  form.credential_id.value = credential.id;
+  form.client_data_json.value = bufferToBase64url(credential.response.clientDataJSON);
+  form.authenticator_data.value = bufferToBase64url(credential.response.authenticatorData);
+  form.signature.value = bufferToBase64url(credential.response.signature);
+  form.submit();
+}
+
+// Creates a passkey for the logged in account, then posts it with `form`.
+async function passkeyRegister(form) {
+  const options = await fetchOptions("/account/passkey/options", form);
+  options.challenge = base64urlToBuffer(options.challenge);
+  options.user.id = base64urlToBuffer(options.user.id);
+  options.excludeCredentials = decodeDescriptors(options.excludeCredentials);
+  const credential = await navigator.credentials.create({ publicKey: options });
+  form.client_data_json.value = bufferToBase64url(credential.response.clientDataJSON);
+  form.attestation_object.value = bufferToBase64url(credential.response.attestationObject);
+  form.submit();
+}